@@ -0,0 +1,256 @@
+use crate::characteristic::Characteristic;
+use crate::configuration::{ActionConfig, RelativeValue, SceneValue, SceneWrite};
+use crate::homebridge::{GroupFailure, GroupResult, HBError, Homebridge};
+use crate::override_detector::numeric_value;
+use log::{info, warn};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Program name recorded for writes made by actions.
+pub const ACTION_SOURCE: &str = "action";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ActionError {
+    #[error("{0}")]
+    ConfigError(String),
+    #[error("No action named '{0}'.")]
+    UnknownAction(String),
+    #[error("Error during Homebridge interaction: {0}")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("Scene '{scene}' was not fully applied: {result}")]
+    SceneIncomplete { scene: String, result: GroupResult },
+}
+
+/// Toggle and cycle actions run on request.
+#[derive(Debug, Default)]
+pub struct Actions {
+    scenes: BTreeMap<String, Vec<SceneWrite>>,
+    actions: BTreeMap<String, ActionConfig>,
+}
+
+impl Actions {
+    pub fn new(
+        scenes: &BTreeMap<String, Vec<SceneWrite>>,
+        actions: &BTreeMap<String, ActionConfig>,
+    ) -> Result<Self, ActionError> {
+        for (name, writes) in scenes.iter() {
+            for write in writes.iter() {
+                let SceneValue::Relative(relative) = &write.value else {
+                    continue;
+                };
+                if relative.percent < 0.0 {
+                    return Err(ActionError::ConfigError(format!(
+                        "Scene '{}' writes a negative share of a value to '{}'.",
+                        name, write.accessory
+                    )));
+                }
+                let unchanged = relative.from.is_none()
+                    && relative.percent == 100.0
+                    && relative
+                        .characteristic
+                        .as_ref()
+                        .map_or(true, |c| *c == write.characteristic);
+                if unchanged {
+                    return Err(ActionError::ConfigError(format!(
+                        "Scene '{}' writes {} of '{}' back unchanged; give `from` or `percent`.",
+                        name, write.characteristic, write.accessory
+                    )));
+                }
+            }
+        }
+        for (name, action) in actions.iter() {
+            if let ActionConfig::Cycle { scenes: cycle } = action {
+                if cycle.is_empty() {
+                    return Err(ActionError::ConfigError(format!(
+                        "Cycle action '{}' has no scenes.",
+                        name
+                    )));
+                }
+                if let Some(missing) = cycle.iter().find(|s| !scenes.contains_key(*s)) {
+                    return Err(ActionError::ConfigError(format!(
+                        "Cycle action '{}' refers to unknown scene '{}'.",
+                        name, missing
+                    )));
+                }
+            }
+        }
+        Ok(Self {
+            scenes: scenes.clone(),
+            actions: actions.clone(),
+        })
+    }
+
+    /// Run an action, returning what it did.
+    ///
+    /// `positions` holds the last scene index of each cycle action and is advanced here.
+    pub async fn run(
+        &self,
+        client: &Client,
+        homebridge: &mut Homebridge,
+        name: &str,
+        positions: &mut BTreeMap<String, usize>,
+    ) -> Result<String, ActionError> {
+        let action = self
+            .actions
+            .get(name)
+            .ok_or_else(|| ActionError::UnknownAction(name.to_string()))?;
+        match action {
+            ActionConfig::Toggle {
+                accessory,
+                characteristic,
+            } => {
+                let current = homebridge
+                    .get_characteristic(client, accessory, characteristic)
+                    .await?;
+                let on = numeric_value(&current).is_some_and(|v| v != 0.0);
+                homebridge
+                    .apply_values(
+                        client,
+                        ACTION_SOURCE,
+                        accessory,
+                        &[(characteristic.clone(), json!(!on as u8))],
+                        false,
+                    )
+                    .await?;
+                Ok(format!(
+                    "Turned {} of '{}' {}.",
+                    characteristic,
+                    accessory,
+                    if on { "off" } else { "on" }
+                ))
+            }
+            ActionConfig::Cycle { scenes } => {
+                let next = positions.get(name).map_or(0, |i| (i + 1) % scenes.len());
+                let scene = &scenes[next];
+                let result = self.apply_scene(client, homebridge, scene).await;
+                // A scene that reached some of its accessories counts as applied, so the next
+                // run moves on.
+                if !result.succeeded.is_empty() {
+                    positions.insert(name.to_string(), next);
+                }
+                if !result.is_complete() {
+                    return Err(ActionError::SceneIncomplete {
+                        scene: scene.clone(),
+                        result,
+                    });
+                }
+                Ok(format!(
+                    "Applied scene '{}' ({} of {}).",
+                    scene,
+                    next + 1,
+                    scenes.len()
+                ))
+            }
+        }
+    }
+
+    /// Write a scene's values as one group.
+    ///
+    /// Values taken from other accessories are all read before the first write. An accessory
+    /// with a value that cannot be read is left out of the group and counts as failed.
+    async fn apply_scene(
+        &self,
+        client: &Client,
+        homebridge: &mut Homebridge,
+        scene: &str,
+    ) -> GroupResult {
+        let mut writes: Vec<(&str, Characteristic, Value)> = Vec::new();
+        let mut failed: Vec<GroupFailure> = Vec::new();
+        for w in self.scenes[scene].iter() {
+            let value = match &w.value {
+                SceneValue::Fixed(value) => Ok(value.clone()),
+                SceneValue::Relative(relative) => resolve(client, homebridge, w, relative).await,
+            };
+            match value {
+                Ok(value) => writes.push((&w.accessory, w.characteristic.clone(), value)),
+                Err(error) => {
+                    warn!("Scene '{}' left out '{}': {}", scene, w.accessory, error);
+                    if failed.iter().any(|f| f.accessory == w.accessory) {
+                        continue;
+                    }
+                    failed.push(GroupFailure {
+                        accessory: w.accessory.clone(),
+                        error,
+                    });
+                }
+            }
+        }
+        writes.retain(|(accessory, _, _)| !failed.iter().any(|f| f.accessory == *accessory));
+        info!("Applying scene '{}'.", scene);
+        let mut result = homebridge.apply_group(client, ACTION_SOURCE, &writes).await;
+        result.failed.extend(failed);
+        result
+    }
+}
+
+/// Current value a relative scene value is taken from, scaled by its share.
+async fn resolve(
+    client: &Client,
+    homebridge: &mut Homebridge,
+    write: &SceneWrite,
+    relative: &RelativeValue,
+) -> Result<Value, String> {
+    let accessory = relative.from.as_deref().unwrap_or(&write.accessory);
+    let characteristic = relative
+        .characteristic
+        .as_ref()
+        .unwrap_or(&write.characteristic);
+    let current = homebridge
+        .get_characteristic(client, accessory, characteristic)
+        .await
+        .map_err(|e| e.to_string())?;
+    scale(&current, relative.percent).ok_or_else(|| {
+        format!(
+            "{} of '{}' is {}, not a number to take {}% of",
+            characteristic, accessory, current, relative.percent
+        )
+    })
+}
+
+/// `percent` of a characteristic value, rounded to a whole number unless the value has a
+/// fraction. Values other than numbers are only taken in full.
+fn scale(value: &Value, percent: f64) -> Option<Value> {
+    if percent == 100.0 {
+        return Some(value.clone());
+    }
+    let scaled = numeric_value(value)? * percent / 100.0;
+    match value.as_f64() {
+        Some(_) if !value.is_i64() && !value.is_u64() => Some(json!(scaled)),
+        _ => Some(json!(scaled.round() as i64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_values_taken_from_accessories() {
+        assert_eq!(scale(&json!(80), 70.0), Some(json!(56)));
+        assert_eq!(scale(&json!(45), 50.0), Some(json!(23)));
+        assert_eq!(scale(&json!(210.5), 50.0), Some(json!(105.25)));
+        assert_eq!(scale(&json!(true), 0.0), Some(json!(0)));
+        assert_eq!(scale(&json!("warm"), 100.0), Some(json!("warm")));
+        assert_eq!(scale(&json!("warm"), 50.0), None);
+    }
+
+    #[test]
+    fn rejects_scenes_writing_values_back_unchanged() {
+        let scenes = |value: Value| {
+            serde_json::from_value(json!({
+                "match": [{ "accessory": "Hallway", "characteristic": "Brightness", "value": value }]
+            }))
+            .unwrap()
+        };
+        let actions = BTreeMap::new();
+        assert!(Actions::new(&scenes(json!({ "from": "Living Room" })), &actions).is_ok());
+        assert!(Actions::new(&scenes(json!({ "percent": 70 })), &actions).is_ok());
+        assert!(Actions::new(&scenes(json!({})), &actions).is_err());
+        assert!(Actions::new(&scenes(json!({ "percent": -10 })), &actions).is_err());
+        assert!(serde_json::from_value::<SceneWrite>(json!({
+            "accessory": "Hallway", "characteristic": "Brightness", "value": { "form": "Lamp" }
+        }))
+        .is_err());
+    }
+}
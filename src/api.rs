@@ -0,0 +1,695 @@
+use crate::audit;
+use crate::audit_log::{self, AuditQuery};
+use crate::clock;
+use crate::configuration::{ApiScope, ApiTokenConfig, ControlApiConfig};
+use crate::control::{execute, ControlCommand, ControlError, SharedState};
+use crate::metrics;
+use chrono::{DateTime, Duration, Local};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, info, warn};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug)]
+struct SnoozeRequest {
+    until: Option<DateTime<Local>>,
+    duration: Option<String>,
+}
+
+impl SnoozeRequest {
+    fn into_command(self) -> Result<ControlCommand, ControlError> {
+        Ok(ControlCommand::Snooze { until: self.end()? })
+    }
+
+    /// The time given, or the duration from now.
+    fn end(self) -> Result<DateTime<Local>, ControlError> {
+        Ok(match (self.until, self.duration) {
+            (Some(until), None) => until,
+            (None, Some(duration)) => {
+                let duration = humantime::parse_duration(&duration).map_err(|e| {
+                    ControlError::InvalidCommand(format!("Invalid duration '{}': {}", duration, e))
+                })?;
+                let duration = Duration::from_std(duration).map_err(|e| {
+                    ControlError::InvalidCommand(format!("Duration out of range: {}", e))
+                })?;
+                clock::now() + duration
+            }
+            _ => {
+                return Err(ControlError::InvalidCommand(
+                    "Provide exactly one of `until` or `duration`.".to_string(),
+                ))
+            }
+        })
+    }
+}
+
+/// End given in a request body as the time or the duration, if the body is not empty.
+async fn read_end(req: Request<Body>) -> Result<Option<DateTime<Local>>, ControlError> {
+    let bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| ControlError::InvalidCommand(format!("Failed to read body: {}", e)))?;
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    serde_json::from_slice::<SnoozeRequest>(&bytes)
+        .map_err(|e| ControlError::InvalidCommand(format!("Invalid body: {}", e)))?
+        .end()
+        .map(Some)
+}
+
+/// Pause of a program until the time or for the duration of the body, or else until midnight.
+async fn read_pause(req: Request<Body>, program: String) -> Result<ControlCommand, ControlError> {
+    let until = read_end(req)
+        .await?
+        .unwrap_or_else(|| end_of_day(&clock::now()));
+    Ok(ControlCommand::PauseProgram { program, until })
+}
+
+/// The coming midnight.
+fn end_of_day(now: &DateTime<Local>) -> DateTime<Local> {
+    now.date_naive()
+        .succ_opt()
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+        .unwrap_or(*now + Duration::hours(12))
+}
+
+#[derive(Deserialize, Debug)]
+struct NudgeRequest {
+    accessory: String,
+    delta: i32,
+}
+
+/// Parse a JSON request body into a command.
+async fn read_command<T, F>(
+    req: Request<Body>,
+    into_command: F,
+) -> Result<ControlCommand, ControlError>
+where
+    T: for<'de> Deserialize<'de>,
+    F: FnOnce(T) -> Result<ControlCommand, ControlError>,
+{
+    match hyper::body::to_bytes(req.into_body()).await {
+        Ok(bytes) => serde_json::from_slice::<T>(&bytes)
+            .map_err(|e| ControlError::InvalidCommand(format!("Invalid body: {}", e)))
+            .and_then(into_command),
+        Err(e) => Err(ControlError::InvalidCommand(format!(
+            "Failed to read body: {}",
+            e
+        ))),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap_or_default()))
+        .expect("Valid response.")
+}
+
+fn error_response(status: StatusCode, msg: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": msg }))
+}
+
+fn command_response(
+    state: &SharedState,
+    command: Result<ControlCommand, ControlError>,
+) -> Response<Body> {
+    match command.and_then(|c| execute(state, c)) {
+        Ok(status) => json_response(StatusCode::OK, &status),
+        Err(ControlError::InvalidCommand(msg)) => error_response(StatusCode::BAD_REQUEST, &msg),
+        Err(ControlError::NotFound(msg)) => error_response(StatusCode::NOT_FOUND, &msg),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Program named by a `/programs/<name>/<verb>` path.
+fn program_path<'a>(path: &'a str, verb: &str) -> Option<&'a str> {
+    path.strip_prefix("/programs/")?
+        .strip_suffix(verb)?
+        .strip_suffix('/')
+        .filter(|name| !name.is_empty() && !name.contains('/'))
+}
+
+/// Program named by a `/programs/<name>/trigger` path.
+fn trigger_program(path: &str) -> Option<&str> {
+    program_path(path, "trigger")
+}
+
+/// Sensor named by a `/sensors/<name>` path, percent-decoded.
+fn sensor_name(path: &str) -> Option<String> {
+    let name = path.strip_prefix("/sensors/")?;
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    Some(percent_decode_str(name).decode_utf8_lossy().into_owned())
+}
+
+/// Action named by an `/actions/<name>` path, percent-decoded.
+fn action_name(path: &str) -> Option<String> {
+    let name = path.strip_prefix("/actions/")?;
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    Some(percent_decode_str(name).decode_utf8_lossy().into_owned())
+}
+
+/// Accessory named by an `/accessories/<name>/maintenance` path, percent-decoded.
+fn maintenance_accessory(path: &str) -> Option<String> {
+    let name = path
+        .strip_prefix("/accessories/")?
+        .strip_suffix("/maintenance")?;
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    Some(percent_decode_str(name).decode_utf8_lossy().into_owned())
+}
+
+/// Body of a sensor report: JSON, or plain text such as `ON` as sent by many DIY devices.
+async fn read_sensor_report(
+    req: Request<Body>,
+    name: String,
+) -> Result<ControlCommand, ControlError> {
+    let bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| ControlError::InvalidCommand(format!("Failed to read body: {}", e)))?;
+    let body = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).trim().to_string()));
+    Ok(ControlCommand::ReportSensor { name, body })
+}
+
+/// Query parameters as overrides; values that parse as JSON (numbers, booleans) are taken as
+/// such, anything else as a string.
+fn parse_overrides(query: Option<&str>) -> Result<Map<String, Value>, ControlError> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query.unwrap_or_default())
+        .map_err(|e| ControlError::InvalidCommand(format!("Invalid query: {}", e)))?;
+    Ok(pairs
+        .into_iter()
+        .map(|(key, value)| {
+            let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+            (key, value)
+        })
+        .collect())
+}
+
+/// Read-only status for dashboards, served without a token when enabled.
+const PUBLIC_STATUS_PATH: &str = "/public/status";
+
+/// The built-in dashboard, which loads its data from `/status/dashboard` with the token the
+/// user enters.
+const DASHBOARD_PAGE: &str = include_str!("dashboard.html");
+
+/// Scope needed for an endpoint, or `None` for unknown and public endpoints.
+fn required_scope(method: &Method, path: &str) -> Option<ApiScope> {
+    match (method, path) {
+        (
+            &Method::GET,
+            "/snooze"
+            | "/status/bridge"
+            | "/status/accessories"
+            | "/status/decisions"
+            | "/status/suntimes"
+            | "/status/dashboard"
+            | "/status/maintenance"
+            | "/audit"
+            | "/metrics",
+        ) => Some(ApiScope::ReadStatus),
+        (&Method::POST | &Method::DELETE, "/snooze") => Some(ApiScope::ControlPrograms),
+        (&Method::POST, "/nudge") => Some(ApiScope::ControlAccessories),
+        (&Method::POST, p) if trigger_program(p).is_some() => Some(ApiScope::ControlPrograms),
+        (&Method::POST, p)
+            if program_path(p, "disable").is_some() || program_path(p, "enable").is_some() =>
+        {
+            Some(ApiScope::ControlPrograms)
+        }
+        (&Method::POST, p) if action_name(p).is_some() => Some(ApiScope::ControlAccessories),
+        (&Method::POST, p) if sensor_name(p).is_some() => Some(ApiScope::ReportSensors),
+        (&Method::POST | &Method::DELETE, p) if maintenance_accessory(p).is_some() => {
+            Some(ApiScope::ControlAccessories)
+        }
+        _ => None,
+    }
+}
+
+/// Compare secrets without returning early on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Why a request was rejected.
+#[derive(Debug)]
+enum AuthError {
+    /// No or an unknown token (401).
+    Unauthenticated,
+    /// The token lacks the endpoint's scope (403).
+    Forbidden,
+}
+
+impl AuthError {
+    fn response(&self) -> Response<Body> {
+        match self {
+            AuthError::Unauthenticated => {
+                let mut response =
+                    error_response(StatusCode::UNAUTHORIZED, "Missing or unknown token.");
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Bearer"),
+                );
+                response
+            }
+            AuthError::Forbidden => {
+                error_response(StatusCode::FORBIDDEN, "Token does not allow this request.")
+            }
+        }
+    }
+}
+
+/// Check the request's bearer token, returning the token's name.
+///
+/// Without configured tokens, the API is open.
+fn authorize(
+    req: &Request<Body>,
+    tokens: &[ApiTokenConfig],
+    peer: &SocketAddr,
+) -> Result<Option<String>, AuthError> {
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    let Some(scope) = required_scope(req.method(), req.uri().path()) else {
+        return Ok(None);
+    };
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let Some(token) = presented.and_then(|p| {
+        tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.as_bytes(), p.as_bytes()))
+    }) else {
+        warn!(
+            target: audit::LOG_TARGET,
+            "Rejected {} {} from {}: missing or unknown token.",
+            req.method(),
+            req.uri().path(),
+            peer
+        );
+        return Err(AuthError::Unauthenticated);
+    };
+    if !token.scopes.contains(&scope) {
+        warn!(
+            target: audit::LOG_TARGET,
+            "Rejected {} {} from {}: token '{}' lacks scope {:?}.",
+            req.method(),
+            req.uri().path(),
+            peer,
+            token.name,
+            scope
+        );
+        return Err(AuthError::Forbidden);
+    }
+    Ok(Some(token.name.clone()))
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: SharedState,
+    tokens: Arc<Vec<ApiTokenConfig>>,
+    public_status: bool,
+    peer: SocketAddr,
+) -> Result<Response<Body>, Infallible> {
+    debug!("Control API request: {} {}", req.method(), req.uri().path());
+    let token_name = match authorize(&req, &tokens, &peer) {
+        Ok(name) => name,
+        Err(e) => return Ok(e.response()),
+    };
+    if req.method() != Method::GET {
+        info!(
+            target: audit::LOG_TARGET,
+            "{} {} from {} (token '{}').",
+            req.method(),
+            req.uri().path(),
+            peer,
+            token_name.as_deref().unwrap_or("none")
+        );
+    }
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/snooze") => command_response(&state, Ok(ControlCommand::SnoozeStatus)),
+        (&Method::DELETE, "/snooze") => command_response(&state, Ok(ControlCommand::Unsnooze)),
+        (&Method::POST, "/snooze") => {
+            let command = read_command(req, SnoozeRequest::into_command).await;
+            command_response(&state, command)
+        }
+        (&Method::POST, "/nudge") => {
+            let command = read_command(req, |n: NudgeRequest| {
+                Ok(ControlCommand::Nudge {
+                    accessory: n.accessory,
+                    delta: n.delta,
+                })
+            })
+            .await;
+            command_response(&state, command)
+        }
+        (&Method::POST, p) if action_name(p).is_some() => {
+            let name = action_name(p).expect("Action path.");
+            command_response(&state, Ok(ControlCommand::RunAction { name }))
+        }
+        (&Method::POST, p) if trigger_program(p).is_some() => {
+            let command = parse_overrides(req.uri().query()).map(|overrides| {
+                ControlCommand::TriggerProgram {
+                    program: trigger_program(p).expect("Trigger path.").to_string(),
+                    overrides,
+                }
+            });
+            command_response(&state, command)
+        }
+        (&Method::POST, p) if program_path(p, "disable").is_some() => {
+            let program = program_path(p, "disable")
+                .expect("Disable path.")
+                .to_string();
+            let command = read_pause(req, program).await;
+            command_response(&state, command)
+        }
+        (&Method::POST, p) if program_path(p, "enable").is_some() => {
+            let program = program_path(p, "enable").expect("Enable path.").to_string();
+            command_response(&state, Ok(ControlCommand::ResumeProgram { program }))
+        }
+        (&Method::POST, p) if sensor_name(p).is_some() => {
+            let name = sensor_name(p).expect("Sensor path.");
+            let command = read_sensor_report(req, name).await;
+            command_response(&state, command)
+        }
+        (&Method::POST, p) if maintenance_accessory(p).is_some() => {
+            let accessory = maintenance_accessory(p).expect("Maintenance path.");
+            let command = read_end(req)
+                .await
+                .map(|until| ControlCommand::StartMaintenance { accessory, until });
+            command_response(&state, command)
+        }
+        (&Method::DELETE, p) if maintenance_accessory(p).is_some() => {
+            let accessory = maintenance_accessory(p).expect("Maintenance path.");
+            command_response(&state, Ok(ControlCommand::EndMaintenance { accessory }))
+        }
+        (&Method::GET, "/status/bridge") => {
+            let state = state.lock().expect("State lock poisoned.");
+            match &state.bridge_status {
+                Some(status) => json_response(StatusCode::OK, status),
+                None => error_response(StatusCode::NOT_FOUND, "No bridge status yet."),
+            }
+        }
+        (&Method::GET, "/status/accessories") => {
+            let state = state.lock().expect("State lock poisoned.");
+            json_response(StatusCode::OK, &state.accessory_latency)
+        }
+        (&Method::GET, "/status/suntimes") => {
+            let state = state.lock().expect("State lock poisoned.");
+            match &state.suntimes {
+                Some(freshness) => json_response(StatusCode::OK, freshness),
+                None => error_response(StatusCode::NOT_FOUND, "No sun times yet."),
+            }
+        }
+        (&Method::GET, "/status/decisions") => {
+            let state = state.lock().expect("State lock poisoned.");
+            json_response(StatusCode::OK, &state.decisions.timeline())
+        }
+        (&Method::GET, "/status/maintenance") => {
+            let state = state.lock().expect("State lock poisoned.");
+            json_response(StatusCode::OK, &state.maintenance.current(&clock::now()))
+        }
+        (&Method::GET, "/status/dashboard") => {
+            let mut state = state.lock().expect("State lock poisoned.");
+            json_response(StatusCode::OK, &state.dashboard_status(&clock::now()))
+        }
+        (&Method::GET, "/audit") => {
+            let path = state
+                .lock()
+                .expect("State lock poisoned.")
+                .audit_log
+                .clone();
+            let query = serde_urlencoded::from_str::<AuditQuery>(req.uri().query().unwrap_or(""));
+            match (path, query) {
+                (None, _) => error_response(StatusCode::NOT_FOUND, "No audit log configured."),
+                (_, Err(e)) => {
+                    error_response(StatusCode::BAD_REQUEST, &format!("Invalid query: {}", e))
+                }
+                (Some(path), Ok(query)) => match audit_log::query(&path, &query) {
+                    Ok(records) => json_response(StatusCode::OK, &records),
+                    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                },
+            }
+        }
+        (&Method::GET, "/dashboard") => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD_PAGE))
+            .expect("Valid response."),
+        (&Method::GET, PUBLIC_STATUS_PATH) if public_status => {
+            let mut state = state.lock().expect("State lock poisoned.");
+            let mut response = json_response(StatusCode::OK, &state.public_status(&clock::now()));
+            // Dashboards may load it from pages served elsewhere.
+            response.headers_mut().insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                header::HeaderValue::from_static("*"),
+            );
+            response
+        }
+        (&Method::GET, "/metrics") => {
+            let body = metrics::render(&state.lock().expect("State lock poisoned."));
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(body))
+                .expect("Valid response.")
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "Unknown endpoint."),
+    };
+    Ok(response)
+}
+
+/// Serve the control API until the server fails.
+pub async fn serve(config: ControlApiConfig, state: SharedState) {
+    let address = config.address;
+    if config.tokens.is_empty() {
+        warn!("No control API tokens configured - the API accepts unauthenticated requests.");
+    }
+    if config.public_status {
+        info!(
+            "Serving the read-only program status at {} without a token.",
+            PUBLIC_STATUS_PATH
+        );
+    }
+    let tokens = Arc::new(config.tokens);
+    let public_status = config.public_status;
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let tokens = tokens.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, state.clone(), tokens.clone(), public_status, peer)
+            }))
+        }
+    });
+    let builder = match Server::try_bind(&address) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Could not bind control API to {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Started control API on {}.", address);
+    if let Err(e) = builder.serve(make_svc).await {
+        error!("Control API stopped: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trigger_paths_and_overrides() {
+        assert_eq!(
+            trigger_program("/programs/morning_light/trigger"),
+            Some("morning_light")
+        );
+        assert_eq!(trigger_program("/programs//trigger"), None);
+        assert_eq!(trigger_program("/programs/a/b/trigger"), None);
+        assert_eq!(
+            program_path("/programs/control_evening_lights:office/disable", "disable"),
+            Some("control_evening_lights:office")
+        );
+        assert_eq!(program_path("/programs/irrigation/enabled", "enable"), None);
+
+        let overrides = parse_overrides(Some("duration=20&light=Bed%20Light")).unwrap();
+        assert_eq!(overrides["duration"], json!(20));
+        assert_eq!(overrides["light"], json!("Bed Light"));
+        assert!(parse_overrides(None).unwrap().is_empty());
+
+        assert_eq!(
+            sensor_name("/sensors/Garage%20Door"),
+            Some("Garage Door".to_string())
+        );
+        assert_eq!(sensor_name("/sensors/"), None);
+        assert_eq!(
+            maintenance_accessory("/accessories/Bed%20Light/maintenance"),
+            Some("Bed Light".to_string())
+        );
+        assert_eq!(maintenance_accessory("/accessories//maintenance"), None);
+        assert_eq!(maintenance_accessory("/accessories/Bed Light"), None);
+        assert_eq!(
+            action_name("/actions/Movie%20Night"),
+            Some("Movie Night".to_string())
+        );
+        assert_eq!(action_name("/actions/"), None);
+    }
+
+    #[test]
+    fn public_status_needs_no_scope() {
+        assert_eq!(required_scope(&Method::GET, PUBLIC_STATUS_PATH), None);
+        assert_eq!(required_scope(&Method::GET, "/dashboard"), None);
+        assert_eq!(
+            required_scope(&Method::GET, "/status/decisions"),
+            Some(ApiScope::ReadStatus)
+        );
+        assert_eq!(
+            required_scope(&Method::DELETE, "/accessories/Bed%20Light/maintenance"),
+            Some(ApiScope::ControlAccessories)
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/audit"),
+            Some(ApiScope::ReadStatus)
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_only_known_actions() {
+        use crate::control::ControllerState;
+        use crate::state::StateStore;
+        use std::sync::Mutex;
+
+        let path = std::env::temp_dir().join(format!("hb-api-actions-{}.json", std::process::id()));
+        let mut controller = ControllerState::new(StateStore::load(&path).unwrap());
+        controller.action_names = vec!["Movie Night".to_string()];
+        let state: SharedState = Arc::new(Mutex::new(controller));
+        let post = |path: &str| {
+            let request = Request::post(path).body(Body::empty()).unwrap();
+            let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+            handle(request, state.clone(), Arc::new(Vec::new()), false, peer)
+        };
+
+        let response = post("/actions/Movie%20Night").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post("/actions/Movie%20Nite").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            state.lock().unwrap().actions,
+            vec!["Movie Night".to_string()]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn maintenance_is_kept_and_found_by_unique_id() {
+        use crate::control::ControllerState;
+        use crate::homebridge::{AccessoryCache, AccessoryCapabilities};
+        use crate::state::StateStore;
+        use std::sync::Mutex;
+
+        let path =
+            std::env::temp_dir().join(format!("hb-api-maintenance-{}.json", std::process::id()));
+        let mut store = StateStore::load(&path).unwrap();
+        let mut cache = AccessoryCache::default();
+        cache.accessories.insert(
+            "abc123".to_string(),
+            AccessoryCapabilities {
+                service_name: "Bed Light".to_string(),
+                characteristics: vec!["On".to_string()],
+                read_only: Vec::new(),
+            },
+        );
+        store.update(|s| s.accessory_cache = cache).unwrap();
+        let state: SharedState = Arc::new(Mutex::new(ControllerState::new(store)));
+        let request = |method: Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+            handle(request, state.clone(), Arc::new(Vec::new()), false, peer)
+        };
+
+        let response = request(Method::POST, "/accessories/Bed%20Light/maintenance")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Also after a restart, and when addressed by `uniqueId`.
+        let restarted = ControllerState::new(StateStore::load(&path).unwrap());
+        let key = restarted.accessory_key("uniqueId:abc123");
+        assert!(restarted.maintenance.find(&key, &clock::now()).is_some());
+        let nudge = ControlCommand::Nudge {
+            accessory: "uniqueId:abc123".to_string(),
+            delta: 10,
+        };
+        let error = execute(&state, nudge).unwrap_err().to_string();
+        assert!(error.contains("'Bed Light' is in maintenance"), "{}", error);
+        let response = request(Method::DELETE, "/accessories/uniqueId:abc123/maintenance")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let restarted = ControllerState::new(StateStore::load(&path).unwrap());
+        assert!(restarted.maintenance.current(&clock::now()).is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn pauses_are_kept_under_the_full_program_name() {
+        use crate::control::ControllerState;
+        use crate::state::StateStore;
+        use std::sync::Mutex;
+
+        let path = std::env::temp_dir().join(format!("hb-api-pauses-{}.json", std::process::id()));
+        let state: SharedState = Arc::new(Mutex::new(ControllerState::new(
+            StateStore::load(&path).unwrap(),
+        )));
+        let post = |path: &str, body: &str| {
+            let request = Request::post(path)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+            handle(request, state.clone(), Arc::new(Vec::new()), false, peer)
+        };
+
+        let response = post("/programs/evening_lights/disable", r#"{"duration": "2h"}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post("/programs/evening/disable", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // Also after a restart, and for the instances of the program.
+        let restarted = ControllerState::new(StateStore::load(&path).unwrap());
+        let now = clock::now();
+        assert!(restarted
+            .paused_until("control_evening_lights:office", &now)
+            .is_some());
+
+        let response = post("/programs/control_evening_lights/enable", "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post("/programs/evening_lights/enable", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let restarted = ControllerState::new(StateStore::load(&path).unwrap());
+        assert!(restarted
+            .paused_until("control_evening_lights", &now)
+            .is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+}
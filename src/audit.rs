@@ -0,0 +1,92 @@
+use chrono::{DateTime, Local};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+
+/// Log target of audit entries, e.g. for routing them to their own file.
+pub const LOG_TARGET: &str = "homebridge_controller::audit";
+
+/// Number of writes kept in memory by default.
+const DEFAULT_JOURNAL_CAPACITY: usize = 500;
+
+/// A single characteristic write issued by the controller.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WriteRecord {
+    pub when: DateTime<Local>,
+    pub program: String,
+    pub accessory: String,
+    pub characteristic: String,
+    pub before: Option<Value>,
+    pub after: Value,
+}
+
+/// Consumer of write records (e.g. history database or metrics).
+pub trait AuditSink: Send + Sync {
+    fn record(&mut self, record: &WriteRecord);
+}
+
+/// Journal of the controller's own writes.
+///
+/// Every accessory write goes through `Homebridge`, which hands a record to the journal. The
+/// journal logs it, keeps the most recent records in memory, and forwards it to any registered
+/// sinks.
+pub struct WriteJournal {
+    capacity: usize,
+    records: VecDeque<WriteRecord>,
+    sinks: Vec<Box<dyn AuditSink>>,
+}
+
+impl Default for WriteJournal {
+    fn default() -> Self {
+        Self::new(DEFAULT_JOURNAL_CAPACITY)
+    }
+}
+
+impl WriteJournal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn AuditSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn record(&mut self, record: WriteRecord) {
+        info!(
+            "[{}] {} '{}': {} -> {}",
+            record.program,
+            record.characteristic,
+            record.accessory,
+            record
+                .before
+                .as_ref()
+                .map_or("?".to_string(), |v| v.to_string()),
+            record.after
+        );
+        for sink in self.sinks.iter_mut() {
+            sink.record(&record);
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Recorded writes, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &WriteRecord> {
+        self.records.iter()
+    }
+
+    /// Most recent write to a characteristic of an accessory.
+    pub fn last_write(&self, accessory: &str, characteristic: &str) -> Option<&WriteRecord> {
+        self.records
+            .iter()
+            .rev()
+            .find(|r| r.accessory == accessory && r.characteristic == characteristic)
+    }
+}
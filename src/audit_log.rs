@@ -0,0 +1,256 @@
+use crate::audit::{AuditSink, WriteRecord};
+use crate::configuration::AuditLogConfig;
+use crate::time_format;
+use chrono::{DateTime, Duration, Local};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuditLogError {
+    #[error("Failed to read '{0}': {1}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Failed to write '{0}': {1}")]
+    Write(PathBuf, #[source] io::Error),
+}
+
+/// Which writes to list; every field left out matches all of them.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuditQuery {
+    #[serde(default, deserialize_with = "time_format::deserialize_local_option")]
+    pub since: Option<DateTime<Local>>,
+    #[serde(default, deserialize_with = "time_format::deserialize_local_option")]
+    pub until: Option<DateTime<Local>>,
+    /// Program name, e.g. `evening_lights`.
+    pub program: Option<String>,
+    pub accessory: Option<String>,
+    pub characteristic: Option<String>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &WriteRecord) -> bool {
+        self.since.map_or(true, |since| record.when >= since)
+            && self.until.map_or(true, |until| record.when <= until)
+            && self.program.as_ref().map_or(true, |p| &record.program == p)
+            && self
+                .accessory
+                .as_ref()
+                .map_or(true, |a| &record.accessory == a)
+            && self
+                .characteristic
+                .as_ref()
+                .map_or(true, |c| &record.characteristic == c)
+    }
+}
+
+/// Appends every write of the controller to a file, one JSON record per line, so they can be
+/// looked up after a restart.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    /// Open the log for appending, first dropping records older than the configured retention.
+    pub fn open(config: &AuditLogConfig, now: &DateTime<Local>) -> Result<Self, AuditLogError> {
+        let cutoff = *now - Duration::days(config.keep_days);
+        let dropped = prune(&config.path, &cutoff)?;
+        if dropped > 0 {
+            info!(
+                "Dropped {} write(s) older than {} day(s) from the audit log.",
+                dropped, config.keep_days
+            );
+        }
+        if let Some(dir) = config.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| AuditLogError::Write(dir.to_path_buf(), e))?;
+        }
+        let write_error = |e| AuditLogError::Write(config.path.clone(), e);
+        // A line cut short by a crash is ended, so the next record starts on its own line.
+        let cut_short = fs::read(&config.path)
+            .ok()
+            .and_then(|contents| contents.last().copied())
+            .is_some_and(|last| last != b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .map_err(write_error)?;
+        if cut_short {
+            writeln!(file).map_err(write_error)?;
+        }
+        Ok(Self {
+            path: config.path.clone(),
+            file,
+        })
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&mut self, record: &WriteRecord) {
+        let written = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.file, "{}", line));
+        if let Err(e) = written {
+            error!("Failed to append to '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Records of the log at `path`, oldest first; a missing log has none.
+fn read_all(path: &Path) -> Result<Vec<WriteRecord>, AuditLogError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(AuditLogError::Read(path.to_path_buf(), e)),
+    };
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| AuditLogError::Read(path.to_path_buf(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A malformed line should not hide the rest of the log.
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping line {} of '{}': {}", i + 1, path.display(), e),
+        }
+    }
+    Ok(records)
+}
+
+/// Writes in the log at `path` matching the query, oldest first.
+pub fn query(path: &Path, query: &AuditQuery) -> Result<Vec<WriteRecord>, AuditLogError> {
+    let mut records = read_all(path)?;
+    records.retain(|r| query.matches(r));
+    Ok(records)
+}
+
+/// Remove records from before `cutoff`, returning how many were removed.
+pub fn prune(path: &Path, cutoff: &DateTime<Local>) -> Result<usize, AuditLogError> {
+    let records = read_all(path)?;
+    let kept: Vec<&WriteRecord> = records.iter().filter(|r| r.when >= *cutoff).collect();
+    let dropped = records.len() - kept.len();
+    if dropped == 0 {
+        return Ok(0);
+    }
+    let mut contents = String::new();
+    for record in kept {
+        let line = serde_json::to_string(record)
+            .map_err(|e| AuditLogError::Write(path.to_path_buf(), e.into()))?;
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    // Replace the log in one step, so a failure leaves the old one in place.
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents)
+        .and_then(|()| fs::rename(&temporary, path))
+        .map_err(|e| AuditLogError::Write(path.to_path_buf(), e))?;
+    Ok(dropped)
+}
+
+/// One line describing a write, as printed by the `audit` subcommand.
+pub fn describe(record: &WriteRecord) -> String {
+    format!(
+        "{} [{}] {} '{}': {} -> {}",
+        record.when.format("%Y-%m-%d %H:%M:%S"),
+        record.program,
+        record.characteristic,
+        record.accessory,
+        record
+            .before
+            .as_ref()
+            .map_or("?".to_string(), |v| v.to_string()),
+        record.after
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write(when: DateTime<Local>, program: &str, accessory: &str) -> WriteRecord {
+        WriteRecord {
+            when,
+            program: program.to_string(),
+            accessory: accessory.to_string(),
+            characteristic: "Brightness".to_string(),
+            before: Some(json!(40)),
+            after: json!(60),
+        }
+    }
+
+    #[test]
+    fn appends_queries_and_prunes_writes() {
+        let dir = std::env::temp_dir().join(format!("hb-audit-log-{}", std::process::id()));
+        let config = AuditLogConfig {
+            path: dir.join("audit.jsonl"),
+            keep_days: 7,
+        };
+        let now = Local::now();
+        let mut log = AuditLog::open(&config, &now).unwrap();
+        log.record(&write(now - Duration::hours(10), "evening_lights", "Lamp"));
+        log.record(&write(now - Duration::hours(9), "morning_light", "Lamp"));
+        log.record(&write(now - Duration::hours(8), "evening_lights", "Hall"));
+        drop(log);
+        // A line cut short by a crash is skipped, and the next record starts on a new line.
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        write!(file, "{{\"when\": \"2024").unwrap();
+        let mut log = AuditLog::open(&config, &now).unwrap();
+        log.record(&write(now - Duration::days(10), "evening_lights", "Lamp"));
+        drop(log);
+        assert_eq!(
+            query(&config.path, &AuditQuery::default()).unwrap().len(),
+            4
+        );
+
+        let last_night = AuditQuery {
+            since: Some(now - Duration::hours(12)),
+            program: Some("evening_lights".to_string()),
+            ..AuditQuery::default()
+        };
+        let found = query(&config.path, &last_night).unwrap();
+        assert_eq!(
+            found
+                .iter()
+                .map(|r| r.accessory.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Lamp", "Hall"]
+        );
+        assert!(describe(&found[0]).ends_with("[evening_lights] Brightness 'Lamp': 40 -> 60"));
+
+        // Reopening drops the write from before the retention.
+        AuditLog::open(&config, &now).unwrap();
+        assert_eq!(
+            query(&config.path, &AuditQuery::default()).unwrap().len(),
+            3
+        );
+        assert!(query(&dir.join("missing.jsonl"), &AuditQuery::default())
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_api_queries() {
+        let query: AuditQuery =
+            serde_urlencoded::from_str("since=2024-12-01T22:00&accessory=Bed%20Light").unwrap();
+        assert_eq!(
+            query.since,
+            time_format::parse_local("2024-12-01T22:00").ok()
+        );
+        assert_eq!(query.accessory.as_deref(), Some("Bed Light"));
+        assert_eq!(query.until, None);
+        let offset: AuditQuery =
+            serde_urlencoded::from_str("until=2024-12-02T06:00:00%2B01:00").unwrap();
+        assert_eq!(
+            offset.until,
+            time_format::parse_local("2024-12-02T05:00:00Z").ok()
+        );
+        assert!(serde_urlencoded::from_str::<AuditQuery>("since=yesterday").is_err());
+        assert!(serde_urlencoded::from_str::<AuditQuery>("room=Kitchen").is_err());
+    }
+}
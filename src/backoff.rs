@@ -0,0 +1,96 @@
+use crate::time_format;
+use chrono::{DateTime, Duration, Local};
+use log::{info, warn};
+use std::collections::HashMap;
+
+/// Failures in a row before a program is held back.
+const FAILURES_BEFORE_BACKOFF: u32 = 2;
+const MIN_BACKOFF_MINUTES: i64 = 1;
+const MAX_BACKOFF_MINUTES: i64 = 30;
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    retry_at: Option<DateTime<Local>>,
+}
+
+/// Holds back programs that keep failing, each on its own schedule.
+///
+/// A program whose accessories are unreachable otherwise waits for its requests to fail on
+/// every loop, delaying the programs after it. After repeated failures it is only run again
+/// once its backoff (doubling from 1 up to 30 minutes) has passed; one success resets it.
+#[derive(Debug, Default)]
+pub struct ProgramBackoff {
+    programs: HashMap<String, Failures>,
+}
+
+impl ProgramBackoff {
+    /// When the program may run again, if it is backing off at `now`.
+    pub fn retry_at(&self, program: &str, now: &DateTime<Local>) -> Option<DateTime<Local>> {
+        self.programs
+            .get(program)
+            .and_then(|f| f.retry_at)
+            .filter(|t| now < t)
+    }
+
+    pub fn record(&mut self, program: &str, succeeded: bool, now: &DateTime<Local>) {
+        if succeeded {
+            if self
+                .programs
+                .remove(program)
+                .is_some_and(|f| f.retry_at.is_some())
+            {
+                info!("{} succeeded again - no longer backing off.", program);
+            }
+            return;
+        }
+        let failures = self
+            .programs
+            .entry(program.to_string())
+            .or_insert(Failures {
+                count: 0,
+                retry_at: None,
+            });
+        failures.count += 1;
+        if failures.count >= FAILURES_BEFORE_BACKOFF {
+            let exponent = (failures.count - FAILURES_BEFORE_BACKOFF).min(16);
+            let minutes = (MIN_BACKOFF_MINUTES * 2_i64.pow(exponent)).min(MAX_BACKOFF_MINUTES);
+            let retry_at = *now + Duration::minutes(minutes);
+            warn!(
+                "{} failed {} times in a row - backing off until {}.",
+                program,
+                failures.count,
+                time_format::show(&retry_at)
+            );
+            failures.retry_at = Some(retry_at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn backs_off_after_repeated_failures() {
+        let now = Local.with_ymd_and_hms(2024, 12, 1, 18, 0, 0).unwrap();
+        let mut backoff = ProgramBackoff::default();
+        backoff.record("evening", false, &now);
+        assert_eq!(backoff.retry_at("evening", &now), None);
+        backoff.record("evening", false, &now);
+        assert_eq!(
+            backoff.retry_at("evening", &now),
+            Some(now + Duration::minutes(1))
+        );
+        backoff.record("evening", false, &now);
+        assert_eq!(
+            backoff.retry_at("evening", &now),
+            Some(now + Duration::minutes(2))
+        );
+        assert_eq!(backoff.retry_at("morning", &now), None);
+
+        backoff.record("evening", true, &now);
+        assert_eq!(backoff.retry_at("evening", &now), None);
+    }
+}
@@ -0,0 +1,119 @@
+use chrono::{DateTime, Local};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Format version of backup archives written by this build.
+const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("Failed to read '{0}': {1}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Failed to write '{0}': {1}")]
+    Write(PathBuf, #[source] io::Error),
+    #[error("'{0}' is not a backup archive: {1}")]
+    Invalid(PathBuf, #[source] serde_json::Error),
+    #[error("Backup archive version {0} is not supported (expected {ARCHIVE_VERSION}).")]
+    UnsupportedVersion(u32),
+    #[error("'{0}' already exists; restore with `--force` to overwrite it.")]
+    Exists(PathBuf),
+}
+
+/// Configuration and state files of the controller, bundled into a single JSON document.
+///
+/// Files are kept by the path they were read from, so restoring in the same working
+/// directory puts them back where the configuration expects them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Archive {
+    pub version: u32,
+    pub created: DateTime<Local>,
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+impl Archive {
+    /// Read the files into an archive; files that do not exist (yet) are left out.
+    pub fn collect(paths: &[PathBuf], created: DateTime<Local>) -> Result<Self, BackupError> {
+        let mut files = BTreeMap::new();
+        for path in paths.iter() {
+            match fs::read_to_string(path) {
+                Ok(contents) => {
+                    files.insert(path.clone(), contents);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    info!("No '{}' to back up.", path.display());
+                }
+                Err(e) => return Err(BackupError::Read(path.clone(), e)),
+            }
+        }
+        Ok(Self {
+            version: ARCHIVE_VERSION,
+            created,
+            files,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), BackupError> {
+        let json = serde_json::to_string_pretty(self).expect("Archive serializes.");
+        fs::write(path, json).map_err(|e| BackupError::Write(path.to_path_buf(), e))
+    }
+
+    pub fn read(path: &Path) -> Result<Self, BackupError> {
+        let json =
+            fs::read_to_string(path).map_err(|e| BackupError::Read(path.to_path_buf(), e))?;
+        let archive: Self =
+            serde_json::from_str(&json).map_err(|e| BackupError::Invalid(path.to_path_buf(), e))?;
+        if archive.version != ARCHIVE_VERSION {
+            return Err(BackupError::UnsupportedVersion(archive.version));
+        }
+        Ok(archive)
+    }
+
+    /// Write the files back to their paths, refusing to overwrite any unless `force` is set.
+    pub fn restore(&self, force: bool) -> Result<Vec<PathBuf>, BackupError> {
+        if !force {
+            if let Some(existing) = self.files.keys().find(|p| p.exists()) {
+                return Err(BackupError::Exists(existing.clone()));
+            }
+        }
+        for (path, contents) in self.files.iter() {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).map_err(|e| BackupError::Write(path.clone(), e))?;
+            }
+            fs::write(path, contents).map_err(|e| BackupError::Write(path.clone(), e))?;
+        }
+        Ok(self.files.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_what_was_backed_up() {
+        let dir = std::env::temp_dir().join(format!("hb-backup-{}", std::process::id()));
+        let config = dir.join("conf").join("config.json");
+        let state = dir.join("state.json");
+        fs::create_dir_all(config.parent().unwrap()).unwrap();
+        fs::write(&config, "{\"a\": 1}").unwrap();
+
+        let archive = Archive::collect(&[config.clone(), state.clone()], Local::now()).unwrap();
+        assert_eq!(archive.files.len(), 1);
+        let file = dir.join("backup.json");
+        archive.write(&file).unwrap();
+
+        let archive = Archive::read(&file).unwrap();
+        assert!(matches!(
+            archive.restore(false),
+            Err(BackupError::Exists(_))
+        ));
+        fs::remove_dir_all(config.parent().unwrap()).unwrap();
+        assert_eq!(archive.restore(false).unwrap(), vec![config.clone()]);
+        assert_eq!(fs::read_to_string(&config).unwrap(), "{\"a\": 1}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use crate::characteristic::Characteristic;
+use crate::configuration::LatencyConfig;
+use crate::homebridge::Homebridge;
+use crate::latency::LatencyTracker;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Program name recorded for the benchmark's writes.
+pub const SOURCE: &str = "bench_bridge";
+
+/// Round-trip times of one scenario.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub scenario: String,
+    pub requests: usize,
+    pub failed: usize,
+    pub total: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Summary {
+    /// Summarize the round-trip times of the requests that succeeded, out of `requests` made in
+    /// `total`.
+    pub fn new(
+        scenario: &str,
+        mut samples: Vec<Duration>,
+        requests: usize,
+        total: Duration,
+    ) -> Self {
+        samples.sort();
+        let percentile = |p: usize| match samples.len() {
+            0 => Duration::ZERO,
+            n => samples[((n * p).div_ceil(100)).clamp(1, n) - 1],
+        };
+        Self {
+            scenario: scenario.to_string(),
+            requests,
+            failed: requests - samples.len(),
+            total,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Requests per second, failed ones included.
+    pub fn throughput(&self) -> f64 {
+        self.requests as f64 / self.total.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{:<20} {:>6} {:>6} {:>9.1} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+            self.scenario,
+            self.requests,
+            self.failed,
+            self.throughput(),
+            ms(self.p50),
+            ms(self.p90),
+            ms(self.p99),
+            ms(self.max)
+        )
+    }
+}
+
+/// Header of the lines of [`Summary`].
+pub const HEADER: &str =
+    "scenario             requests failed     req/s  p50 ms   p90 ms   p99 ms   max ms";
+
+/// Payload of a mock light, as served by the Homebridge UI API.
+fn mock_accessory(i: usize) -> Value {
+    let name = format!("Bench Light {}", i + 1);
+    let characteristic = |iid: usize, kind: &str, value: Value, format: &str| {
+        json!({
+            "aid": i + 2, "iid": iid, "uuid": format!("{}-{}", i, iid), "type": kind,
+            "serviceType": "Lightbulb", "serviceName": name, "description": kind,
+            "value": value, "format": format, "perms": ["ev", "pr", "pw"],
+            "canRead": true, "canWrite": true, "ev": true
+        })
+    };
+    json!({
+        "uuid": format!("bench-{}", i),
+        "uniqueId": format!("bench-{}", i),
+        "type": "Lightbulb",
+        "humanType": "Lightbulb",
+        "serviceName": name,
+        "values": { "On": 1, "Brightness": 50 },
+        "serviceCharacteristics": [
+            characteristic(10, "On", json!(1), "bool"),
+            characteristic(11, "Brightness", json!(50), "int")
+        ]
+    })
+}
+
+async fn mock_response(
+    req: Request<Body>,
+    accessories: Arc<Mutex<Vec<Value>>>,
+    latency: Duration,
+) -> Result<Response<Body>, Infallible> {
+    tokio::time::sleep(latency).await;
+    let path = req.uri().path().to_string();
+    let uuid = path.strip_prefix("/api/accessories/").map(str::to_string);
+    let (status, body) = match (req.method().clone(), path.as_str(), uuid) {
+        (Method::POST, "/api/auth/login", _) => (
+            StatusCode::CREATED,
+            json!({"access_token": "bench", "token_type": "Bearer", "expires_in": 28800}),
+        ),
+        (Method::GET, "/api/status/homebridge-version", _) => {
+            (StatusCode::OK, json!({"installedVersion": "1.8.0"}))
+        }
+        (Method::GET, "/api/accessories", _) => (
+            StatusCode::OK,
+            Value::Array(accessories.lock().expect("Mock lock poisoned.").clone()),
+        ),
+        (method, _, Some(uuid)) => {
+            let write = match method {
+                Method::PUT => hyper::body::to_bytes(req.into_body())
+                    .await
+                    .ok()
+                    .and_then(|b| serde_json::from_slice::<Value>(&b).ok()),
+                _ => None,
+            };
+            let mut accessories = accessories.lock().expect("Mock lock poisoned.");
+            match accessories.iter_mut().find(|a| a["uuid"] == uuid.as_str()) {
+                Some(accessory) => {
+                    if let Some(write) = write {
+                        if let Some(kind) = write["characteristicType"].as_str() {
+                            accessory["values"][kind] = write["value"].clone();
+                        }
+                    }
+                    (StatusCode::OK, accessory.clone())
+                }
+                None => (StatusCode::NOT_FOUND, json!({})),
+            }
+        }
+        _ => (StatusCode::NOT_FOUND, json!({})),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Valid response."))
+}
+
+/// Serve a mock bridge of `count` lights on a free local port, answering after `latency`.
+///
+/// Returns the base URL and the names of the lights.
+pub fn mock_bridge(count: usize, latency: Duration) -> Result<(String, Vec<String>), hyper::Error> {
+    let lights: Vec<Value> = (0..count).map(mock_accessory).collect();
+    let names = lights
+        .iter()
+        .map(|a| a["serviceName"].as_str().unwrap_or_default().to_string())
+        .collect();
+    let accessories = Arc::new(Mutex::new(lights));
+    let make_svc = make_service_fn(move |_: &AddrStream| {
+        let accessories = accessories.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                mock_response(req, accessories.clone(), latency)
+            }))
+        }
+    });
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_svc);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    Ok((url, names))
+}
+
+/// Requests of one scenario, timed one by one.
+struct Timer {
+    samples: Vec<Duration>,
+    requests: usize,
+}
+
+impl Timer {
+    fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+            requests: 0,
+        }
+    }
+
+    fn record(&mut self, started: Instant, ok: bool) {
+        self.requests += 1;
+        if ok {
+            self.samples.push(started.elapsed());
+        }
+    }
+}
+
+/// Run the scenarios against `accessories` of the bridge: `requests` single reads and writes,
+/// group writes to all accessories at once, and reads from `concurrency` clients at a time,
+/// each spaced by `latency`.
+///
+/// Writes set `On` to the value read before, so a real bridge's lights stay as they are.
+pub async fn run(
+    client: &Client,
+    homebridge: &mut Homebridge,
+    accessories: &[String],
+    requests: usize,
+    concurrency: usize,
+    latency: &LatencyConfig,
+) -> Vec<Summary> {
+    let mut summaries = Vec::new();
+    // Accessories whose state cannot be read are left out rather than switched blindly.
+    let mut on = Vec::new();
+    for name in accessories.iter() {
+        if let Ok(value) = homebridge
+            .accessory(name)
+            .get(client, &Characteristic::On)
+            .await
+        {
+            on.push((name.as_str(), value));
+        }
+    }
+    if on.is_empty() {
+        return summaries;
+    }
+    let round_robin = || on.iter().cycle().take(requests);
+
+    let mut timer = Timer::new();
+    let started = Instant::now();
+    for (name, _) in round_robin() {
+        let request = Instant::now();
+        let result = homebridge.accessory(name).values(client).await;
+        timer.record(request, result.is_ok());
+    }
+    summaries.push(Summary::new(
+        "read",
+        timer.samples,
+        timer.requests,
+        started.elapsed(),
+    ));
+
+    let mut timer = Timer::new();
+    let started = Instant::now();
+    for (name, value) in round_robin() {
+        let request = Instant::now();
+        let result = homebridge
+            .accessory(name)
+            .set(client, SOURCE, &Characteristic::On, value.clone())
+            .await;
+        timer.record(request, result.is_ok());
+    }
+    summaries.push(Summary::new(
+        "write",
+        timer.samples,
+        timer.requests,
+        started.elapsed(),
+    ));
+
+    let writes: Vec<(&str, Characteristic, Value)> = on
+        .iter()
+        .map(|(name, value)| (*name, Characteristic::On, value.clone()))
+        .collect();
+    let groups = requests.div_ceil(writes.len().max(1));
+    let mut timer = Timer::new();
+    let started = Instant::now();
+    for _ in 0..groups {
+        let request = Instant::now();
+        let result = homebridge.apply_group(client, SOURCE, &writes).await;
+        timer.record(request, result.is_complete());
+    }
+    summaries.push(Summary::new(
+        &format!("group write ({})", writes.len()),
+        timer.samples,
+        timer.requests,
+        started.elapsed(),
+    ));
+
+    let per_client = requests.div_ceil(concurrency.max(1));
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..concurrency.max(1))
+        .map(|i| {
+            let mut homebridge = homebridge.detached();
+            homebridge
+                .latency
+                .replace(LatencyTracker::from_config(latency));
+            let client = client.clone();
+            let names: Vec<String> = accessories
+                .iter()
+                .cycle()
+                .skip(i)
+                .take(per_client)
+                .cloned()
+                .collect();
+            tokio::spawn(async move {
+                let mut timer = Timer::new();
+                for name in names.iter() {
+                    let request = Instant::now();
+                    let result = homebridge.accessory(name).values(&client).await;
+                    timer.record(request, result.is_ok());
+                }
+                timer
+            })
+        })
+        .collect();
+    let mut timer = Timer::new();
+    for task in tasks {
+        if let Ok(done) = task.await {
+            timer.samples.extend(done.samples);
+            timer.requests += done.requests;
+        }
+    }
+    summaries.push(Summary::new(
+        &format!("read x{}", concurrency.max(1)),
+        timer.samples,
+        timer.requests,
+        started.elapsed(),
+    ));
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_round_trip_times() {
+        let samples = (1..=100).rev().map(Duration::from_millis).collect();
+        let summary = Summary::new("read", samples, 104, Duration::from_secs(2));
+        assert_eq!(summary.failed, 4);
+        assert_eq!(summary.p50, Duration::from_millis(50));
+        assert_eq!(summary.p90, Duration::from_millis(90));
+        assert_eq!(summary.p99, Duration::from_millis(99));
+        assert_eq!(summary.max, Duration::from_millis(100));
+        assert_eq!(summary.throughput(), 52.0);
+
+        let empty = Summary::new("write", Vec::new(), 3, Duration::from_secs(1));
+        assert_eq!((empty.failed, empty.p99), (3, Duration::ZERO));
+        assert!(empty.to_string().starts_with("write "));
+    }
+}
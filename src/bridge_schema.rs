@@ -0,0 +1,195 @@
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// First Homebridge version whose UI reports accessories in the current shape.
+const CURRENT_SINCE: [u64; 3] = [1, 3, 0];
+
+/// Response of `/api/status/homebridge-version`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HomebridgeVersion {
+    #[serde(alias = "currentVersion")]
+    pub installed_version: String,
+}
+
+/// Shape of the accessory payloads of a bridge, decided by its Homebridge version.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BridgeSchema {
+    /// Accessories without `uniqueId`, `humanType`, or `values`, and characteristics with only
+    /// `perms` for what may be written.
+    Legacy,
+    /// The payloads the rest of the client parses.
+    Current,
+}
+
+/// Numeric components of a version such as "1.2.3" or "v1.2.3-beta.1".
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    core.split('.').map(|part| part.parse().ok()).collect()
+}
+
+impl BridgeSchema {
+    /// Schema of a Homebridge version; unknown versions are taken to be current.
+    pub fn for_version(version: &str) -> Self {
+        match parse_version(version) {
+            Some(v) if v.as_slice() < CURRENT_SINCE.as_slice() => BridgeSchema::Legacy,
+            Some(_) => BridgeSchema::Current,
+            None => {
+                debug!("Unrecognized Homebridge version '{}'.", version);
+                BridgeSchema::Current
+            }
+        }
+    }
+
+    /// Bring an accessory (from the list or fetched alone) into the current shape.
+    pub fn normalize_accessory(&self, mut accessory: Value) -> Value {
+        if *self == BridgeSchema::Current {
+            return accessory;
+        }
+        let Some(fields) = accessory.as_object_mut() else {
+            return accessory;
+        };
+        fill(fields, "uniqueId", "uuid");
+        fill(fields, "humanType", "type");
+        if let Some(characteristics) = fields
+            .get_mut("serviceCharacteristics")
+            .and_then(Value::as_array_mut)
+        {
+            for characteristic in characteristics.iter_mut().filter_map(Value::as_object_mut) {
+                let perms = characteristic
+                    .get("perms")
+                    .and_then(Value::as_array)
+                    .cloned()
+                    .unwrap_or_default();
+                let has = |perm: &str| Value::Bool(perms.iter().any(|p| p == perm));
+                characteristic.entry("canRead").or_insert_with(|| has("pr"));
+                characteristic
+                    .entry("canWrite")
+                    .or_insert_with(|| has("pw"));
+            }
+        }
+        if !fields.contains_key("values") {
+            let values: Map<String, Value> = fields
+                .get("serviceCharacteristics")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|c| {
+                    Some((
+                        c.get("type")?.as_str()?.to_string(),
+                        c.get("value")?.clone(),
+                    ))
+                })
+                .collect();
+            fields.insert("values".to_string(), Value::Object(values));
+        }
+        accessory
+    }
+
+    /// Bring an accessory list into the current shape.
+    pub fn normalize_accessories(&self, accessories: Value) -> Value {
+        match accessories {
+            Value::Array(list) => Value::Array(
+                list.into_iter()
+                    .map(|a| self.normalize_accessory(a))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+}
+
+/// Copy `from` into a missing `field`.
+fn fill(fields: &mut Map<String, Value>, field: &str, from: &str) {
+    if !fields.contains_key(field) {
+        if let Some(value) = fields.get(from).cloned() {
+            fields.insert(field.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homebridge::{HBAccessoryDetails, HBLightbulb};
+    use serde_json::json;
+
+    /// `/api/accessories/<id>` of a Homebridge 1.1 bridge.
+    fn legacy_fixture() -> Value {
+        json!({
+            "aid": 2,
+            "iid": 9,
+            "uuid": "0a1b2c",
+            "type": "Lightbulb",
+            "serviceName": "Bed Light",
+            "serviceCharacteristics": [
+                {"iid": 10, "type": "On", "description": "On", "value": true,
+                 "format": "bool", "perms": ["ev", "pr", "pw"]},
+                {"iid": 11, "type": "Brightness", "description": "Brightness", "value": 40,
+                 "format": "int", "perms": ["ev", "pr", "pw"], "unit": "percentage",
+                 "minValue": 0, "maxValue": 100, "minStep": 1},
+                {"iid": 12, "type": "Name", "description": "Name", "value": "Bed Light",
+                 "format": "string", "perms": ["pr"]}
+            ]
+        })
+    }
+
+    /// `/api/accessories/<id>` of a Homebridge 1.8 bridge.
+    fn current_fixture() -> Value {
+        json!({
+            "aid": 2,
+            "iid": 9,
+            "uuid": "0a1b2c",
+            "uniqueId": "f00d",
+            "type": "Lightbulb",
+            "humanType": "Lightbulb",
+            "serviceName": "Bed Light",
+            "serviceCharacteristics": [
+                {"iid": 10, "type": "On", "description": "On", "value": 1, "format": "bool",
+                 "perms": ["ev", "pr", "pw"], "canRead": true, "canWrite": true},
+                {"iid": 11, "type": "Brightness", "description": "Brightness", "value": 40,
+                 "format": "int", "perms": ["ev", "pr", "pw"], "unit": "percentage",
+                 "minValue": 0, "maxValue": 100, "minStep": 1, "canRead": true, "canWrite": true},
+                {"iid": 12, "type": "Name", "description": "Name", "value": "Bed Light",
+                 "format": "string", "perms": ["pr"], "canRead": true, "canWrite": false}
+            ],
+            "values": {"On": 1, "Brightness": 40, "Name": "Bed Light"}
+        })
+    }
+
+    #[test]
+    fn schema_by_version() {
+        assert_eq!(BridgeSchema::for_version("1.1.7"), BridgeSchema::Legacy);
+        assert_eq!(BridgeSchema::for_version("v1.3.0"), BridgeSchema::Current);
+        assert_eq!(
+            BridgeSchema::for_version("2.0.0-beta.3"),
+            BridgeSchema::Current
+        );
+        assert_eq!(BridgeSchema::for_version("unknown"), BridgeSchema::Current);
+        let version: HomebridgeVersion = serde_json::from_value(json!({
+            "name": "homebridge", "installedVersion": "1.6.1", "latestVersion": "1.8.4"
+        }))
+        .unwrap();
+        assert_eq!(version.installed_version, "1.6.1");
+    }
+
+    #[test]
+    fn fixtures_of_each_version_parse_alike() {
+        for (version, fixture) in [("1.1.7", legacy_fixture()), ("1.8.4", current_fixture())] {
+            let accessory = BridgeSchema::for_version(version).normalize_accessory(fixture);
+            let light: HBLightbulb = serde_json::from_value(accessory.clone()).unwrap();
+            assert!(light.values.is_on(), "{}", version);
+            assert_eq!(light.values.brightness, Some(40), "{}", version);
+            let details: HBAccessoryDetails = serde_json::from_value(accessory).unwrap();
+            let writable: Vec<_> = details
+                .service_characteristics
+                .iter()
+                .filter(|c| c.can_write)
+                .map(|c| c.char_type.as_str())
+                .collect();
+            assert_eq!(writable, vec!["On", "Brightness"], "{}", version);
+        }
+    }
+}
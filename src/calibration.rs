@@ -0,0 +1,241 @@
+use crate::clock;
+use crate::configuration::{CalibrationMode, SunsetCalibrationConfig};
+use crate::control::SharedState;
+use crate::homebridge::Homebridge;
+use crate::programs::control_evening_lights::ControlEveningLightsProgram;
+use crate::state::{CalibrationDay, CalibrationRecord};
+use crate::suntimes::SunTimes;
+use chrono::{DateTime, Duration, Local, NaiveDate};
+use log::{debug, info, warn};
+use reqwest::Client;
+
+/// What was seen of the evening light before today's ramp.
+#[derive(Debug, Clone, Copy)]
+struct Watch {
+    day: NaiveDate,
+    last_on: Option<bool>,
+    manual_on_minutes: Option<i64>,
+}
+
+/// Suggest the evening start from when the evening light is switched on by hand.
+///
+/// Before the ramp starts, the evening light is read every loop. Switching it on by hand in that
+/// period is recorded as minutes before sunset; evenings without that count as the start in
+/// effect. The suggestion is the median over the recent evenings and is logged once the
+/// evening window has ended. In `apply` mode the start is moved towards it, a few minutes
+/// per day, and kept across restarts.
+pub struct SunsetCalibration {
+    config: SunsetCalibrationConfig,
+    state: SharedState,
+    watch: Option<Watch>,
+    reported: Option<NaiveDate>,
+}
+
+/// Median start over the evenings, if there are enough of them.
+fn suggested_start(days: &[CalibrationDay], min_days: usize) -> Option<i64> {
+    if days.is_empty() || days.len() < min_days {
+        return None;
+    }
+    let mut starts: Vec<i64> = days
+        .iter()
+        .map(|d| d.manual_on_minutes.unwrap_or(d.start_minutes))
+        .collect();
+    starts.sort_unstable();
+    Some(starts[(starts.len() - 1) / 2])
+}
+
+/// Start for tomorrow, moved at most `max_step` minutes towards the suggestion.
+fn next_start(current: i64, suggested: i64, max_step: i64) -> i64 {
+    current + (suggested - current).clamp(-max_step, max_step)
+}
+
+impl SunsetCalibration {
+    pub fn new(config: &SunsetCalibrationConfig, state: SharedState) -> Self {
+        Self {
+            config: config.clone(),
+            state,
+            watch: None,
+            reported: None,
+        }
+    }
+
+    fn record(&self) -> CalibrationRecord {
+        self.state
+            .lock()
+            .expect("State lock poisoned.")
+            .store
+            .state()
+            .calibration
+            .clone()
+    }
+
+    fn save_record(&self, record: CalibrationRecord) {
+        let mut state = self.state.lock().expect("State lock poisoned.");
+        if let Err(e) = state.store.update(|s| s.calibration = record) {
+            warn!("Failed to persist sunset calibration: {}", e);
+        }
+    }
+
+    /// Restore the start applied before a restart.
+    pub fn restore(&self, program: &mut ControlEveningLightsProgram) {
+        if self.config.mode != CalibrationMode::Apply {
+            return;
+        }
+        if let Some(start) = self.record().applied_start_minutes {
+            info!(
+                "Using calibrated evening start of {} minutes before sunset.",
+                start
+            );
+            program.minutes_before_sunset_start = start;
+        }
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &Client,
+        homebridge: &mut Homebridge,
+        suntimes: &mut SunTimes,
+        program: &mut ControlEveningLightsProgram,
+    ) {
+        let sunset = match suntimes.sunset(client).await {
+            Ok(sunset) => sunset,
+            Err(e) => {
+                debug!("No sunset calibration without sunset data: {}", e);
+                return;
+            }
+        };
+        let now = clock::now();
+        let start = sunset - Duration::minutes(program.minutes_before_sunset_start);
+        let end = sunset + Duration::minutes(program.minutes_after_sunset_finish);
+        if start - Duration::minutes(self.config.watch_minutes) <= now && now < start {
+            self.watch_light(client, homebridge, &program.light, &sunset, &now)
+                .await;
+        } else if end < now && self.reported != Some(now.date_naive()) {
+            self.reported = Some(now.date_naive());
+            self.finish_day(&now, program);
+        }
+    }
+
+    async fn watch_light(
+        &mut self,
+        client: &Client,
+        homebridge: &mut Homebridge,
+        light: &str,
+        sunset: &DateTime<Local>,
+        now: &DateTime<Local>,
+    ) {
+        let is_on = match homebridge.accessory(light).is_off(client).await {
+            Ok(off) => !off,
+            Err(e) => {
+                warn!("Could not read '{}' for calibration: {}", light, e);
+                return;
+            }
+        };
+        let mut watch = self
+            .watch
+            .filter(|w| w.day == now.date_naive())
+            .unwrap_or(Watch {
+                day: now.date_naive(),
+                last_on: None,
+                manual_on_minutes: None,
+            });
+        if watch.last_on == Some(false) && is_on && watch.manual_on_minutes.is_none() {
+            let minutes = (*sunset - *now).num_minutes();
+            info!(
+                "Evening light switched on by hand {} minutes before sunset.",
+                minutes
+            );
+            watch.manual_on_minutes = Some(minutes);
+        }
+        watch.last_on = Some(is_on);
+        self.watch = Some(watch);
+    }
+
+    /// Record the evening and report (or apply) the suggested start.
+    fn finish_day(&mut self, now: &DateTime<Local>, program: &mut ControlEveningLightsProgram) {
+        let Some(watch) = self.watch.filter(|w| w.day == now.date_naive()) else {
+            debug!("Evening light not watched this evening - nothing to calibrate.");
+            return;
+        };
+        let mut record = self.record();
+        record.days.retain(|d| d.day != watch.day);
+        record.days.push(CalibrationDay {
+            day: watch.day,
+            start_minutes: program.minutes_before_sunset_start,
+            manual_on_minutes: watch.manual_on_minutes,
+        });
+        let excess = record.days.len().saturating_sub(self.config.days);
+        record.days.drain(..excess);
+
+        let current = program.minutes_before_sunset_start;
+        match suggested_start(&record.days, self.config.min_days) {
+            None => info!(
+                "Sunset calibration: {} of {} evenings needed for a suggestion.",
+                record.days.len(),
+                self.config.min_days
+            ),
+            Some(suggested) => {
+                let manual = record
+                    .days
+                    .iter()
+                    .filter(|d| d.manual_on_minutes.is_some())
+                    .count();
+                info!(
+                    "Sunset calibration: evening light switched on by hand before the ramp on {} of the last {} evenings - suggested `minutes_before_sunset_start`: {} (currently {}).",
+                    manual,
+                    record.days.len(),
+                    suggested,
+                    current
+                );
+                if self.config.mode == CalibrationMode::Apply && suggested != current {
+                    // The start must stay before the peak.
+                    let next = next_start(current, suggested, self.config.max_step_minutes)
+                        .max(-program.minutes_after_sunset_peak);
+                    info!("Evening start moved to {} minutes before sunset.", next);
+                    program.minutes_before_sunset_start = next;
+                    record.applied_start_minutes = Some(next);
+                }
+            }
+        }
+        self.save_record(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(n: u32, start_minutes: i64, manual_on_minutes: Option<i64>) -> CalibrationDay {
+        CalibrationDay {
+            day: NaiveDate::from_ymd_opt(2024, 10, n).unwrap(),
+            start_minutes,
+            manual_on_minutes,
+        }
+    }
+
+    #[test]
+    fn no_suggestion_before_enough_evenings() {
+        let days = vec![day(1, 30, Some(60)), day(2, 30, Some(60))];
+        assert_eq!(suggested_start(&days, 3), None);
+        assert_eq!(suggested_start(&[], 0), None);
+    }
+
+    #[test]
+    fn evenings_without_manual_switch_on_count_as_the_start() {
+        let days = vec![
+            day(1, 30, Some(60)),
+            day(2, 30, None),
+            day(3, 30, Some(45)),
+            day(4, 30, None),
+            day(5, 30, Some(50)),
+        ];
+        assert_eq!(suggested_start(&days, 5), Some(45));
+    }
+
+    #[test]
+    fn start_moves_at_most_one_step_per_day() {
+        assert_eq!(next_start(30, 60, 5), 35);
+        assert_eq!(next_start(30, 32, 5), 32);
+        assert_eq!(next_start(30, 10, 5), 25);
+    }
+}
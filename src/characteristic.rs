@@ -0,0 +1,155 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// HomeKit characteristic of an accessory, by its type name in the Homebridge UI API.
+///
+/// Characteristics the controller knows are variants; any other name is kept as `Other`, but
+/// names a few typos away from a known one (e.g. "Brightnes") are rejected when read from the
+/// configuration.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Characteristic {
+    On,
+    Brightness,
+    ColorTemperature,
+    Hue,
+    Saturation,
+    Active,
+    InUse,
+    SetDuration,
+    RemainingDuration,
+    CurrentAmbientLightLevel,
+    CurrentTemperature,
+    ContactSensorState,
+    MotionDetected,
+    OccupancyDetected,
+    StatusLowBattery,
+    Name,
+    Other(String),
+}
+
+const KNOWN: [Characteristic; 16] = [
+    Characteristic::On,
+    Characteristic::Brightness,
+    Characteristic::ColorTemperature,
+    Characteristic::Hue,
+    Characteristic::Saturation,
+    Characteristic::Active,
+    Characteristic::InUse,
+    Characteristic::SetDuration,
+    Characteristic::RemainingDuration,
+    Characteristic::CurrentAmbientLightLevel,
+    Characteristic::CurrentTemperature,
+    Characteristic::ContactSensorState,
+    Characteristic::MotionDetected,
+    Characteristic::OccupancyDetected,
+    Characteristic::StatusLowBattery,
+    Characteristic::Name,
+];
+
+impl Characteristic {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Characteristic::On => "On",
+            Characteristic::Brightness => "Brightness",
+            Characteristic::ColorTemperature => "ColorTemperature",
+            Characteristic::Hue => "Hue",
+            Characteristic::Saturation => "Saturation",
+            Characteristic::Active => "Active",
+            Characteristic::InUse => "InUse",
+            Characteristic::SetDuration => "SetDuration",
+            Characteristic::RemainingDuration => "RemainingDuration",
+            Characteristic::CurrentAmbientLightLevel => "CurrentAmbientLightLevel",
+            Characteristic::CurrentTemperature => "CurrentTemperature",
+            Characteristic::ContactSensorState => "ContactSensorState",
+            Characteristic::MotionDetected => "MotionDetected",
+            Characteristic::OccupancyDetected => "OccupancyDetected",
+            Characteristic::StatusLowBattery => "StatusLowBattery",
+            Characteristic::Name => "Name",
+            Characteristic::Other(name) => name,
+        }
+    }
+
+    /// Characteristic of a name, rejecting names that look like a misspelled known one.
+    pub fn parse_strict(name: &str) -> Result<Self, String> {
+        let characteristic = Characteristic::from(name);
+        if !matches!(characteristic, Characteristic::Other(_)) {
+            return Ok(characteristic);
+        }
+        let normalized = name.to_lowercase();
+        let near = KNOWN.iter().find(|known| {
+            let known = known.as_str().to_lowercase();
+            strsim::levenshtein(&known, &normalized) <= known.len() / 5
+        });
+        match near {
+            Some(known) => Err(format!(
+                "Unknown characteristic '{}'. Did you mean '{}'?",
+                name, known
+            )),
+            None => Ok(characteristic),
+        }
+    }
+}
+
+impl From<&str> for Characteristic {
+    /// Known characteristic of a name, or `Other`.
+    fn from(name: &str) -> Self {
+        KNOWN
+            .iter()
+            .find(|known| known.as_str() == name)
+            .cloned()
+            .unwrap_or_else(|| Characteristic::Other(name.to_string()))
+    }
+}
+
+impl FromStr for Characteristic {
+    type Err = std::convert::Infallible;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(Characteristic::from(name))
+    }
+}
+
+impl fmt::Display for Characteristic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Characteristic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Characteristic {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Characteristic::parse_strict(&name).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_misspelled_names() {
+        assert_eq!(
+            Characteristic::parse_strict("Brightness"),
+            Ok(Characteristic::Brightness)
+        );
+        assert_eq!(
+            Characteristic::parse_strict("TargetPosition"),
+            Ok(Characteristic::Other("TargetPosition".to_string()))
+        );
+        assert!(Characteristic::parse_strict("Brightnes").is_err());
+        assert!(Characteristic::parse_strict("brightness").is_err());
+        assert!(Characteristic::parse_strict("colortemprature").is_err());
+
+        let parsed: Characteristic = serde_json::from_str("\"Hue\"").unwrap();
+        assert_eq!(parsed, Characteristic::Hue);
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), "\"Hue\"");
+        assert!(serde_json::from_str::<Characteristic>("\"Hu\"").is_ok());
+    }
+}
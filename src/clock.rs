@@ -0,0 +1,78 @@
+use chrono::{DateTime, Duration, Local};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Clock running faster than real time (soak tests) or standing still (explaining decisions).
+struct Accelerated {
+    /// 0 for a stopped clock.
+    factor: u32,
+    real_start: Instant,
+    start: DateTime<Local>,
+}
+
+static ACCELERATED: OnceLock<Accelerated> = OnceLock::new();
+
+/// Let the controller's clock run `factor` times faster than real time from now on.
+///
+/// Only the first call has an effect.
+pub fn accelerate(factor: u32) {
+    let _ = ACCELERATED.set(Accelerated {
+        factor: factor.max(1),
+        real_start: Instant::now(),
+        start: Local::now(),
+    });
+}
+
+/// Stop the controller's clock at `at`.
+///
+/// Only takes effect if the clock was not set before.
+pub fn freeze(at: DateTime<Local>) {
+    let _ = ACCELERATED.set(Accelerated {
+        factor: 0,
+        real_start: Instant::now(),
+        start: at,
+    });
+}
+
+/// How many times faster than real time the clock runs, if accelerated.
+pub fn acceleration() -> Option<u32> {
+    ACCELERATED
+        .get()
+        .map(|clock| clock.factor)
+        .filter(|factor| *factor > 0)
+}
+
+fn scaled(
+    start: DateTime<Local>,
+    real_elapsed: std::time::Duration,
+    factor: u32,
+) -> DateTime<Local> {
+    let elapsed = real_elapsed * factor;
+    start + Duration::from_std(elapsed).unwrap_or(Duration::MAX)
+}
+
+/// Current time as seen by programs and schedules.
+pub fn now() -> DateTime<Local> {
+    match ACCELERATED.get() {
+        Some(clock) => scaled(clock.start, clock.real_start.elapsed(), clock.factor),
+        None => Local::now(),
+    }
+}
+
+/// Sleep for `duration` of controller time.
+pub async fn sleep(duration: std::time::Duration) {
+    let factor = acceleration().unwrap_or(1);
+    tokio::time::sleep(duration / factor).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accelerated_time_runs_faster() {
+        let start = Local::now();
+        let later = scaled(start, std::time::Duration::from_secs(60), 1440);
+        assert_eq!(later - start, Duration::days(1));
+    }
+}
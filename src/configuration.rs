@@ -1,37 +1,2005 @@
-use serde::{Deserialize, Serialize};
+use crate::characteristic::Characteristic;
+use crate::homebridge::BED_LIGHT;
+use crate::programs::ProgramId;
+use crate::suntimes::SunEvent;
+use chrono::{FixedOffset, NaiveTime, Weekday};
+use log::LevelFilter;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 
 const fn _true() -> bool {
     true
 }
 
+fn _bed_light() -> String {
+    BED_LIGHT.to_string()
+}
+
+const fn _sunrise() -> SunEvent {
+    SunEvent::Sunrise
+}
+
+const fn _sunset() -> SunEvent {
+    SunEvent::Sunset
+}
+
+fn _default_state_file() -> PathBuf {
+    PathBuf::from("hb-controller-state.json")
+}
+
+const fn _default_rain_lookback_hours() -> i64 {
+    24
+}
+
+const fn _default_rain_lookahead_hours() -> i64 {
+    12
+}
+
+const fn _default_bridge_port() -> u16 {
+    8581
+}
+
+const fn _default_bridge_status_interval() -> i64 {
+    5
+}
+
+const fn _default_smoothing_window() -> usize {
+    5
+}
+
+const fn _default_suntimes_stale_days() -> i64 {
+    3
+}
+
+const fn _default_program_timeout() -> u64 {
+    60
+}
+
+const fn _default_watch_config_seconds() -> u64 {
+    5
+}
+
+const fn _default_min_spacing_ms() -> u64 {
+    250
+}
+
+const fn _default_slow_threshold_ms() -> u64 {
+    1000
+}
+
+const fn _default_group_spacing_ms() -> u64 {
+    150
+}
+
+const fn _default_group_max_total_ms() -> u64 {
+    3000
+}
+
+const fn _default_max_learned_tolerance() -> f64 {
+    3.0
+}
+
+const fn _default_tolerance_samples() -> usize {
+    20
+}
+
+const fn _default_tolerance_min_samples() -> usize {
+    5
+}
+
+const fn _default_calibration_watch_minutes() -> i64 {
+    120
+}
+
+const fn _default_calibration_days() -> usize {
+    14
+}
+
+const fn _default_calibration_min_days() -> usize {
+    5
+}
+
+const fn _default_calibration_max_step_minutes() -> i64 {
+    5
+}
+
+const fn _default_poll_interval_minutes() -> i64 {
+    15
+}
+
+fn _default_release_url() -> String {
+    "https://api.github.com/repos/jhrcook/homebridge-controller/releases/latest".to_string()
+}
+
+const fn _default_update_check_hours() -> i64 {
+    24
+}
+
+const fn _default_usage_ping_hours() -> i64 {
+    24 * 7
+}
+
+const fn _default_audit_log_days() -> i64 {
+    30
+}
+
+fn _default_telegram_api_url() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+const fn _default_telegram_poll_seconds() -> u64 {
+    30
+}
+
+fn _default_color_shift_tag() -> String {
+    "color_shift".to_string()
+}
+
+const fn _default_color_shift_step() -> i64 {
+    10
+}
+
+const fn _default_color_shift_tolerance() -> f64 {
+    5.0
+}
+
+fn _default_announce_instance() -> String {
+    "Homebridge Controller".to_string()
+}
+
+const fn _default_gpio_poll_ms() -> u64 {
+    20
+}
+
+const fn _default_debounce_ms() -> u64 {
+    50
+}
+
+fn _default_lead_minutes() -> i64 {
+    10
+}
+
+fn _default_archive_directory() -> PathBuf {
+    PathBuf::from("log-archive")
+}
+
+fn _default_presence_token_env() -> String {
+    "HA_TOKEN".to_string()
+}
+
+const fn _default_presence_interval() -> i64 {
+    5
+}
+
+fn _default_media_token_env() -> String {
+    "MEDIA_SERVER_TOKEN".to_string()
+}
+
+const fn _default_media_interval() -> i64 {
+    30
+}
+
+const fn _default_media_release() -> i64 {
+    5
+}
+
+const fn _default_preview_weekday() -> Weekday {
+    Weekday::Sun
+}
+
+fn _default_preview_time() -> NaiveTime {
+    NaiveTime::from_hms_opt(18, 0, 0).expect("Valid time.")
+}
+
+const fn _default_preview_days() -> u32 {
+    7
+}
+
+fn _on_characteristic() -> Characteristic {
+    Characteristic::On
+}
+
+const fn _debug() -> LevelFilter {
+    LevelFilter::Debug
+}
+
+const fn _info() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn _default_log_file() -> PathBuf {
+    PathBuf::from("hb-controller.log")
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TurningMorningLightsOffConfig {
     #[serde(default = "_true")]
     pub active: bool,
-    pub duration: u32,
+    #[serde(default = "_bed_light")]
+    pub light: String,
     pub off_time: Option<String>,
     pub after_sunrise: Option<i64>,
+    /// Event `after_sunrise` counts from, e.g. `civil_dawn`.
+    #[serde(default = "_sunrise")]
+    pub sun_event: SunEvent,
+    /// Sunrise (or time of `sun_event`) to use for `after_sunrise` when sun times are unavailable.
+    #[serde(default)]
+    pub sunrise_fallback: Option<NaiveTime>,
     pub last_call_after_scheduled_off: u32,
+    /// How often to try turning the light off before the last call.
+    #[serde(default)]
+    pub retry: RetryPolicyConfig,
+    /// Checks that the light stays off after it was turned off.
+    #[serde(default)]
+    pub verification: Option<OffVerificationConfig>,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+}
+
+/// Attempts at a write that has to happen within a window.
+///
+/// By default, every loop of the window tries again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetryPolicyConfig {
+    /// Minutes to wait after a failed attempt.
+    #[serde(default)]
+    pub interval_minutes: i64,
+    /// Give up after this many attempts (default: keep trying until the window ends).
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+const fn _default_verification_interval_minutes() -> i64 {
+    5
+}
+
+/// Checks after turning a light off, for plugins whose state bounces back on after a write.
+///
+/// The light is read every `interval_minutes` until `window_minutes` after it was turned off,
+/// and switched off again whenever it is found on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OffVerificationConfig {
+    pub window_minutes: i64,
+    #[serde(default = "_default_verification_interval_minutes")]
+    pub interval_minutes: i64,
+}
+
+/// Restrict a lighting program to the time between sunset and sunrise, or between the
+/// twilight events set in `dark_from` and `dark_until`.
+///
+/// Negative margins move the boundary earlier, e.g. `sunset_margin_minutes: -30` allows
+/// starting half an hour before sunset.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct DarkHoursConfig {
+    #[serde(default = "_sunset")]
+    pub dark_from: SunEvent,
+    #[serde(default = "_sunrise")]
+    pub dark_until: SunEvent,
+    #[serde(default)]
+    pub sunset_margin_minutes: i64,
+    #[serde(default)]
+    pub sunrise_margin_minutes: i64,
+    #[serde(default)]
+    pub sunset_fallback: Option<NaiveTime>,
+    #[serde(default)]
+    pub sunrise_fallback: Option<NaiveTime>,
+}
+
+/// Reduce or skip the morning fade when a light sensor reports the room is already bright.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DaylightCheckConfig {
+    pub lux_sensor: String,
+    pub reduce_above_lux: f64,
+    pub skip_above_lux: f64,
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MorningLightConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    #[serde(default = "_bed_light")]
+    pub light: String,
+    pub start: String,
+    pub duration: u32,
+    pub final_brightness: u8,
+    pub start_hue: u32,
+    pub final_hue: u32,
+    #[serde(default)]
+    pub daylight_check: Option<DaylightCheckConfig>,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+    #[serde(default)]
+    pub only_when_dark: Option<DarkHoursConfig>,
+}
+
+/// A program section: one instance, or a list of instances with different settings, e.g. one
+/// per room.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Instances<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Instances<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            Instances::One(instance) => std::slice::from_ref(instance).iter(),
+            Instances::Many(instances) => instances.iter(),
+        }
+    }
+}
+
+/// Deserialized by shape rather than `untagged`, so errors in an instance keep their message.
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Instances<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::Array(items) => items
+                .into_iter()
+                .map(serde_json::from_value)
+                .collect::<Result<Vec<T>, _>>()
+                .map(Instances::Many),
+            other => serde_json::from_value(other).map(Instances::One),
+        }
+        .map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ControlEveningLightsConfig {
+    /// Distinguishes instances in logs and the decision history; needed with several instances.
+    #[serde(default)]
+    pub name: Option<String>,
     #[serde(default = "_true")]
     pub active: bool,
+    #[serde(default = "_bed_light")]
+    pub light: String,
+    /// Event the `minutes_..._sunset_...` offsets count from, e.g. `civil_dusk`.
+    #[serde(default = "_sunset")]
+    pub sun_event: SunEvent,
     pub minutes_before_sunset_start: i64,
     pub minutes_after_sunset_peak: i64,
     pub minutes_after_sunset_finish: i64,
+    /// Sunset (or time of `sun_event`) to use when sun times are unavailable.
+    #[serde(default)]
+    pub sunset_fallback: Option<NaiveTime>,
     pub start_brightness: u8,
     pub max_brightness: u8,
     pub final_brightness: u8,
+    #[serde(default)]
+    pub resume_after_minutes: Option<i64>,
+    #[serde(default)]
+    pub override_tolerance: f64,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+    #[serde(default)]
+    pub only_when_dark: Option<DarkHoursConfig>,
+    #[serde(default)]
+    pub calibration: Option<SunsetCalibrationConfig>,
+    #[serde(default)]
+    pub bias_lighting: Option<BiasLightingConfig>,
+}
+
+/// Shift tagged lights from cool to warm between two fixed times of day.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColorShiftConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    /// Lights with this tag in `accessories` are shifted.
+    #[serde(default = "_default_color_shift_tag")]
+    pub tag: String,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Color temperature at `start`, in mired (lower is cooler).
+    pub from_mired: u32,
+    /// Color temperature at `end`, in mired.
+    pub to_mired: u32,
+    #[serde(default = "_default_color_shift_step")]
+    pub step_minutes: i64,
+    #[serde(default)]
+    pub resume_after_minutes: Option<i64>,
+    #[serde(default = "_default_color_shift_tolerance")]
+    pub override_tolerance: f64,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+}
+
+/// Hold the evening ramp back while a switch (e.g. a TV backlight scene) is on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BiasLightingConfig {
+    /// Accessory whose `On` marks the bias lighting as active.
+    pub switch: String,
+    /// Brightness cap while it is on; without one, the light is left alone.
+    #[serde(default)]
+    pub max_brightness: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibrationMode {
+    /// Only log the suggested start.
+    Suggest,
+    /// Move the start towards the suggestion a few minutes per day.
+    Apply,
+}
+
+/// Learn the evening start from when the evening light is switched on by hand before the ramp.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SunsetCalibrationConfig {
+    pub mode: CalibrationMode,
+    /// How long before the ramp starts to watch for the light being switched on.
+    #[serde(default = "_default_calibration_watch_minutes")]
+    pub watch_minutes: i64,
+    /// Number of recent evenings the suggestion is based on.
+    #[serde(default = "_default_calibration_days")]
+    pub days: usize,
+    /// Evenings needed before anything is suggested.
+    #[serde(default = "_default_calibration_min_days")]
+    pub min_days: usize,
+    /// Largest change of the start per day in `apply` mode.
+    #[serde(default = "_default_calibration_max_step_minutes")]
+    pub max_step_minutes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IrrigationZoneConfig {
+    pub valve: String,
+    pub minutes_after_sunrise: i64,
+    /// Sunrise to use when sun times are unavailable.
+    #[serde(default)]
+    pub sunrise_fallback: Option<NaiveTime>,
+    pub duration_minutes: u32,
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IrrigationConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    pub zones: Vec<IrrigationZoneConfig>,
+    #[serde(default)]
+    pub rain_threshold_mm: Option<f32>,
+    #[serde(default = "_default_rain_lookback_hours")]
+    pub rain_lookback_hours: i64,
+    #[serde(default = "_default_rain_lookahead_hours")]
+    pub rain_lookahead_hours: i64,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+}
+
+/// How a light is switched on to a target brightness.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TurnOnSequence {
+    /// Switch on, then set the brightness.
+    #[default]
+    OnFirst,
+    /// Set the brightness while off, then switch on (for lights that accept it while off).
+    BrightnessFirst,
+    /// Set the lowest brightness, switch on, then set the target brightness.
+    RampFromMinimum,
+}
+
+/// Settings for a single accessory, keyed by its name.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccessoryConfig {
+    #[serde(default)]
+    pub turn_on_sequence: TurnOnSequence,
+    /// When a light is switched on without a brightness, use the one the controller last set.
+    #[serde(default)]
+    pub restore_brightness: bool,
+    /// Brightness for switching on without one, if none can be restored.
+    #[serde(default)]
+    pub on_brightness: Option<u8>,
+    /// Labels selecting the accessory for programs, e.g. "color_shift".
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Writes to the accessory before this time of day are refused, whatever issues them.
+    #[serde(default)]
+    pub earliest_control_time: Option<NaiveTime>,
+    /// Writes to the accessory after this time of day are refused, whatever issues them.
+    #[serde(default)]
+    pub latest_control_time: Option<NaiveTime>,
+    /// Minutes the accessory may stay on after the controller turned it on, before it is turned
+    /// off and an alert raised.
+    #[serde(default)]
+    pub max_on_minutes: Option<u64>,
+}
+
+/// Light setting applied when a condition published by another program is set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConditionActionConfig {
+    pub name: String,
+    pub triggered_by: String,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+    pub accessory: String,
+    #[serde(default)]
+    pub on: Option<bool>,
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    #[serde(default)]
+    pub only_when_dark: Option<DarkHoursConfig>,
+    /// Restore the earlier values if one of the writes fails.
+    #[serde(default)]
+    pub rollback_on_failure: bool,
+    /// Set the accessory back to how it was this many minutes after the action ran.
+    #[serde(default)]
+    pub revert_after_minutes: Option<i64>,
+}
+
+/// Thresholds and minimum dwell times for programs switching on a measured value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HysteresisConfig {
+    pub on_threshold: f64,
+    pub off_threshold: f64,
+    #[serde(default)]
+    pub min_on_minutes: i64,
+    #[serde(default)]
+    pub min_off_minutes: i64,
+    /// Filter applied to the measurements before comparing them to the thresholds.
+    #[serde(default)]
+    pub smoothing: Option<SmoothingConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmoothingMethod {
+    /// Exponential moving average over about `window` readings.
+    Ema,
+    /// Median of the last `window` readings.
+    Median,
+}
+
+/// Smoothing of noisy measurements, so a single spike does not switch anything.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SmoothingConfig {
+    pub method: SmoothingMethod,
+    #[serde(default = "_default_smoothing_window")]
+    pub window: usize,
+}
+
+/// Characteristic value written by a config-declared action.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CharacteristicWrite {
+    pub characteristic: Characteristic,
+    pub value: Value,
+}
+
+/// Sensor outside Homebridge (e.g. an ESPHome or Tasmota device) reporting its values to the
+/// control API.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VirtualSensorConfig {
+    /// Characteristic a bare reported value is stored as, e.g. "ContactSensorState".
+    pub characteristic: Characteristic,
+}
+
+/// Poll a URL and write an accessory when a value in the response crosses thresholds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpPollConfig {
+    pub name: String,
+    pub url: String,
+    /// JSON pointer to the value in the response, e.g. `/data/aqi`.
+    pub pointer: String,
+    #[serde(default = "_default_poll_interval_minutes")]
+    pub interval_minutes: i64,
+    pub thresholds: HysteresisConfig,
+    pub accessory: String,
+    /// Written in order when the value crosses the on threshold.
+    #[serde(default)]
+    pub on_writes: Vec<CharacteristicWrite>,
+    /// Written in order when the value crosses back over the off threshold.
+    #[serde(default)]
+    pub off_writes: Vec<CharacteristicWrite>,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Expression over accessory values that must hold, e.g. `light('Porch').off`.
+    #[serde(default)]
+    pub condition: Option<String>,
+}
+
+/// Characteristic value of an accessory set by a scene.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SceneWrite {
+    pub accessory: String,
+    pub characteristic: Characteristic,
+    pub value: SceneValue,
+}
+
+/// Value written by a scene: as given, or an object taking it from an accessory's current state
+/// when the scene is applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "Value", into = "Value")]
+pub enum SceneValue {
+    Fixed(Value),
+    Relative(RelativeValue),
+}
+
+/// Share of an accessory's current value, e.g. `{"from": "Living Room Lamp"}` to match it or
+/// `{"percent": 70}` for 70% of the written characteristic's own value.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RelativeValue {
+    /// Accessory read, the written one if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// Characteristic read, the written one if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub characteristic: Option<Characteristic>,
+    #[serde(default = "_default_percent")]
+    pub percent: f64,
+}
+
+fn _default_percent() -> f64 {
+    100.0
+}
+
+impl TryFrom<Value> for SceneValue {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Object(_) => serde_json::from_value(value)
+                .map(SceneValue::Relative)
+                .map_err(|e| format!("Invalid scene value: {}", e)),
+            value => Ok(SceneValue::Fixed(value)),
+        }
+    }
+}
+
+impl From<SceneValue> for Value {
+    fn from(value: SceneValue) -> Self {
+        match value {
+            SceneValue::Fixed(value) => value,
+            SceneValue::Relative(relative) => serde_json::to_value(relative).unwrap_or_default(),
+        }
+    }
+}
+
+/// Named action run on request (control API or the `set` subcommand).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ActionConfig {
+    /// Flip a boolean characteristic.
+    Toggle {
+        accessory: String,
+        #[serde(default = "_on_characteristic")]
+        characteristic: Characteristic,
+    },
+    /// Apply the next of the scenes each time the action runs.
+    Cycle { scenes: Vec<String> },
+}
+
+/// Periodic check for a newer release of the controller.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateCheckConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    /// Endpoint returning the latest release in the GitHub API format.
+    #[serde(default = "_default_release_url")]
+    pub url: String,
+    #[serde(default = "_default_update_check_hours")]
+    pub interval_hours: i64,
+}
+
+/// File every write of the controller is appended to, for looking up what it did.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditLogConfig {
+    pub path: PathBuf,
+    /// Days writes are kept; older ones are dropped when the controller starts.
+    #[serde(default = "_default_audit_log_days")]
+    pub keep_days: i64,
+}
+
+/// Opt-in report of the controller version and how many of each program type are enabled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsagePingConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    /// Endpoint the counts are posted to as JSON.
+    pub url: String,
+    #[serde(default = "_default_usage_ping_hours")]
+    pub interval_hours: i64,
+}
+
+/// Telegram bot reporting failures and accepting commands from allowed chats.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TelegramConfig {
+    /// Token of the bot, from BotFather.
+    pub token: String,
+    /// Chats whose commands are accepted and that receive alerts.
+    pub allowed_chats: Vec<i64>,
+    /// Send a message when a program starts failing or recovers.
+    #[serde(default = "_true")]
+    pub alerts: bool,
+    /// How long each request for new messages waits for one.
+    #[serde(default = "_default_telegram_poll_seconds")]
+    pub poll_seconds: u64,
+    #[serde(default = "_default_telegram_api_url")]
+    pub api_url: String,
+}
+
+/// Pruning of rolled log files, on startup, daily, and with the `prune` subcommand.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetentionConfig {
+    /// Directory of rolled files to prune.
+    #[serde(default = "_default_archive_directory")]
+    pub directory: PathBuf,
+    /// Remove files last written more than this many days ago.
+    #[serde(default)]
+    pub max_age_days: Option<i64>,
+    /// Remove the oldest files while all together are larger than this.
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+}
+
+/// Pause between program loops chosen by the controller instead of `program_loop_pause`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoopPauseConfig {
+    /// Pause near program windows and after a program acted.
+    pub min_seconds: f32,
+    /// Pause while no program is due.
+    pub max_seconds: f32,
+    /// Minutes before a program window to start polling quickly.
+    #[serde(default = "_default_lead_minutes")]
+    pub lead_minutes: i64,
+}
+
+/// Push button wired to a GPIO input, running an action or triggering a program when pressed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GpioButtonConfig {
+    /// GPIO number of the input (BCM numbering on a Raspberry Pi).
+    pub pin: u32,
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub program: Option<String>,
+    /// Whether the input reads 0 while pressed (a button to ground with a pull-up).
+    #[serde(default = "_true")]
+    pub active_low: bool,
+    /// Time the input must stay pressed before the press counts, and released before the next.
+    #[serde(default = "_default_debounce_ms")]
+    pub debounce_ms: u64,
+}
+
+/// GPIO inputs of the host, read through sysfs (requires the `gpio` feature).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GpioConfig {
+    /// Number of the GPIO chip's first line in sysfs; 0 on older kernels, 512 on Raspberry Pi OS
+    /// since kernel 6.6.
+    #[serde(default)]
+    pub base: u32,
+    #[serde(default = "_default_gpio_poll_ms")]
+    pub poll_ms: u64,
+    pub buttons: BTreeMap<String, GpioButtonConfig>,
+}
+
+/// Who is home, read from Home Assistant.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PresenceConfig {
+    /// e.g. "http://homeassistant.local:8123".
+    pub home_assistant_url: String,
+    /// Environment variable holding a Home Assistant long-lived access token.
+    #[serde(default = "_default_presence_token_env")]
+    pub token_env: String,
+    /// `person` or `device_tracker` entity of each person, by name.
+    pub people: BTreeMap<String, String>,
+    #[serde(default = "_default_presence_interval")]
+    pub interval_minutes: i64,
+}
+
+/// Where sunrise and sunset times come from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuntimesSource {
+    /// api.sunrise-sunset.org
+    Api,
+    /// Computed from the latitude and longitude, without network access.
+    #[default]
+    Calculated,
+}
+
+/// Media server whose sessions are watched.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaServer {
+    Plex,
+    Jellyfin,
+}
+
+const fn _default_state_poll_seconds() -> u64 {
+    30
+}
+
+/// Background polling of the accessories named in the configuration; programs read the polled
+/// states instead of requesting them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatePollingConfig {
+    #[serde(default = "_default_state_poll_seconds")]
+    pub interval_seconds: u64,
+    /// Older states are requested from the bridge instead (default: twice the interval).
+    #[serde(default)]
+    pub max_age_seconds: Option<u64>,
+}
+
+/// Keys whose values name accessories, wherever they are in the configuration.
+const ACCESSORY_KEYS: [&str; 5] = ["light", "accessory", "switch", "valve", "lux_sensor"];
+
+/// Snoozing the programs while a media player is playing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaPauseConfig {
+    pub server: MediaServer,
+    /// e.g. "http://plex.local:32400" or "http://jellyfin.local:8096".
+    pub url: String,
+    /// Environment variable holding the Plex token or Jellyfin API key.
+    #[serde(default = "_default_media_token_env")]
+    pub token_env: String,
+    /// Names of the players that count (e.g. "Living Room TV"); any player if empty.
+    #[serde(default)]
+    pub players: Vec<String>,
+    #[serde(default = "_default_media_interval")]
+    pub interval_seconds: i64,
+    /// Minutes after playback was last seen until the programs run again.
+    #[serde(default = "_default_media_release")]
+    pub release_after_minutes: i64,
+}
+
+/// Weekly summary of the coming days' program times.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SchedulePreviewConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    #[serde(default = "_default_preview_weekday")]
+    pub weekday: Weekday,
+    #[serde(default = "_default_preview_time")]
+    pub time: NaiveTime,
+    #[serde(default = "_default_preview_days")]
+    pub days: u32,
+}
+
+/// Outbound webhook sent when an accessory characteristic changes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub accessory: Option<String>,
+    #[serde(default)]
+    pub characteristic: Option<Characteristic>,
+    #[serde(default)]
+    pub value: Option<Value>,
+    #[serde(default)]
+    pub payload: Option<Value>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+}
+
+/// Shape of a webhook's body.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The change itself, or the `payload` template filled from it.
+    #[default]
+    Default,
+    /// `value1` to `value3` of IFTTT's Webhooks service.
+    Ifttt,
+    /// Flat event data for a Home Assistant webhook trigger (`trigger.json`).
+    HomeAssistant,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheme::Http => write!(f, "http"),
+            Scheme::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// Address of the Homebridge UI, as in the `bridge` section of the configuration.
+#[derive(Deserialize)]
+struct BridgeAddressParts {
+    host: String,
+    #[serde(default = "_default_bridge_port")]
+    port: u16,
+    #[serde(default)]
+    scheme: Scheme,
+    #[serde(default)]
+    base_path: String,
+    #[serde(default)]
+    accept_invalid_certs: bool,
+}
+
+/// The bridge address is either a URL with scheme and port or the address parts.
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "a URL like \"http://192.168.0.213:8581\" or an object with `host` and optional `port`, `scheme`, `base_path`, `accept_invalid_certs`"
+)]
+enum BridgeAddressRepr {
+    Url(String),
+    Parts(BridgeAddressParts),
+}
+
+/// Validated address of the Homebridge UI.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "BridgeAddressRepr")]
+pub struct BridgeAddressConfig {
+    pub host: String,
+    pub port: u16,
+    pub scheme: Scheme,
+    /// Path prefix when the UI is served behind a reverse proxy, e.g. "/homebridge".
+    pub base_path: String,
+    /// Accept self-signed certificates (HTTPS only).
+    pub accept_invalid_certs: bool,
+}
+
+impl BridgeAddressConfig {
+    fn from_parts(parts: BridgeAddressParts) -> Result<Self, String> {
+        let host = parts.host.trim();
+        if host.is_empty() {
+            return Err("The bridge host is empty.".to_string());
+        }
+        if host.contains("://") {
+            return Err(format!(
+                "The bridge host '{}' includes a scheme; set `scheme` instead.",
+                host
+            ));
+        }
+        if host.contains('/') {
+            return Err(format!(
+                "The bridge host '{}' includes a path; set `base_path` instead.",
+                host
+            ));
+        }
+        let is_ipv6 = host.starts_with('[') && host.ends_with(']');
+        if host.contains(':') && !is_ipv6 {
+            return Err(format!(
+                "The bridge host '{}' includes a port; set `port` instead.",
+                host
+            ));
+        }
+        if parts.port == 0 {
+            return Err("The bridge port must not be 0.".to_string());
+        }
+        if parts.accept_invalid_certs && parts.scheme != Scheme::Https {
+            return Err("`accept_invalid_certs` only applies to the https scheme.".to_string());
+        }
+        let base_path = parts.base_path.trim().trim_end_matches('/');
+        let base_path = match base_path {
+            "" => String::new(),
+            p if p.starts_with('/') => p.to_string(),
+            p => format!("/{}", p),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port: parts.port,
+            scheme: parts.scheme,
+            base_path,
+            accept_invalid_certs: parts.accept_invalid_certs,
+        })
+    }
+
+    /// Parse a URL such as "http://192.168.0.213:8581", requiring the scheme and port.
+    fn from_url(url: &str) -> Result<Self, String> {
+        let url = url.trim();
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (Scheme::Http, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            (Scheme::Https, rest)
+        } else {
+            return Err(format!(
+                "The bridge address '{}' must start with http:// or https://.",
+                url
+            ));
+        };
+        let (authority, base_path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        // Skip over the brackets of an IPv6 host when looking for the port.
+        let host_end = match authority.starts_with('[') {
+            true => authority.find(']').map_or(0, |i| i + 1),
+            false => 0,
+        };
+        let Some((host, port)) = authority[host_end..]
+            .rfind(':')
+            .map(|i| authority.split_at(host_end + i))
+        else {
+            return Err(format!(
+                "The bridge address '{}' has no port (the Homebridge UI default is {}).",
+                url,
+                _default_bridge_port()
+            ));
+        };
+        let port = port[1..].parse::<u16>().map_err(|_| {
+            format!(
+                "The bridge address '{}' has an invalid port '{}'.",
+                url,
+                &port[1..]
+            )
+        })?;
+        Self::from_parts(BridgeAddressParts {
+            host: host.to_string(),
+            port,
+            scheme,
+            base_path: base_path.to_string(),
+            accept_invalid_certs: false,
+        })
+    }
+
+    /// URL the Homebridge UI API paths are appended to.
+    pub fn base_url(&self) -> String {
+        format!(
+            "{}://{}:{}{}",
+            self.scheme, self.host, self.port, self.base_path
+        )
+    }
+}
+
+impl TryFrom<BridgeAddressRepr> for BridgeAddressConfig {
+    type Error = String;
+
+    fn try_from(repr: BridgeAddressRepr) -> Result<Self, Self::Error> {
+        match repr {
+            BridgeAddressRepr::Url(url) => Self::from_url(&url),
+            BridgeAddressRepr::Parts(parts) => Self::from_parts(parts),
+        }
+    }
+}
+
+/// What to do when the controller is stopped by SIGTERM or SIGINT.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ShutdownConfig {
+    /// Put back the accessories changed by temporary effects that are still running.
+    #[serde(default = "_true")]
+    pub restore_effects: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            restore_effects: true,
+        }
+    }
+}
+
+/// Spacing of requests to the same accessory.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LatencyConfig {
+    #[serde(default = "_default_min_spacing_ms")]
+    pub min_spacing_ms: u64,
+    #[serde(default = "_default_slow_threshold_ms")]
+    pub slow_threshold_ms: u64,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self {
+            min_spacing_ms: _default_min_spacing_ms(),
+            slow_threshold_ms: _default_slow_threshold_ms(),
+        }
+    }
+}
+
+/// Spacing of writes to several accessories at once (e.g. a scene), for mesh networks
+/// like Zigbee and Z-Wave that drop commands sent in a burst.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GroupWriteConfig {
+    /// Pause between two accessories.
+    #[serde(default = "_default_group_spacing_ms")]
+    pub spacing_ms: u64,
+    /// Upper bound on all pauses of one group; the spacing shrinks to fit it.
+    #[serde(default = "_default_group_max_total_ms")]
+    pub max_total_ms: u64,
+    /// Write the accessories in random order.
+    #[serde(default = "_true")]
+    pub shuffle: bool,
+}
+
+impl Default for GroupWriteConfig {
+    fn default() -> Self {
+        Self {
+            spacing_ms: _default_group_spacing_ms(),
+            max_total_ms: _default_group_max_total_ms(),
+            shuffle: true,
+        }
+    }
+}
+
+/// Learning per accessory how far values read back stray from what the controller wrote.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdaptiveToleranceConfig {
+    #[serde(default = "_true")]
+    pub active: bool,
+    /// Largest deviation taken for device rounding rather than a manual change.
+    #[serde(default = "_default_max_learned_tolerance")]
+    pub max_tolerance: f64,
+    /// Read-backs kept per accessory characteristic.
+    #[serde(default = "_default_tolerance_samples")]
+    pub samples: usize,
+    /// Read-backs needed before the learned tolerance is used.
+    #[serde(default = "_default_tolerance_min_samples")]
+    pub min_samples: usize,
+}
+
+impl Default for AdaptiveToleranceConfig {
+    fn default() -> Self {
+        Self {
+            active: true,
+            max_tolerance: _default_max_learned_tolerance(),
+            samples: _default_tolerance_samples(),
+            min_samples: _default_tolerance_min_samples(),
+        }
+    }
+}
+
+/// Permission granted to a control API token.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiScope {
+    ReadStatus,
+    ControlPrograms,
+    ControlAccessories,
+    ReportSensors,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiTokenConfig {
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<ApiScope>,
+}
+
+/// Announcement of the control API on the local network over mDNS (DNS-SD).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnnounceConfig {
+    /// Name of the service instance shown to browsers.
+    #[serde(default = "_default_announce_instance")]
+    pub instance: String,
+    /// Host name announced as `<hostname>.local` (default: the system's host name followed by
+    /// `-controller`). The system's own host name is left to its mDNS responder.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Address announced for the host (default: the API's address, or the address of the
+    /// interface towards the local network if the API listens on all of them).
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlApiConfig {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub tokens: Vec<ApiTokenConfig>,
+    #[serde(default)]
+    pub announce: Option<AnnounceConfig>,
+    /// Serve a read-only status of the programs at `GET /public/status` without a token.
+    #[serde(default)]
+    pub public_status: bool,
+}
+
+/// Time zone times are shown in: `local` (the system's), `utc`, or a fixed offset like `+01:00`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum TimeZoneSetting {
+    #[default]
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl TryFrom<String> for TimeZoneSetting {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.to_lowercase().as_str() {
+            "local" => Ok(TimeZoneSetting::Local),
+            "utc" | "z" => Ok(TimeZoneSetting::Utc),
+            _ => name.parse().map(TimeZoneSetting::Fixed).map_err(|_| {
+                format!(
+                    "Time zone '{}' must be `local`, `utc`, or an offset like `+01:00`.",
+                    name
+                )
+            }),
+        }
+    }
+}
+
+impl From<TimeZoneSetting> for String {
+    fn from(zone: TimeZoneSetting) -> Self {
+        match zone {
+            TimeZoneSetting::Local => "local".to_string(),
+            TimeZoneSetting::Utc => "utc".to_string(),
+            TimeZoneSetting::Fixed(offset) => offset.to_string(),
+        }
+    }
+}
+
+/// How times are shown in logs and status API responses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TimeFormatConfig {
+    #[serde(default)]
+    pub timezone: TimeZoneSetting,
+    /// ISO 8601 (`2024-12-01T17:45:00+01:00`) rather than `2024-12-01 17:45:00 +01:00`.
+    #[serde(default = "_true")]
+    pub iso8601: bool,
+}
+
+impl Default for TimeFormatConfig {
+    fn default() -> Self {
+        Self {
+            timezone: TimeZoneSetting::default(),
+            iso8601: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoggerConfig {
+    pub level: LevelFilter,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoggingConfig {
+    #[serde(default = "_debug")]
+    pub level: LevelFilter,
+    #[serde(default = "_info")]
+    pub stdout_level: LevelFilter,
+    #[serde(default = "_default_log_file")]
+    pub file: PathBuf,
+    #[serde(default)]
+    pub loggers: BTreeMap<String, LoggerConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Configuration {
+    #[serde(default)]
+    pub morning_light: Option<MorningLightConfig>,
     pub turn_morning_lights_off: TurningMorningLightsOffConfig,
-    pub control_evening_lights: ControlEveningLightsConfig,
+    pub control_evening_lights: Instances<ControlEveningLightsConfig>,
+    #[serde(default)]
+    pub irrigation: Option<IrrigationConfig>,
+    #[serde(default)]
+    pub color_shift: Option<ColorShiftConfig>,
     pub program_loop_pause: f32,
-    pub ip_address: String,
+    /// Seconds after which a program run is abandoned and counted as failed.
+    #[serde(default = "_default_program_timeout")]
+    pub program_timeout_seconds: u64,
+    #[serde(default)]
+    pub loop_pause_tuning: Option<LoopPauseConfig>,
+    pub bridge: BridgeAddressConfig,
     pub latitude: f32,
     pub longitude: f32,
+    /// Age after which earlier sun times are no longer used in place of today's.
+    #[serde(default = "_default_suntimes_stale_days")]
+    pub suntimes_stale_after_days: i64,
+    #[serde(default)]
+    pub suntimes_source: SuntimesSource,
+    /// With calculated sun times, compare them with the API's once a day and warn if they
+    /// differ by more than this many minutes.
+    #[serde(default)]
+    pub suntimes_cross_check_minutes: Option<i64>,
+    #[serde(default = "_default_state_file")]
+    pub state_file: PathBuf,
+    /// File a machine-readable report is written to when the controller exits on a fatal error.
+    #[serde(default)]
+    pub crash_report: Option<PathBuf>,
+    #[serde(default)]
+    pub audit_log: Option<AuditLogConfig>,
+    #[serde(default)]
+    pub control_api: Option<ControlApiConfig>,
+    #[serde(default)]
+    pub accessories: BTreeMap<String, AccessoryConfig>,
+    #[serde(default)]
+    pub condition_actions: Vec<ConditionActionConfig>,
+    #[serde(default)]
+    pub http_polls: Vec<HttpPollConfig>,
+    /// Directory of user scripts (`*.script`), each run as a program.
+    #[serde(default)]
+    pub scripts_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub virtual_sensors: BTreeMap<String, VirtualSensorConfig>,
+    #[serde(default)]
+    pub scenes: BTreeMap<String, Vec<SceneWrite>>,
+    #[serde(default)]
+    pub actions: BTreeMap<String, ActionConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default = "_default_bridge_status_interval")]
+    pub bridge_status_interval_minutes: i64,
+    #[serde(default)]
+    pub latency: LatencyConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub group_writes: GroupWriteConfig,
+    #[serde(default)]
+    pub adaptive_tolerance: AdaptiveToleranceConfig,
+    /// Minutes after start during which programs run without writing.
+    #[serde(default)]
+    pub startup_grace_minutes: i64,
+    /// Seconds between checks of the configuration files for changes; 0 to reload only on
+    /// SIGHUP.
+    #[serde(default = "_default_watch_config_seconds")]
+    pub watch_config_seconds: u64,
+    #[serde(default)]
+    pub desktop_notifications: bool,
+    #[serde(default)]
+    pub update_check: Option<UpdateCheckConfig>,
+    #[serde(default)]
+    pub usage_ping: Option<UsagePingConfig>,
+    #[serde(default)]
+    pub telegram: Option<TelegramConfig>,
+    #[serde(default)]
+    pub schedule_preview: Option<SchedulePreviewConfig>,
+    #[serde(default)]
+    pub presence: Option<PresenceConfig>,
+    #[serde(default)]
+    pub media_pause: Option<MediaPauseConfig>,
+    #[serde(default)]
+    pub state_polling: Option<StatePollingConfig>,
+    #[serde(default)]
+    pub retention: Option<RetentionConfig>,
+    #[serde(default)]
+    pub gpio: Option<GpioConfig>,
+    #[serde(default)]
+    pub logging: Option<LoggingConfig>,
+    #[serde(default)]
+    pub time_format: TimeFormatConfig,
+    /// Settings of an older configuration layout that were upgraded when loading it.
+    #[serde(skip)]
+    pub deprecations: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read '{0}': {1}")]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse '{0}': {1}")]
+    Json(PathBuf, #[source] serde_json::Error),
+    #[error("Failed to parse '{0}': {1}")]
+    Yaml(PathBuf, #[source] serde_yaml::Error),
+    #[error("Failed to parse '{0}': {1}")]
+    Toml(PathBuf, #[source] crate::toml::TomlError),
+    #[error("'{0}' does not contain a mapping of configuration keys.")]
+    NotAMapping(PathBuf),
+    #[error("No configuration files (*.json, *.yaml, *.yml, *.toml) in '{0}'.")]
+    EmptyDirectory(PathBuf),
+    #[error("'{key}' is set differently in '{first}' and '{second}'.")]
+    Conflict {
+        key: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("{list} '{name}' is defined twice: at {first} and at {second}.")]
+    DuplicateName {
+        list: String,
+        name: String,
+        first: String,
+        second: String,
+    },
+    #[error("Invalid configuration: {0}")]
+    Invalid(#[source] serde_json::Error),
+    #[error("Invalid configuration: {0}")]
+    OutOfRange(String),
+}
+
+impl Configuration {
+    /// Check values that deserialize fine but cannot be right.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let swapped = match self.latitude.abs() > 90.0 && self.longitude.abs() <= 90.0 {
+            true => " - are `latitude` and `longitude` swapped?",
+            false => "",
+        };
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(ConfigError::OutOfRange(format!(
+                "`latitude` must be between -90 and 90, not {}{}",
+                self.latitude, swapped
+            )));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(ConfigError::OutOfRange(format!(
+                "`longitude` must be between -180 and 180, not {}",
+                self.longitude
+            )));
+        }
+        if let Some(days) = self.retention.as_ref().and_then(|r| r.max_age_days) {
+            if days < 1 {
+                return Err(ConfigError::OutOfRange(format!(
+                    "`retention.max_age_days` must be at least 1, not {}",
+                    days
+                )));
+            }
+        }
+        if let Some((name, _)) = self
+            .accessories
+            .iter()
+            .find(|(_, a)| a.max_on_minutes == Some(0))
+        {
+            return Err(ConfigError::OutOfRange(format!(
+                "`max_on_minutes` of '{}' must be at least 1",
+                name
+            )));
+        }
+        if self.audit_log.as_ref().is_some_and(|a| a.keep_days < 1) {
+            return Err(ConfigError::OutOfRange(
+                "`audit_log.keep_days` must be at least 1".to_string(),
+            ));
+        }
+        if self
+            .usage_ping
+            .as_ref()
+            .is_some_and(|p| p.interval_hours < 1)
+        {
+            return Err(ConfigError::OutOfRange(
+                "`usage_ping.interval_hours` must be at least 1".to_string(),
+            ));
+        }
+        if let Some(telegram) = &self.telegram {
+            if telegram.allowed_chats.is_empty() {
+                return Err(ConfigError::OutOfRange(
+                    "`telegram.allowed_chats` must list at least one chat".to_string(),
+                ));
+            }
+            if telegram.poll_seconds == 0 {
+                return Err(ConfigError::OutOfRange(
+                    "`telegram.poll_seconds` must be at least 1".to_string(),
+                ));
+            }
+        }
+        for (name, button) in self.gpio.iter().flat_map(|g| g.buttons.iter()) {
+            match (&button.action, &button.program) {
+                (Some(action), None) if !self.actions.contains_key(action) => {
+                    return Err(ConfigError::OutOfRange(format!(
+                        "GPIO button '{}' runs unknown action '{}'",
+                        name, action
+                    )));
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => {
+                    return Err(ConfigError::OutOfRange(format!(
+                        "GPIO button '{}' needs either an `action` or a `program`",
+                        name
+                    )));
+                }
+            }
+        }
+        if let Instances::Many(instances) = &self.control_evening_lights {
+            let mut names = Vec::new();
+            for instance in instances.iter() {
+                match &instance.name {
+                    Some(name) if !names.contains(&name) => names.push(name),
+                    Some(name) => {
+                        return Err(ConfigError::OutOfRange(format!(
+                            "`control_evening_lights` has several instances named '{}'",
+                            name
+                        )))
+                    }
+                    None => {
+                        return Err(ConfigError::OutOfRange(
+                            "Each instance of `control_evening_lights` needs a `name`".to_string(),
+                        ))
+                    }
+                }
+            }
+            if instances.iter().filter(|i| i.calibration.is_some()).count() > 1 {
+                return Err(ConfigError::OutOfRange(
+                    "Only one instance of `control_evening_lights` can have a `calibration`"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(verification) = &self.turn_morning_lights_off.verification {
+            if verification.interval_minutes < 1
+                || verification.window_minutes < verification.interval_minutes
+            {
+                return Err(ConfigError::OutOfRange(format!(
+                    "`turn_morning_lights_off.verification` needs 1 <= `interval_minutes` <= `window_minutes`, not {} and {}",
+                    verification.interval_minutes, verification.window_minutes
+                )));
+            }
+        }
+        if self
+            .state_polling
+            .as_ref()
+            .is_some_and(|p| p.interval_seconds == 0)
+        {
+            return Err(ConfigError::OutOfRange(
+                "`state_polling.interval_seconds` must be at least 1".to_string(),
+            ));
+        }
+        if self.bridge_status_interval_minutes < 1 {
+            return Err(ConfigError::OutOfRange(
+                "`bridge_status_interval_minutes` must be at least 1".to_string(),
+            ));
+        }
+        if self.program_timeout_seconds == 0 {
+            return Err(ConfigError::OutOfRange(
+                "`program_timeout_seconds` must be at least 1".to_string(),
+            ));
+        }
+        if let Some(hook) = self
+            .webhooks
+            .iter()
+            .find(|h| h.format != WebhookFormat::Default && h.payload.is_some())
+        {
+            return Err(ConfigError::OutOfRange(format!(
+                "Webhook to {} has both a `payload` template and a `format` preset",
+                hook.url
+            )));
+        }
+        if let Some(tuning) = &self.loop_pause_tuning {
+            if tuning.min_seconds <= 0.0 || tuning.max_seconds < tuning.min_seconds {
+                return Err(ConfigError::OutOfRange(format!(
+                    "`loop_pause_tuning` needs 0 < `min_seconds` <= `max_seconds`, not {} and {}",
+                    tuning.min_seconds, tuning.max_seconds
+                )));
+            }
+            if tuning.lead_minutes < 0 {
+                return Err(ConfigError::OutOfRange(format!(
+                    "`loop_pause_tuning.lead_minutes` must not be negative, not {}",
+                    tuning.lead_minutes
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lists of the configuration whose entries are told apart by their `name`.
+const NAMED_LISTS: [&str; 2] = ["condition_actions", "http_polls"];
+
+/// Place in a configuration file, for error messages.
+#[derive(Debug, Clone, PartialEq)]
+struct Location {
+    path: PathBuf,
+    line: Option<usize>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "'{}' line {}", self.path.display(), line),
+            None => write!(f, "'{}'", self.path.display()),
+        }
+    }
+}
+
+impl Location {
+    /// The line of the `nth` (from 0) entry named `name` in a file, found by its text.
+    fn of_name(path: &Path, name: &str, nth: usize) -> Self {
+        let line = fs::read_to_string(path).ok().and_then(|text| {
+            text.lines()
+                .enumerate()
+                .filter(|(_, l)| l.contains("name") && l.contains(name))
+                .nth(nth)
+                .map(|(i, _)| i + 1)
+        });
+        Self {
+            path: path.to_path_buf(),
+            line,
+        }
+    }
+}
+
+/// Reject entries of named lists that share a name, also across files.
+fn check_unique_names(values: &[(PathBuf, Value)]) -> Result<(), ConfigError> {
+    for list in NAMED_LISTS {
+        let mut seen: HashMap<&str, (&Path, usize)> = HashMap::new();
+        for (path, value) in values.iter() {
+            let mut in_file: HashMap<&str, usize> = HashMap::new();
+            let entries = value.get(list).and_then(Value::as_array);
+            for entry in entries.into_iter().flatten() {
+                let Some(name) = entry.get("name").and_then(Value::as_str) else {
+                    continue;
+                };
+                let count = in_file.entry(name).or_default();
+                let nth = *count;
+                *count += 1;
+                if let Some((first_path, first_nth)) = seen.get(name) {
+                    return Err(ConfigError::DuplicateName {
+                        list: list.to_string(),
+                        name: name.to_string(),
+                        first: Location::of_name(first_path, name, *first_nth).to_string(),
+                        second: Location::of_name(path, name, nth).to_string(),
+                    });
+                }
+                seen.insert(name, (path, nth));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_config_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("json" | "yaml" | "yml" | "toml")
+    )
+}
+
+/// Read a JSON, YAML, or TOML file (by extension) into a JSON value.
+fn read_value(path: &Path) -> Result<Value, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(&text).map_err(|e| ConfigError::Yaml(path.to_path_buf(), e))
+        }
+        Some("toml") => {
+            crate::toml::from_str(&text).map_err(|e| ConfigError::Toml(path.to_path_buf(), e))
+        }
+        _ => serde_json::from_str(&text).map_err(|e| ConfigError::Json(path.to_path_buf(), e)),
+    }
+}
+
+/// Merge `value` from `path` into `merged`, recording which file set each key.
+///
+/// Mappings are merged key by key and lists are concatenated. Any other key set in two files
+/// must have the same value in both.
+fn merge_value(
+    merged: &mut Value,
+    value: Value,
+    key: &str,
+    path: &Path,
+    sources: &mut BTreeMap<String, PathBuf>,
+) -> Result<(), ConfigError> {
+    match (merged, value) {
+        (Value::Object(merged), Value::Object(map)) => {
+            for (k, v) in map {
+                let child_key = match key {
+                    "" => k.clone(),
+                    _ => format!("{}.{}", key, k),
+                };
+                match merged.get_mut(&k) {
+                    Some(existing) => merge_value(existing, v, &child_key, path, sources)?,
+                    None => {
+                        sources.insert(child_key, path.to_path_buf());
+                        merged.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Array(merged), Value::Array(items)) => merged.extend(items),
+        (merged, value) => {
+            if *merged != value {
+                return Err(ConfigError::Conflict {
+                    key: key.to_string(),
+                    first: sources.get(key).cloned().unwrap_or_default(),
+                    second: path.to_path_buf(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Differences between two configurations, by top-level section.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    /// Programs whose sections changed.
+    pub programs: Vec<ProgramId>,
+    /// Other top-level keys that changed.
+    pub other: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.programs.is_empty() && self.other.is_empty()
+    }
+}
+
+impl Configuration {
+    /// Accessories named anywhere in the configuration, except virtual sensors.
+    pub fn referenced_accessories(&self) -> BTreeSet<String> {
+        fn collect(value: &Value, names: &mut BTreeSet<String>) {
+            match value {
+                Value::Object(map) => {
+                    for (key, value) in map.iter() {
+                        match value {
+                            Value::String(name) if ACCESSORY_KEYS.contains(&key.as_str()) => {
+                                names.insert(name.clone());
+                            }
+                            _ => collect(value, names),
+                        }
+                    }
+                }
+                Value::Array(items) => items.iter().for_each(|item| collect(item, names)),
+                _ => {}
+            }
+        }
+        let mut names: BTreeSet<String> = self.accessories.keys().cloned().collect();
+        if let Ok(value) = serde_json::to_value(self) {
+            collect(&value, &mut names);
+        }
+        names.retain(|name| !self.virtual_sensors.contains_key(name));
+        names
+    }
+
+    /// Top-level sections that differ in `other`.
+    pub fn diff(&self, other: &Configuration) -> ConfigDiff {
+        let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return ConfigDiff {
+                programs: ProgramId::ALL.to_vec(),
+                other: Vec::new(),
+            };
+        };
+        let mut diff = ConfigDiff::default();
+        for (key, value) in old.iter() {
+            if new.get(key) == Some(value) {
+                continue;
+            }
+            match ProgramId::from_config_key(key) {
+                Some(id) => diff.programs.push(id),
+                None => diff.other.push(key.clone()),
+            }
+        }
+        diff
+    }
+}
+
+/// Files making up the configuration at `path`, in the order they are merged.
+pub fn files(path: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(path)
+        .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && is_config_file(p))
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        return Err(ConfigError::EmptyDirectory(path.to_path_buf()));
+    }
+    Ok(files)
+}
+
+/// Upgrade settings of the original configuration layout in place, describing each change.
+fn migrate_legacy(value: &mut Value) -> Vec<String> {
+    let mut deprecations = Vec::new();
+    let Some(config) = value.as_object_mut() else {
+        return deprecations;
+    };
+    if let Some(address) = config.remove("ip_address") {
+        if config.contains_key("bridge") {
+            deprecations
+                .push("`ip_address` is ignored in favor of `bridge` - remove it".to_string());
+        } else {
+            config.insert("bridge".to_string(), address);
+            deprecations.push("`ip_address` is deprecated - rename it to `bridge`".to_string());
+        }
+    }
+    if let Some(lights_off) = config
+        .get_mut("turn_morning_lights_off")
+        .and_then(Value::as_object_mut)
+    {
+        if lights_off.remove("duration").is_some() {
+            deprecations.push(
+                "`turn_morning_lights_off.duration` is no longer used and is ignored - remove it"
+                    .to_string(),
+            );
+        }
+    }
+    deprecations
+}
+
+/// Load the configuration from a JSON, YAML, or TOML file, or from all such files in a
+/// directory.
+///
+/// Files in a directory are merged in name order (e.g. one file per program or room).
+pub fn load(path: &Path) -> Result<Configuration, ConfigError> {
+    let values = files(path)?
+        .into_iter()
+        .map(|file| read_value(&file).map(|value| (file, value)))
+        .collect::<Result<Vec<_>, _>>()?;
+    check_unique_names(&values)?;
+    let value = if path.is_dir() {
+        let mut merged = Value::Object(Map::new());
+        let mut sources = BTreeMap::new();
+        for (file, value) in values {
+            if !value.is_object() {
+                return Err(ConfigError::NotAMapping(file));
+            }
+            merge_value(&mut merged, value, "", &file, &mut sources)?;
+        }
+        merged
+    } else {
+        values
+            .into_iter()
+            .next()
+            .map(|(_, value)| value)
+            .unwrap_or_default()
+    };
+    let mut value = value;
+    let deprecations = migrate_legacy(&mut value);
+    let mut config: Configuration = serde_json::from_value(value).map_err(ConfigError::Invalid)?;
+    config.validate()?;
+    config.deprecations = deprecations;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(start: i64, loop_pause: f32) -> Configuration {
+        serde_json::from_value(json!({
+            "turn_morning_lights_off": {
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": {
+                "minutes_before_sunset_start": start,
+                "minutes_after_sunset_peak": 15,
+                "minutes_after_sunset_finish": 60,
+                "start_brightness": 30,
+                "max_brightness": 100,
+                "final_brightness": 75
+            },
+            "program_loop_pause": loop_pause,
+            "bridge": { "host": "127.0.0.1" },
+            "latitude": 42.36,
+            "longitude": -71.06
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_separates_programs_from_other_sections() {
+        assert!(config(45, 2.0).diff(&config(45, 2.0)).is_empty());
+        assert_eq!(
+            config(45, 2.0).diff(&config(60, 5.0)),
+            ConfigDiff {
+                programs: vec![ProgramId::ControlEveningLights],
+                other: vec!["program_loop_pause".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn calculates_sun_times_by_default() {
+        // Programs must keep running without internet access unless the API is asked for.
+        assert_eq!(config(45, 2.0).suntimes_source, SuntimesSource::Calculated);
+    }
+
+    #[test]
+    fn rejects_bridge_status_intervals_below_a_minute() {
+        let mut config = config(45, 2.0);
+        assert!(config.validate().is_ok());
+        for minutes in [0, -5] {
+            config.bridge_status_interval_minutes = minutes;
+            assert!(matches!(config.validate(), Err(ConfigError::OutOfRange(_))));
+        }
+    }
+
+    #[test]
+    fn upgrades_the_original_layout() {
+        let mut value = json!({
+            "turn_morning_lights_off": {
+                "active": true,
+                "duration": 5,
+                "off_time": null,
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": {
+                "minutes_before_sunset_start": 45,
+                "minutes_after_sunset_peak": 15,
+                "minutes_after_sunset_finish": 60,
+                "start_brightness": 30,
+                "max_brightness": 100,
+                "final_brightness": 75
+            },
+            "program_loop_pause": 30,
+            "ip_address": "http://192.168.0.213:8581",
+            "latitude": 42.361145,
+            "longitude": -71.057083
+        });
+        assert_eq!(migrate_legacy(&mut value).len(), 2);
+        assert!(value["turn_morning_lights_off"].get("duration").is_none());
+        let config: Configuration = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(config.bridge.host, "192.168.0.213");
+        assert_eq!(config.bridge.port, 8581);
+        assert!(migrate_legacy(&mut value).is_empty());
+    }
+
+    #[test]
+    fn finds_referenced_accessories() {
+        let mut value = serde_json::to_value(config(45, 2.0)).unwrap();
+        value["control_evening_lights"]["bias_lighting"] = json!({"switch": "TV Bias Lighting"});
+        value["accessories"] = json!({"Desk Lamp": {"tags": ["color_shift"]}});
+        value["virtual_sensors"] = json!({"Desk Lamp": {"characteristic": "On"}});
+        let config: Configuration = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            config.referenced_accessories(),
+            BTreeSet::from(["Bed Light".to_string(), "TV Bias Lighting".to_string()])
+        );
+    }
+
+    #[test]
+    fn requires_distinct_instance_names() {
+        let instances = |names: [Option<&str>; 2]| {
+            let mut value = serde_json::to_value(config(45, 2.0)).unwrap();
+            let instance = value["control_evening_lights"].clone();
+            value["control_evening_lights"] = names
+                .iter()
+                .map(|name| {
+                    let mut instance = instance.clone();
+                    instance["name"] = json!(name);
+                    instance
+                })
+                .collect();
+            serde_json::from_value::<Configuration>(value).unwrap()
+        };
+        assert!(instances([Some("bedroom"), Some("office")])
+            .validate()
+            .is_ok());
+        assert!(instances([Some("bedroom"), Some("bedroom")])
+            .validate()
+            .is_err());
+        assert!(instances([Some("bedroom"), None]).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_names_across_files() {
+        let dir = std::env::temp_dir().join(format!("hb-names-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("a.json");
+        let second = dir.join("b.yaml");
+        fs::write(
+            &first,
+            "{\"condition_actions\": [\n  {\"name\": \"porch\"},\n  {\"name\": \"hall\"}\n]}",
+        )
+        .unwrap();
+        fs::write(&second, "condition_actions:\n  - name: porch\n").unwrap();
+        let values = vec![
+            (first.clone(), read_value(&first).unwrap()),
+            (second.clone(), read_value(&second).unwrap()),
+        ];
+        let error = check_unique_names(&values).unwrap_err().to_string();
+        assert!(error.contains("a.json' line 2"), "{}", error);
+        assert!(error.contains("b.yaml' line 2"), "{}", error);
+        assert!(check_unique_names(&values[..1]).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,570 @@
+use crate::audit::WriteRecord;
+use crate::backoff::ProgramBackoff;
+use crate::clock;
+use crate::configuration::MorningLightConfig;
+use crate::decisions::{Decision, DecisionLog};
+use crate::homebridge::BridgeStatus;
+use crate::latency::LatencyStatus;
+use crate::maintenance::{Maintenance, MaintenanceEntry};
+use crate::programs::morning_light::{self, MorningLightProgram};
+use crate::programs::ProgramId;
+use crate::sensors::VirtualSensors;
+use crate::state::{StateError, StateStore};
+use crate::suntimes::SuntimesFreshness;
+use crate::time_format;
+use chrono::{DateTime, Local};
+use log::info;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Program name recorded for writes made by nudges.
+pub const NUDGE_SOURCE: &str = "nudge";
+
+/// Program name recorded for writes made by brightness requests.
+pub const BRIGHTNESS_SOURCE: &str = "set_brightness";
+
+/// State shared between the program loop and the control API.
+#[derive(Debug)]
+pub struct ControllerState {
+    pub store: StateStore,
+    pub bridge_status: Option<BridgeStatus>,
+    pub accessory_latency: Vec<LatencyStatus>,
+    /// Nudges waiting for the program loop to apply them.
+    pub nudges: Vec<Nudge>,
+    /// Named actions waiting for the program loop to run them.
+    pub actions: Vec<String>,
+    /// Program runs waiting for the program loop to start them.
+    pub triggers: Vec<ProgramTrigger>,
+    /// Configuration of the morning light, to validate triggered fades against.
+    pub morning_light: Option<MorningLightConfig>,
+    /// Names of the configured actions, to validate requests against.
+    pub action_names: Vec<String>,
+    /// Recent decisions of each program.
+    pub decisions: DecisionLog,
+    /// Programs held back after repeated failures.
+    pub backoff: ProgramBackoff,
+    /// Set to have the program loop read the configuration again.
+    pub reload_requested: bool,
+    /// Values reported by sensors outside Homebridge, waiting for the program loop.
+    pub sensors: VirtualSensors,
+    /// Pause before the next program loop, once the first loop has finished.
+    pub loop_pause_seconds: Option<f32>,
+    /// Programs and their schedule for today, for the public status page.
+    pub schedule: Vec<ProgramSchedule>,
+    /// Brightness changes waiting for the program loop to apply them.
+    pub brightness_requests: Vec<BrightnessRequest>,
+    /// Where today's sun times come from, as of the last program loop.
+    pub suntimes: Option<SuntimesFreshness>,
+    /// Today's brightness curves of the evening lights, for the dashboard.
+    pub curves: Vec<BrightnessCurve>,
+    /// The controller's latest writes, newest first, as of the last program loop.
+    pub recent_writes: Vec<WriteRecord>,
+    /// Accessories the programs leave alone until the maintenance ends.
+    pub maintenance: Maintenance,
+    /// File of the audit log, if writes are recorded.
+    pub audit_log: Option<PathBuf>,
+}
+
+impl ControllerState {
+    pub fn new(store: StateStore) -> Self {
+        let maintenance = Maintenance::restore(&store.state().maintenance);
+        Self {
+            store,
+            bridge_status: None,
+            accessory_latency: Vec::new(),
+            nudges: Vec::new(),
+            actions: Vec::new(),
+            triggers: Vec::new(),
+            morning_light: None,
+            action_names: Vec::new(),
+            decisions: DecisionLog::default(),
+            backoff: ProgramBackoff::default(),
+            reload_requested: false,
+            sensors: VirtualSensors::default(),
+            loop_pause_seconds: None,
+            schedule: Vec::new(),
+            brightness_requests: Vec::new(),
+            suntimes: None,
+            curves: Vec::new(),
+            recent_writes: Vec::new(),
+            maintenance,
+            audit_log: None,
+        }
+    }
+
+    /// Key of an accessory in maintenance, as resolved by the cached accessories.
+    pub fn accessory_key(&self, accessory: &str) -> String {
+        self.store.state().accessory_cache.key(accessory)
+    }
+
+    /// Write the maintenance to the state file.
+    pub fn save_maintenance(&mut self) -> Result<(), StateError> {
+        let records = self.maintenance.records();
+        self.store.update(|s| s.maintenance = records)
+    }
+
+    /// Until when a program instance is paused, also by a pause of all its instances.
+    pub fn paused_until(&self, program: &str, now: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let paused = &self.store.state().paused;
+        let base = program.split(':').next().unwrap_or(program);
+        paused
+            .get(program)
+            .or_else(|| paused.get(base))
+            .copied()
+            .filter(|until| until > now)
+    }
+
+    /// The read-only status shown without a token: whether programs are snoozed, and what
+    /// each program did last and is set to do today.
+    pub fn public_status(&mut self, now: &DateTime<Local>) -> PublicStatus<'_> {
+        PublicStatus {
+            snoozed_until: self.store.snoozed_until(now),
+            programs: self
+                .schedule
+                .iter()
+                .map(|s| PublicProgramStatus {
+                    program: &s.program,
+                    active: s.active,
+                    today: s.today.as_deref(),
+                    last_decision: self.decisions.latest(&s.program),
+                })
+                .collect(),
+        }
+    }
+
+    /// Everything the dashboard shows: the public status with pauses, the sun times, today's
+    /// curves, and the latest writes.
+    pub fn dashboard_status(&mut self, now: &DateTime<Local>) -> DashboardStatus<'_> {
+        let programs: Vec<String> = self.schedule.iter().map(|s| s.program.clone()).collect();
+        let paused: BTreeMap<String, DateTime<Local>> = programs
+            .into_iter()
+            .filter_map(|program| {
+                let until = self.paused_until(&program, now)?;
+                Some((program, until))
+            })
+            .collect();
+        DashboardStatus {
+            snoozed_until: self.store.snoozed_until(now),
+            programs: self
+                .schedule
+                .iter()
+                .map(|s| DashboardProgramStatus {
+                    program: &s.program,
+                    active: s.active,
+                    today: s.today.as_deref(),
+                    paused_until: paused.get(&s.program).copied(),
+                    last_decision: self.decisions.latest(&s.program),
+                })
+                .collect(),
+            suntimes: self.suntimes.as_ref(),
+            curves: &self.curves,
+            recent_writes: &self.recent_writes,
+            maintenance: self.maintenance.current(now),
+        }
+    }
+}
+
+pub type SharedState = Arc<Mutex<ControllerState>>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ControlError {
+    #[error("{0}")]
+    InvalidCommand(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Failed to persist state: {0}")]
+    State(#[from] StateError),
+}
+
+/// Commands accepted by the controller at runtime.
+#[derive(Debug)]
+pub enum ControlCommand {
+    /// Disable all write-capable programs until the given time.
+    Snooze { until: DateTime<Local> },
+    /// Re-enable programs before a snooze would expire.
+    Unsnooze,
+    /// Report the current snooze.
+    SnoozeStatus,
+    /// Change a light's brightness relative to its current value.
+    Nudge { accessory: String, delta: i32 },
+    /// Run a configured toggle or cycle action.
+    RunAction { name: String },
+    /// Run a program now, with some of its parameters overridden for this run.
+    TriggerProgram {
+        program: String,
+        overrides: Map<String, Value>,
+    },
+    /// Record values reported by a virtual sensor.
+    ReportSensor { name: String, body: Value },
+    /// Hold one program (or all its instances) until the given time.
+    PauseProgram {
+        program: String,
+        until: DateTime<Local>,
+    },
+    /// Let a paused program run again.
+    ResumeProgram { program: String },
+    /// Set a light's brightness.
+    SetBrightness { accessory: String, brightness: u8 },
+    /// Keep the programs off an accessory until the given time, or until ended.
+    StartMaintenance {
+        accessory: String,
+        until: Option<DateTime<Local>>,
+    },
+    /// Hand an accessory in maintenance back to the programs.
+    EndMaintenance { accessory: String },
+}
+
+/// Relative brightness change requested through the control API.
+#[derive(Serialize, Debug, Clone)]
+pub struct Nudge {
+    pub accessory: String,
+    pub delta: i32,
+    #[serde(serialize_with = "time_format::serialize")]
+    pub requested_at: DateTime<Local>,
+}
+
+/// Absolute brightness change requested through the control layer.
+#[derive(Serialize, Debug, Clone)]
+pub struct BrightnessRequest {
+    pub accessory: String,
+    pub brightness: u8,
+    #[serde(serialize_with = "time_format::serialize")]
+    pub requested_at: DateTime<Local>,
+}
+
+/// Program run requested through the control API.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProgramTrigger {
+    pub program: String,
+    pub overrides: Map<String, Value>,
+    #[serde(serialize_with = "time_format::serialize")]
+    pub requested_at: DateTime<Local>,
+}
+
+/// A program and what it is set to do today.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramSchedule {
+    pub program: String,
+    pub active: bool,
+    /// Today's schedule, e.g. `lights off 07:15`, if the program has one.
+    pub today: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicProgramStatus<'a> {
+    pub program: &'a str,
+    pub active: bool,
+    pub today: Option<&'a str>,
+    pub last_decision: Option<&'a Decision>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicStatus<'a> {
+    #[serde(serialize_with = "time_format::serialize_option")]
+    pub snoozed_until: Option<DateTime<Local>>,
+    pub programs: Vec<PublicProgramStatus<'a>>,
+}
+
+/// Brightness an evening lights instance follows through today's window.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BrightnessCurve {
+    pub program: String,
+    pub light: String,
+    pub points: Vec<CurvePoint>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CurvePoint {
+    #[serde(serialize_with = "time_format::serialize")]
+    pub at: DateTime<Local>,
+    pub brightness: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DashboardProgramStatus<'a> {
+    pub program: &'a str,
+    pub active: bool,
+    pub today: Option<&'a str>,
+    #[serde(serialize_with = "time_format::serialize_option")]
+    pub paused_until: Option<DateTime<Local>>,
+    pub last_decision: Option<&'a Decision>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DashboardStatus<'a> {
+    #[serde(serialize_with = "time_format::serialize_option")]
+    pub snoozed_until: Option<DateTime<Local>>,
+    pub programs: Vec<DashboardProgramStatus<'a>>,
+    pub suntimes: Option<&'a SuntimesFreshness>,
+    pub curves: &'a [BrightnessCurve],
+    pub recent_writes: &'a [WriteRecord],
+    pub maintenance: Vec<&'a MaintenanceEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnoozeStatus {
+    #[serde(serialize_with = "time_format::serialize_option")]
+    pub snoozed_until: Option<DateTime<Local>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ControlResponse {
+    Snooze(SnoozeStatus),
+    Queued {
+        queued: Nudge,
+    },
+    ActionQueued {
+        queued_action: String,
+    },
+    TriggerQueued {
+        queued_trigger: ProgramTrigger,
+    },
+    SensorReported {
+        sensor: String,
+        values: Map<String, Value>,
+    },
+    Paused {
+        program: String,
+        #[serde(serialize_with = "time_format::serialize_option")]
+        paused_until: Option<DateTime<Local>>,
+    },
+    BrightnessQueued {
+        queued_brightness: BrightnessRequest,
+    },
+    Maintenance {
+        accessory: String,
+        in_maintenance: bool,
+        #[serde(serialize_with = "time_format::serialize_option")]
+        until: Option<DateTime<Local>>,
+    },
+}
+
+/// Refuse a write requested by hand to an accessory in maintenance.
+fn reject_in_maintenance(state: &ControllerState, accessory: &str) -> Result<(), ControlError> {
+    match state
+        .maintenance
+        .find(&state.accessory_key(accessory), &clock::now())
+    {
+        Some(entry) => Err(ControlError::InvalidCommand(format!(
+            "{} - writes to it are rejected.",
+            entry
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn execute(
+    state: &SharedState,
+    command: ControlCommand,
+) -> Result<ControlResponse, ControlError> {
+    let mut state = state.lock().expect("State lock poisoned.");
+    match command {
+        ControlCommand::Snooze { until } => {
+            if until <= clock::now() {
+                return Err(ControlError::InvalidCommand(format!(
+                    "Snooze end {} is in the past.",
+                    time_format::show(&until)
+                )));
+            }
+            info!(
+                "Snoozing write-capable programs until {}.",
+                time_format::show(&until)
+            );
+            state.store.update(|s| s.snoozed_until = Some(until))?;
+        }
+        ControlCommand::Unsnooze => {
+            info!("Clearing snooze.");
+            state.store.update(|s| s.snoozed_until = None)?;
+        }
+        ControlCommand::SnoozeStatus => {}
+        ControlCommand::Nudge { accessory, delta } => {
+            if delta == 0 || delta.abs() > 100 {
+                return Err(ControlError::InvalidCommand(format!(
+                    "Nudge must change the brightness by 1 to 100, not {}.",
+                    delta
+                )));
+            }
+            reject_in_maintenance(&state, &accessory)?;
+            info!("Queuing nudge of '{}' by {:+}.", accessory, delta);
+            let nudge = Nudge {
+                accessory,
+                delta,
+                requested_at: clock::now(),
+            };
+            state.nudges.push(nudge.clone());
+            return Ok(ControlResponse::Queued { queued: nudge });
+        }
+        ControlCommand::RunAction { name } => {
+            if !state.action_names.contains(&name) {
+                return Err(ControlError::NotFound(format!(
+                    "Unknown action '{}'.",
+                    name
+                )));
+            }
+            info!("Queuing action '{}'.", name);
+            state.actions.push(name.clone());
+            return Ok(ControlResponse::ActionQueued {
+                queued_action: name,
+            });
+        }
+        ControlCommand::TriggerProgram { program, overrides } => {
+            let now = clock::now();
+            // Only the morning fade has a meaningful "start now".
+            if program != morning_light::PROGRAM_NAME {
+                return Err(ControlError::InvalidCommand(format!(
+                    "Program '{}' cannot be triggered.",
+                    program
+                )));
+            }
+            let Some(config) = &state.morning_light else {
+                return Err(ControlError::InvalidCommand(
+                    "No morning light is configured.".to_string(),
+                ));
+            };
+            MorningLightProgram::triggered(config, &overrides, &now)
+                .map_err(|e| ControlError::InvalidCommand(e.to_string()))?;
+            info!("Queuing trigger of '{}' with {:?}.", program, overrides);
+            let trigger = ProgramTrigger {
+                program,
+                overrides,
+                requested_at: now,
+            };
+            state.triggers.push(trigger.clone());
+            return Ok(ControlResponse::TriggerQueued {
+                queued_trigger: trigger,
+            });
+        }
+        ControlCommand::ReportSensor { name, body } => {
+            let values = state
+                .sensors
+                .report(&name, &body)
+                .map_err(|e| ControlError::InvalidCommand(e.to_string()))?;
+            return Ok(ControlResponse::SensorReported {
+                sensor: name,
+                values,
+            });
+        }
+        ControlCommand::PauseProgram { program, until } => {
+            let now = clock::now();
+            if until <= now {
+                return Err(ControlError::InvalidCommand(format!(
+                    "Pause end {} is in the past.",
+                    time_format::show(&until)
+                )));
+            }
+            let Some(program) = ProgramId::full_name(&program) else {
+                return Err(ControlError::InvalidCommand(format!(
+                    "No program '{}'.",
+                    program
+                )));
+            };
+            // Until the first loop publishes the schedule, any program is taken.
+            let known = state.schedule.is_empty()
+                || state
+                    .schedule
+                    .iter()
+                    .any(|s| s.program == program || s.program.split(':').next() == Some(&program));
+            if !known {
+                return Err(ControlError::InvalidCommand(format!(
+                    "No program '{}'.",
+                    program
+                )));
+            }
+            info!("Pausing {} until {}.", program, time_format::show(&until));
+            state.store.update(|s| {
+                s.paused.retain(|_, until| *until > now);
+                s.paused.insert(program.clone(), until);
+            })?;
+            return Ok(ControlResponse::Paused {
+                program,
+                paused_until: Some(until),
+            });
+        }
+        ControlCommand::ResumeProgram { program } => {
+            let now = clock::now();
+            let program = ProgramId::full_name(&program).unwrap_or(program);
+            let paused = state
+                .store
+                .state()
+                .paused
+                .get(&program)
+                .is_some_and(|until| *until > now);
+            if !paused {
+                return Err(ControlError::InvalidCommand(format!(
+                    "Program '{}' is not paused.",
+                    program
+                )));
+            }
+            state.store.update(|s| {
+                s.paused.remove(&program);
+                s.paused.retain(|_, until| *until > now);
+            })?;
+            info!("Resuming {}.", program);
+            return Ok(ControlResponse::Paused {
+                program,
+                paused_until: None,
+            });
+        }
+        ControlCommand::SetBrightness {
+            accessory,
+            brightness,
+        } => {
+            if brightness > 100 {
+                return Err(ControlError::InvalidCommand(format!(
+                    "Brightness must be 0 to 100, not {}.",
+                    brightness
+                )));
+            }
+            reject_in_maintenance(&state, &accessory)?;
+            info!("Queuing brightness {} for '{}'.", brightness, accessory);
+            let request = BrightnessRequest {
+                accessory,
+                brightness,
+                requested_at: clock::now(),
+            };
+            state.brightness_requests.push(request.clone());
+            return Ok(ControlResponse::BrightnessQueued {
+                queued_brightness: request,
+            });
+        }
+        ControlCommand::StartMaintenance { accessory, until } => {
+            if until.is_some_and(|until| until <= clock::now()) {
+                return Err(ControlError::InvalidCommand(
+                    "Maintenance end is in the past.".to_string(),
+                ));
+            }
+            let key = state.accessory_key(&accessory);
+            let entry = state.maintenance.start(&key, &accessory, until);
+            state.save_maintenance()?;
+            info!("{} - programs leave it alone.", entry);
+            return Ok(ControlResponse::Maintenance {
+                accessory,
+                in_maintenance: true,
+                until,
+            });
+        }
+        ControlCommand::EndMaintenance { accessory } => {
+            let key = state.accessory_key(&accessory);
+            if !state.maintenance.end(&key) {
+                return Err(ControlError::InvalidCommand(format!(
+                    "'{}' is not in maintenance.",
+                    accessory
+                )));
+            }
+            state.save_maintenance()?;
+            info!("Ended the maintenance of '{}'.", accessory);
+            return Ok(ControlResponse::Maintenance {
+                accessory,
+                in_maintenance: false,
+                until: None,
+            });
+        }
+    }
+    Ok(ControlResponse::Snooze(SnoozeStatus {
+        snoozed_until: state.store.snoozed_until(&clock::now()),
+    }))
+}
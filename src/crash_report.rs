@@ -0,0 +1,249 @@
+use crate::clock;
+use crate::configuration::Configuration;
+use crate::control::SharedState;
+use crate::decisions::Decision;
+use crate::time_format;
+use chrono::{DateTime, Local};
+use log::{error, info};
+use serde::Serialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, TryLockError};
+
+/// Decisions of the programs included in a report, the most recent ones.
+const REPORTED_EVENTS: usize = 30;
+
+/// Decision of a program, as listed in a report.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReportedEvent {
+    pub program: String,
+    #[serde(flatten)]
+    pub decision: Decision,
+}
+
+/// Machine-readable account of a fatal exit, so a post-mortem does not depend on the logs
+/// surviving the restart.
+#[derive(Serialize, Debug)]
+pub struct CrashReport {
+    #[serde(serialize_with = "time_format::serialize")]
+    pub when: DateTime<Local>,
+    pub controller_version: &'static str,
+    /// Operating system and architecture of the host, e.g. "linux-aarch64".
+    pub platform: String,
+    /// Node.js version of the Homebridge host, once its status was fetched.
+    pub bridge_node_version: Option<String>,
+    /// Hash of the configuration in effect, to tell which version of it was running.
+    pub config_hash: Option<String>,
+    /// The error followed by its causes.
+    pub errors: Vec<String>,
+    /// Latest decisions of the programs, oldest first.
+    pub last_events: Vec<ReportedEvent>,
+}
+
+/// Where reports go and what they draw on, set once the configuration is loaded.
+struct Reporter {
+    path: PathBuf,
+    config_hash: String,
+    state: Option<SharedState>,
+}
+
+static REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+static PANIC_HOOK: Once = Once::new();
+
+/// 64-bit FNV-1a hash of the configuration, stable across runs and builds.
+pub fn config_hash(config: &Configuration) -> String {
+    let serialized = serde_json::to_string(&serde_json::to_value(config).unwrap_or_default())
+        .unwrap_or_default();
+    let hash = serialized
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Write reports to `path` from now on, also for panics of the calling thread, for the
+/// configuration in effect.
+///
+/// Panics of other threads, e.g. in tasks spawned on the runtime's workers, only end that task
+/// and are left to the log.
+pub fn install(path: &Path, config: &Configuration) {
+    if let Ok(mut reporter) = REPORTER.lock() {
+        *reporter = Some(Reporter {
+            path: path.to_path_buf(),
+            config_hash: config_hash(config),
+            state: None,
+        });
+    }
+    PANIC_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        let main = std::thread::current().id();
+        std::panic::set_hook(Box::new(move |info| {
+            if std::thread::current().id() == main {
+                write(vec![info.to_string()]);
+            }
+            previous(info);
+        }));
+    });
+}
+
+/// Include the latest decisions of the programs in reports.
+pub fn attach(state: SharedState) {
+    if let Ok(Some(reporter)) = REPORTER.lock().as_deref_mut() {
+        reporter.state = Some(state);
+    }
+}
+
+/// Take note of a reloaded configuration.
+pub fn update_config(config: &Configuration) {
+    if let Ok(Some(reporter)) = REPORTER.lock().as_deref_mut() {
+        reporter.config_hash = config_hash(config);
+    }
+}
+
+/// The error followed by its causes.
+pub fn error_chain(error: &dyn Error) -> Vec<String> {
+    let mut chain = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push(cause.to_string());
+        source = cause.source();
+    }
+    chain
+}
+
+/// Write a report of a fatal error, if reports are installed.
+pub fn report(error: &dyn Error) {
+    write(error_chain(error));
+}
+
+/// Write a report of a fatal error without causes, if reports are installed.
+pub fn report_message(message: &str) {
+    write(vec![message.to_string()]);
+}
+
+fn write(errors: Vec<String>) {
+    // A panic while the reporter is locked must not lock it again.
+    let reporter = match REPORTER.try_lock() {
+        Ok(reporter) => reporter,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    let Some(reporter) = reporter.as_ref() else {
+        return;
+    };
+    let report = CrashReport {
+        when: clock::now(),
+        controller_version: env!("CARGO_PKG_VERSION"),
+        platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        bridge_node_version: None,
+        config_hash: Some(reporter.config_hash.clone()),
+        errors,
+        last_events: Vec::new(),
+    };
+    let report = match &reporter.state {
+        Some(state) => with_state(report, state),
+        None => report,
+    };
+    let written = serde_json::to_string_pretty(&report)
+        .map_err(|e| e.to_string())
+        .and_then(|json| std::fs::write(&reporter.path, json).map_err(|e| e.to_string()));
+    match written {
+        Ok(()) => info!("Wrote crash report to {}.", reporter.path.display()),
+        Err(e) => error!(
+            "Could not write crash report to {}: {}",
+            reporter.path.display(),
+            e
+        ),
+    }
+}
+
+/// Add what the controller's state knows, unless the state is locked by the failing thread.
+fn with_state(mut report: CrashReport, state: &SharedState) -> CrashReport {
+    let state = match state.try_lock() {
+        Ok(state) => state,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return report,
+    };
+    report.bridge_node_version = state.bridge_status.as_ref().map(|s| s.node_version.clone());
+    report.last_events = latest_events(
+        state
+            .decisions
+            .timeline()
+            .into_iter()
+            .flat_map(|(program, decisions)| {
+                decisions.into_iter().map(move |d| ReportedEvent {
+                    program: program.to_string(),
+                    decision: d.clone(),
+                })
+            })
+            .collect(),
+    );
+    report
+}
+
+/// The most recent events, oldest first.
+fn latest_events(mut events: Vec<ReportedEvent>) -> Vec<ReportedEvent> {
+    events.sort_by_key(|e| e.decision.when);
+    let skip = events.len().saturating_sub(REPORTED_EVENTS);
+    events.split_off(skip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::ConfigError;
+    use chrono::Duration;
+
+    #[test]
+    fn reports_errors_with_their_causes() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let error = ConfigError::Io(PathBuf::from("config.json"), io);
+        assert_eq!(
+            error_chain(&error),
+            vec!["Failed to read 'config.json': no such file", "no such file"]
+        );
+
+        let start = Local::now();
+        let events = (0..40)
+            .rev()
+            .map(|i| ReportedEvent {
+                program: format!("program {}", i),
+                decision: Decision {
+                    when: start + Duration::minutes(i),
+                    ..Decision::ran("Done")
+                },
+            })
+            .collect();
+        let latest = latest_events(events);
+        assert_eq!(latest.len(), REPORTED_EVENTS);
+        assert_eq!(latest[0].program, "program 10");
+        assert_eq!(latest[REPORTED_EVENTS - 1].program, "program 39");
+    }
+
+    #[test]
+    fn reports_only_panics_of_the_installing_thread() {
+        let path = std::env::temp_dir().join(format!("hb-crash-{}.json", std::process::id()));
+        let config: Configuration = serde_json::from_value(serde_json::json!({
+            "turn_morning_lights_off": {
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": [],
+            "program_loop_pause": 2.0,
+            "bridge": { "host": "127.0.0.1" },
+            "latitude": 42.36,
+            "longitude": -71.06
+        }))
+        .unwrap();
+        install(&path, &config);
+
+        let _ = std::thread::spawn(|| panic!("Task failed")).join();
+        assert!(!path.exists());
+
+        let _ = std::panic::catch_unwind(|| panic!("Loop failed"));
+        let report = std::fs::read_to_string(&path).unwrap();
+        assert!(report.contains("Loop failed"), "{}", report);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use crate::clock;
+use crate::suntimes::SuntimesFreshness;
+use crate::time_format;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Decisions kept per program.
+const DECISIONS_PER_PROGRAM: usize = 50;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The program acted (or checked and found its work done).
+    Ran,
+    /// The program had nothing to do or was held back.
+    Skipped,
+    /// The program hit an error.
+    Failed,
+}
+
+/// What a program did in one loop, and why.
+#[derive(Serialize, Debug, Clone)]
+pub struct Decision {
+    #[serde(serialize_with = "time_format::serialize")]
+    pub when: DateTime<Local>,
+    pub outcome: Outcome,
+    pub reason: String,
+    /// Sun times the decision rests on, if the program asked for any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suntimes: Option<SuntimesFreshness>,
+}
+
+impl Decision {
+    fn new(outcome: Outcome, reason: impl Into<String>) -> Self {
+        Self {
+            when: clock::now(),
+            outcome,
+            reason: reason.into(),
+            suntimes: None,
+        }
+    }
+
+    pub fn ran(reason: impl Into<String>) -> Self {
+        Self::new(Outcome::Ran, reason)
+    }
+
+    pub fn skipped(reason: impl Into<String>) -> Self {
+        Self::new(Outcome::Skipped, reason)
+    }
+
+    pub fn failed(reason: impl Into<String>) -> Self {
+        Self::new(Outcome::Failed, reason)
+    }
+}
+
+/// Number of decisions of a program by outcome, since the controller started.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutcomeCounts {
+    pub ran: u64,
+    pub skipped: u64,
+    pub failed: u64,
+}
+
+/// Recent decisions of each program, newest last.
+#[derive(Debug, Default)]
+pub struct DecisionLog {
+    programs: BTreeMap<String, VecDeque<Decision>>,
+    counts: BTreeMap<String, OutcomeCounts>,
+}
+
+impl DecisionLog {
+    pub fn record(&mut self, program: &str, decision: Decision) {
+        let counts = self.counts.entry(program.to_string()).or_default();
+        match decision.outcome {
+            Outcome::Ran => counts.ran += 1,
+            Outcome::Skipped => counts.skipped += 1,
+            Outcome::Failed => counts.failed += 1,
+        }
+        let decisions = self.programs.entry(program.to_string()).or_default();
+        if decisions.len() == DECISIONS_PER_PROGRAM {
+            decisions.pop_front();
+        }
+        decisions.push_back(decision);
+    }
+
+    /// Decisions per program, newest first.
+    pub fn timeline(&self) -> BTreeMap<&str, Vec<&Decision>> {
+        self.programs
+            .iter()
+            .map(|(program, decisions)| (program.as_str(), decisions.iter().rev().collect()))
+            .collect()
+    }
+
+    /// The newest decision of a program.
+    pub fn latest(&self, program: &str) -> Option<&Decision> {
+        self.programs.get(program).and_then(|d| d.back())
+    }
+
+    /// Decisions of each program by outcome.
+    pub fn counts(&self) -> &BTreeMap<String, OutcomeCounts> {
+        &self.counts
+    }
+
+    /// Decisions of all programs in which they acted.
+    pub fn total_ran(&self) -> u64 {
+        self.counts.values().map(|c| c.ran).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_newest_decisions_first() {
+        let mut log = DecisionLog::default();
+        for i in 0..(DECISIONS_PER_PROGRAM + 5) {
+            log.record("evening", Decision::ran(format!("run {}", i)));
+        }
+        log.record("morning", Decision::skipped("inactive"));
+
+        let timeline = log.timeline();
+        assert_eq!(timeline["evening"].len(), DECISIONS_PER_PROGRAM);
+        assert_eq!(timeline["evening"][0].reason, "run 54");
+        assert_eq!(timeline["morning"][0].outcome, Outcome::Skipped);
+        assert_eq!(log.latest("evening").unwrap().reason, "run 54");
+        assert!(log.latest("irrigation").is_none());
+        assert_eq!(
+            log.counts()["evening"].ran,
+            DECISIONS_PER_PROGRAM as u64 + 5
+        );
+        assert_eq!(log.total_ran(), DECISIONS_PER_PROGRAM as u64 + 5);
+    }
+}
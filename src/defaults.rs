@@ -0,0 +1,41 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Logging configuration used when neither the configuration nor "log4rs.yaml" sets one.
+pub const LOG_CONFIG: &str = include_str!("../log4rs.yaml");
+
+/// Example configuration to start from.
+pub const EXAMPLE_CONFIG: &str = include_str!("../config.json");
+
+/// Files embedded in the binary, by the name they are written out as.
+pub const FILES: [(&str, &str); 2] = [("log4rs.yaml", LOG_CONFIG), ("config.json", EXAMPLE_CONFIG)];
+
+/// Write the embedded files into `dir` for customization, leaving existing files alone.
+///
+/// Returns the files that were written.
+pub fn dump(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    for (name, contents) in FILES {
+        let path = dir.join(name);
+        if path.exists() {
+            continue;
+        }
+        fs::write(&path, contents)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Configuration;
+
+    #[test]
+    fn embedded_files_parse() {
+        serde_yaml::from_str::<log4rs::config::RawConfig>(LOG_CONFIG).unwrap();
+        serde_json::from_str::<Configuration>(EXAMPLE_CONFIG).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::audit::{AuditSink, WriteRecord};
+use log::warn;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Writes made within this long of the first one are shown in one notification.
+const COALESCE_SECS: u64 = 2;
+/// Writes listed in a notification; the rest are counted.
+const LISTED_WRITES: usize = 5;
+
+/// Shows desktop notifications of the controller's writes, one per burst of writes (e.g. an
+/// evening fade step over several lights).
+///
+/// Uses `osascript` on macOS and `notify-send` elsewhere, so no GUI libraries are linked.
+#[derive(Default)]
+pub struct DesktopNotifier {
+    pending: Arc<Mutex<Vec<String>>>,
+}
+
+impl DesktopNotifier {
+    /// Show a notification without waiting for it; a failing command is logged.
+    pub fn notify(title: &str, body: &str) -> std::io::Result<()> {
+        let mut child = if cfg!(target_os = "macos") {
+            let script = format!(
+                "display notification {:?} with title {:?}",
+                body.replace('"', "'"),
+                title
+            );
+            Command::new("osascript").args(["-e", &script]).spawn()?
+        } else {
+            Command::new("notify-send").args([title, body]).spawn()?
+        };
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if !status.success() => {
+                    warn!("Desktop notification command exited with {}.", status)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to show desktop notification: {}", e),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Body of the notification for a burst of writes.
+fn summary(writes: &[String]) -> String {
+    if let [write] = writes {
+        return write.clone();
+    }
+    let mut lines: Vec<String> = writes.iter().take(LISTED_WRITES).cloned().collect();
+    if writes.len() > LISTED_WRITES {
+        lines.push(format!("and {} more", writes.len() - LISTED_WRITES));
+    }
+    format!("{} changes:\n{}", writes.len(), lines.join("\n"))
+}
+
+impl AuditSink for DesktopNotifier {
+    fn record(&mut self, record: &WriteRecord) {
+        let write = format!(
+            "{} {} -> {} ({})",
+            record.accessory, record.characteristic, record.after, record.program
+        );
+        let mut pending = self.pending.lock().expect("Notification lock poisoned.");
+        pending.push(write);
+        if pending.len() > 1 {
+            return;
+        }
+        // The first write of a burst shows the whole burst once it is over.
+        let pending = Arc::clone(&self.pending);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(COALESCE_SECS)).await;
+            let writes = std::mem::take(&mut *pending.lock().expect("Notification lock poisoned."));
+            if let Err(e) = Self::notify("Homebridge controller", &summary(&writes)) {
+                warn!("Failed to show desktop notification: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_of_writes_are_summarized() {
+        let writes: Vec<String> = (1..=7).map(|i| format!("Lamp {} On -> 1", i)).collect();
+        assert_eq!(summary(&writes[..1]), "Lamp 1 On -> 1");
+        assert_eq!(
+            summary(&writes[..2]),
+            "2 changes:\nLamp 1 On -> 1\nLamp 2 On -> 1"
+        );
+        let summarized = summary(&writes);
+        assert!(summarized.starts_with("7 changes:\nLamp 1 On -> 1\n"));
+        assert!(summarized.ends_with("Lamp 5 On -> 1\nand 2 more"));
+    }
+}
@@ -0,0 +1,385 @@
+use crate::configuration::{AnnounceConfig, ControlApiConfig};
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// DNS-SD service type of the control API.
+const SERVICE_TYPE: [&str; 3] = ["_homebridge-controller", "_tcp", "local"];
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Time other hosts may cache the records.
+const TTL_SECONDS: u32 = 120;
+/// Unsolicited announcements on startup, one second apart.
+const ANNOUNCEMENTS: u32 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Class bit telling caches to replace earlier records of a unique name.
+const CACHE_FLUSH: u16 = 0x8000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DiscoveryError {
+    #[error("Could not open the mDNS socket: {0}")]
+    Socket(#[from] std::io::Error),
+    #[error("The control API only listens on {0}, which other hosts cannot reach.")]
+    Loopback(IpAddr),
+    #[error("Could not tell the address to announce; set `control_api.announce.address`.")]
+    NoAddress,
+}
+
+/// DNS-SD records advertising the control API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub instance: String,
+    /// Host name without the `.local` domain.
+    pub hostname: String,
+    pub address: Ipv4Addr,
+    /// Whether the A record of the host is published; not for the system's own host name,
+    /// which the system's responder (e.g. Avahi) already answers for.
+    pub owns_host: bool,
+    pub port: u16,
+    pub txt: Vec<String>,
+}
+
+impl Announcement {
+    pub fn new(config: &AnnounceConfig, api: &ControlApiConfig) -> Result<Self, DiscoveryError> {
+        let address = match (config.address, api.address.ip()) {
+            (Some(address), _) => address,
+            (None, ip) if ip.is_loopback() => return Err(DiscoveryError::Loopback(ip)),
+            (None, IpAddr::V4(ip)) if !ip.is_unspecified() => ip,
+            (None, _) => local_address().ok_or(DiscoveryError::NoAddress)?,
+        };
+        let system = system_hostname().map(|name| label(&name));
+        // A label of its own by default, so the record never conflicts with the system's.
+        let hostname = match (&config.hostname, &system) {
+            (Some(name), _) => label(name),
+            (None, Some(system)) => format!("{}-controller", system),
+            (None, None) => "homebridge-controller".to_string(),
+        };
+        let owns_host = !system.is_some_and(|system| system.eq_ignore_ascii_case(&hostname));
+        let auth = match api.tokens.is_empty() {
+            true => "none",
+            false => "token",
+        };
+        Ok(Self {
+            instance: config.instance.clone(),
+            hostname,
+            address,
+            owns_host,
+            port: api.address.port(),
+            txt: vec![
+                "txtvers=1".to_string(),
+                format!("version={}", env!("CARGO_PKG_VERSION")),
+                format!("auth={}", auth),
+                "metrics=/metrics".to_string(),
+            ],
+        })
+    }
+
+    fn service_name(&self) -> Vec<&str> {
+        SERVICE_TYPE.to_vec()
+    }
+
+    fn instance_name(&self) -> Vec<&str> {
+        let mut name = vec![self.instance.as_str()];
+        name.extend(SERVICE_TYPE);
+        name
+    }
+
+    fn host_name(&self) -> Vec<&str> {
+        vec![self.hostname.as_str(), "local"]
+    }
+
+    /// mDNS response with the PTR, SRV, and TXT records of the service, and the A record of
+    /// its host if owned.
+    pub fn response(&self, id: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        let answers = 3 + u16::from(self.owns_host);
+        // Header: id, flags (authoritative response), questions, answers, authority, additional.
+        for field in [id, 0x8400, 0, answers, 0, 0] {
+            out.extend(field.to_be_bytes());
+        }
+        let instance = encode_name(&self.instance_name());
+        record(
+            &mut out,
+            &self.service_name(),
+            TYPE_PTR,
+            CLASS_IN,
+            &instance,
+        );
+
+        let mut srv = Vec::new();
+        // Priority and weight.
+        srv.extend([0, 0, 0, 0]);
+        srv.extend(self.port.to_be_bytes());
+        srv.extend(encode_name(&self.host_name()));
+        let unique = CLASS_IN | CACHE_FLUSH;
+        record(&mut out, &self.instance_name(), TYPE_SRV, unique, &srv);
+
+        let mut txt = Vec::new();
+        for entry in self.txt.iter() {
+            let bytes = &entry.as_bytes()[..entry.len().min(255)];
+            txt.push(bytes.len() as u8);
+            txt.extend(bytes);
+        }
+        record(&mut out, &self.instance_name(), TYPE_TXT, unique, &txt);
+        if self.owns_host {
+            record(
+                &mut out,
+                &self.host_name(),
+                TYPE_A,
+                unique,
+                &self.address.octets(),
+            );
+        }
+        out
+    }
+
+    /// Whether a packet is a query for the service type, this instance, or its owned host.
+    pub fn is_asked_for(&self, packet: &[u8]) -> bool {
+        let mut ours = vec![self.service_name(), self.instance_name()];
+        if self.owns_host {
+            ours.push(self.host_name());
+        }
+        questions(packet).iter().any(|question| {
+            ours.iter().any(|name| {
+                name.len() == question.len()
+                    && name
+                        .iter()
+                        .zip(question.iter())
+                        .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            })
+        })
+    }
+}
+
+/// Name in DNS wire format, without compression.
+fn encode_name(labels: &[&str]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for label in labels.iter() {
+        let bytes = &label.as_bytes()[..label.len().min(63)];
+        out.push(bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.push(0);
+    out
+}
+
+fn record(out: &mut Vec<u8>, name: &[&str], rtype: u16, class: u16, data: &[u8]) {
+    out.extend(encode_name(name));
+    out.extend(rtype.to_be_bytes());
+    out.extend(class.to_be_bytes());
+    out.extend(TTL_SECONDS.to_be_bytes());
+    out.extend((data.len() as u16).to_be_bytes());
+    out.extend(data);
+}
+
+/// Name starting at `pos`, following compression pointers, and the position after it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so a malicious packet cannot loop.
+    for _ in 0..32 {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some((labels, end.unwrap_or(pos + 1))),
+            l if l & 0xC0 == 0xC0 => {
+                let target = ((l & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            l => {
+                let label = packet.get(pos + 1..pos + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+/// Names asked for by a query; empty for responses and malformed packets.
+fn questions(packet: &[u8]) -> Vec<Vec<String>> {
+    if packet.len() < 12 || packet[2] & 0x80 != 0 {
+        return Vec::new();
+    }
+    let count = u16::from_be_bytes([packet[4], packet[5]]);
+    let mut names = Vec::new();
+    let mut pos = 12;
+    for _ in 0..count {
+        let Some((name, after)) = read_name(packet, pos) else {
+            break;
+        };
+        names.push(name);
+        // Type and class.
+        pos = after + 4;
+    }
+    names
+}
+
+/// The system's host name.
+fn system_hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+}
+
+/// First label of a host name, e.g. `raspberrypi` for `raspberrypi.local`.
+fn label(name: &str) -> String {
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+/// Address of the interface multicast goes out of. Connecting a UDP socket sends nothing.
+fn local_address() -> Option<Ipv4Addr> {
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+/// Socket on the mDNS port, shared with other responders on the host (e.g. Avahi).
+fn mdns_socket() -> Result<UdpSocket, std::io::Error> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Announce the control API on the local network and answer queries for it until the socket
+/// fails.
+pub async fn announce(config: AnnounceConfig, api: ControlApiConfig) {
+    let announcement = match Announcement::new(&config, &api) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Not announcing the control API: {}", e);
+            return;
+        }
+    };
+    let socket = match mdns_socket() {
+        Ok(s) => s,
+        Err(e) => {
+            error!(
+                "Not announcing the control API: {}",
+                DiscoveryError::from(e)
+            );
+            return;
+        }
+    };
+    info!(
+        "Announcing the control API as '{}' on {}.local ({}:{}).",
+        announcement.instance, announcement.hostname, announcement.address, announcement.port
+    );
+    let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+    for i in 0..ANNOUNCEMENTS {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        if let Err(e) = socket.send_to(&announcement.response(0), group).await {
+            warn!("Failed to announce the control API: {}", e);
+        }
+    }
+    let mut buffer = [0u8; 9000];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buffer).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Stopped answering mDNS queries: {}", e);
+                return;
+            }
+        };
+        let query = &buffer[..len];
+        if !announcement.is_asked_for(query) {
+            continue;
+        }
+        debug!("Answering mDNS query from {}.", peer);
+        // Queries from other ports are one-shot lookups expecting a direct reply.
+        let (id, to) = match peer.port() == MDNS_PORT {
+            true => (0, group),
+            false => (u16::from_be_bytes([query[0], query[1]]), peer),
+        };
+        if let Err(e) = socket.send_to(&announcement.response(id), to).await {
+            warn!("Failed to answer mDNS query from {}: {}", peer, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &[&str]) -> Vec<u8> {
+        let mut packet = vec![0, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend(encode_name(name));
+        packet.extend(TYPE_PTR.to_be_bytes());
+        packet.extend(CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn answers_queries_for_the_service() {
+        let announcement = Announcement {
+            instance: "Homebridge Controller".to_string(),
+            hostname: "raspberrypi-controller".to_string(),
+            address: Ipv4Addr::new(192, 168, 0, 20),
+            owns_host: true,
+            port: 8080,
+            txt: vec!["txtvers=1".to_string()],
+        };
+        assert!(announcement.is_asked_for(&query(&SERVICE_TYPE)));
+        assert!(announcement.is_asked_for(&query(&["RaspberryPi-Controller", "local"])));
+        assert!(!announcement.is_asked_for(&query(&["_http", "_tcp", "local"])));
+
+        let response = announcement.response(0);
+        assert!(!announcement.is_asked_for(&response), "not a query");
+        assert_eq!(u16::from_be_bytes([response[6], response[7]]), 4);
+        let (name, after) = read_name(&response, 12).unwrap();
+        assert_eq!(name, SERVICE_TYPE);
+        let (target, _) = read_name(&response, after + 10).unwrap();
+        assert_eq!(target[0], "Homebridge Controller");
+        assert!(response.windows(6).any(|w| w == [0, 0, 0, 0, 0x1f, 0x90]));
+        assert!(response.ends_with(&[192, 168, 0, 20]));
+    }
+
+    #[test]
+    fn leaves_the_system_host_to_its_responder() {
+        let Some(system) = system_hostname().map(|name| label(&name)) else {
+            return;
+        };
+        let api: ControlApiConfig = serde_json::from_value(serde_json::json!({
+            "address": "192.168.0.20:8080"
+        }))
+        .unwrap();
+        let config = |hostname: Option<&str>| AnnounceConfig {
+            instance: "Homebridge Controller".to_string(),
+            hostname: hostname.map(str::to_string),
+            address: None,
+        };
+
+        let announcement = Announcement::new(&config(None), &api).unwrap();
+        assert_eq!(announcement.hostname, format!("{}-controller", system));
+        assert!(announcement.owns_host);
+
+        let announcement = Announcement::new(&config(Some(&system)), &api).unwrap();
+        assert!(!announcement.owns_host);
+        assert!(!announcement.is_asked_for(&query(&[&system, "local"])));
+        let response = announcement.response(0);
+        assert_eq!(u16::from_be_bytes([response[6], response[7]]), 3);
+        assert!(!response.ends_with(&[192, 168, 0, 20]));
+    }
+}
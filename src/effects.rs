@@ -0,0 +1,237 @@
+use crate::characteristic::Characteristic;
+use crate::homebridge::Homebridge;
+use crate::time_format;
+use chrono::{DateTime, Local};
+use log::{debug, info, warn};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Program name recorded for writes restoring accessories after temporary effects.
+pub const SOURCE: &str = "temporary_effects";
+
+/// A temporary change of an accessory.
+#[derive(Debug, Clone)]
+struct Effect {
+    name: String,
+    until: DateTime<Local>,
+    /// Values of the characteristics it writes from before it started.
+    before: Map<String, Value>,
+}
+
+/// Temporary changes of accessories (e.g. flashing a light while a condition is set) and the
+/// values to put back once they end.
+///
+/// Effects on an accessory stack up, each remembering the values from before it started. When
+/// the newest effect ends, its values are restored, returning the accessory to the effect below
+/// it; when an older effect ends first, the next one takes over the values it would have
+/// restored. The accessory is back to how it was before the first effect once all have ended.
+#[derive(Debug, Clone, Default)]
+pub struct TemporaryEffects {
+    effects: HashMap<String, Vec<Effect>>,
+}
+
+impl TemporaryEffects {
+    /// Start an effect that writes `characteristics` of an accessory until `until`, given the
+    /// accessory's values before the writes. Starting a running effect again extends it.
+    pub fn start(
+        &mut self,
+        name: &str,
+        accessory: &str,
+        characteristics: &[Characteristic],
+        current: &Value,
+        until: DateTime<Local>,
+    ) {
+        let stack = self.effects.entry(accessory.to_string()).or_default();
+        if let Some(effect) = stack.iter_mut().find(|e| e.name == name) {
+            debug!(
+                "Extending effect '{}' on '{}' to {}.",
+                name, accessory, until
+            );
+            effect.until = effect.until.max(until);
+            return;
+        }
+        info!(
+            "Temporary effect '{}' on '{}' until {}.",
+            name,
+            accessory,
+            time_format::show(&until)
+        );
+        let before = characteristics
+            .iter()
+            .filter_map(|c| current.get(c.as_str()).map(|v| (c.to_string(), v.clone())))
+            .collect();
+        stack.push(Effect {
+            name: name.to_string(),
+            until,
+            before,
+        });
+    }
+
+    /// Take over the effects a program started or extended on its copy.
+    pub fn merge(&mut self, copy: TemporaryEffects) {
+        for (accessory, effects) in copy.effects {
+            let stack = self.effects.entry(accessory).or_default();
+            for effect in effects {
+                match stack.iter_mut().find(|e| e.name == effect.name) {
+                    Some(running) => running.until = running.until.max(effect.until),
+                    None => stack.push(effect),
+                }
+            }
+        }
+    }
+
+    /// Remove effects that ended by `now`, returning the values to restore by accessory.
+    fn end(&mut self, now: &DateTime<Local>) -> Vec<(String, Map<String, Value>)> {
+        let mut restores = Vec::new();
+        for (accessory, stack) in self.effects.iter_mut() {
+            let mut i = 0;
+            while i < stack.len() {
+                if *now < stack[i].until {
+                    i += 1;
+                    continue;
+                }
+                let effect = stack.remove(i);
+                debug!("Effect '{}' on '{}' ended.", effect.name, accessory);
+                match stack.get_mut(i) {
+                    Some(next) => next.before.extend(effect.before),
+                    None => restores.push((accessory.clone(), effect.before)),
+                }
+            }
+        }
+        self.effects.retain(|_, stack| !stack.is_empty());
+        restores
+    }
+
+    /// Let all running effects end by `now`.
+    fn expire(&mut self, now: &DateTime<Local>) {
+        for effect in self.effects.values_mut().flatten() {
+            effect.until = effect.until.min(*now);
+        }
+    }
+
+    /// End all effects and put back the values from before them, e.g. when shutting down.
+    pub async fn restore_all(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        now: &DateTime<Local>,
+    ) {
+        self.expire(now);
+        self.restore_ended(client, homebridge, now).await;
+    }
+
+    /// Put back the values of accessories whose effects ended.
+    pub async fn restore_ended(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        now: &DateTime<Local>,
+    ) {
+        for (accessory, values) in self.end(now) {
+            info!("Restoring '{}' after temporary effects.", accessory);
+            let writes: Vec<(Characteristic, Value)> = values
+                .iter()
+                .map(|(c, v)| (Characteristic::from(c.as_str()), v.clone()))
+                .collect();
+            if let Err(e) = homebridge
+                .apply_values(client, SOURCE, &accessory, &writes, false)
+                .await
+            {
+                warn!("Failed to restore '{}': {}", accessory, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::json;
+
+    #[test]
+    fn overlapping_effects_restore_the_original_values() {
+        let start = Local::now();
+        let mut effects = TemporaryEffects::default();
+        effects.start(
+            "doorbell",
+            "Porch",
+            &[Characteristic::On, Characteristic::Brightness],
+            &json!({"On": 0, "Brightness": 20}),
+            start + Duration::minutes(10),
+        );
+        effects.start(
+            "alarm",
+            "Porch",
+            &[Characteristic::Brightness, Characteristic::Hue],
+            &json!({"On": 1, "Brightness": 100, "Hue": 30}),
+            start + Duration::minutes(20),
+        );
+
+        // The older effect ends first: nothing is written, the newer one keeps running.
+        assert!(effects.end(&(start + Duration::minutes(15))).is_empty());
+
+        let restores = effects.end(&(start + Duration::minutes(20)));
+        assert_eq!(restores.len(), 1);
+        let (accessory, values) = &restores[0];
+        assert_eq!(accessory, "Porch");
+        assert_eq!(
+            Value::Object(values.clone()),
+            json!({"On": 0, "Brightness": 20, "Hue": 30})
+        );
+        assert!(effects.effects.is_empty());
+    }
+
+    #[test]
+    fn newest_effect_ending_returns_to_the_one_below() {
+        let start = Local::now();
+        let mut effects = TemporaryEffects::default();
+        effects.start(
+            "alarm",
+            "Porch",
+            &[Characteristic::Brightness],
+            &json!({"Brightness": 20}),
+            start + Duration::minutes(20),
+        );
+        effects.start(
+            "doorbell",
+            "Porch",
+            &[Characteristic::Brightness],
+            &json!({"Brightness": 100}),
+            start + Duration::minutes(5),
+        );
+        let restores = effects.end(&(start + Duration::minutes(5)));
+        assert_eq!(
+            Value::Object(restores[0].1.clone()),
+            json!({"Brightness": 100})
+        );
+        let restores = effects.end(&(start + Duration::minutes(20)));
+        assert_eq!(
+            Value::Object(restores[0].1.clone()),
+            json!({"Brightness": 20})
+        );
+    }
+
+    #[test]
+    fn expiring_all_effects_restores_the_original_values() {
+        let start = Local::now();
+        let mut effects = TemporaryEffects::default();
+        for (name, brightness, minutes) in [("alarm", 20, 20), ("doorbell", 100, 5)] {
+            effects.start(
+                name,
+                "Porch",
+                &[Characteristic::Brightness],
+                &json!({ "Brightness": brightness }),
+                start + Duration::minutes(minutes),
+            );
+        }
+        effects.expire(&start);
+        let restores = effects.end(&start);
+        assert_eq!(restores.len(), 1);
+        assert_eq!(
+            Value::Object(restores[0].1.clone()),
+            json!({"Brightness": 20})
+        );
+        assert!(effects.effects.is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use chrono::{DateTime, Local};
+use log::{debug, info};
+use std::collections::HashMap;
+
+/// Named conditions published by programs for other programs to require or react to.
+///
+/// A condition is set from the time it is published until it is cleared. Publishing an already
+/// set condition again does not change when it was set.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    conditions: HashMap<String, DateTime<Local>>,
+}
+
+impl EventBus {
+    pub fn publish(&mut self, name: &str, now: &DateTime<Local>) {
+        if !self.conditions.contains_key(name) {
+            info!("Condition '{}' set.", name);
+            self.conditions.insert(name.to_string(), *now);
+        }
+    }
+
+    pub fn clear(&mut self, name: &str) {
+        if self.conditions.remove(name).is_some() {
+            debug!("Condition '{}' cleared.", name);
+        }
+    }
+
+    /// Take over what a program set or cleared on its copy of `base`.
+    pub fn merge(&mut self, base: &EventBus, copy: EventBus) {
+        for name in base.conditions.keys().filter(|c| !copy.is_set(c)) {
+            self.conditions.remove(name);
+        }
+        for (name, since) in copy.conditions {
+            if !base.is_set(&name) {
+                self.conditions.entry(name).or_insert(since);
+            }
+        }
+    }
+
+    pub fn is_set(&self, name: &str) -> bool {
+        self.conditions.contains_key(name)
+    }
+
+    /// When the condition was set, if it is set.
+    pub fn set_since(&self, name: &str) -> Option<DateTime<Local>> {
+        self.conditions.get(name).copied()
+    }
+
+    /// Names of required conditions that are not set.
+    pub fn missing<'a>(&self, required: &'a [String]) -> Vec<&'a str> {
+        required
+            .iter()
+            .filter(|c| !self.is_set(c))
+            .map(|c| c.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn conditions_stay_set_from_their_first_publication_until_cleared() {
+        let start = Local::now();
+        let mut events = EventBus::default();
+        assert!(!events.is_set("movie"));
+        events.publish("movie", &start);
+        events.publish("movie", &(start + Duration::minutes(5)));
+        assert_eq!(events.set_since("movie"), Some(start));
+        let required = vec!["movie".to_string(), "evening".to_string()];
+        assert_eq!(events.missing(&required), vec!["evening"]);
+
+        events.clear("movie");
+        events.clear("never set");
+        assert!(!events.is_set("movie"));
+        events.publish("movie", &(start + Duration::minutes(10)));
+        assert_eq!(
+            events.set_since("movie"),
+            Some(start + Duration::minutes(10))
+        );
+    }
+
+    #[test]
+    fn merges_what_a_copy_set_and_cleared() {
+        let start = Local::now();
+        let mut events = EventBus::default();
+        events.publish("evening", &start);
+        events.publish("movie", &start);
+        let base = events.clone();
+
+        let mut copy = base.clone();
+        copy.clear("movie");
+        copy.publish("away", &(start + Duration::minutes(1)));
+        // Set by another program meanwhile.
+        events.publish("door_open", &(start + Duration::minutes(2)));
+        events.merge(&base, copy);
+
+        assert!(events.is_set("evening"));
+        assert!(!events.is_set("movie"));
+        assert_eq!(events.set_since("away"), Some(start + Duration::minutes(1)));
+        assert!(events.is_set("door_open"));
+    }
+}
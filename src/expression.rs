@@ -0,0 +1,349 @@
+use crate::characteristic::Characteristic;
+use crate::homebridge::{HBError, Homebridge};
+use crate::override_detector::numeric_value;
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExpressionError {
+    #[error("Invalid condition '{expression}': {message}")]
+    Parse { expression: String, message: String },
+    #[error("'{accessory}' has no {characteristic}.")]
+    MissingValue {
+        accessory: String,
+        characteristic: Characteristic,
+    },
+    #[error("Error during Homebridge interaction: {0}")]
+    HomebridgeInteraction(#[from] HBError),
+}
+
+/// Boolean condition over accessory values, e.g.
+/// `sensor('Hallway Motion').occupied && light('Porch').off`.
+///
+/// `sensor`, `light`, and `accessory` all look up an accessory by name. Properties are `on`,
+/// `off`, `occupied`, `open`, `closed`, `active`, or the name of a characteristic, which holds
+/// when its value is non-zero. Combine them with `!`, `&&`, `||`, and parentheses.
+///
+/// A property that looks like a misspelled characteristic (e.g. `Brightnes`) fails to parse.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Check { accessory: String, property: String },
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Check {
+                accessory,
+                property,
+            } => write!(f, "accessory('{}').{}", accessory, property),
+            Expression::Not(inner) => write!(f, "!{}", inner),
+            Expression::And(a, b) => write!(f, "({} && {})", a, b),
+            Expression::Or(a, b) => write!(f, "({} || {})", a, b),
+        }
+    }
+}
+
+/// Properties that stand for one or more characteristics.
+const PROPERTIES: [&str; 6] = ["on", "off", "occupied", "open", "closed", "active"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    Dot,
+    Not,
+    And,
+    Or,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '.' | '!' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '.' => Token::Dot,
+                    _ => Token::Not,
+                });
+            }
+            '&' | '|' => {
+                chars.next();
+                if chars.next() != Some(c) {
+                    return Err(format!("expected '{}{}'", c, c));
+                }
+                tokens.push(match c {
+                    '&' => Token::And,
+                    _ => Token::Or,
+                });
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => s.push(ch),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_alphanumeric() || ch == '_') {
+                        break;
+                    }
+                    s.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(s));
+            }
+            c => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser; `||` binds weaker than `&&`, which binds weaker than `!`.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(format!("expected {}", what)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            left = Expression::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let inner = self.or()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(inner)
+            }
+            Some(Token::Ident(function)) => {
+                if !["sensor", "light", "accessory"].contains(&function.as_str()) {
+                    return Err(format!("unknown function '{}'", function));
+                }
+                self.expect(Token::LParen, "'(' after the function name")?;
+                let Some(Token::Str(accessory)) = self.next() else {
+                    return Err("expected a quoted accessory name".to_string());
+                };
+                self.expect(Token::RParen, "')'")?;
+                self.expect(Token::Dot, "'.' and a property")?;
+                let Some(Token::Ident(property)) = self.next() else {
+                    return Err("expected a property".to_string());
+                };
+                Expression::check(&accessory, &property)
+            }
+            _ => Err("expected a check, '!' or '('".to_string()),
+        }
+    }
+}
+
+fn is_set(value: Option<&Value>) -> bool {
+    value.and_then(numeric_value).is_some_and(|v| v != 0.0)
+}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Self, ExpressionError> {
+        let error = |message: String| ExpressionError::Parse {
+            expression: input.to_string(),
+            message,
+        };
+        let mut parser = Parser {
+            tokens: tokenize(input).map_err(error)?,
+            pos: 0,
+        };
+        let expression = parser.or().map_err(error)?;
+        if parser.pos < parser.tokens.len() {
+            return Err(error("unexpected trailing input".to_string()));
+        }
+        Ok(expression)
+    }
+
+    /// A check of one property of an accessory, e.g. `on` or `Brightness`.
+    pub fn check(accessory: &str, property: &str) -> Result<Self, String> {
+        if !PROPERTIES.contains(&property) {
+            Characteristic::parse_strict(property)?;
+        }
+        Ok(Expression::Check {
+            accessory: accessory.to_string(),
+            property: property.to_string(),
+        })
+    }
+
+    /// Accessories the expression reads, each once.
+    pub fn accessories(&self) -> Vec<&str> {
+        let mut accessories = Vec::new();
+        self.collect_accessories(&mut accessories);
+        accessories
+    }
+
+    fn collect_accessories<'a>(&'a self, accessories: &mut Vec<&'a str>) {
+        match self {
+            Expression::Check { accessory, .. } => {
+                if !accessories.contains(&accessory.as_str()) {
+                    accessories.push(accessory);
+                }
+            }
+            Expression::Not(inner) => inner.collect_accessories(accessories),
+            Expression::And(a, b) | Expression::Or(a, b) => {
+                a.collect_accessories(accessories);
+                b.collect_accessories(accessories);
+            }
+        }
+    }
+
+    /// Evaluate against the characteristic values of each accessory.
+    pub fn evaluate(&self, values: &HashMap<String, Value>) -> Result<bool, ExpressionError> {
+        match self {
+            Expression::Check {
+                accessory,
+                property,
+            } => {
+                let missing = |characteristic: Characteristic| ExpressionError::MissingValue {
+                    accessory: accessory.clone(),
+                    characteristic,
+                };
+                let values = values
+                    .get(accessory)
+                    .ok_or_else(|| missing(Characteristic::from(property.as_str())))?;
+                let get = |characteristic: &Characteristic| values.get(characteristic.as_str());
+                let required = |characteristic: Characteristic| {
+                    get(&characteristic)
+                        .map(|v| is_set(Some(v)))
+                        .ok_or_else(|| missing(characteristic))
+                };
+                match property.as_str() {
+                    "on" => required(Characteristic::On),
+                    "off" => required(Characteristic::On).map(|on| !on),
+                    "occupied" => match (
+                        get(&Characteristic::OccupancyDetected),
+                        get(&Characteristic::MotionDetected),
+                    ) {
+                        (None, None) => Err(missing(Characteristic::OccupancyDetected)),
+                        (occupancy, motion) => Ok(is_set(occupancy) || is_set(motion)),
+                    },
+                    "open" => required(Characteristic::ContactSensorState),
+                    "closed" => required(Characteristic::ContactSensorState).map(|open| !open),
+                    "active" => required(Characteristic::Active),
+                    characteristic => required(Characteristic::from(characteristic)),
+                }
+            }
+            Expression::Not(inner) => Ok(!inner.evaluate(values)?),
+            Expression::And(a, b) => Ok(a.evaluate(values)? && b.evaluate(values)?),
+            Expression::Or(a, b) => Ok(a.evaluate(values)? || b.evaluate(values)?),
+        }
+    }
+
+    /// Read the accessories and evaluate the expression.
+    pub async fn holds(
+        &self,
+        client: &Client,
+        homebridge: &mut Homebridge,
+    ) -> Result<bool, ExpressionError> {
+        let mut values = HashMap::new();
+        for accessory in self.accessories() {
+            let current = homebridge.get_accessory_values(client, accessory).await?;
+            values.insert(accessory.to_string(), current);
+        }
+        self.evaluate(&values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_and_evaluates() {
+        let expression =
+            Expression::parse("sensor('Hallway Motion').occupied && !light(\"Porch\").on").unwrap();
+        assert_eq!(expression.accessories(), vec!["Hallway Motion", "Porch"]);
+
+        let mut values = HashMap::new();
+        values.insert("Hallway Motion".to_string(), json!({"MotionDetected": 1}));
+        values.insert("Porch".to_string(), json!({"On": 0}));
+        assert!(expression.evaluate(&values).unwrap());
+
+        values.insert("Porch".to_string(), json!({"On": true}));
+        assert!(!expression.evaluate(&values).unwrap());
+
+        assert!(Expression::parse("light('Porch').Brightness").is_ok());
+        assert!(Expression::parse("light('Porch').Brightnes").is_err());
+    }
+
+    #[test]
+    fn or_binds_weaker_than_and() {
+        let expression =
+            Expression::parse("light('A').on || light('B').on && light('C').on").unwrap();
+        let mut values = HashMap::new();
+        values.insert("A".to_string(), json!({"On": 1}));
+        values.insert("B".to_string(), json!({"On": 0}));
+        values.insert("C".to_string(), json!({"On": 0}));
+        assert!(expression.evaluate(&values).unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for input in [
+            "light('A')",
+            "lamp('A').on",
+            "light('A').on &&",
+            "light('A).on",
+        ] {
+            assert!(Expression::parse(input).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+/// Lowercase letters and digits only, so "Bed-Light" and "bed light" compare equal.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Whether two names are the same, ignoring case and punctuation.
+pub fn same(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+/// Edit distance between two names, ignoring case and punctuation.
+fn distance(a: &str, b: &str) -> usize {
+    strsim::levenshtein(&normalize(a), &normalize(b))
+}
+
+/// The `count` names closest to `name`, best first.
+pub fn closest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+    count: usize,
+) -> Vec<&'a str> {
+    let mut ranked: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .collect();
+    ranked.sort();
+    ranked.into_iter().take(count).map(|(_, c)| c).collect()
+}
+
+/// Names containing `filter` or within a few typos of it, ignoring case and punctuation.
+///
+/// Names containing the filter come first, then the others by distance.
+pub fn search<'a>(filter: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let filter = normalize(filter);
+    let max_typos = filter.chars().count().div_ceil(3);
+    let mut ranked: Vec<(bool, usize, &str)> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let normalized = normalize(candidate);
+            let contains = normalized.contains(&filter);
+            let distance = strsim::levenshtein(&normalized, &filter);
+            (contains || distance <= max_typos).then_some((!contains, distance, candidate))
+        })
+        .collect();
+    ranked.sort();
+    ranked.into_iter().map(|(_, _, c)| c).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 4] = [
+        "Bed Light",
+        "Bedroom Lamp",
+        "Porch Light",
+        "Front Lawn Valve",
+    ];
+
+    #[test]
+    fn closest_ignores_case_and_punctuation() {
+        assert_eq!(closest("bed-light", NAMES, 1), vec!["Bed Light"]);
+        assert_eq!(closest("Frontlawn valve", NAMES, 3).len(), 3);
+        assert_eq!(closest("Frontlawn valve", NAMES, 3)[0], "Front Lawn Valve");
+    }
+
+    #[test]
+    fn search_matches_substrings_and_typos() {
+        assert_eq!(search("light", NAMES), vec!["Bed Light", "Porch Light"]);
+        assert_eq!(search("prch lihgt", NAMES), vec!["Porch Light"]);
+        assert!(search("garage", NAMES).is_empty());
+    }
+}
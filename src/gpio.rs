@@ -0,0 +1,166 @@
+use crate::configuration::{GpioButtonConfig, GpioConfig};
+use crate::control::{self, ControlCommand, SharedState};
+use crate::hysteresis::Debounce;
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use serde_json::Map;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SYSFS_GPIO: &str = "/sys/class/gpio";
+
+#[derive(thiserror::Error, Debug)]
+pub enum GpioError {
+    #[error("Failed to set up GPIO {0} as an input: {1}")]
+    Setup(u32, #[source] io::Error),
+    #[error("Failed to read GPIO {0}: {1}")]
+    Read(u32, #[source] io::Error),
+}
+
+/// Button on an input exported through sysfs.
+struct Button {
+    name: String,
+    config: GpioButtonConfig,
+    line: u32,
+    value: PathBuf,
+    contact: Debounce,
+    failing: bool,
+}
+
+impl Button {
+    /// Export the button's line and make it an input.
+    async fn setup(name: &str, config: &GpioButtonConfig, base: u32) -> Result<Self, GpioError> {
+        let line = base + config.pin;
+        let dir = Path::new(SYSFS_GPIO).join(format!("gpio{}", line));
+        if !dir.exists() {
+            fs::write(Path::new(SYSFS_GPIO).join("export"), line.to_string())
+                .map_err(|e| GpioError::Setup(line, e))?;
+        }
+        // udev may still be fixing the permissions of a freshly exported line.
+        let mut attempts = 0;
+        while let Err(e) = fs::write(dir.join("direction"), "in") {
+            attempts += 1;
+            if attempts == 10 {
+                return Err(GpioError::Setup(line, e));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Ok(Self {
+            name: name.to_string(),
+            config: config.clone(),
+            line,
+            value: dir.join("value"),
+            contact: Debounce::new(
+                chrono::Duration::milliseconds(config.debounce_ms as i64),
+                false,
+            ),
+            failing: false,
+        })
+    }
+
+    fn is_pressed(&self) -> Result<bool, GpioError> {
+        let value = fs::read_to_string(&self.value).map_err(|e| GpioError::Read(self.line, e))?;
+        Ok((value.trim() == "1") != self.config.active_low)
+    }
+
+    /// Whether the button was just pressed, logging a failing input once.
+    fn poll(&mut self, now: &DateTime<Local>) -> bool {
+        match self.is_pressed() {
+            Ok(pressed) => {
+                if std::mem::take(&mut self.failing) {
+                    info!("GPIO button '{}' readable again.", self.name);
+                }
+                is_press(&mut self.contact, pressed, now)
+            }
+            Err(e) => {
+                if !self.failing {
+                    warn!("GPIO button '{}': {}", self.name, e);
+                    self.failing = true;
+                }
+                false
+            }
+        }
+    }
+
+    /// Queue the button's action or program run, as if requested through the control API.
+    fn press(&self, state: &SharedState) {
+        let command = match (&self.config.action, &self.config.program) {
+            (Some(action), _) => ControlCommand::RunAction {
+                name: action.clone(),
+            },
+            (None, Some(program)) => ControlCommand::TriggerProgram {
+                program: program.clone(),
+                overrides: Map::new(),
+            },
+            (None, None) => return,
+        };
+        info!("GPIO button '{}' pressed.", self.name);
+        if let Err(e) = control::execute(state, command) {
+            error!("Error handling GPIO button '{}': {}", self.name, e);
+        }
+    }
+}
+
+/// Whether a reading completes a press: the contact has been closed for the debounce time,
+/// for the first time since it was last open.
+fn is_press(contact: &mut Debounce, pressed: bool, now: &DateTime<Local>) -> bool {
+    let was_pressed = contact.value();
+    contact.update(pressed, now) && !was_pressed
+}
+
+/// Watch the configured buttons and queue their actions when pressed.
+pub async fn watch(config: GpioConfig, state: SharedState) {
+    let mut buttons = Vec::new();
+    for (name, button) in config.buttons.iter() {
+        match Button::setup(name, button, config.base).await {
+            Ok(b) => {
+                debug!("GPIO button '{}' on line {}.", name, b.line);
+                buttons.push(b);
+            }
+            Err(e) => error!("Not watching GPIO button '{}': {}", name, e),
+        }
+    }
+    if buttons.is_empty() {
+        return;
+    }
+    info!("Watching {} GPIO button(s).", buttons.len());
+    let mut interval = tokio::time::interval(Duration::from_millis(config.poll_ms.max(1)));
+    loop {
+        interval.tick().await;
+        // Real time, as a button is pressed in it even when the controller's clock is not.
+        let now = Local::now();
+        for button in buttons.iter_mut() {
+            if button.poll(&now) {
+                button.press(&state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_one_press_per_closing() {
+        let start = Local::now();
+        let at = |ms| start + chrono::Duration::milliseconds(ms);
+        let mut contact = Debounce::new(chrono::Duration::milliseconds(50), false);
+        let mut press = |pressed, ms| is_press(&mut contact, pressed, &at(ms));
+        // Bouncing while closing.
+        assert!(!press(true, 0));
+        assert!(!press(false, 5));
+        assert!(!press(true, 10));
+        assert!(!press(true, 40));
+        assert!(press(true, 60));
+        // Held down.
+        assert!(!press(true, 500));
+        // Released and pressed again.
+        assert!(!press(false, 600));
+        assert!(!press(false, 660));
+        assert!(!press(true, 700));
+        assert!(press(true, 760));
+    }
+}
@@ -1,9 +1,34 @@
-use chrono::{DateTime, Duration, Local};
-use log::{debug, error, info};
-use reqwest::Client;
+use crate::audit::{WriteJournal, WriteRecord};
+use crate::bridge_schema::{BridgeSchema, HomebridgeVersion};
+use crate::characteristic::Characteristic;
+use crate::clock;
+use crate::configuration::TurnOnSequence;
+use crate::fuzzy;
+use crate::latency::LatencyTracker;
+use crate::maintenance::{self, Maintenance, MaintenanceEntry};
+use crate::override_detector::numeric_value;
+use crate::poller::PolledStates;
+use crate::run_time::RunTimeLimits;
+use crate::time_format;
+use crate::time_window::TimeWindow;
+use crate::tolerance::ToleranceTuner;
+use crate::write_queue::WriteQueue;
+use chrono::{DateTime, Duration, Local, NaiveTime};
+use log::{debug, error, info, warn};
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+pub const BED_LIGHT: &str = "Bed Light";
+
+/// Prefix addressing an accessory by its `uniqueId` instead of its name, for accessories
+/// sharing a name.
+pub const UNIQUE_ID_PREFIX: &str = "uniqueId:";
 
 #[derive(Debug, thiserror::Error)]
 pub enum HBError {
@@ -15,8 +40,64 @@ pub enum HBError {
     AuthError(String),
     #[error("No access token when one is expected.")]
     NoAccessToken(),
-    #[error("No accessory registered for '{0}'.")]
-    UnrecognizedAccessory(String),
+    #[error("No accessory registered for '{name}'.{}", did_you_mean(.suggestions))]
+    UnrecognizedAccessory {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    #[error("Homebridge refused accessory access; it must run in insecure mode (`-I`).")]
+    InsecureModeRequired(),
+    #[error("{characteristic} of '{accessory}' is read-only.")]
+    ReadOnlyCharacteristic {
+        accessory: String,
+        characteristic: Characteristic,
+    },
+    #[error("'{accessory}' may only be controlled {hours}.")]
+    OutsideControlHours {
+        accessory: String,
+        hours: ControlHours,
+    },
+    #[error("{0} - writes to it are rejected.")]
+    InMaintenance(MaintenanceEntry),
+    #[error("Writing {characteristic} of '{accessory}' was cancelled.")]
+    WriteCancelled {
+        accessory: String,
+        characteristic: Characteristic,
+    },
+    /// A write skipped while observing only; programs keep their plans as if it was not tried.
+    #[error("Observing only - the write was skipped.")]
+    ObserveOnly,
+    #[error(
+        "Writing {characteristic} of '{accessory}' failed (rolled back: {rolled_back}): {source}"
+    )]
+    WriteFailed {
+        accessory: String,
+        characteristic: Characteristic,
+        rolled_back: bool,
+        source: Box<HBError>,
+    },
+}
+
+/// Whether `error` comes from a write skipped while observing only.
+pub fn observing_only(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|e| matches!(e.downcast_ref::<HBError>(), Some(HBError::ObserveOnly)))
+}
+
+/// " Did you mean ...?" listing the suggestions, if there are any.
+fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        _ => format!(
+            " Did you mean {}?",
+            suggestions
+                .iter()
+                .map(|s| format!("'{}'", s))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,6 +111,88 @@ struct HBAccessory {
     huamn_type: String,
     #[serde(rename = "serviceName")]
     service_name: String,
+    #[serde(rename = "serviceCharacteristics", default)]
+    service_characteristics: Vec<HBCharacteristicPermissions>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HBCharacteristicPermissions {
+    #[serde(rename = "type")]
+    char_type: String,
+    #[serde(default = "_true")]
+    can_write: bool,
+}
+
+const fn _true() -> bool {
+    true
+}
+
+/// Hours after which the accessory cache is refreshed the next time an accessory is looked up.
+const ACCESSORY_CACHE_REFRESH_HOURS: i64 = 24;
+
+/// Characteristics of an accessory from the bridge's accessory list.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessoryCapabilities {
+    pub service_name: String,
+    pub characteristics: Vec<String>,
+    pub read_only: Vec<String>,
+}
+
+/// Accessories of the bridge by `uniqueId`, so names resolve and read-only characteristics are
+/// known while the bridge is briefly unreachable.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AccessoryCache {
+    #[serde(default)]
+    pub refreshed: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub accessories: BTreeMap<String, AccessoryCapabilities>,
+}
+
+impl AccessoryCache {
+    /// `uniqueId` and capabilities of the accessory with the service name (or prefixed
+    /// `uniqueId`).
+    fn find(&self, accessory: &str) -> Option<(&String, &AccessoryCapabilities)> {
+        match accessory.strip_prefix(UNIQUE_ID_PREFIX) {
+            Some(unique_id) => self.accessories.get_key_value(unique_id),
+            None => self
+                .accessories
+                .iter()
+                .find(|(_, a)| a.service_name == accessory),
+        }
+    }
+
+    /// Key to find an accessory by, however it is addressed: its prefixed `uniqueId` if it is
+    /// known, else the name as given.
+    pub fn key(&self, accessory: &str) -> String {
+        match self.find(accessory) {
+            Some((unique_id, _)) => format!("{}{}", UNIQUE_ID_PREFIX, unique_id),
+            None => accessory.to_string(),
+        }
+    }
+
+    /// Service names used by more than one accessory.
+    fn duplicate_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .accessories
+            .values()
+            .map(|a| a.service_name.as_str())
+            .collect();
+        names.sort();
+        let mut duplicates: Vec<&str> = names
+            .windows(2)
+            .filter(|pair| pair[0] == pair[1])
+            .map(|pair| pair[0])
+            .collect();
+        duplicates.dedup();
+        duplicates
+    }
+
+    fn is_stale(&self, now: &DateTime<Local>) -> bool {
+        self.refreshed.map_or(true, |t| {
+            *now - t >= Duration::hours(ACCESSORY_CACHE_REFRESH_HOURS)
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,26 +201,237 @@ struct HBAccessories {
     accessories: Vec<HBAccessory>,
 }
 
+/// Token of the UI API and when to renew it.
+#[derive(Debug, Default)]
+struct AccessToken {
+    token: Option<String>,
+    expiration: Option<DateTime<Local>>,
+}
+
+/// Part of a client its clones share, e.g. the journal every program writes through.
+///
+/// Locks are held for the update at hand only, never across an `.await`.
+#[derive(Debug, Default)]
+pub struct Shared<T>(Arc<Mutex<T>>);
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(Mutex::new(value)))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().expect("Client state lock poisoned.")
+    }
+
+    /// Replace the value for all clones, e.g. after the configuration is reloaded.
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut *self.lock(), value)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+/// Client of a Homebridge instance.
+///
+/// Clones are handles on the same client: they share the token, accessory cache, observed
+/// values, journal, spacing, and write queue, so programs can run side by side. Settings
+/// like `observe_only` are per handle.
+#[derive(Clone)]
 pub struct Homebridge {
-    pub ip_address: String,
+    pub base_url: String,
     username: String,
     password: String,
-    access_token: Option<String>,
-    access_token_expiration: Option<DateTime<Local>>,
-    accessory_uuids: HashMap<String, String>,
+    /// Shared with the detached clients of the same bridge, so they log in once between them.
+    access_token: Arc<tokio::sync::Mutex<AccessToken>>,
+    /// Shape of the bridge's accessory payloads, once probed.
+    schema: Shared<Option<BridgeSchema>>,
+    /// Capabilities of the bridge's accessories, also kept in the state file.
+    accessories: Shared<AccessoryCache>,
+    accessories_changed: Shared<bool>,
+    observed_values: Shared<HashMap<String, Map<String, Value>>>,
+    /// Values of sensors reporting to the controller instead of Homebridge, by name.
+    virtual_sensors: Shared<HashMap<String, Map<String, Value>>>,
+    changes: Shared<Vec<StateChange>>,
+    pub journal: Shared<WriteJournal>,
+    pub latency: Shared<LatencyTracker>,
+    pub tolerances: Shared<ToleranceTuner>,
+    /// Runs of the switches and outlets the controller turned on, against their maximum.
+    pub run_times: Shared<RunTimeLimits>,
+    pub write_queue: Shared<WriteQueue>,
+    pub turn_on_sequences: HashMap<String, TurnOnSequence>,
+    pub on_brightness: HashMap<String, OnBrightness>,
+    /// Guards against writes outside an accessory's hours, by accessory name.
+    pub control_hours: HashMap<String, ControlHours>,
+    /// Accessories in maintenance, as of the last program loop.
+    pub maintenance: Maintenance,
+    /// States kept fresh by the background poller, read in place of requests.
+    pub polled: Option<PolledStates>,
+    /// Client for requests to the bridge in place of the caller's, e.g. one accepting its
+    /// self-signed certificate.
+    pub bridge_client: Option<Client>,
+    /// Skip writes (e.g. during the startup grace period), failing them with
+    /// [`HBError::ObserveOnly`]; reads are unaffected.
+    pub observe_only: bool,
+}
+
+/// Time of day an accessory may be written, whatever program writes it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ControlHours {
+    pub earliest: Option<NaiveTime>,
+    pub latest: Option<NaiveTime>,
+}
+
+impl ControlHours {
+    /// Whether writes are allowed at `time`; a window with `earliest` after `latest` spans
+    /// midnight.
+    pub fn allows(&self, time: NaiveTime) -> bool {
+        match (self.earliest, self.latest) {
+            (Some(earliest), Some(latest)) => TimeWindow::new(earliest, latest).contains(time),
+            (earliest, latest) => {
+                earliest.map_or(true, |e| time >= e) && latest.map_or(true, |l| time <= l)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ControlHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.earliest, self.latest) {
+            (Some(earliest), Some(latest)) => write!(
+                f,
+                "from {} to {}",
+                earliest.format("%H:%M"),
+                latest.format("%H:%M")
+            ),
+            (Some(earliest), None) => write!(f, "from {}", earliest.format("%H:%M")),
+            (None, Some(latest)) => write!(f, "until {}", latest.format("%H:%M")),
+            (None, None) => write!(f, "at any time"),
+        }
+    }
+}
+
+/// Brightness a light is switched on at when a write only sets `On`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OnBrightness {
+    /// Use the brightness the controller last wrote to the light.
+    pub restore: bool,
+    /// Used when there is nothing to restore.
+    pub brightness: Option<u8>,
+}
+
+/// Accessory of a group write that failed, and why.
+#[derive(Serialize, Debug, Clone)]
+pub struct GroupFailure {
+    pub accessory: String,
+    pub error: String,
+}
+
+/// Which accessories of a group write were written and which failed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GroupResult {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<GroupFailure>,
+}
+
+impl GroupResult {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for GroupResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} accessories written",
+            self.succeeded.len(),
+            self.succeeded.len() + self.failed.len()
+        )?;
+        for failure in self.failed.iter() {
+            write!(f, "; '{}' failed: {}", failure.accessory, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Change of a characteristic value seen by the controller, either read or written.
+#[derive(Serialize, Debug, Clone)]
+pub struct StateChange {
+    pub when: DateTime<Local>,
+    pub accessory: String,
+    pub characteristic: String,
+    pub old: Value,
+    pub new: Value,
+    /// "observed" for changes read from Homebridge, else the program that wrote the value.
+    pub source: String,
+}
+
+/// A write on its way to the bridge.
+struct Write {
+    program: String,
+    accessory: String,
+    characteristic: Characteristic,
+    body: Value,
+}
+
+/// Compare characteristic values, treating e.g. `1` and `"1"` as equal.
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (numeric_value(a), numeric_value(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
 }
 
 impl Homebridge {
-    pub fn new(ip_address: &str, username: &str, password: &str) -> Self {
+    pub fn new(base_url: &str, username: &str, password: &str) -> Self {
         Self {
-            ip_address: ip_address.to_string(),
+            base_url: base_url.to_string(),
             username: username.to_string(),
             password: password.to_string(),
-            access_token: None,
-            access_token_expiration: None,
-            accessory_uuids: HashMap::new(),
+            access_token: Arc::default(),
+            schema: Shared::default(),
+            accessories: Shared::default(),
+            accessories_changed: Shared::default(),
+            observed_values: Shared::default(),
+            virtual_sensors: Shared::default(),
+            changes: Shared::default(),
+            journal: Shared::default(),
+            latency: Shared::default(),
+            tolerances: Shared::default(),
+            run_times: Shared::default(),
+            write_queue: Shared::default(),
+            turn_on_sequences: HashMap::new(),
+            on_brightness: HashMap::new(),
+            control_hours: HashMap::new(),
+            maintenance: Maintenance::default(),
+            polled: None,
+            observe_only: false,
+            bridge_client: None,
         }
     }
+
+    /// The client to reach the bridge with: its own, if it has one, else `client`.
+    fn http<'a>(&'a self, client: &'a Client) -> &'a Client {
+        self.bridge_client.as_ref().unwrap_or(client)
+    }
+
+    /// Another client of the same bridge, e.g. for a background task, sharing the token, the
+    /// read-back tolerances, and the run-time limits and knowing the same accessories, but with
+    /// its own journal and spacing (unlike a clone).
+    pub fn detached(&self) -> Self {
+        let mut homebridge = Self::new(&self.base_url, &self.username, &self.password);
+        homebridge.access_token = Arc::clone(&self.access_token);
+        homebridge.tolerances = self.tolerances.clone();
+        homebridge.run_times = self.run_times.clone();
+        homebridge.schema = Shared::new(*self.schema.lock());
+        homebridge.accessories = Shared::new(self.accessories.lock().clone());
+        homebridge.bridge_client = self.bridge_client.clone();
+        homebridge
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -67,14 +441,120 @@ struct HBAuth {
     expires_in: u32,
 }
 
+/// Deserializers accepting the value shapes different Homebridge plugins report.
+mod lenient {
+    use serde::de::{self, Deserializer, Visitor};
+    use std::fmt;
+
+    struct FlagVisitor;
+
+    impl<'de> Visitor<'de> for FlagVisitor {
+        type Value = u32;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a bool, 0/1, or \"true\"/\"false\"")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<u32, E> {
+            Ok(v as u32)
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<u32, E> {
+            Ok((v != 0) as u32)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<u32, E> {
+            Ok((v != 0) as u32)
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<u32, E> {
+            Ok((v != 0.0) as u32)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<u32, E> {
+            match v.trim().to_ascii_lowercase().as_str() {
+                "true" | "on" | "1" => Ok(1),
+                "false" | "off" | "0" => Ok(0),
+                other => Err(E::invalid_value(de::Unexpected::Str(other), &self)),
+            }
+        }
+    }
+
+    /// On/off as 0 or 1.
+    pub fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        deserializer.deserialize_any(FlagVisitor)
+    }
+
+    struct NumberVisitor;
+
+    impl<'de> Visitor<'de> for NumberVisitor {
+        type Value = Option<f64>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number, a numeric string, or null")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            Ok(Some(v as f64))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            Ok(Some(v as f64))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+            Ok(Some(v))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            match v.trim() {
+                "" => Ok(None),
+                t => t
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self)),
+            }
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+    }
+
+    fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+        deserializer.deserialize_any(NumberVisitor)
+    }
+
+    /// Optional percentage, rounded and clamped to 0-100.
+    pub fn percent<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+        Ok(number(deserializer)?.map(|v| v.round().clamp(0.0, 100.0) as u8))
+    }
+
+    /// Optional non-negative whole number.
+    pub fn whole<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+        Ok(number(deserializer)?.map(|v| v.round().max(0.0) as u32))
+    }
+}
+
+/// Values of a light. Only `On` is required; plugins differ in which other characteristics
+/// they report and in how they encode them.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct HBLightbulbValues {
+    #[serde(deserialize_with = "lenient::flag")]
     pub on: u32,
-    pub brightness: u8,
-    pub color_temperature: u32,
-    pub hue: u32,
-    pub saturation: u32,
+    #[serde(default, deserialize_with = "lenient::percent")]
+    pub brightness: Option<u8>,
+    #[serde(default, deserialize_with = "lenient::whole")]
+    pub color_temperature: Option<u32>,
+    #[serde(default, deserialize_with = "lenient::whole")]
+    pub hue: Option<u32>,
+    #[serde(default, deserialize_with = "lenient::whole")]
+    pub saturation: Option<u32>,
 }
 
 impl HBLightbulbValues {
@@ -84,6 +564,12 @@ impl HBLightbulbValues {
     pub fn is_off(&self) -> bool {
         !self.is_on()
     }
+
+    /// Brightness, or full/none for lights that do not report one.
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+            .unwrap_or(if self.is_on() { 100 } else { 0 })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -100,10 +586,170 @@ pub struct HBLightbulb {
     pub values: HBLightbulbValues,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct HBLightSensorValues {
+    pub current_ambient_light_level: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HBLightSensor {
+    pub uuid: String,
+    #[serde(rename = "uniqueId")]
+    pub unique_id: String,
+    #[serde(rename = "serviceName")]
+    pub service_name: String,
+    pub values: HBLightSensorValues,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct HBValveValues {
+    pub active: u8,
+    pub in_use: u8,
+    pub set_duration: Option<u32>,
+    pub remaining_duration: Option<u32>,
+}
+
+impl HBValveValues {
+    pub fn is_active(&self) -> bool {
+        self.active == 1
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HBValve {
+    pub uuid: String,
+    #[serde(rename = "uniqueId")]
+    pub unique_id: String,
+    #[serde(rename = "serviceName")]
+    pub service_name: String,
+    pub values: HBValveValues,
+}
+
+/// A characteristic of an accessory's service, as reported by Homebridge.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HBServiceCharacteristic {
+    #[serde(rename = "type")]
+    pub char_type: String,
+    pub description: String,
+    pub value: Value,
+    pub format: String,
+    #[serde(default)]
+    pub perms: Vec<String>,
+    pub unit: Option<String>,
+    pub min_value: Option<f64>,
+    pub max_value: Option<f64>,
+    pub min_step: Option<f64>,
+    #[serde(default)]
+    pub can_read: bool,
+    /// Missing for some plugins; taken as writable, as the write path does.
+    #[serde(default = "_true")]
+    pub can_write: bool,
+}
+
+/// Full description of an accessory, including all of its service characteristics.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HBAccessoryDetails {
+    pub unique_id: String,
+    #[serde(rename = "type")]
+    pub acc_type: String,
+    pub human_type: String,
+    pub service_name: String,
+    pub service_characteristics: Vec<HBServiceCharacteristic>,
+}
+
+impl fmt::Display for HBServiceCharacteristic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}", self.char_type, self.format)?;
+        if let (Some(min), Some(max)) = (self.min_value, self.max_value) {
+            write!(f, ", {}-{}", min, max)?;
+        }
+        if let Some(step) = self.min_step {
+            write!(f, " step {}", step)?;
+        }
+        if let Some(unit) = &self.unit {
+            write!(f, " {}", unit)?;
+        }
+        write!(f, ") [{}] = {}", self.perms.join(","), self.value)?;
+        if !self.can_write {
+            write!(f, " (read-only)")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HBAccessoryDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} ({}, uniqueId {})",
+            self.service_name, self.human_type, self.unique_id
+        )?;
+        let n = self.service_characteristics.len();
+        for (i, characteristic) in self.service_characteristics.iter().enumerate() {
+            let branch = if i + 1 == n { "└──" } else { "├──" };
+            writeln!(f, "{} {}", branch, characteristic)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HBCpuStatus {
+    current_load: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct HBMemory {
+    total: u64,
+    available: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct HBRamStatus {
+    mem: HBMemory,
+}
+
+#[derive(Deserialize, Debug)]
+struct HBHostUptime {
+    uptime: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HBUptimeStatus {
+    time: HBHostUptime,
+    process_uptime: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HBNodeStatus {
+    current_version: String,
+}
+
+/// Health of the machine and process running Homebridge.
+#[derive(Serialize, Debug, Clone)]
+pub struct BridgeStatus {
+    #[serde(serialize_with = "time_format::serialize")]
+    pub fetched_at: DateTime<Local>,
+    pub cpu_load_percent: f32,
+    pub memory_total_bytes: u64,
+    pub memory_available_bytes: u64,
+    pub host_uptime_seconds: f64,
+    pub process_uptime_seconds: f64,
+    pub node_version: String,
+}
+
 impl Homebridge {
     pub async fn check_connection(&self, client: &reqwest::Client) -> Result<(), HBError> {
-        _ = client
-            .post(&self.ip_address)
+        _ = self
+            .http(client)
+            .post(&self.base_url)
             .send()
             .await
             .map_err(HBError::UnableToConnect)?;
@@ -112,13 +758,18 @@ impl Homebridge {
 }
 
 impl Homebridge {
-    async fn renew_access_token(&mut self, client: &reqwest::Client) -> Result<(), HBError> {
+    async fn renew_access_token(
+        &self,
+        client: &reqwest::Client,
+        access_token: &mut AccessToken,
+    ) -> Result<(), HBError> {
         let mut map = HashMap::new();
         map.insert("username", &self.username);
         map.insert("password", &self.password);
-        let mut endpt = self.ip_address.clone();
+        let mut endpt = self.base_url.clone();
         endpt.push_str("/api/auth/login");
-        let res = client
+        let res = self
+            .http(client)
             .post(endpt)
             .json(&map)
             .send()
@@ -130,172 +781,1303 @@ impl Homebridge {
             })?,
             other => return Err(HBError::AuthError(format!("Status code {}", other))),
         };
-        self.access_token = Some(parsed_auth.access_token);
-        self.access_token_expiration =
+        access_token.token = Some(parsed_auth.access_token);
+        access_token.expiration =
             Some(Local::now() + Duration::seconds(parsed_auth.expires_in as i64 - 60));
         Ok(())
     }
 
+    /// A valid token, renewed if needed.
+    ///
+    /// The token stays locked while it is renewed, so clients sharing it wait for one login
+    /// instead of each logging in.
     pub async fn access_token(&mut self, client: &Client) -> Result<String, HBError> {
-        if self.access_token.is_none() | self.access_token_expiration.is_none() {
-            debug!("No access token, requesting one.");
-            self.renew_access_token(client).await?;
-        } else if let Some(access_token_expiration) = self.access_token_expiration {
-            if access_token_expiration < Local::now() {
+        let mut access_token = self.access_token.lock().await;
+        match access_token.expiration {
+            None => {
+                debug!("No access token, requesting one.");
+                self.renew_access_token(client, &mut access_token).await?;
+            }
+            Some(expiration) if expiration < Local::now() => {
                 debug!("Access token expired, requesting new one.");
-                self.renew_access_token(client).await?;
+                self.renew_access_token(client, &mut access_token).await?;
             }
+            Some(_) => {}
         }
-        match self.access_token.clone() {
-            Some(token) => Ok(token),
-            None => Err(HBError::NoAccessToken()),
-        }
+        access_token.token.clone().ok_or(HBError::NoAccessToken())
     }
 }
 
 impl Homebridge {
-    async fn get_accessory_uuid(
-        &mut self,
-        client: &Client,
-        acc_name: &str,
-    ) -> Result<String, HBError> {
-        if let Some(acc_uuid) = self.accessory_uuids.get(acc_name) {
-            debug!("Found UUID for {} in accessory UUID table.", acc_name);
-            return Ok(acc_uuid.clone());
+    /// GET an endpoint of the Homebridge UI API.
+    async fn get_api<T>(&mut self, client: &Client, path: &str) -> Result<T, HBError>
+    where
+        T: DeserializeOwned,
+    {
+        let access_token = self.access_token(client).await?;
+        let mut endpt = self.base_url.clone();
+        endpt.push_str(path);
+        let res = self
+            .http(client)
+            .get(endpt)
+            .bearer_auth(&access_token)
+            .send()
+            .await
+            .map_err(HBError::UnableToConnect)?;
+        res.json::<T>()
+            .await
+            .map_err(|e| HBError::ParsingError(format!("Error parsing '{}' data - {}", path, e)))
+    }
+
+    /// Shape of the bridge's accessory payloads, probed once from its Homebridge version.
+    ///
+    /// Bridges that cannot tell their version are taken to be current.
+    async fn schema(&mut self, client: &Client) -> BridgeSchema {
+        if let Some(schema) = *self.schema.lock() {
+            return schema;
+        }
+        let version = self
+            .get_api::<HomebridgeVersion>(client, "/api/status/homebridge-version")
+            .await;
+        let schema = match version {
+            Ok(version) => {
+                let schema = BridgeSchema::for_version(&version.installed_version);
+                info!(
+                    "Homebridge {} - reading {:?} accessory payloads.",
+                    version.installed_version, schema
+                );
+                schema
+            }
+            Err(HBError::ParsingError(e)) => {
+                warn!(
+                    "Unknown Homebridge version, assuming current payloads: {}",
+                    e
+                );
+                BridgeSchema::Current
+            }
+            // Probe again once the bridge is reachable.
+            Err(e) => {
+                debug!("Could not probe the Homebridge version: {}", e);
+                return BridgeSchema::Current;
+            }
         };
+        *self.schema.lock() = Some(schema);
+        schema
+    }
 
-        let access_token = self.access_token(&client).await?;
+    pub async fn get_bridge_status(&mut self, client: &Client) -> Result<BridgeStatus, HBError> {
+        debug!("Retrieving bridge status.");
+        let cpu: HBCpuStatus = self.get_api(client, "/api/status/cpu").await?;
+        let ram: HBRamStatus = self.get_api(client, "/api/status/ram").await?;
+        let uptime: HBUptimeStatus = self.get_api(client, "/api/status/uptime").await?;
+        let node: HBNodeStatus = self.get_api(client, "/api/status/nodejs").await?;
+        Ok(BridgeStatus {
+            fetched_at: clock::now(),
+            cpu_load_percent: cpu.current_load,
+            memory_total_bytes: ram.mem.total,
+            memory_available_bytes: ram.mem.available,
+            host_uptime_seconds: uptime.time.uptime,
+            process_uptime_seconds: uptime.process_uptime,
+            node_version: node.current_version,
+        })
+    }
+}
+
+impl Homebridge {
+    /// Fetch the bridge's accessory list into the accessory cache.
+    async fn refresh_accessories(&mut self, client: &Client) -> Result<(), HBError> {
+        let access_token = self.access_token(client).await?;
 
-        let mut endpt = self.ip_address.clone();
+        let mut endpt = self.base_url.clone();
         endpt.push_str("/api/accessories");
 
-        let res = client
+        let res = self
+            .http(client)
             .get(endpt)
             .bearer_auth(&access_token)
             .send()
             .await
             .map_err(HBError::UnableToConnect)?;
-        let accesories = res.json::<HBAccessories>().await.map_err(|e| {
+        if res.status() == reqwest::StatusCode::BAD_REQUEST {
+            return Err(HBError::InsecureModeRequired());
+        }
+        let parsing_error =
+            |e| HBError::ParsingError(format!("Error parsing `HBAccessories` data - {}", e));
+        let data = res.json::<Value>().await.map_err(parsing_error)?;
+        let data = self.schema(client).await.normalize_accessories(data);
+        let accesories = serde_json::from_value::<HBAccessories>(data).map_err(|e| {
             HBError::ParsingError(format!("Error parsing `HBAccessories` data - {}", e))
         })?;
-        for accessory in accesories.accessories.iter() {
-            let acc_id = accessory.unique_id.clone();
-            if accessory.service_name == acc_name {
-                debug!("Adding UUID for '{}' to accessory UUID table.", acc_name);
-                self.accessory_uuids
-                    .insert(acc_name.to_string(), acc_id.clone());
+        let accessories: BTreeMap<String, AccessoryCapabilities> = accesories
+            .accessories
+            .into_iter()
+            .map(|accessory| {
+                let capabilities = AccessoryCapabilities {
+                    service_name: accessory.service_name,
+                    characteristics: accessory
+                        .service_characteristics
+                        .iter()
+                        .map(|c| c.char_type.clone())
+                        .collect(),
+                    read_only: accessory
+                        .service_characteristics
+                        .iter()
+                        .filter(|c| !c.can_write)
+                        .map(|c| c.char_type.clone())
+                        .collect(),
+                };
+                (accessory.unique_id, capabilities)
+            })
+            .collect();
+        debug!("Cached {} accessories.", accessories.len());
+        let mut cache = self.accessories.lock();
+        *self.accessories_changed.lock() |= accessories != cache.accessories;
+        *cache = AccessoryCache {
+            refreshed: Some(clock::now()),
+            accessories,
+        };
+        for name in cache.duplicate_names() {
+            warn!(
+                "Several accessories are named '{}'; address them as '{}<id>' (see `list-accessories`).",
+                name, UNIQUE_ID_PREFIX
+            );
+        }
+        Ok(())
+    }
+
+    async fn get_accessory_uuid(
+        &mut self,
+        client: &Client,
+        acc_name: &str,
+    ) -> Result<String, HBError> {
+        if let Some(unique_id) = acc_name.strip_prefix(UNIQUE_ID_PREFIX) {
+            return Ok(unique_id.to_string());
+        }
+        let (cached, stale) = {
+            let cache = self.accessories.lock();
+            let cached = cache.find(acc_name).map(|(id, _)| id.clone());
+            (cached, cache.is_stale(&clock::now()))
+        };
+        if let Some(acc_id) = cached.as_ref() {
+            if !stale {
+                debug!("Found UUID for {} in accessory cache.", acc_name);
+                return Ok(acc_id.clone());
+            }
+        }
+
+        match (self.refresh_accessories(client).await, cached) {
+            (Ok(()), _) => {}
+            (Err(e), Some(acc_id)) => {
+                warn!(
+                    "Could not refresh the accessory list, using the cached UUID of '{}': {}",
+                    acc_name, e
+                );
                 return Ok(acc_id);
             }
+            (Err(e), None) => return Err(e),
+        }
+        let cache = self.accessories.lock();
+        if let Some((acc_id, _)) = cache.find(acc_name) {
+            return Ok(acc_id.clone());
         }
 
         error!(
             "Did not find an accessory with service name '{}'.",
             acc_name
         );
-        Err(HBError::UnrecognizedAccessory(acc_name.to_string()))
+        Err(HBError::UnrecognizedAccessory {
+            name: acc_name.to_string(),
+            suggestions: fuzzy::closest(
+                acc_name,
+                cache.accessories.values().map(|a| a.service_name.as_str()),
+                3,
+            )
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        })
     }
 
-    async fn bed_light_uuid(&mut self, client: &Client) -> Result<String, HBError> {
-        self.get_accessory_uuid(client, "Bed Light").await
+    /// All accessories of the bridge by `uniqueId`, freshly fetched.
+    pub async fn list_accessories(
+        &mut self,
+        client: &Client,
+    ) -> Result<BTreeMap<String, AccessoryCapabilities>, HBError> {
+        self.refresh_accessories(client).await?;
+        Ok(self.accessories.lock().accessories.clone())
+    }
+
+    /// Start from the accessories cached by an earlier run of the controller.
+    pub fn restore_accessories(&mut self, cache: AccessoryCache) {
+        self.accessories.replace(cache);
     }
 
-    pub async fn get_bed_light_status(&mut self, client: &Client) -> Result<HBLightbulb, HBError> {
-        debug!("Retrieving bed light status.");
-        let access_token = self.access_token(&client).await?;
-        let light_uuid = self.get_accessory_uuid(client, "Bed Light").await?;
+    /// The accessory's maintenance, whether it is addressed by name or `uniqueId`.
+    pub fn maintenance_of(&self, accessory: &str) -> Option<&MaintenanceEntry> {
+        let key = self.accessories.lock().key(accessory);
+        self.maintenance.find(&key, &clock::now())
+    }
+
+    /// Accessory cache to persist, if it changed since the last call.
+    pub fn take_accessory_cache(&mut self) -> Option<AccessoryCache> {
+        std::mem::take(&mut *self.accessories_changed.lock())
+            .then(|| self.accessories.lock().clone())
+    }
 
-        let mut endpt = self.ip_address.clone();
+    pub async fn get_accessory_details(
+        &mut self,
+        client: &Client,
+        acc_name: &str,
+    ) -> Result<HBAccessoryDetails, HBError> {
+        debug!("Retrieving details of '{}'.", acc_name);
+        let acc_uuid = self.get_accessory_uuid(client, acc_name).await?;
+        let data: Value = self
+            .get_api(client, &format!("/api/accessories/{}", acc_uuid))
+            .await?;
+        let data = self.schema(client).await.normalize_accessory(data);
+        serde_json::from_value(data).map_err(|e| {
+            HBError::ParsingError(format!("Error parsing '{}' details - {}", acc_name, e))
+        })
+    }
+
+    /// Take over values reported by a virtual sensor, recording the changes.
+    pub fn update_virtual_sensor(&mut self, name: &str, values: &Map<String, Value>) {
+        for (characteristic, value) in values.iter() {
+            self.observe(name, characteristic, value, "observed");
+        }
+        self.virtual_sensors
+            .lock()
+            .entry(name.to_string())
+            .or_default()
+            .extend(values.clone());
+    }
+
+    /// Fetch the current state of an accessory, recording the observed values.
+    ///
+    /// Virtual sensors answer with their last reported values, in the shape of the bridge's.
+    async fn get_accessory_status<T>(
+        &mut self,
+        client: &Client,
+        acc_name: &str,
+    ) -> Result<T, HBError>
+    where
+        T: DeserializeOwned,
+    {
+        let virtual_values = self.virtual_sensors.lock().get(acc_name).cloned();
+        if let Some(values) = virtual_values {
+            let data = json!({
+                "uuid": format!("virtual:{}", acc_name),
+                "uniqueId": format!("virtual:{}", acc_name),
+                "type": "VirtualSensor",
+                "humanType": "Virtual Sensor",
+                "serviceName": acc_name,
+                "values": values,
+            });
+            return serde_json::from_value::<T>(data).map_err(|e| {
+                HBError::ParsingError(format!("Error parsing '{}' data - {}", acc_name, e))
+            });
+        }
+        if let Some((data, age)) = self.polled.as_ref().and_then(|p| p.fresh(acc_name)) {
+            debug!(
+                "Using '{}' state polled {} s ago.",
+                acc_name,
+                age.num_seconds()
+            );
+            self.read_values(acc_name, &data);
+            return serde_json::from_value::<T>(data).map_err(|e| {
+                HBError::ParsingError(format!("Error parsing '{}' data - {}", acc_name, e))
+            });
+        }
+        let access_token = self.access_token(client).await?;
+        let acc_uuid = self.get_accessory_uuid(client, acc_name).await?;
+
+        let mut endpt = self.base_url.clone();
         endpt.push_str("/api/accessories/");
-        endpt.push_str(&light_uuid);
+        endpt.push_str(&acc_uuid);
 
-        let res = client
+        self.wait_for_accessory(acc_name).await;
+        let started = Instant::now();
+        let res = self
+            .http(client)
             .get(endpt)
             .bearer_auth(&access_token)
             .send()
             .await
             .map_err(HBError::UnableToConnect)?;
-        debug!("Parsing bed light data.");
-        res.json::<HBLightbulb>().await.map_err(|e| {
-            HBError::ParsingError(format!("Error parsing `HBAccessories` data - {}", e))
+        debug!("Parsing '{}' data.", acc_name);
+        let data = res.json::<Value>().await.map_err(|e| {
+            HBError::ParsingError(format!("Error parsing '{}' data - {}", acc_name, e))
+        })?;
+        self.latency.lock().record(acc_name, started.elapsed());
+        let data = self.schema(client).await.normalize_accessory(data);
+        self.read_values(acc_name, &data);
+        serde_json::from_value::<T>(data).map_err(|e| {
+            HBError::ParsingError(format!("Error parsing '{}' data - {}", acc_name, e))
         })
     }
 
-    pub async fn bed_light_is_off(&mut self, client: &Client) -> Result<bool, HBError> {
-        let values = self.get_bed_light_status(client).await?.values;
-        Ok(values.on == 0)
+    /// Take note of the values of an accessory status read from the bridge, live or polled.
+    fn read_values(&mut self, acc_name: &str, data: &Value) {
+        let Some(values) = data.get("values").and_then(Value::as_object) else {
+            return;
+        };
+        for (characteristic, value) in values.iter() {
+            self.observe(acc_name, characteristic, value, "observed");
+            self.tolerances.lock().read(acc_name, characteristic, value);
+            self.run_times
+                .lock()
+                .read(acc_name, characteristic, value, clock::now());
+        }
+    }
+
+    /// Age of the polled state reads of an accessory are served from, if it is polled.
+    pub fn state_age(&self, accessory: &str) -> Option<Duration> {
+        self.polled.as_ref().and_then(|p| p.age(accessory))
+    }
+
+    /// Wait until the accessory may receive another request.
+    async fn wait_for_accessory(&self, accessory: &str) {
+        let wait = self.latency.lock().wait_time(accessory);
+        if !wait.is_zero() {
+            debug!(
+                "Waiting {:?} before the next request to '{}'.",
+                wait, accessory
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Current values of all characteristics of an accessory.
+    pub async fn get_accessory_values(
+        &mut self,
+        client: &Client,
+        accessory: &str,
+    ) -> Result<Value, HBError> {
+        let status: Value = self.get_accessory_status(client, accessory).await?;
+        Ok(status.get("values").cloned().unwrap_or_default())
+    }
+
+    /// Current value of a single characteristic.
+    pub async fn get_characteristic(
+        &mut self,
+        client: &Client,
+        accessory: &str,
+        characteristic: &Characteristic,
+    ) -> Result<Value, HBError> {
+        self.get_accessory_values(client, accessory)
+            .await?
+            .get(characteristic.as_str())
+            .cloned()
+            .ok_or_else(|| {
+                HBError::ParsingError(format!("'{}' has no {}.", accessory, characteristic))
+            })
+    }
+
+    /// Handle on an accessory of the bridge, by name or `uniqueId:<id>`.
+    pub fn accessory(&mut self, name: &str) -> Accessory<'_> {
+        Accessory {
+            homebridge: self,
+            name: name.to_string(),
+        }
+    }
+}
+
+/// One accessory of the bridge, to read and write its characteristics.
+///
+/// Writes go through the same journal, spacing, and read-only checks as every other write.
+pub struct Accessory<'a> {
+    homebridge: &'a mut Homebridge,
+    name: String,
+}
+
+impl Accessory<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The accessory's payload, parsed as e.g. `HBLightbulb`.
+    pub async fn status<T>(&mut self, client: &Client) -> Result<T, HBError>
+    where
+        T: DeserializeOwned,
+    {
+        self.homebridge
+            .get_accessory_status(client, &self.name)
+            .await
+    }
+
+    /// Current values of all characteristics.
+    pub async fn values(&mut self, client: &Client) -> Result<Value, HBError> {
+        self.homebridge
+            .get_accessory_values(client, &self.name)
+            .await
+    }
+
+    /// Current value of a characteristic.
+    pub async fn get(
+        &mut self,
+        client: &Client,
+        characteristic: &Characteristic,
+    ) -> Result<Value, HBError> {
+        self.homebridge
+            .get_characteristic(client, &self.name, characteristic)
+            .await
+    }
+
+    /// Write a characteristic.
+    pub async fn set<T>(
+        &mut self,
+        client: &Client,
+        program: &str,
+        characteristic: &Characteristic,
+        value: T,
+    ) -> Result<(), HBError>
+    where
+        T: Serialize,
+    {
+        self.homebridge
+            .set_characteristic(client, program, &self.name, characteristic, value)
+            .await
+    }
+
+    /// Write several characteristics in order, see `Homebridge::apply_values`.
+    pub async fn apply(
+        &mut self,
+        client: &Client,
+        program: &str,
+        writes: &[(Characteristic, Value)],
+        rollback: bool,
+    ) -> Result<(), HBError> {
+        self.homebridge
+            .apply_values(client, program, &self.name, writes, rollback)
+            .await
+    }
+
+    /// Whether the accessory is switched off.
+    pub async fn is_off(&mut self, client: &Client) -> Result<bool, HBError> {
+        let light: HBLightbulb = self.status(client).await?;
+        Ok(light.values.is_off())
     }
 }
 
 impl Homebridge {
-    async fn _set_bedlight<T>(
+    /// Single choke point for all accessory writes.
+    ///
+    /// Records the issuing program and the before/after values in the write journal.
+    async fn set_characteristic<T>(
         &mut self,
         client: &Client,
-        characteristic: &str,
+        program: &str,
+        accessory: &str,
+        characteristic: &Characteristic,
         value: T,
     ) -> Result<(), HBError>
     where
         T: Serialize,
     {
-        let access_token = self.access_token(&client).await?;
+        if self.observe_only {
+            info!(
+                "[{}] Observing only - not setting {} of '{}' to {}.",
+                program,
+                characteristic,
+                accessory,
+                json!(value)
+            );
+            return Err(HBError::ObserveOnly);
+        }
+        if let Some(entry) = self.maintenance_of(accessory) {
+            if maintenance::rejects(program) {
+                error!(
+                    "[{}] Refusing to set {} - {}.",
+                    program, characteristic, entry
+                );
+                return Err(HBError::InMaintenance(entry.clone()));
+            }
+            info!(
+                "[{}] {} - not setting {} to {}.",
+                program,
+                entry,
+                characteristic,
+                json!(value)
+            );
+            return Ok(());
+        }
+        if let Some(hours) = self
+            .control_hours
+            .get(accessory)
+            .filter(|h| !h.allows(clock::now().time()))
+        {
+            error!(
+                "[{}] Refusing to set {} of '{}' - it may only be controlled {}.",
+                program, characteristic, accessory, hours
+            );
+            return Err(HBError::OutsideControlHours {
+                accessory: accessory.to_string(),
+                hours: *hours,
+            });
+        }
+        let access_token = self.access_token(client).await?;
 
-        let mut endpt = self.ip_address.clone();
+        let mut endpt = self.base_url.clone();
         endpt.push_str("/api/accessories/");
-        endpt.push_str(&self.bed_light_uuid(client).await?);
+        endpt.push_str(&self.get_accessory_uuid(client, accessory).await?);
+        if self
+            .accessories
+            .lock()
+            .find(accessory)
+            .is_some_and(|(_, a)| a.read_only.iter().any(|c| c == characteristic.as_str()))
+        {
+            error!(
+                "[{}] Refusing to write read-only {} of '{}'.",
+                program, characteristic, accessory
+            );
+            return Err(HBError::ReadOnlyCharacteristic {
+                accessory: accessory.to_string(),
+                characteristic: characteristic.clone(),
+            });
+        }
 
         let body = json!({
             "characteristicType": characteristic,
             "value": value,
         });
 
-        client
+        self.wait_for_accessory(accessory).await;
+        // The request and its journal entry go on in a task of their own, so a caller that
+        // stops waiting (e.g. at the program timeout) cannot leave a write the bridge applied
+        // out of the journal, where it would be taken for a manual change.
+        let write = Write {
+            program: program.to_string(),
+            accessory: accessory.to_string(),
+            characteristic: characteristic.clone(),
+            body,
+        };
+        let request = self
+            .http(client)
             .put(endpt)
             .bearer_auth(&access_token)
-            .json(&body)
+            .json(&write.body);
+        match tokio::spawn(self.clone().send(request, write)).await {
+            Ok(result) => result,
+            // E.g. when the runtime shuts down.
+            Err(e) if e.is_cancelled() => Err(HBError::WriteCancelled {
+                accessory: accessory.to_string(),
+                characteristic: characteristic.clone(),
+            }),
+            // Panics again rather than resuming, so the panic hook (e.g. a crash report) sees it
+            // on the caller's thread.
+            Err(e) => {
+                let panic = e.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|m| m.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                panic!("Write to '{}' panicked: {}", accessory, message)
+            }
+        }
+    }
+
+    /// Send a write and record it once the bridge took it.
+    async fn send(mut self, request: RequestBuilder, write: Write) -> Result<(), HBError> {
+        let Write {
+            program,
+            accessory,
+            characteristic,
+            body,
+        } = write;
+        let started = Instant::now();
+        request
             .send()
             .await
+            .and_then(|res| res.error_for_status())
             .map_err(HBError::UnableToConnect)?;
+        self.latency.lock().record(&accessory, started.elapsed());
 
+        let after = body["value"].clone();
+        if let Some(polled) = &self.polled {
+            polled.written(&accessory, characteristic.as_str(), &after);
+        }
+        let before = self.observe(&accessory, characteristic.as_str(), &after, &program);
+        self.tolerances
+            .lock()
+            .written(&accessory, characteristic.as_str(), &after);
+        self.run_times
+            .lock()
+            .written(&accessory, characteristic.as_str(), &after, clock::now());
+        self.journal.lock().record(WriteRecord {
+            when: clock::now(),
+            program,
+            accessory,
+            characteristic: characteristic.to_string(),
+            before,
+            after,
+        });
         Ok(())
     }
 
-    pub async fn turn_bedlight_on(&mut self, client: &Client) -> Result<(), HBError> {
-        info!("Turning bed light ON.");
-        self._set_bedlight(client, "On", "1").await
+    pub async fn get_light_status(
+        &mut self,
+        client: &Client,
+        light: &str,
+    ) -> Result<HBLightbulb, HBError> {
+        debug!("Retrieving status of light '{}'.", light);
+        self.get_accessory_status(client, light).await
     }
-    pub async fn turn_bedlight_off(&mut self, client: &Client) -> Result<(), HBError> {
-        info!("Turning bed light OFF.");
-        self._set_bedlight(client, "On", "0").await
+
+    pub async fn get_light_sensor_status(
+        &mut self,
+        client: &Client,
+        sensor: &str,
+    ) -> Result<HBLightSensor, HBError> {
+        debug!("Retrieving status of light sensor '{}'.", sensor);
+        self.get_accessory_status(client, sensor).await
     }
 
-    pub async fn set_bedlight_brightness(
+    pub async fn set_light_hue(
         &mut self,
         client: &Client,
+        program: &str,
+        light: &str,
+        hue: u32,
+    ) -> Result<(), HBError> {
+        self.set_characteristic(client, program, light, &Characteristic::Hue, hue)
+            .await
+    }
+
+    pub async fn set_light_on(
+        &mut self,
+        client: &Client,
+        program: &str,
+        light: &str,
+        on: bool,
+    ) -> Result<(), HBError> {
+        self.set_characteristic(client, program, light, &Characteristic::On, on as u8)
+            .await
+    }
+
+    /// Writes that switch a light on at the given brightness, in its configured turn-on order.
+    pub fn turn_on_writes(&self, light: &str, brightness: u8) -> Vec<(Characteristic, Value)> {
+        let sequence = self
+            .turn_on_sequences
+            .get(light)
+            .copied()
+            .unwrap_or_default();
+        debug!("Turning '{}' on with {:?}.", light, sequence);
+        match sequence {
+            TurnOnSequence::OnFirst => vec![
+                (Characteristic::On, json!(1)),
+                (Characteristic::Brightness, json!(brightness)),
+            ],
+            TurnOnSequence::BrightnessFirst => vec![
+                (Characteristic::Brightness, json!(brightness)),
+                (Characteristic::On, json!(1)),
+            ],
+            TurnOnSequence::RampFromMinimum => vec![
+                (Characteristic::Brightness, json!(1)),
+                (Characteristic::On, json!(1)),
+                (Characteristic::Brightness, json!(brightness)),
+            ],
+        }
+    }
+
+    /// Brightness to switch a light on at when no brightness is given.
+    fn implied_brightness(&self, light: &str) -> Option<u8> {
+        let setting = self.on_brightness.get(light)?;
+        let restored = match setting.restore {
+            true => self
+                .journal
+                .lock()
+                .last_write(light, Characteristic::Brightness.as_str())
+                .and_then(|w| numeric_value(&w.after))
+                .map(|b| b.round().clamp(0.0, 100.0) as u8)
+                .filter(|b| *b > 0),
+            false => None,
+        };
+        restored.or(setting.brightness)
+    }
+
+    /// `writes`, with a bare switch-on replaced by the light's turn-on sequence if it has a
+    /// brightness to switch on at.
+    fn with_on_brightness(
+        &self,
+        light: &str,
+        writes: &[(Characteristic, Value)],
+    ) -> Vec<(Characteristic, Value)> {
+        let switches_on = writes
+            .iter()
+            .any(|(c, v)| *c == Characteristic::On && numeric_value(v).is_some_and(|v| v != 0.0));
+        let sets_brightness = writes.iter().any(|(c, _)| *c == Characteristic::Brightness);
+        let brightness = match switches_on && !sets_brightness {
+            true => self.implied_brightness(light),
+            false => None,
+        };
+        let Some(brightness) = brightness else {
+            return writes.to_vec();
+        };
+        let mut expanded = Vec::new();
+        for (characteristic, value) in writes.iter() {
+            match characteristic {
+                Characteristic::On => expanded.extend(self.turn_on_writes(light, brightness)),
+                _ => expanded.push((characteristic.clone(), value.clone())),
+            }
+        }
+        expanded
+    }
+
+    /// Switch a light on at the given brightness using its configured turn-on sequence.
+    pub async fn turn_light_on_at(
+        &mut self,
+        client: &Client,
+        program: &str,
+        light: &str,
         brightness: u8,
     ) -> Result<(), HBError> {
-        info!("Setting bed light brightness: {}.", brightness);
-        self._set_bedlight(client, "Brightness", &brightness).await
+        let writes = self.turn_on_writes(light, brightness);
+        self.apply_values(client, program, light, &writes, false)
+            .await
     }
 
-    pub async fn set_bedlight(
+    /// Write several characteristics of an accessory in order.
+    ///
+    /// With `rollback`, the accessory's values are read first and, if a write fails, the
+    /// characteristics written so far are set back to them. The error names the
+    /// characteristic that failed.
+    ///
+    /// Switching a light on without a brightness uses its configured `OnBrightness`, if any.
+    pub async fn apply_values(
         &mut self,
         client: &Client,
-        values: &HBLightbulbValues,
+        program: &str,
+        accessory: &str,
+        writes: &[(Characteristic, Value)],
+        rollback: bool,
     ) -> Result<(), HBError> {
-        info!("Setting bed light values: {:?}", values);
-        self._set_bedlight(client, "On", &values.on.to_string())
-            .await?;
-        self._set_bedlight(client, "Brightness", &values.brightness.to_string())
-            .await?;
-        self._set_bedlight(
+        let writes = self.with_on_brightness(accessory, writes);
+        let snapshot = match rollback {
+            true => {
+                let status: Value = self.get_accessory_status(client, accessory).await?;
+                status.get("values").cloned().unwrap_or_default()
+            }
+            false => Value::Null,
+        };
+        for (i, (characteristic, value)) in writes.iter().enumerate() {
+            let Err(e) = self
+                .set_characteristic(client, program, accessory, characteristic, value)
+                .await
+            else {
+                continue;
+            };
+            // Nothing was written, so there is nothing to roll back.
+            if matches!(e, HBError::ObserveOnly) {
+                return Err(e);
+            }
+            if rollback {
+                self.roll_back(client, program, accessory, &writes[..=i], &snapshot)
+                    .await;
+            }
+            return Err(HBError::WriteFailed {
+                accessory: accessory.to_string(),
+                characteristic: characteristic.clone(),
+                rolled_back: rollback,
+                source: Box::new(e),
+            });
+        }
+        Ok(())
+    }
+
+    /// Write values to several accessories, spaced and ordered by the write queue.
+    ///
+    /// `writes` are (accessory, characteristic, value); each accessory's values are written
+    /// together in the order they first appear. An accessory that fails does not keep the
+    /// others from being written.
+    pub async fn apply_group(
+        &mut self,
+        client: &Client,
+        program: &str,
+        writes: &[(&str, Characteristic, Value)],
+    ) -> GroupResult {
+        let mut result = GroupResult::default();
+        let mut accessories: Vec<&str> = Vec::new();
+        for (accessory, _, _) in writes.iter() {
+            if !accessories.contains(accessory) {
+                accessories.push(accessory);
+            }
+        }
+        let schedule = self.write_queue.lock().schedule(&accessories);
+        for (accessory, pause) in schedule {
+            if !pause.is_zero() {
+                debug!("Waiting {:?} before writing to '{}'.", pause, accessory);
+                tokio::time::sleep(pause).await;
+            }
+            let values: Vec<(Characteristic, Value)> = writes
+                .iter()
+                .filter(|(a, _, _)| *a == accessory)
+                .map(|(_, c, v)| (c.clone(), v.clone()))
+                .collect();
+            match self
+                .apply_values(client, program, accessory, &values, false)
+                .await
+            {
+                Ok(()) => result.succeeded.push(accessory.to_string()),
+                Err(e) => {
+                    warn!("Group write to '{}' failed: {}", accessory, e);
+                    result.failed.push(GroupFailure {
+                        accessory: accessory.to_string(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    /// Restore the snapshot values of the written characteristics, most recent first.
+    async fn roll_back(
+        &mut self,
+        client: &Client,
+        program: &str,
+        accessory: &str,
+        written: &[(Characteristic, Value)],
+        snapshot: &Value,
+    ) {
+        let mut restored: Vec<&Characteristic> = Vec::new();
+        for (characteristic, _) in written.iter().rev() {
+            if restored.contains(&characteristic) {
+                continue;
+            }
+            restored.push(characteristic);
+            let Some(value) = snapshot.get(characteristic.as_str()) else {
+                warn!(
+                    "No earlier {} of '{}' to roll back to.",
+                    characteristic, accessory
+                );
+                continue;
+            };
+            info!(
+                "Rolling back {} of '{}' to {}.",
+                characteristic, accessory, value
+            );
+            if let Err(e) = self
+                .set_characteristic(client, program, accessory, characteristic, value)
+                .await
+            {
+                error!(
+                    "Could not roll back {} of '{}': {}",
+                    characteristic, accessory, e
+                );
+            }
+        }
+    }
+
+    pub async fn set_light_brightness(
+        &mut self,
+        client: &Client,
+        program: &str,
+        light: &str,
+        brightness: u8,
+    ) -> Result<(), HBError> {
+        self.set_characteristic(
             client,
-            "ColorTemperature",
-            &values.color_temperature.to_string(),
+            program,
+            light,
+            &Characteristic::Brightness,
+            brightness,
         )
-        .await?;
-        self._set_bedlight(client, "Hue", &values.hue.to_string())
-            .await?;
-        self._set_bedlight(client, "Saturation", &values.saturation.to_string())
-            .await?;
-        Ok(())
+        .await
+    }
+}
+
+impl Homebridge {
+    pub async fn get_valve_status(
+        &mut self,
+        client: &Client,
+        valve: &str,
+    ) -> Result<HBValve, HBError> {
+        debug!("Retrieving status of valve '{}'.", valve);
+        self.get_accessory_status(client, valve).await
+    }
+
+    pub async fn set_valve_active(
+        &mut self,
+        client: &Client,
+        program: &str,
+        valve: &str,
+        active: bool,
+    ) -> Result<(), HBError> {
+        self.set_characteristic(
+            client,
+            program,
+            valve,
+            &Characteristic::Active,
+            active as u8,
+        )
+        .await
+    }
+
+    /// Set how long (in seconds) the valve stays open once activated.
+    pub async fn set_valve_duration(
+        &mut self,
+        client: &Client,
+        program: &str,
+        valve: &str,
+        seconds: u32,
+    ) -> Result<(), HBError> {
+        self.set_characteristic(
+            client,
+            program,
+            valve,
+            &Characteristic::SetDuration,
+            seconds,
+        )
+        .await
+    }
+}
+
+impl Homebridge {
+    /// Record a characteristic value, noting a change if it differs from the last one seen.
+    ///
+    /// Returns the previously observed value.
+    fn observe(
+        &mut self,
+        accessory: &str,
+        characteristic: &str,
+        value: &Value,
+        source: &str,
+    ) -> Option<Value> {
+        let previous = self
+            .observed_values
+            .lock()
+            .entry(accessory.to_string())
+            .or_default()
+            .insert(characteristic.to_string(), value.clone());
+        if let Some(old) = &previous {
+            if !same_value(old, value) {
+                self.changes.lock().push(StateChange {
+                    when: clock::now(),
+                    accessory: accessory.to_string(),
+                    characteristic: characteristic.to_string(),
+                    old: old.clone(),
+                    new: value.clone(),
+                    source: source.to_string(),
+                });
+            }
+        }
+        previous
+    }
+
+    /// Changes seen since the last call.
+    pub fn take_changes(&mut self) -> Vec<StateChange> {
+        std::mem::take(&mut *self.changes.lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(values: Value) -> HBLightbulbValues {
+        serde_json::from_value(values).unwrap()
+    }
+
+    #[test]
+    fn full_payload_with_numbers() {
+        let values = parse(json!({
+            "On": 1,
+            "Brightness": 40,
+            "ColorTemperature": 300,
+            "Hue": 30,
+            "Saturation": 20
+        }));
+        assert!(values.is_on());
+        assert_eq!(values.brightness, Some(40));
+        assert_eq!(values.color_temperature, Some(300));
+        assert_eq!(values.hue, Some(30));
+        assert_eq!(values.saturation, Some(20));
+    }
+
+    #[test]
+    fn accessory_cache_finds_by_service_name() {
+        let mut cache = AccessoryCache::default();
+        cache.accessories.insert(
+            "abc123".to_string(),
+            AccessoryCapabilities {
+                service_name: "Bed Light".to_string(),
+                characteristics: vec!["On".to_string(), "Name".to_string()],
+                read_only: vec!["Name".to_string()],
+            },
+        );
+        assert_eq!(
+            cache.find("Bed Light").map(|(id, _)| id.as_str()),
+            Some("abc123")
+        );
+        assert!(cache.find("Porch").is_none());
+        assert!(cache.find("uniqueId:abc123").is_some());
+        assert!(cache.duplicate_names().is_empty());
+        assert_eq!(cache.key("Bed Light"), "uniqueId:abc123");
+        assert_eq!(cache.key("uniqueId:abc123"), "uniqueId:abc123");
+        assert_eq!(cache.key("Porch"), "Porch");
+
+        let twin = cache.accessories["abc123"].clone();
+        cache.accessories.insert("def456".to_string(), twin);
+        assert_eq!(cache.duplicate_names(), vec!["Bed Light"]);
+        assert_eq!(
+            cache.find("uniqueId:def456").map(|(id, _)| id.as_str()),
+            Some("def456")
+        );
+
+        let now = clock::now();
+        assert!(cache.is_stale(&now));
+        cache.refreshed = Some(now - Duration::hours(1));
+        assert!(!cache.is_stale(&now));
+    }
+
+    #[test]
+    fn switching_on_uses_restored_brightness() {
+        let mut homebridge = Homebridge::new("http://localhost", "u", "p");
+        homebridge.on_brightness.insert(
+            BED_LIGHT.to_string(),
+            OnBrightness {
+                restore: true,
+                brightness: Some(30),
+            },
+        );
+        let on = [(Characteristic::On, json!(1))];
+        assert_eq!(
+            homebridge.with_on_brightness(BED_LIGHT, &on),
+            vec![
+                (Characteristic::On, json!(1)),
+                (Characteristic::Brightness, json!(30))
+            ]
+        );
+
+        homebridge.journal.lock().record(WriteRecord {
+            when: clock::now(),
+            program: "test".to_string(),
+            accessory: BED_LIGHT.to_string(),
+            characteristic: "Brightness".to_string(),
+            before: None,
+            after: json!(55),
+        });
+        assert_eq!(
+            homebridge.with_on_brightness(BED_LIGHT, &on),
+            vec![
+                (Characteristic::On, json!(1)),
+                (Characteristic::Brightness, json!(55))
+            ]
+        );
+
+        let explicit = [
+            (Characteristic::On, json!(1)),
+            (Characteristic::Brightness, json!(80)),
+        ];
+        assert_eq!(
+            homebridge.with_on_brightness(BED_LIGHT, &explicit),
+            explicit
+        );
+        assert_eq!(homebridge.with_on_brightness("Porch", &on), on);
+    }
+
+    #[test]
+    fn on_as_bool() {
+        assert!(parse(json!({"On": true})).is_on());
+        assert!(parse(json!({"On": false})).is_off());
+    }
+
+    #[test]
+    fn on_as_string() {
+        assert!(parse(json!({"On": "true"})).is_on());
+        assert!(parse(json!({"On": "1"})).is_on());
+        assert!(parse(json!({"On": "0"})).is_off());
+        assert!(parse(json!({"On": "false"})).is_off());
+    }
+
+    #[test]
+    fn invalid_on_is_an_error() {
+        assert!(serde_json::from_value::<HBLightbulbValues>(json!({"On": "maybe"})).is_err());
+        assert!(serde_json::from_value::<HBLightbulbValues>(json!({"Brightness": 10})).is_err());
+    }
+
+    #[test]
+    fn missing_optional_characteristics() {
+        let values = parse(json!({"On": 1}));
+        assert_eq!(values.brightness, None);
+        assert_eq!(values.brightness(), 100);
+        assert_eq!(values.hue, None);
+        assert_eq!(parse(json!({"On": 0})).brightness(), 0);
+    }
+
+    #[test]
+    fn numbers_as_strings_floats_and_null() {
+        let values = parse(json!({
+            "On": 1,
+            "Brightness": "55",
+            "ColorTemperature": 153.4,
+            "Hue": null,
+            "Saturation": ""
+        }));
+        assert_eq!(values.brightness, Some(55));
+        assert_eq!(values.color_temperature, Some(153));
+        assert_eq!(values.hue, None);
+        assert_eq!(values.saturation, None);
+    }
+
+    #[test]
+    fn brightness_is_clamped() {
+        assert_eq!(
+            parse(json!({"On": 1, "Brightness": 254})).brightness,
+            Some(100)
+        );
+        assert_eq!(
+            parse(json!({"On": 1, "Brightness": -3})).brightness,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn lightbulb_with_minimal_values() {
+        let bulb: HBLightbulb = serde_json::from_value(json!({
+            "uuid": "abc",
+            "uniqueId": "def",
+            "type": "Lightbulb",
+            "humanType": "Lightbulb",
+            "serviceName": "Bed Light",
+            "values": {"On": true}
+        }))
+        .unwrap();
+        assert!(bulb.values.is_on());
+        assert_eq!(bulb.values.brightness(), 100);
+    }
+
+    #[test]
+    fn group_result_lists_failures() {
+        let result = GroupResult {
+            succeeded: vec!["Bed Light".to_string(), "Desk".to_string()],
+            failed: vec![GroupFailure {
+                accessory: "Lamp".to_string(),
+                error: "unreachable".to_string(),
+            }],
+        };
+        assert!(!result.is_complete());
+        assert_eq!(
+            result.to_string(),
+            "2 of 3 accessories written; 'Lamp' failed: unreachable"
+        );
+    }
+
+    #[test]
+    fn detached_clients_share_the_token() {
+        let homebridge = Homebridge::new("http://127.0.0.1:8581", "user", "password");
+        let detached = homebridge.detached();
+        homebridge.access_token.try_lock().unwrap().token = Some("token".to_string());
+        assert_eq!(
+            detached.access_token.try_lock().unwrap().token.as_deref(),
+            Some("token")
+        );
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_journal_and_accessories() {
+        let (url, lights) = crate::bench::mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let client = Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        let detached = homebridge.detached();
+        let mut clone = homebridge.clone();
+        clone
+            .accessory(&lights[0])
+            .set(&client, "test", &Characteristic::Brightness, 30)
+            .await
+            .unwrap();
+
+        let written = homebridge
+            .journal
+            .lock()
+            .last_write(&lights[0], "Brightness")
+            .map(|w| w.after.clone());
+        assert_eq!(written, Some(json!(30)));
+        assert!(homebridge.take_accessory_cache().is_some());
+        assert!(detached.journal.lock().iter().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn abandoned_writes_are_still_journaled() {
+        let latency = std::time::Duration::from_millis(200);
+        let (url, lights) = crate::bench::mock_bridge(1, latency).unwrap();
+        let client = Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        homebridge.latency.replace(LatencyTracker::new(
+            std::time::Duration::ZERO,
+            std::time::Duration::from_secs(60),
+        ));
+        let mut light = homebridge.accessory(&lights[0]);
+        light.values(&client).await.unwrap();
+
+        // The caller gives up while the bridge is still applying the write.
+        let write = light.set(&client, "test", &Characteristic::Brightness, 20);
+        let abandoned = tokio::time::timeout(latency / 4, write).await;
+        assert!(abandoned.is_err());
+        tokio::time::sleep(latency * 2).await;
+
+        let written = homebridge
+            .journal
+            .lock()
+            .last_write(&lights[0], "Brightness")
+            .map(|w| w.after.clone());
+        assert_eq!(written, Some(json!(20)));
+        let values = homebridge
+            .get_accessory_values(&client, &lights[0])
+            .await
+            .unwrap();
+        assert_eq!(values["Brightness"], json!(20));
+    }
+
+    #[test]
+    fn characteristics_are_writable_unless_marked_otherwise() {
+        let characteristic = |can_write: Value| {
+            let mut value = json!({
+                "type": "Brightness", "description": "Brightness", "value": 40,
+                "format": "int", "perms": ["pr", "pw"]
+            });
+            if !can_write.is_null() {
+                value["canWrite"] = can_write;
+            }
+            serde_json::from_value::<HBServiceCharacteristic>(value).unwrap()
+        };
+        assert!(characteristic(Value::Null).can_write);
+        assert!(!characteristic(Value::Null)
+            .to_string()
+            .contains("read-only"));
+        assert!(characteristic(json!(false))
+            .to_string()
+            .ends_with("(read-only)"));
+        let permissions: HBCharacteristicPermissions =
+            serde_json::from_value(json!({"type": "Brightness"})).unwrap();
+        assert!(permissions.can_write);
+    }
+
+    #[test]
+    fn control_hours_span_midnight() {
+        let at = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        let day = ControlHours {
+            earliest: Some(at(7)),
+            latest: Some(at(20)),
+        };
+        assert!(day.allows(at(7)));
+        assert!(!day.allows(at(21)));
+        assert!(!day.allows(at(6)));
+        let night = ControlHours {
+            earliest: Some(at(18)),
+            latest: Some(at(2)),
+        };
+        assert!(night.allows(at(23)));
+        assert!(night.allows(at(1)));
+        assert!(!night.allows(at(12)));
+        let bedtime = ControlHours {
+            earliest: None,
+            latest: Some(at(19)),
+        };
+        assert!(bedtime.allows(at(0)));
+        assert!(!bedtime.allows(at(20)));
+        assert_eq!(bedtime.to_string(), "until 19:00");
     }
 }
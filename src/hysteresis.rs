@@ -0,0 +1,215 @@
+use crate::configuration::HysteresisConfig;
+use crate::smoothing::Smoother;
+use chrono::{DateTime, Duration, Local};
+use log::debug;
+
+/// On/off switch driven by a measured value with separate on and off thresholds.
+///
+/// If `on_threshold` is above `off_threshold`, the switch turns on when the value rises to the
+/// on threshold (e.g. a humidity-controlled fan). Otherwise it turns on when the value falls to
+/// the on threshold (e.g. a lux-gated light). Between the thresholds the state is kept, and a
+/// change is only allowed once the current state has lasted its minimum dwell time.
+#[derive(Debug, Clone)]
+pub struct Hysteresis {
+    on_threshold: f64,
+    off_threshold: f64,
+    min_on: Duration,
+    min_off: Duration,
+    on: bool,
+    last_change: Option<DateTime<Local>>,
+    smoother: Option<Smoother>,
+}
+
+impl Hysteresis {
+    pub fn new(on_threshold: f64, off_threshold: f64) -> Self {
+        Self {
+            on_threshold,
+            off_threshold,
+            min_on: Duration::zero(),
+            min_off: Duration::zero(),
+            on: false,
+            last_change: None,
+            smoother: None,
+        }
+    }
+
+    /// Smooth measurements before comparing them to the thresholds.
+    pub fn with_smoothing(mut self, smoother: Smoother) -> Self {
+        self.smoother = Some(smoother);
+        self
+    }
+
+    /// Minimum time to stay on and off before switching again.
+    pub fn with_min_dwell(mut self, min_on: Duration, min_off: Duration) -> Self {
+        self.min_on = min_on;
+        self.min_off = min_off;
+        self
+    }
+
+    pub fn from_config(config: &HysteresisConfig) -> Self {
+        let hysteresis = Self::new(config.on_threshold, config.off_threshold).with_min_dwell(
+            Duration::minutes(config.min_on_minutes),
+            Duration::minutes(config.min_off_minutes),
+        );
+        match &config.smoothing {
+            Some(smoothing) => hysteresis.with_smoothing(Smoother::from_config(smoothing)),
+            None => hysteresis,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    fn rising(&self) -> bool {
+        self.on_threshold >= self.off_threshold
+    }
+
+    fn crosses_on(&self, value: f64) -> bool {
+        match self.rising() {
+            true => value >= self.on_threshold,
+            false => value <= self.on_threshold,
+        }
+    }
+
+    fn crosses_off(&self, value: f64) -> bool {
+        match self.rising() {
+            true => value <= self.off_threshold,
+            false => value >= self.off_threshold,
+        }
+    }
+
+    /// Feed a new measurement and return the resulting state.
+    pub fn update(&mut self, value: f64, now: &DateTime<Local>) -> bool {
+        let value = match self.smoother.as_mut() {
+            Some(smoother) => {
+                let smoothed = smoother.update(value);
+                debug!("Measured {}, smoothed to {}.", value, smoothed);
+                smoothed
+            }
+            None => value,
+        };
+        let wants_change = match self.on {
+            true => self.crosses_off(value),
+            false => self.crosses_on(value),
+        };
+        if !wants_change {
+            return self.on;
+        }
+        let min_dwell = if self.on { self.min_on } else { self.min_off };
+        if let Some(last_change) = self.last_change {
+            if *now - last_change < min_dwell {
+                debug!(
+                    "Value {} would switch state, but minimum dwell not reached.",
+                    value
+                );
+                return self.on;
+            }
+        }
+        self.on = !self.on;
+        self.last_change = Some(*now);
+        self.on
+    }
+}
+
+/// Boolean signal that only changes after the new value has held for a delay.
+#[derive(Debug, Clone)]
+pub struct Debounce {
+    delay: Duration,
+    stable: bool,
+    pending_since: Option<DateTime<Local>>,
+}
+
+impl Debounce {
+    pub fn new(delay: Duration, initial: bool) -> Self {
+        Self {
+            delay,
+            stable: initial,
+            pending_since: None,
+        }
+    }
+
+    pub fn value(&self) -> bool {
+        self.stable
+    }
+
+    /// Feed a new raw value and return the debounced value.
+    pub fn update(&mut self, value: bool, now: &DateTime<Local>) -> bool {
+        if value == self.stable {
+            self.pending_since = None;
+            return self.stable;
+        }
+        match self.pending_since {
+            Some(since) if *now - since >= self.delay => {
+                self.stable = value;
+                self.pending_since = None;
+            }
+            Some(_) => {}
+            None => {
+                if self.delay <= Duration::zero() {
+                    self.stable = value;
+                } else {
+                    self.pending_since = Some(*now);
+                }
+            }
+        }
+        self.stable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(minutes: i64) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 27, 12, 0, 0).unwrap() + Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn switches_at_the_thresholds_and_keeps_its_state_between_them() {
+        // Humidity-controlled fan.
+        let mut fan = Hysteresis::new(70.0, 60.0);
+        assert!(!fan.update(69.9, &at(0)));
+        assert!(fan.update(70.0, &at(1)));
+        assert!(fan.update(65.0, &at(2)));
+        assert!(!fan.update(60.0, &at(3)));
+        assert!(!fan.update(65.0, &at(4)));
+
+        // Lux-gated light.
+        let mut light = Hysteresis::new(10.0, 50.0);
+        assert!(!light.update(30.0, &at(0)));
+        assert!(light.update(10.0, &at(1)));
+        assert!(light.update(49.0, &at(2)));
+        assert!(!light.update(50.0, &at(3)));
+    }
+
+    #[test]
+    fn waits_out_the_minimum_dwell_times() {
+        let mut fan =
+            Hysteresis::new(70.0, 60.0).with_min_dwell(Duration::minutes(10), Duration::minutes(5));
+        assert!(fan.update(75.0, &at(0)));
+        assert!(fan.update(50.0, &at(9)));
+        assert!(!fan.update(50.0, &at(10)));
+        assert!(!fan.update(75.0, &at(14)));
+        assert!(fan.update(75.0, &at(15)));
+    }
+
+    #[test]
+    fn changes_only_once_the_new_value_has_held() {
+        let mut contact = Debounce::new(Duration::minutes(2), false);
+        assert!(!contact.update(true, &at(0)));
+        // Bouncing back restarts the delay.
+        assert!(!contact.update(false, &at(1)));
+        assert!(!contact.update(true, &at(2)));
+        assert!(!contact.update(true, &at(3)));
+        assert!(contact.update(true, &at(4)));
+        assert!(contact.value());
+        assert!(contact.update(false, &at(5)));
+        assert!(!contact.update(false, &at(7)));
+
+        let mut immediate = Debounce::new(Duration::zero(), false);
+        assert!(immediate.update(true, &at(0)));
+        assert!(!immediate.update(false, &at(0)));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::configuration::LatencyConfig;
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Weight of a new sample in the moving average.
+const SMOOTHING: f64 = 0.2;
+/// Samples needed before an accessory can be considered consistently slow.
+const MIN_SAMPLES: u64 = 3;
+
+#[derive(Debug, Default)]
+struct AccessoryLatency {
+    average: f64,
+    last: f64,
+    samples: u64,
+    last_request: Option<Instant>,
+}
+
+/// Round-trip times of an accessory, as reported in the status output.
+#[derive(Serialize, Debug, Clone)]
+pub struct LatencyStatus {
+    pub accessory: String,
+    pub average_seconds: f64,
+    pub last_seconds: f64,
+    pub samples: u64,
+    pub slow: bool,
+    pub spacing_seconds: f64,
+}
+
+/// Per-accessory round-trip times of reads and writes, used to space out requests.
+///
+/// Every accessory gets at least `min_spacing` between requests. Accessories whose average
+/// round trip reaches `slow_threshold` additionally wait that average.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    min_spacing: Duration,
+    slow_threshold: Duration,
+    accessories: HashMap<String, AccessoryLatency>,
+}
+
+impl Default for LatencyTracker {
+    fn default() -> Self {
+        Self::from_config(&LatencyConfig::default())
+    }
+}
+
+impl LatencyTracker {
+    pub fn new(min_spacing: Duration, slow_threshold: Duration) -> Self {
+        Self {
+            min_spacing,
+            slow_threshold,
+            accessories: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(config: &LatencyConfig) -> Self {
+        Self::new(
+            Duration::from_millis(config.min_spacing_ms),
+            Duration::from_millis(config.slow_threshold_ms),
+        )
+    }
+
+    fn slow(&self, latency: &AccessoryLatency) -> bool {
+        latency.samples >= MIN_SAMPLES && latency.average >= self.slow_threshold.as_secs_f64()
+    }
+
+    fn spacing(&self, latency: &AccessoryLatency) -> Duration {
+        match self.slow(latency) {
+            true => self.min_spacing + Duration::from_secs_f64(latency.average),
+            false => self.min_spacing,
+        }
+    }
+
+    pub fn is_slow(&self, accessory: &str) -> bool {
+        self.accessories
+            .get(accessory)
+            .is_some_and(|l| self.slow(l))
+    }
+
+    /// How long to wait before the next request to the accessory.
+    pub fn wait_time(&self, accessory: &str) -> Duration {
+        match self.accessories.get(accessory) {
+            Some(latency) => match latency.last_request {
+                Some(last) => self.spacing(latency).saturating_sub(last.elapsed()),
+                None => Duration::ZERO,
+            },
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Record the round-trip time of a request that just finished.
+    pub fn record(&mut self, accessory: &str, elapsed: Duration) {
+        let was_slow = self.is_slow(accessory);
+        let latency = self.accessories.entry(accessory.to_string()).or_default();
+        let secs = elapsed.as_secs_f64();
+        latency.average = match latency.samples {
+            0 => secs,
+            _ => SMOOTHING * secs + (1.0 - SMOOTHING) * latency.average,
+        };
+        latency.last = secs;
+        latency.samples += 1;
+        latency.last_request = Some(Instant::now());
+        if !was_slow && self.is_slow(accessory) {
+            warn!(
+                "'{}' is slow to respond (average {:.2} s) - spacing out its requests.",
+                accessory, self.accessories[accessory].average
+            );
+        }
+    }
+
+    pub fn status(&self) -> Vec<LatencyStatus> {
+        let mut status: Vec<LatencyStatus> = self
+            .accessories
+            .iter()
+            .map(|(accessory, latency)| LatencyStatus {
+                accessory: accessory.clone(),
+                average_seconds: latency.average,
+                last_seconds: latency.last,
+                samples: latency.samples,
+                slow: self.slow(latency),
+                spacing_seconds: self.spacing(latency).as_secs_f64(),
+            })
+            .collect();
+        status.sort_by(|a, b| a.accessory.cmp(&b.accessory));
+        status
+    }
+}
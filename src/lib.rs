@@ -1,4 +1,57 @@
+pub mod actions;
+pub mod api;
+pub mod audit;
+pub mod audit_log;
+pub mod backoff;
+pub mod backup;
+pub mod bench;
+pub mod bridge_schema;
+pub mod calibration;
+pub mod characteristic;
+pub mod clock;
 pub mod configuration;
+pub mod control;
+pub mod crash_report;
+pub mod decisions;
+pub mod defaults;
+#[cfg(feature = "desktop")]
+pub mod desktop;
+pub mod discovery;
+pub mod effects;
+pub mod events;
+pub mod expression;
+pub mod fuzzy;
+#[cfg(feature = "gpio")]
+pub mod gpio;
 pub mod homebridge;
+pub mod hysteresis;
+pub mod latency;
+pub mod logging;
+pub mod loop_pause;
+pub mod maintenance;
+pub mod media;
+pub mod metrics;
+pub mod overlaps;
+pub mod override_detector;
+pub mod poller;
+pub mod presence;
 pub mod programs;
+pub mod retention;
+pub mod run_time;
+pub mod schedule_preview;
+pub mod script;
+pub mod sensors;
+pub mod smoothing;
+pub mod state;
 pub mod suntimes;
+pub mod telegram;
+pub mod time_format;
+pub mod time_window;
+pub mod tolerance;
+pub mod toml;
+pub mod update_check;
+pub mod usage_ping;
+pub mod validate;
+pub mod weather;
+pub mod webhooks;
+pub mod write_queue;
@@ -0,0 +1,144 @@
+use crate::configuration::{LoggerConfig, LoggingConfig};
+use crate::defaults;
+use crate::programs::ProgramId;
+use crate::time_format;
+use chrono::Local;
+use log::{LevelFilter, Record};
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::time::{
+    TimeTrigger, TimeTriggerConfig, TimeTriggerInterval,
+};
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::{self, Encode};
+use log4rs::filter::threshold::ThresholdFilter;
+use std::path::Path;
+
+const CRATE_TARGET: &str = "homebridge_controller";
+
+#[derive(thiserror::Error, Debug)]
+pub enum LoggingError {
+    #[error("Error building log appender: {0}")]
+    Appender(#[from] anyhow::Error),
+    #[error("Invalid logging configuration: {0}")]
+    Config(#[from] log4rs::config::runtime::ConfigErrors),
+    #[error("Invalid default logging configuration: {0}")]
+    Default(#[from] serde_yaml::Error),
+    #[error("Error initializing logger: {0}")]
+    Init(#[from] log::SetLoggerError),
+    #[error("Error initializing logger: {0}")]
+    InitDefault(#[from] log4rs::config::InitError),
+}
+
+/// Initialize logging from the configuration, falling back to the log4rs YAML file and then
+/// to the copy of it embedded in the binary.
+pub fn init(config: Option<&LoggingConfig>, fallback_file: &Path) -> Result<(), LoggingError> {
+    match config {
+        Some(config) => {
+            log4rs::init_config(build_config(config)?)?;
+        }
+        None if fallback_file.exists() => log4rs::init_file(fallback_file, Default::default())?,
+        None => log4rs::init_raw_config(serde_yaml::from_str(defaults::LOG_CONFIG)?)?,
+    };
+    Ok(())
+}
+
+/// Lines like log4rs' default pattern (`{d} {l} {t} - {m}{n}`), with the timestamp in the
+/// configured time format.
+#[derive(Debug)]
+struct TimeFormatEncoder;
+
+impl Encode for TimeFormatEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+        writeln!(
+            w,
+            "{} {} {} - {}",
+            time_format::show_precise(&Local::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        )?;
+        Ok(())
+    }
+}
+
+/// Resolve a logger name from the configuration into a log target.
+///
+/// Program names map to their module, other short names to a module of this crate, and
+/// anything containing `::` is used as-is.
+fn log_target(name: &str) -> String {
+    if name.contains("::") {
+        name.to_string()
+    } else if ProgramId::ALL.iter().any(|id| id.name() == name) {
+        format!("{}::programs::{}", CRATE_TARGET, name)
+    } else {
+        format!("{}::{}", CRATE_TARGET, name)
+    }
+}
+
+/// Daily rolling file keeping the last 5 logs in "log-archive/".
+fn rolling_file(path: &Path) -> Result<RollingFileAppender, anyhow::Error> {
+    let stem = path
+        .file_stem()
+        .map_or("hb-controller".into(), |s| s.to_string_lossy());
+    let pattern = format!("log-archive/{}.{{}}.log", stem);
+    let trigger = TimeTrigger::new(TimeTriggerConfig {
+        interval: TimeTriggerInterval::Day(1),
+        ..Default::default()
+    });
+    let roller = FixedWindowRoller::builder().base(1).build(&pattern, 5)?;
+    let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+    Ok(RollingFileAppender::builder()
+        .encoder(Box::new(TimeFormatEncoder))
+        .build(path, Box::new(policy))?)
+}
+
+fn build_logger(
+    name: &str,
+    config: &LoggerConfig,
+) -> Result<(Option<Appender>, Logger), anyhow::Error> {
+    let target = log_target(name);
+    match &config.file {
+        Some(file) => {
+            let appender_name = format!("file_{}", name);
+            let appender = Appender::builder().build(&appender_name, Box::new(rolling_file(file)?));
+            let logger = Logger::builder()
+                .appender(&appender_name)
+                .appender("stdout")
+                .additive(false)
+                .build(target, config.level);
+            Ok((Some(appender), logger))
+        }
+        None => Ok((None, Logger::builder().build(target, config.level))),
+    }
+}
+
+fn build_config(config: &LoggingConfig) -> Result<Config, LoggingError> {
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(TimeFormatEncoder))
+        .build();
+    let mut builder = Config::builder()
+        .appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(config.stdout_level)))
+                .build("stdout", Box::new(stdout)),
+        )
+        .appender(Appender::builder().build("log_file", Box::new(rolling_file(&config.file)?)))
+        .logger(
+            Logger::builder()
+                .appender("log_file")
+                .appender("stdout")
+                .additive(false)
+                .build(CRATE_TARGET, config.level),
+        );
+    for (name, logger_config) in config.loggers.iter() {
+        let (appender, logger) = build_logger(name, logger_config)?;
+        if let Some(appender) = appender {
+            builder = builder.appender(appender);
+        }
+        builder = builder.logger(logger);
+    }
+    Ok(builder.build(Root::builder().appender("stdout").build(LevelFilter::Info))?)
+}
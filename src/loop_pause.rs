@@ -0,0 +1,63 @@
+use crate::configuration::LoopPauseConfig;
+use crate::overlaps::ProgramWindow;
+use chrono::{DateTime, Duration, Local};
+
+/// Pause before the next program loop, in seconds.
+///
+/// Short while a program window is near or running and right after a program acted, so
+/// changes are followed closely. Otherwise long, but never past the lead time of the next
+/// window.
+pub fn tuned_pause(
+    config: &LoopPauseConfig,
+    windows: &[ProgramWindow],
+    acted: bool,
+    now: &DateTime<Local>,
+) -> f32 {
+    let lead = Duration::minutes(config.lead_minutes);
+    let near = windows
+        .iter()
+        .any(|w| w.start - lead <= *now && *now <= w.end);
+    if acted || near {
+        return config.min_seconds;
+    }
+    let until_next = windows
+        .iter()
+        .map(|w| w.start - lead - *now)
+        .filter(|d| *d > Duration::zero())
+        .min();
+    match until_next {
+        Some(d) => {
+            (d.num_milliseconds() as f32 / 1000.0).clamp(config.min_seconds, config.max_seconds)
+        }
+        None => config.max_seconds,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn fast_near_windows_and_slow_otherwise() {
+        let config = LoopPauseConfig {
+            min_seconds: 30.0,
+            max_seconds: 600.0,
+            lead_minutes: 10,
+        };
+        let midnight = Local.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let at = |minutes| midnight + Duration::minutes(minutes);
+        let windows = vec![ProgramWindow {
+            program: "morning_light".to_string(),
+            accessory: "Bed Light".to_string(),
+            start: at(360),
+            end: at(390),
+        }];
+        assert_eq!(tuned_pause(&config, &windows, false, &at(100)), 600.0);
+        assert_eq!(tuned_pause(&config, &windows, false, &at(347)), 180.0);
+        assert_eq!(tuned_pause(&config, &windows, false, &at(350)), 30.0);
+        assert_eq!(tuned_pause(&config, &windows, false, &at(390)), 30.0);
+        assert_eq!(tuned_pause(&config, &windows, false, &at(400)), 600.0);
+        assert_eq!(tuned_pause(&config, &windows, true, &at(400)), 30.0);
+    }
+}
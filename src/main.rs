@@ -1,22 +1,110 @@
-use crate::configuration::Configuration;
-use crate::homebridge::Homebridge;
+use crate::actions::{Actions, ACTION_SOURCE};
+use crate::audit_log::{AuditLog, AuditQuery};
+use crate::backup::Archive;
+use crate::calibration::SunsetCalibration;
+use crate::characteristic::Characteristic;
+use crate::configuration::{
+    Configuration, DarkHoursConfig, GroupWriteConfig, LatencyConfig, LoopPauseConfig,
+};
+use crate::control::{
+    BrightnessCurve, BrightnessRequest, ControllerState, CurvePoint, Nudge, ProgramSchedule,
+    SharedState, BRIGHTNESS_SOURCE, NUDGE_SOURCE,
+};
+use crate::decisions::{Decision, Outcome};
+use crate::effects::TemporaryEffects;
+use crate::events::EventBus;
+use crate::expression::Expression;
+use crate::homebridge::{
+    AccessoryCache, ControlHours, HBError, Homebridge, OnBrightness, UNIQUE_ID_PREFIX,
+};
+use crate::latency::LatencyTracker;
+use crate::media::MediaPause;
+use crate::overlaps::ProgramWindow;
+use crate::poller::PolledStates;
+use crate::presence::Presence;
 use crate::programs::control_evening_lights::ControlEveningLightsProgram;
-use crate::programs::turn_morning_lights_off::TurnMorningLightsOffProgram;
-use crate::suntimes::SunTimes;
-use clap::Parser;
-use log::{error, info};
+use crate::programs::morning_light::{self, MorningLightProgram};
+use crate::programs::{within, Fork, Program, ProgramId, ProgramRegistry};
+use crate::run_time::RunTimeLimits;
+use crate::schedule_preview::SchedulePreview;
+use crate::sensors::VirtualSensors;
+use crate::state::StateStore;
+use crate::suntimes::{SunDay, SunTimes, SuntimesFreshness};
+use crate::tolerance::ToleranceTuner;
+use crate::update_check::UpdateCheck;
+use crate::usage_ping::UsagePing;
+use crate::weather::Weather;
+use crate::webhooks::Webhooks;
+use crate::write_queue::WriteQueue;
+use chrono::{DateTime, Local, NaiveDate};
+use clap::{Args, Parser, Subcommand};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::env::VarError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::time::Duration;
-use std::{env, fs};
-use tokio::time::sleep;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
 
+pub mod actions;
+pub mod api;
+pub mod audit;
+pub mod audit_log;
+pub mod backoff;
+pub mod backup;
+pub mod bench;
+pub mod bridge_schema;
+pub mod calibration;
+pub mod characteristic;
+pub mod clock;
 pub mod configuration;
+pub mod control;
+pub mod crash_report;
+pub mod decisions;
+pub mod defaults;
+#[cfg(feature = "desktop")]
+pub mod desktop;
+pub mod discovery;
+pub mod effects;
+pub mod events;
+pub mod expression;
+pub mod fuzzy;
+#[cfg(feature = "gpio")]
+pub mod gpio;
 pub mod homebridge;
+pub mod hysteresis;
+pub mod latency;
+pub mod logging;
+pub mod loop_pause;
+pub mod maintenance;
+pub mod media;
+pub mod metrics;
+pub mod overlaps;
+pub mod override_detector;
+pub mod poller;
+pub mod presence;
 pub mod programs;
+pub mod retention;
+pub mod run_time;
+pub mod schedule_preview;
+pub mod script;
+pub mod sensors;
+pub mod smoothing;
+pub mod state;
 pub mod suntimes;
+pub mod telegram;
+pub mod time_format;
+pub mod time_window;
+pub mod tolerance;
+pub mod toml;
+pub mod update_check;
+pub mod usage_ping;
+pub mod validate;
+pub mod weather;
+pub mod webhooks;
+pub mod write_queue;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Secrets {
@@ -28,93 +116,2023 @@ impl Secrets {
     fn from_env() -> Result<Self, VarError> {
         let username = env::var("HB_USER")?;
         let password = env::var("HB_PASSWORD")?;
-        return Ok(Self { username, password });
+        Ok(Self { username, password })
     }
 }
 
 /// Automated programs controlling Homebridge accessories.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Arguments {
-    /// Configuration file.
-    config: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+    #[arg(required_unless_present = "dump_defaults")]
+    config: Option<PathBuf>,
+    /// Write the default log configuration and example configuration into this directory.
+    #[arg(long, value_name = "DIR", conflicts_with = "config")]
+    dump_defaults: Option<PathBuf>,
+    /// Run the clock this many times faster than real time (for soak tests).
+    #[arg(long, hide = true)]
+    accelerate: Option<u32>,
+    /// Run all due programs once and exit (for systemd timers or cron).
+    #[arg(long)]
+    once: bool,
 }
 
-#[tokio::main]
-async fn main() -> ExitCode {
-    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the characteristics of an accessory.
+    Describe {
+        /// Service name of the accessory.
+        #[arg(long)]
+        accessory: String,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// List the bridge's accessories.
+    ListAccessories {
+        /// Only list accessories whose name contains this or is close to it.
+        #[arg(long)]
+        filter: Option<String>,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// Show how a program would decide at a given time, without touching accessories.
+    Explain {
+        /// Program name, e.g. `evening_lights`.
+        program: String,
+        /// Time to explain, e.g. "2024-12-01T17:45" (default: now).
+        #[arg(long, value_parser = time_format::parse_local)]
+        at: Option<DateTime<Local>>,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// Bundle the configuration, state file, and log configuration into one archive.
+    Backup {
+        /// Archive file to write.
+        #[arg(long)]
+        output: PathBuf,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// List the controller's writes from the audit log, e.g. `--last 12h` for last night.
+    Audit {
+        /// Only writes at or after this time, e.g. "2024-12-01T17:45".
+        #[arg(long, value_parser = time_format::parse_local, conflicts_with = "last")]
+        since: Option<DateTime<Local>>,
+        /// Only writes within this long before now, e.g. "12h".
+        #[arg(long, value_parser = humantime::parse_duration)]
+        last: Option<Duration>,
+        /// Only writes at or before this time.
+        #[arg(long, value_parser = time_format::parse_local)]
+        until: Option<DateTime<Local>>,
+        /// Only writes of this program, e.g. `evening_lights`.
+        #[arg(long)]
+        program: Option<String>,
+        /// Only writes to this accessory.
+        #[arg(long)]
+        accessory: Option<String>,
+        /// Only writes of this characteristic, e.g. `Brightness`.
+        #[arg(long)]
+        characteristic: Option<String>,
+        /// Print the writes as JSON lines instead of text.
+        #[arg(long)]
+        json: bool,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// Remove rolled log files beyond the configured retention.
+    Prune {
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// Write the files of a backup archive back to where they were read from.
+    Restore {
+        /// Archive written by `backup`.
+        archive: PathBuf,
+        /// Overwrite files that already exist.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check a configuration without starting the controller.
+    Validate {
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// Run a single pass of one program and exit.
+    RunOnce {
+        /// Program name, e.g. `evening_lights`, or one instance, e.g. `evening_lights:office`.
+        #[arg(long)]
+        program: String,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+    /// Measure request throughput and latency against a mock bridge, or the configured one.
+    #[command(hide = true)]
+    BenchBridge(BenchBridgeArgs),
+    /// Run a configured toggle or cycle action once.
+    Set {
+        /// Name of the action.
+        #[arg(long)]
+        action: String,
+        /// Configuration file, or a directory of JSON/YAML/TOML files to merge.
+        config: PathBuf,
+    },
+}
 
-    let args = Arguments::parse();
-    info!("Parsed CLI arguments.");
+#[derive(Args, Debug)]
+struct BenchBridgeArgs {
+    /// Requests per scenario.
+    #[arg(long, default_value_t = 200)]
+    requests: usize,
+    /// Clients reading at the same time in the concurrent scenario.
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+    /// Lights of the mock bridge.
+    #[arg(long, default_value_t = 8)]
+    mock_lights: usize,
+    /// Response time of the mock bridge in milliseconds.
+    #[arg(long, default_value_t = 0)]
+    mock_latency_ms: u64,
+    /// Spacing of requests to the same accessory in milliseconds (default: as configured).
+    #[arg(long)]
+    min_spacing_ms: Option<u64>,
+    /// Pause between the accessories of a group write in milliseconds (default: as configured).
+    #[arg(long)]
+    group_spacing_ms: Option<u64>,
+    /// Accessory of the configured bridge to use instead of the mock bridge; its `On` value is
+    /// written back unchanged.
+    #[arg(long, requires = "config")]
+    accessory: Vec<String>,
+    /// Configuration file of the bridge, with `--accessory`.
+    #[arg(requires = "accessory")]
+    config: Option<PathBuf>,
+}
 
-    // Configuration.
-    let config_file = fs::File::open(args.config).unwrap();
-    let config: Configuration = serde_json::from_reader(config_file).unwrap();
-    info!("Config:\n{:?}", config);
+/// Read the configuration and initialize logging.
+fn setup(config_path: &Path) -> Result<Configuration, ExitCode> {
+    let config = match configuration::load(config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            // Logging is configured by the configuration, so it is not available yet.
+            eprintln!("Error reading configuration: {}", e);
+            return Err(ExitCode::from(4));
+        }
+    };
+
+    // Logging (configured in the config file or in "log4rs.yaml").
+    time_format::init(&config.time_format);
+    logging::init(config.logging.as_ref(), Path::new("log4rs.yaml")).unwrap();
+    for deprecation in config.deprecations.iter() {
+        warn!("Outdated configuration: {}.", deprecation);
+    }
+    Ok(config)
+}
 
+/// Create the HTTP and Homebridge clients and check the connection.
+///
+/// With a non-empty accessory cache, an unreachable bridge is not fatal.
+async fn connect(
+    config: &Configuration,
+    accessory_cache: Option<AccessoryCache>,
+) -> Result<(reqwest::Client, Homebridge), ExitCode> {
     // Secrets.
-    // let secrets_file = fs::File::open(args.secrets).unwrap();
-    // let secrets: Secrets = serde_json::from_reader(secrets_file).unwrap();
     let secrets = match Secrets::from_env() {
         Ok(s) => s,
         Err(e) => {
             error!("Error getting Homebridge auth values: {}.", e);
-            return ExitCode::from(4);
+            crash_report::report(&e);
+            return Err(ExitCode::from(4));
         }
     };
 
-    // Create `reqwest` client.
-    let client = reqwest::Client::new();
+    // Create `reqwest` clients; only the bridge's may accept an invalid certificate.
+    let client = reqwest::Client::builder().build().and_then(|client| {
+        let bridge_client = match config.bridge.accept_invalid_certs {
+            true => Some(
+                reqwest::Client::builder()
+                    .danger_accept_invalid_certs(true)
+                    .build()?,
+            ),
+            false => None,
+        };
+        Ok((client, bridge_client))
+    });
+    let (client, bridge_client) = match client {
+        Ok(clients) => clients,
+        Err(e) => {
+            error!("Could not create HTTP client: {}", e);
+            crash_report::report(&e);
+            return Err(ExitCode::from(4));
+        }
+    };
 
     // Create Homebridge client.
-    let mut homebridge = Homebridge::new(&config.ip_address, &secrets.username, &secrets.password);
+    let base_url = config.bridge.base_url();
+    debug!("Homebridge UI at {}.", base_url);
+    let mut homebridge = Homebridge::new(&base_url, &secrets.username, &secrets.password);
+    homebridge.bridge_client = bridge_client;
+    homebridge
+        .latency
+        .replace(LatencyTracker::from_config(&config.latency));
+    homebridge
+        .write_queue
+        .replace(WriteQueue::from_config(&config.group_writes));
+    homebridge
+        .tolerances
+        .replace(ToleranceTuner::from_config(&config.adaptive_tolerance));
+    homebridge
+        .run_times
+        .replace(RunTimeLimits::from_config(&config.accessories));
+    homebridge.turn_on_sequences = config
+        .accessories
+        .iter()
+        .map(|(name, a)| (name.clone(), a.turn_on_sequence))
+        .collect();
+    homebridge.on_brightness = config
+        .accessories
+        .iter()
+        .map(|(name, a)| {
+            let setting = OnBrightness {
+                restore: a.restore_brightness,
+                brightness: a.on_brightness,
+            };
+            (name.clone(), setting)
+        })
+        .collect();
+    homebridge.control_hours = config
+        .accessories
+        .iter()
+        .filter(|(_, a)| a.earliest_control_time.is_some() || a.latest_control_time.is_some())
+        .map(|(name, a)| {
+            let hours = ControlHours {
+                earliest: a.earliest_control_time,
+                latest: a.latest_control_time,
+            };
+            (name.clone(), hours)
+        })
+        .collect();
+    // Virtual sensors are known before their first report, just without values.
+    for sensor in config.virtual_sensors.keys() {
+        homebridge.update_virtual_sensor(sensor, &serde_json::Map::new());
+    }
+    if let Some(audit_log) = &config.audit_log {
+        match AuditLog::open(audit_log, &clock::now()) {
+            Ok(log) => homebridge.journal.lock().add_sink(Box::new(log)),
+            Err(e) => {
+                error!("Could not open the audit log: {}", e);
+                return Err(ExitCode::from(4));
+            }
+        }
+    }
+    if config.desktop_notifications {
+        #[cfg(feature = "desktop")]
+        homebridge
+            .journal
+            .lock()
+            .add_sink(Box::new(desktop::DesktopNotifier::default()));
+        #[cfg(not(feature = "desktop"))]
+        warn!("`desktop_notifications` requires building with the `desktop` feature.");
+    }
+    let cached = accessory_cache.map_or(0, |cache| {
+        let cached = cache.accessories.len();
+        homebridge.restore_accessories(cache);
+        cached
+    });
     match homebridge.check_connection(&client).await {
         Ok(()) => info!("Test Homebridge connection successful."),
+        Err(e) if cached > 0 => warn!(
+            "Could not connect to Homebridge, starting with {} cached accessories: {}",
+            cached, e
+        ),
         Err(e) => {
             error!("Could not connect to Homebridge: {}", e);
+            crash_report::report(&e);
+            return Err(ExitCode::from(4));
+        }
+    };
+    Ok((client, homebridge))
+}
+
+/// Add a decision to a program's history.
+fn record_decision(state: &SharedState, program: &str, decision: Decision) {
+    state
+        .lock()
+        .expect("State lock poisoned.")
+        .decisions
+        .record(program, decision);
+}
+
+/// Print what the programs of a single pass decided, failing if any of them failed.
+fn report_single_pass(state: &SharedState, programs: &ProgramRegistry) -> ExitCode {
+    let state = state.lock().expect("State lock poisoned.");
+    let mut failed = false;
+    for name in programs.names() {
+        let Some(decision) = state.decisions.latest(name) else {
+            continue;
+        };
+        println!(
+            "{}: {} - {}",
+            name,
+            format!("{:?}", decision.outcome).to_lowercase(),
+            decision.reason
+        );
+        failed |= decision.outcome == Outcome::Failed;
+    }
+    match failed {
+        true => ExitCode::from(4),
+        false => ExitCode::SUCCESS,
+    }
+}
+
+/// Turn off the switches the controller left on for longer than their maximum run time, and
+/// note the runs that ended in time.
+async fn enforce_run_times(
+    client: &reqwest::Client,
+    homebridge: &mut Homebridge,
+    state: &SharedState,
+    desktop_notifications: bool,
+) {
+    let minutes = |d: chrono::Duration| {
+        humantime::format_duration(Duration::from_secs(60 * d.num_minutes().max(0) as u64))
+    };
+    let overdue = homebridge.run_times.lock().overdue(&clock::now());
+    for (accessory, on_for, limit) in overdue {
+        let (on_for, limit) = (minutes(on_for), minutes(limit));
+        // The write would be skipped without an error, so it is not reported as done.
+        let skipped = match homebridge.observe_only {
+            true => Some("observing only".to_string()),
+            false => homebridge
+                .maintenance_of(&accessory)
+                .map(|entry| entry.to_string()),
+        };
+        if let Some(reason) = skipped {
+            let message = format!(
+                "Left '{}' on after {} on, more than its maximum of {} (skipped: {}).",
+                accessory, on_for, limit, reason
+            );
+            warn!("{}", message);
+            record_decision(state, run_time::SOURCE, Decision::skipped(message));
+            continue;
+        }
+        let message = match homebridge
+            .accessory(&accessory)
+            .set(client, run_time::SOURCE, &Characteristic::On, false)
+            .await
+        {
+            Ok(()) => format!(
+                "Turned off '{}' after {} on, more than its maximum of {}.",
+                accessory, on_for, limit
+            ),
+            Err(e) => format!(
+                "Failed to turn off '{}' after {} on, more than its maximum of {}: {}",
+                accessory, on_for, limit, e
+            ),
+        };
+        error!("{}", message);
+        if desktop_notifications {
+            #[cfg(feature = "desktop")]
+            if let Err(e) = desktop::DesktopNotifier::notify("Homebridge controller", &message) {
+                warn!("Failed to show desktop notification: {}", e);
+            }
+        }
+        record_decision(state, run_time::SOURCE, Decision::failed(message));
+    }
+    let ended = homebridge.run_times.lock().take_ended();
+    for (accessory, on_for, in_time) in ended {
+        if in_time {
+            let reason = format!("'{}' was off again after {}", accessory, minutes(on_for));
+            record_decision(state, run_time::SOURCE, Decision::ran(reason));
+        }
+    }
+}
+
+/// Log a program's result and add it to its decision history.
+fn record_result(
+    state: &SharedState,
+    program: &str,
+    result: anyhow::Result<Decision>,
+    suntimes: Option<SuntimesFreshness>,
+) {
+    let mut decision = match result {
+        Ok(decision) => {
+            info!("Successfully executed {}: {}.", program, decision.reason);
+            decision
+        }
+        // Neither a failure nor a run: the program acts once the grace period is over.
+        Err(e) if homebridge::observing_only(&e) => {
+            info!("Observing only - {} did not write.", program);
+            Decision::skipped("Observing only - writes are held after the restart")
+        }
+        Err(e) => {
+            error!("Error running {}: {}", program, e);
+            Decision::failed(e.to_string())
+        }
+    };
+    decision.suntimes = suntimes;
+    state.lock().expect("State lock poisoned.").backoff.record(
+        program,
+        !matches!(decision.outcome, Outcome::Failed),
+        &decision.when,
+    );
+    record_decision(state, program, decision);
+}
+
+/// Run a program on its copy of the context unless something holds it back, recording the
+/// result.
+async fn run_program(
+    client: &reqwest::Client,
+    state: &SharedState,
+    program: &mut dyn Program,
+    fork: &mut Fork,
+    limit: Duration,
+) {
+    let name = &program.name().to_string();
+    if not_paused(state, name)
+        && not_backing_off(state, name)
+        && conditions_met(state, &fork.events, name, program.requires())
+        && dark_enough(
+            client,
+            &mut fork.suntimes,
+            state,
+            name,
+            program.only_when_dark(),
+        )
+        .await
+        && condition_holds(
+            client,
+            &mut fork.homebridge,
+            state,
+            name,
+            program.condition(),
+        )
+        .await
+    {
+        let result = within(limit, program.run(fork.context(client, state))).await;
+        record_result(state, name, result, fork.suntimes.take_used());
+    }
+}
+
+/// Whether a program may run, recording if it is backing off after repeated failures.
+fn not_backing_off(state: &SharedState, program: &str) -> bool {
+    let retry_at = state
+        .lock()
+        .expect("State lock poisoned.")
+        .backoff
+        .retry_at(program, &clock::now());
+    if let Some(retry_at) = retry_at {
+        let reason = format!(
+            "Backing off after repeated failures until {}",
+            time_format::show(&retry_at)
+        );
+        info!("Skipping {} - {}.", program, reason);
+        record_decision(state, program, Decision::skipped(reason));
+    }
+    retry_at.is_none()
+}
+
+/// Whether a program is not paused by hand, recording a skip if it is.
+fn not_paused(state: &SharedState, program: &str) -> bool {
+    let until = state
+        .lock()
+        .expect("State lock poisoned.")
+        .paused_until(program, &clock::now());
+    if let Some(until) = until {
+        let reason = format!("Paused until {}", time_format::show(&until));
+        info!("Skipping {} - {}.", program, reason);
+        record_decision(state, program, Decision::skipped(reason));
+    }
+    until.is_none()
+}
+
+/// Whether all conditions a program requires are set, logging any that are missing.
+fn conditions_met(
+    state: &SharedState,
+    events: &EventBus,
+    program: &str,
+    requires: &[String],
+) -> bool {
+    let missing = events.missing(requires);
+    if !missing.is_empty() {
+        let reason = format!("Waiting on conditions: {}", missing.join(", "));
+        info!("Skipping {} - {}.", program, reason);
+        record_decision(state, program, Decision::skipped(reason));
+    }
+    missing.is_empty()
+}
+
+/// Whether a program's condition expression holds, recording why not.
+async fn condition_holds(
+    client: &reqwest::Client,
+    homebridge: &mut Homebridge,
+    state: &SharedState,
+    program: &str,
+    condition: Option<&Expression>,
+) -> bool {
+    let Some(condition) = condition else {
+        return true;
+    };
+    let reason = match condition.holds(client, homebridge).await {
+        Ok(true) => return true,
+        Ok(false) => {
+            info!("Skipping {} - its condition does not hold.", program);
+            "Condition does not hold".to_string()
+        }
+        Err(e) => {
+            warn!(
+                "Skipping {} - could not evaluate its condition: {}",
+                program, e
+            );
+            format!("Could not evaluate the condition: {}", e)
+        }
+    };
+    record_decision(state, program, Decision::skipped(reason));
+    false
+}
+
+/// Whether a lighting program may run now under its `only_when_dark` constraint.
+async fn dark_enough(
+    client: &reqwest::Client,
+    suntimes: &mut SunTimes,
+    state: &SharedState,
+    program: &str,
+    hours: Option<&DarkHoursConfig>,
+) -> bool {
+    let Some(hours) = hours else {
+        return true;
+    };
+    let reason = match suntimes.is_dark(client, &clock::now(), hours).await {
+        Ok(true) => return true,
+        Ok(false) => {
+            info!("Skipping {} - it only runs when dark.", program);
+            "Only runs when dark".to_string()
+        }
+        Err(e) => {
+            warn!("Skipping {} - could not tell if it is dark: {}", program, e);
+            format!("Could not tell if it is dark: {}", e)
+        }
+    };
+    record_decision(state, program, Decision::skipped(reason));
+    false
+}
+
+/// Change a light's brightness by the nudge, returning the applied change.
+async fn apply_nudge(
+    client: &reqwest::Client,
+    homebridge: &mut Homebridge,
+    nudge: &Nudge,
+) -> Result<i32, HBError> {
+    let current = homebridge
+        .get_light_status(client, &nudge.accessory)
+        .await?
+        .values
+        .brightness() as i32;
+    let brightness = (current + nudge.delta).clamp(1, 100);
+    homebridge
+        .set_light_brightness(client, NUDGE_SOURCE, &nudge.accessory, brightness as u8)
+        .await?;
+    Ok(brightness - current)
+}
+
+/// Set a light's brightness (off at 0), returning the change from its previous brightness.
+async fn apply_brightness(
+    client: &reqwest::Client,
+    homebridge: &mut Homebridge,
+    request: &BrightnessRequest,
+) -> Result<i32, HBError> {
+    let current = homebridge
+        .get_light_status(client, &request.accessory)
+        .await?
+        .values
+        .brightness() as i32;
+    match request.brightness {
+        0 => {
+            homebridge
+                .set_light_on(client, BRIGHTNESS_SOURCE, &request.accessory, false)
+                .await?
+        }
+        brightness => {
+            homebridge
+                .turn_light_on_at(client, BRIGHTNESS_SOURCE, &request.accessory, brightness)
+                .await?
+        }
+    }
+    Ok(request.brightness as i32 - current)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Arguments::parse();
+    if let Some(dir) = &args.dump_defaults {
+        return dump_defaults(dir);
+    }
+    match args.command {
+        Some(Command::Describe { accessory, config }) => describe(&config, &accessory).await,
+        Some(Command::Explain {
+            program,
+            at,
+            config,
+        }) => explain(&config, &program, at).await,
+        Some(Command::ListAccessories { filter, config }) => {
+            list_accessories(&config, filter.as_deref()).await
+        }
+        Some(Command::Set { action, config }) => set(&config, &action).await,
+        Some(Command::Backup { output, config }) => backup(&config, &output),
+        Some(Command::Restore { archive, force }) => restore(&archive, force),
+        Some(Command::Audit {
+            since,
+            last,
+            until,
+            program,
+            accessory,
+            characteristic,
+            json,
+            config,
+        }) => {
+            let since = match last.map(chrono::Duration::from_std).transpose() {
+                Ok(last) => last.map(|last| clock::now() - last).or(since),
+                Err(e) => {
+                    eprintln!("Duration out of range: {}", e);
+                    return ExitCode::from(4);
+                }
+            };
+            let query = AuditQuery {
+                since,
+                until,
+                program,
+                accessory,
+                characteristic,
+            };
+            audit(&config, &query, json)
+        }
+        Some(Command::Prune { config }) => prune(&config),
+        Some(Command::Validate { config }) => validate(&config),
+        Some(Command::BenchBridge(args)) => bench_bridge(args).await,
+        Some(Command::RunOnce { program, config }) => run(&config, true, Some(&program)).await,
+        None => {
+            if let Some(factor) = args.accelerate {
+                clock::accelerate(factor);
+            }
+            run(
+                &args.config.expect("Configuration file is required."),
+                args.once,
+                None,
+            )
+            .await
+        }
+    }
+}
+
+fn dump_defaults(dir: &Path) -> ExitCode {
+    match defaults::dump(dir) {
+        Ok(written) => {
+            for (name, _) in defaults::FILES {
+                let path = dir.join(name);
+                match written.contains(&path) {
+                    true => println!("Wrote {}", path.display()),
+                    false => println!("Kept existing {}", path.display()),
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error writing defaults to {}: {}", dir.display(), e);
+            ExitCode::from(4)
+        }
+    }
+}
+
+fn backup(config_path: &Path, output: &Path) -> ExitCode {
+    let files = configuration::files(config_path).and_then(|files| {
+        let config = configuration::load(config_path)?;
+        Ok([files, vec![config.state_file, PathBuf::from("log4rs.yaml")]].concat())
+    });
+    let files = match files {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error reading configuration: {}", e);
             return ExitCode::from(4);
         }
     };
+    match Archive::collect(&files, Local::now()).and_then(|a| a.write(output).map(|_| a)) {
+        Ok(archive) => {
+            for path in archive.files.keys() {
+                println!("Backed up {}", path.display());
+            }
+            println!("Wrote {}", output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error writing backup: {}", e);
+            ExitCode::from(4)
+        }
+    }
+}
 
-    // Create programs.
-    let mut lights_off_prog =
-        match TurnMorningLightsOffProgram::new(&config.turn_morning_lights_off) {
-            Ok(p) => p,
-            Err(e) => {
-                error!("{}", e);
-                return ExitCode::from(4);
+fn restore(archive: &Path, force: bool) -> ExitCode {
+    match Archive::read(archive).and_then(|a| a.restore(force)) {
+        Ok(restored) => {
+            for path in restored {
+                println!("Restored {}", path.display());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error restoring {}: {}", archive.display(), e);
+            ExitCode::from(4)
+        }
+    }
+}
+
+fn audit(config_path: &Path, query: &AuditQuery, json: bool) -> ExitCode {
+    let audit_log = match configuration::load(config_path) {
+        Ok(config) => config.audit_log,
+        Err(e) => {
+            eprintln!("Error reading configuration: {}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let Some(audit_log) = audit_log else {
+        eprintln!("No `audit_log` in the configuration - no writes were recorded.");
+        return ExitCode::from(4);
+    };
+    let records = match audit_log::query(&audit_log.path, query) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Error reading the audit log: {}", e);
+            return ExitCode::from(4);
+        }
+    };
+    for record in records.iter() {
+        match json {
+            true => println!("{}", serde_json::to_string(record).unwrap_or_default()),
+            false => println!("{}", audit_log::describe(record)),
+        }
+    }
+    if !json {
+        println!("{} write(s).", records.len());
+    }
+    ExitCode::SUCCESS
+}
+
+fn prune(config_path: &Path) -> ExitCode {
+    let retention = match configuration::load(config_path) {
+        Ok(config) => config.retention,
+        Err(e) => {
+            eprintln!("Error reading configuration: {}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let Some(retention) = retention else {
+        eprintln!("No `retention` in the configuration - nothing to prune.");
+        return ExitCode::from(4);
+    };
+    match retention::prune(&retention, SystemTime::now()) {
+        Ok(removed) => {
+            for path in removed.iter() {
+                println!("Removed {}", path.display());
             }
+            println!("Pruned {} file(s).", removed.len());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error pruning: {}", e);
+            ExitCode::from(4)
+        }
+    }
+}
+
+fn validate(config_path: &Path) -> ExitCode {
+    let config = match configuration::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let report = validate::check(&config, clock::now().date_naive());
+    for warning in report.warnings.iter() {
+        println!("Warning: {}", warning);
+    }
+    for error in report.errors.iter() {
+        println!("Error: {}", error);
+    }
+    match report.errors.is_empty() {
+        true => {
+            println!("{} is valid.", config_path.display());
+            ExitCode::SUCCESS
+        }
+        false => {
+            println!(
+                "{} error(s) in {}.",
+                report.errors.len(),
+                config_path.display()
+            );
+            ExitCode::from(4)
+        }
+    }
+}
+
+async fn bench_bridge(args: BenchBridgeArgs) -> ExitCode {
+    let (client, mut homebridge, accessories, latency, group_writes) = match &args.config {
+        Some(config_path) => {
+            let config = match setup(config_path) {
+                Ok(c) => c,
+                Err(code) => return code,
+            };
+            let (client, homebridge) = match connect(&config, None).await {
+                Ok(c) => c,
+                Err(code) => return code,
+            };
+            let accessories = args.accessory.clone();
+            (
+                client,
+                homebridge,
+                accessories,
+                config.latency,
+                config.group_writes,
+            )
+        }
+        None => {
+            let latency = Duration::from_millis(args.mock_latency_ms);
+            let (url, lights) = match bench::mock_bridge(args.mock_lights, latency) {
+                Ok(mock) => mock,
+                Err(e) => {
+                    eprintln!("Could not start the mock bridge: {}", e);
+                    return ExitCode::from(4);
+                }
+            };
+            let homebridge = Homebridge::new(&url, "bench", "bench");
+            let defaults = (LatencyConfig::default(), GroupWriteConfig::default());
+            (
+                reqwest::Client::new(),
+                homebridge,
+                lights,
+                defaults.0,
+                defaults.1,
+            )
+        }
+    };
+    let latency = LatencyConfig {
+        min_spacing_ms: args.min_spacing_ms.unwrap_or(latency.min_spacing_ms),
+        ..latency
+    };
+    let group_writes = GroupWriteConfig {
+        spacing_ms: args.group_spacing_ms.unwrap_or(group_writes.spacing_ms),
+        ..group_writes
+    };
+    homebridge
+        .latency
+        .replace(LatencyTracker::from_config(&latency));
+    homebridge
+        .write_queue
+        .replace(WriteQueue::from_config(&group_writes));
+    println!(
+        "{} accessory(s), spacing {} ms per accessory, {} ms in group writes",
+        accessories.len(),
+        latency.min_spacing_ms,
+        group_writes.spacing_ms
+    );
+    let summaries = bench::run(
+        &client,
+        &mut homebridge,
+        &accessories,
+        args.requests,
+        args.concurrency,
+        &latency,
+    )
+    .await;
+    if summaries.is_empty() {
+        eprintln!("None of the accessories could be read.");
+        return ExitCode::from(4);
+    }
+    println!("{}", bench::HEADER);
+    for summary in summaries.iter() {
+        println!("{}", summary);
+    }
+    ExitCode::SUCCESS
+}
+
+async fn describe(config_path: &Path, accessory: &str) -> ExitCode {
+    let config = match setup(config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let (client, mut homebridge) = match connect(&config, None).await {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    match homebridge.get_accessory_details(&client, accessory).await {
+        Ok(details) => {
+            print!("{}", details);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Could not describe '{}': {}", accessory, e);
+            ExitCode::from(4)
+        }
+    }
+}
+
+async fn list_accessories(config_path: &Path, filter: Option<&str>) -> ExitCode {
+    let config = match setup(config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let (client, mut homebridge) = match connect(&config, None).await {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let accessories = match homebridge.list_accessories(&client).await {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Could not list accessories: {}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let mut names: Vec<&str> = accessories
+        .values()
+        .map(|a| a.service_name.as_str())
+        .collect();
+    match filter {
+        Some(filter) => names = fuzzy::search(filter, names),
+        None => names.sort(),
+    }
+    names.dedup();
+    for name in names {
+        for (unique_id, accessory) in accessories.iter().filter(|(_, a)| a.service_name == name) {
+            println!(
+                "{}\t{}{}\t{}",
+                accessory.service_name,
+                UNIQUE_ID_PREFIX,
+                unique_id,
+                accessory.characteristics.join(", ")
+            );
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+async fn set(config_path: &Path, action: &str) -> ExitCode {
+    let config = match setup(config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let actions = match Actions::new(&config.scenes, &config.actions) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let mut store = match StateStore::load(&config.state_file) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error loading state file: {}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let (client, mut homebridge) = match connect(&config, None).await {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let mut positions = store.state().cycle_positions.clone();
+    let result = actions
+        .run(&client, &mut homebridge, action, &mut positions)
+        .await;
+    // A partly applied scene still moves the cycle on.
+    if let Err(e) = store.update(|s| s.cycle_positions = positions) {
+        error!("Could not save the cycle position: {}", e);
+        return ExitCode::from(4);
+    }
+    match result {
+        Ok(done) => println!("{}", done),
+        Err(e) => {
+            error!("Could not run action '{}': {}", action, e);
+            return ExitCode::from(4);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+async fn explain(config_path: &Path, program: &str, at: Option<DateTime<Local>>) -> ExitCode {
+    // Programs read the time from the clock, so they see the requested moment.
+    clock::freeze(at.unwrap_or_else(Local::now));
+    let config = match setup(config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    // Instances of a program are named `<program>:<instance>`.
+    let Some(id) = ProgramId::from_name(program.split(':').next().unwrap_or(program)) else {
+        error!("Unknown program '{}'.", program);
+        return ExitCode::from(4);
+    };
+    let programs = match ProgramRegistry::from_config(&config) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(4);
+        }
+    };
+    let found = match program.contains(':') {
+        true => programs.get_named(program),
+        false => programs.get(id),
+    };
+    let Some(program) = found else {
+        error!("'{}' is not configured.", program);
+        return ExitCode::from(4);
+    };
+    let client = reqwest::Client::new();
+    // Without the state store, nothing is persisted for the hypothetical time.
+    let mut suntimes = SunTimes::new(config.longitude, config.latitude)
+        .with_stale_after(config.suntimes_stale_after_days)
+        .with_source(config.suntimes_source, config.suntimes_cross_check_minutes);
+
+    let Some(trace) = program.explain(&client, &mut suntimes).await else {
+        error!("No explanation available for '{}'.", program.name());
+        return ExitCode::from(4);
+    };
+
+    println!("{} at {}", program.name(), time_format::show(&clock::now()));
+    if !program.requires().is_empty() {
+        println!(
+            "- Requires conditions (set at runtime): {}",
+            program.requires().join(", ")
+        );
+    }
+    if let Some(hours) = program.only_when_dark() {
+        match suntimes.is_dark(&client, &clock::now(), hours).await {
+            Ok(true) => println!("- Dark: yes"),
+            Ok(false) => println!("- Dark: no - only runs when dark"),
+            Err(e) => println!("- Dark: could not tell ({})", e),
+        }
+    }
+    if let Some(condition) = program.condition() {
+        println!(
+            "- Condition (checked against live values at runtime): {}",
+            condition
+        );
+    }
+    match trace {
+        Ok(trace) => {
+            for line in trace {
+                println!("- {}", line);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Could not explain '{}': {}", program.name(), e);
+            ExitCode::from(4)
+        }
+    }
+}
+
+/// The evening lights instance with the sunset calibration.
+fn calibrated(programs: &mut ProgramRegistry) -> Option<&mut ControlEveningLightsProgram> {
+    programs
+        .instances_mut::<ControlEveningLightsProgram>()
+        .find(|p| p.calibrated)
+}
+
+/// Learning of the evening start from manual switch-ons, if configured.
+fn sunset_calibration(
+    config: &Configuration,
+    state: &SharedState,
+    programs: &mut ProgramRegistry,
+) -> Option<SunsetCalibration> {
+    let calibration = config
+        .control_evening_lights
+        .iter()
+        .find_map(|c| c.calibration.as_ref())
+        .map(|c| SunsetCalibration::new(c, state.clone()));
+    if let (Some(calibration), Some(evening_lights)) = (&calibration, calibrated(programs)) {
+        calibration.restore(evening_lights);
+    }
+    calibration
+}
+
+/// Read the configuration again and rebuild the programs whose sections changed.
+///
+/// Other programs keep their in-memory state. Returns the rebuilt programs.
+fn reload(
+    config_path: &Path,
+    config: &mut Configuration,
+    programs: &mut ProgramRegistry,
+) -> Vec<ProgramId> {
+    let mut new_config = match configuration::load(config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Not reloading - error reading configuration: {}", e);
+            return Vec::new();
+        }
+    };
+    let diff = config.diff(&new_config);
+    if diff.is_empty() {
+        info!("Configuration unchanged.");
+        return Vec::new();
+    }
+    if !diff.other.is_empty() {
+        warn!(
+            "Changes to {} take effect after a restart.",
+            diff.other.join(", ")
+        );
+    }
+    let mut rebuilt = Vec::new();
+    for id in diff.programs {
+        if let Err(e) = programs.rebuild(id, &new_config) {
+            error!("Keeping the previous {} configuration: {}", id, e);
+            continue;
+        }
+        match id {
+            ProgramId::MorningLight => {
+                std::mem::swap(&mut config.morning_light, &mut new_config.morning_light)
+            }
+            ProgramId::TurnMorningLightsOff => std::mem::swap(
+                &mut config.turn_morning_lights_off,
+                &mut new_config.turn_morning_lights_off,
+            ),
+            ProgramId::ControlEveningLights => std::mem::swap(
+                &mut config.control_evening_lights,
+                &mut new_config.control_evening_lights,
+            ),
+            ProgramId::Irrigation => {
+                std::mem::swap(&mut config.irrigation, &mut new_config.irrigation)
+            }
+            ProgramId::ConditionActions => std::mem::swap(
+                &mut config.condition_actions,
+                &mut new_config.condition_actions,
+            ),
+            ProgramId::HttpPoll => {
+                std::mem::swap(&mut config.http_polls, &mut new_config.http_polls)
+            }
+            ProgramId::ColorShift => {
+                std::mem::swap(&mut config.color_shift, &mut new_config.color_shift)
+            }
+            ProgramId::Script => {
+                std::mem::swap(&mut config.scripts_dir, &mut new_config.scripts_dir)
+            }
+        }
+        info!("Rebuilt {} from the new configuration.", id);
+        rebuilt.push(id);
+    }
+    rebuilt
+}
+
+/// Exit code after stopping on SIGTERM or SIGINT, distinct from failures (4).
+const SHUTDOWN_EXIT_CODE: u8 = 3;
+
+/// Ask the program loop to stop on SIGTERM or SIGINT; a second signal exits right away.
+#[cfg(unix)]
+async fn watch_shutdown_signals(shutdown: Arc<Notify>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!(
+                "Cannot listen for SIGTERM and SIGINT, stopping interrupts the program loop: {}",
+                e
+            );
+            return;
+        }
+    };
+    let mut requested = false;
+    loop {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
         };
+        if requested {
+            warn!(
+                "Received {} again - exiting without finishing the program loop.",
+                name
+            );
+            std::process::exit(SHUTDOWN_EXIT_CODE.into());
+        }
+        requested = true;
+        info!(
+            "Received {} - shutting down after the current program loop.",
+            name
+        );
+        shutdown.notify_one();
+    }
+}
+
+/// Modification times of the configuration files, to notice edits.
+fn modification_times(config_path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    configuration::files(config_path)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|file| {
+            let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+            (file, modified)
+        })
+        .collect()
+}
 
-    let mut evening_lights_prog =
-        match ControlEveningLightsProgram::new(&config.control_evening_lights) {
-            Ok(p) => p,
+/// Request a configuration reload when the configuration files change.
+///
+/// Files are checked every `interval`, and reloaded once they stayed the same for one check, so
+/// a file is not read while an editor is still writing it.
+async fn watch_config_files(
+    config_path: PathBuf,
+    interval: Duration,
+    state: SharedState,
+    wake: Arc<Notify>,
+) {
+    let mut loaded = modification_times(&config_path);
+    let mut last = loaded.clone();
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = modification_times(&config_path);
+        if current == last && current != loaded {
+            info!("Configuration files changed - reloading the configuration.");
+            state.lock().expect("State lock poisoned.").reload_requested = true;
+            wake.notify_one();
+            loaded = current.clone();
+        }
+        last = current;
+    }
+}
+
+/// Request a configuration reload on SIGHUP.
+#[cfg(unix)]
+async fn watch_reload_signal(state: SharedState, wake: Arc<Notify>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, reloads are unavailable: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP - reloading the configuration.");
+        state.lock().expect("State lock poisoned.").reload_requested = true;
+        wake.notify_one();
+    }
+}
+
+/// One line per day with the sun times and the program times they result in.
+async fn week_schedule(
+    client: &reqwest::Client,
+    suntimes: &SunTimes,
+    programs: &ProgramRegistry,
+    days: &[NaiveDate],
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    for day in days {
+        let label = day.format("%a %Y-%m-%d");
+        let times = match suntimes.times_on(client, *day).await {
+            Ok(times) => times,
             Err(e) => {
-                error!("{}", e);
-                return ExitCode::from(4);
+                warn!("No sunrise/sunset times for {}: {}", day, e);
+                lines.push(format!("{}: no sunrise/sunset times", label));
+                continue;
             }
         };
+        let mut parts = vec![format!(
+            "sunrise {}, sunset {}",
+            times.sunrise.format("%H:%M"),
+            times.sunset.format("%H:%M")
+        )];
+        parts.extend(
+            programs
+                .iter()
+                .filter(|p| p.is_active())
+                .filter_map(|p| p.preview(&times)),
+        );
+        lines.push(format!("{}: {}", label, parts.join("; ")));
+    }
+    lines
+}
+
+/// Days ahead checked for programs controlling an accessory at the same time.
+const OVERLAP_CHECK_DAYS: usize = 7;
+
+/// Windows in which the programs may write their lights on a day with the given sun times.
+fn program_windows(programs: &ProgramRegistry, day: &SunDay) -> Vec<ProgramWindow> {
+    programs
+        .iter()
+        .filter(|p| p.is_active())
+        .flat_map(|p| p.windows(day))
+        .collect()
+}
+
+/// Spacing of the points of the evening curves shown on the dashboard.
+const CURVE_STEP_MINUTES: i64 = 5;
+
+/// Writes shown on the dashboard.
+const DASHBOARD_WRITES: usize = 20;
+
+/// Record the programs, their schedule, and the evening curves for today for the status page
+/// and the dashboard.
+async fn publish_schedule(
+    client: &reqwest::Client,
+    suntimes: &mut SunTimes,
+    programs: &ProgramRegistry,
+    state: &SharedState,
+) {
+    let day = match suntimes.today(client).await {
+        Ok(day) => Some(day),
+        Err(e) => {
+            debug!("No schedule for the status page without sun times: {}", e);
+            None
+        }
+    };
+    let schedule = programs
+        .iter()
+        .map(|p| ProgramSchedule {
+            program: p.name().to_string(),
+            active: p.is_active(),
+            today: day.as_ref().and_then(|day| p.preview(day)),
+        })
+        .collect();
+    let curves = programs
+        .instances::<ControlEveningLightsProgram>()
+        .filter_map(|p| {
+            let sunset = day.as_ref()?.at(p.sun_event)?;
+            let points = p
+                .curve(&sunset, chrono::Duration::minutes(CURVE_STEP_MINUTES))
+                .into_iter()
+                .map(|(at, brightness)| CurvePoint { at, brightness })
+                .collect();
+            Some(BrightnessCurve {
+                program: p.name.clone(),
+                light: p.light.clone(),
+                points,
+            })
+        })
+        .collect();
+    let mut state = state.lock().expect("State lock poisoned.");
+    state.schedule = schedule;
+    state.curves = curves;
+}
+
+/// Pause before the next loop, from today's program windows and whether a program acted.
+async fn tuned_loop_pause(
+    client: &reqwest::Client,
+    suntimes: &mut SunTimes,
+    programs: &ProgramRegistry,
+    tuning: &LoopPauseConfig,
+    acted: bool,
+    fallback: f32,
+) -> f32 {
+    let windows = match suntimes.today(client).await {
+        Ok(day) => program_windows(programs, &day),
+        Err(e) => {
+            debug!("Not tuning the loop pause without sun times: {}", e);
+            return fallback;
+        }
+    };
+    loop_pause::tuned_pause(tuning, &windows, acted, &clock::now())
+}
+
+/// Warn about programs that would write the same accessory at the same time in the coming week.
+async fn warn_overlaps(client: &reqwest::Client, suntimes: &SunTimes, programs: &ProgramRegistry) {
+    let today = clock::now().date_naive();
+    let mut windows = Vec::new();
+    for day in today.iter_days().take(OVERLAP_CHECK_DAYS) {
+        match suntimes.times_on(client, day).await {
+            Ok(day) => windows.extend(program_windows(programs, &day)),
+            Err(e) => debug!("Not checking {} for overlapping programs: {}", day, e),
+        }
+    }
+    if windows.is_empty() {
+        info!("No sun times to check for overlapping programs.");
+    }
+    for overlap in overlaps::find_overlaps(&windows) {
+        let (start, end) = overlap.first;
+        warn!(
+            "{} and {} both control '{}' from {} to {} ({} time(s) in the next {} days) - unless their `requires` or conditions keep them apart, they will fight over it.",
+            overlap.programs.0,
+            overlap.programs.1,
+            overlap.accessory,
+            start.format("%a %H:%M"),
+            end.format("%H:%M"),
+            overlap.count,
+            OVERLAP_CHECK_DAYS
+        );
+    }
+}
+
+/// Run the program loop, or a single pass of it if `once` is set, with only the program named
+/// `only` if given.
+async fn run(config_path: &Path, once: bool, only: Option<&str>) -> ExitCode {
+    let mut config = match setup(config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    info!("Config:\n{:?}", config);
+    if let Some(path) = &config.crash_report {
+        crash_report::install(path, &config);
+    }
+    if let Some(factor) = clock::acceleration() {
+        warn!("Clock running {}x faster than real time.", factor);
+    }
+
+    // Persistent state.
+    let state: SharedState = match StateStore::load(&config.state_file) {
+        Ok(s) => Arc::new(Mutex::new(ControllerState::new(s))),
+        Err(e) => {
+            error!("Error loading state file: {}", e);
+            crash_report::report(&e);
+            return ExitCode::from(4);
+        }
+    };
+    crash_report::attach(state.clone());
+
+    {
+        let mut state = state.lock().expect("State lock poisoned.");
+        state.morning_light = config.morning_light.clone();
+        state.action_names = config.actions.keys().cloned().collect();
+        state.sensors = VirtualSensors::new(&config.virtual_sensors);
+        state.audit_log = config.audit_log.as_ref().map(|a| a.path.clone());
+    }
+
+    // Woken by SIGTERM or SIGINT to stop after the current program loop.
+    let shutdown = Arc::new(Notify::new());
+    // Woken to start the next program loop right away, e.g. to apply a reload.
+    let wake = Arc::new(Notify::new());
+
+    // A single pass has no use for reloads or the control API.
+    if !once {
+        #[cfg(unix)]
+        tokio::spawn(watch_reload_signal(state.clone(), wake.clone()));
+        if config.watch_config_seconds > 0 {
+            tokio::spawn(watch_config_files(
+                config_path.to_path_buf(),
+                Duration::from_secs(config.watch_config_seconds),
+                state.clone(),
+                wake.clone(),
+            ));
+        }
+        #[cfg(unix)]
+        tokio::spawn(watch_shutdown_signals(shutdown.clone()));
+
+        // Control API.
+        if let Some(api_config) = &config.control_api {
+            tokio::spawn(api::serve(api_config.clone(), state.clone()));
+            if let Some(announce) = &api_config.announce {
+                tokio::spawn(discovery::announce(announce.clone(), api_config.clone()));
+            }
+        }
+
+        // Push buttons.
+        #[cfg(feature = "gpio")]
+        if let Some(gpio_config) = &config.gpio {
+            tokio::spawn(gpio::watch(gpio_config.clone(), state.clone()));
+        }
+        #[cfg(not(feature = "gpio"))]
+        if config.gpio.is_some() {
+            warn!("`gpio` requires building with the `gpio` feature.");
+        }
+    }
+
+    let cached_accessories = state
+        .lock()
+        .expect("State lock poisoned.")
+        .store
+        .state()
+        .accessory_cache
+        .clone();
+    let (client, mut homebridge) = match connect(&config, Some(cached_accessories)).await {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+
+    // Read-backs sampled before the restart.
+    homebridge.tolerances.lock().restore(
+        state
+            .lock()
+            .expect("State lock poisoned.")
+            .store
+            .state()
+            .read_back_deviations
+            .clone(),
+    );
+    // Runs of limited switches started before the restart.
+    homebridge.run_times.lock().restore(
+        state
+            .lock()
+            .expect("State lock poisoned.")
+            .store
+            .state()
+            .run_starts
+            .clone(),
+    );
+
+    // Background polling of the accessories' state, read by the programs.
+    if let (false, Some(polling)) = (once, &config.state_polling) {
+        let states = PolledStates::new(polling);
+        homebridge.polled = Some(states.clone());
+        tokio::spawn(poller::poll(
+            polling.clone(),
+            config.referenced_accessories().into_iter().collect(),
+            client.clone(),
+            homebridge.detached(),
+            states,
+        ));
+    }
+
+    // Create programs.
+    let mut programs = match ProgramRegistry::from_config(&config) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{}", e);
+            crash_report::report_message(&e);
+            return ExitCode::from(4);
+        }
+    };
+    if let Some(name) = only {
+        if let Err(e) = programs.retain_named(name) {
+            error!("{}", e);
+            return ExitCode::from(4);
+        }
+    }
+
+    // A single pass of one program leaves out everything else the loop does.
+    let single = once && only.is_some();
+
+    let actions = match Actions::new(&config.scenes, &config.actions) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("{}", e);
+            crash_report::report(&e);
+            return ExitCode::from(4);
+        }
+    };
+
+    // Telegram bot, once the programs it controls are known.
+    if let Some(telegram_config) = config.telegram.as_ref().filter(|_| !once) {
+        tokio::spawn(telegram::run(
+            telegram_config.clone(),
+            state.clone(),
+            programs.names().into_iter().map(String::from).collect(),
+            config.referenced_accessories(),
+            config.actions.keys().cloned().collect(),
+        ));
+    }
+
+    // Learning the evening start from manual switch-ons.
+    let mut calibration = sunset_calibration(&config, &state, &mut programs);
+
+    // Conditions published by programs.
+    let mut events = EventBus::default();
+
+    // Temporary changes of accessories waiting to be undone.
+    let mut effects = TemporaryEffects::default();
 
     // Sunrise/sunset data.
-    let mut suntimes = SunTimes::new(config.longitude, config.latitude);
+    let mut suntimes = SunTimes::new(config.longitude, config.latitude)
+        .with_stale_after(config.suntimes_stale_after_days)
+        .with_source(config.suntimes_source, config.suntimes_cross_check_minutes)
+        .with_state(state.clone());
+
+    // Conflicts between programs are worth a look before they fight at runtime.
+    if !once {
+        warn_overlaps(&client, &suntimes, &programs).await;
+    }
+
+    // Precipitation data.
+    let mut weather = Weather::new(config.longitude, config.latitude);
+
+    // Notifications of accessory changes.
+    let mut webhooks = Webhooks::new(&config.webhooks);
+    let program_timeout = Duration::from_secs(config.program_timeout_seconds);
+
+    // Checks for newer releases.
+    let mut update_check = config
+        .update_check
+        .as_ref()
+        .filter(|_| !single)
+        .map(|c| UpdateCheck::new(c, config.desktop_notifications));
+
+    // Opt-in counts of the enabled program types.
+    let mut usage_ping = config
+        .usage_ping
+        .as_ref()
+        .filter(|_| !once)
+        .and_then(|c| UsagePing::new(c, &programs, &config));
+
+    // Who is home, published as conditions.
+    let mut presence = config.presence.as_ref().map(Presence::new);
+
+    // Snoozing while something plays on the media server.
+    let mut media_pause = config.media_pause.as_ref().map(MediaPause::new);
+
+    // Pruning of rolled files, on startup and then daily.
+    let mut last_prune: Option<NaiveDate> = None;
+
+    // Weekly preview of the program times.
+    let mut schedule_preview = config
+        .schedule_preview
+        .as_ref()
+        .filter(|_| !single)
+        .map(|c| SchedulePreview::new(c, config.desktop_notifications));
+
+    // Programs only observe until this time after a restart. One-shot runs are restarted
+    // every time, so they would never act.
+    let grace_minutes = if once {
+        0
+    } else {
+        config.startup_grace_minutes
+    };
+    let grace_until = clock::now() + chrono::Duration::minutes(grace_minutes);
+    if grace_minutes > 0 {
+        info!("Observing only until {}.", time_format::show(&grace_until));
+    }
+
+    // Morning fade started through the control API.
+    let mut triggered_fade: Option<MorningLightProgram> = None;
+
+    // Last attempt to scrape the bridge status.
+    let mut last_bridge_scrape: Option<chrono::DateTime<Local>> = None;
 
     loop {
         info!("Running program loop.");
-        match lights_off_prog
-            .run(&client, &mut homebridge, &mut suntimes)
-            .await
-        {
-            Ok(()) => info!("Successfully executed lights-off program."),
-            Err(e) => error!("Error running programing to turn morning lights off: {}", e),
+        let ran_before = state
+            .lock()
+            .expect("State lock poisoned.")
+            .decisions
+            .total_ran();
+        // Reloads rebuild only the programs whose configuration changed.
+        if std::mem::take(&mut state.lock().expect("State lock poisoned.").reload_requested) {
+            let rebuilt = reload(config_path, &mut config, &mut programs);
+            crash_report::update_config(&config);
+            if rebuilt.contains(&ProgramId::ControlEveningLights) {
+                calibration = sunset_calibration(&config, &state, &mut programs);
+            }
+            if rebuilt.contains(&ProgramId::MorningLight) {
+                state.lock().expect("State lock poisoned.").morning_light =
+                    config.morning_light.clone();
+            }
+            if !rebuilt.is_empty() {
+                warn_overlaps(&client, &suntimes, &programs).await;
+            }
+        }
+        // Sun times are refreshed ahead of the programs, which then read them from memory.
+        if let Err(e) = suntimes.today(&client).await {
+            debug!("No sun times for today: {}", e);
+        }
+        state.lock().expect("State lock poisoned.").suntimes = Some(suntimes.freshness());
+        // Also the program names the control API and the bot accept.
+        if config.telegram.is_some() || config.control_api.is_some() {
+            publish_schedule(&client, &mut suntimes, &programs, &state).await;
+        }
+        // Bridge health is only monitored, so it is also scraped while snoozed.
+        let scrape_due = match last_bridge_scrape {
+            Some(t) => {
+                clock::now() - t >= chrono::Duration::minutes(config.bridge_status_interval_minutes)
+            }
+            None => true,
         };
-        match evening_lights_prog
-            .run(&client, &mut homebridge, &mut suntimes)
-            .await
+        if scrape_due && !single {
+            last_bridge_scrape = Some(clock::now());
+            match homebridge.get_bridge_status(&client).await {
+                Ok(status) => {
+                    debug!("Bridge status: {:?}", status);
+                    state.lock().expect("State lock poisoned.").bridge_status = Some(status);
+                }
+                Err(e) => warn!("Could not retrieve bridge status: {}", e),
+            }
+        }
+
+        if let Some(presence) = presence.as_mut() {
+            presence.run(&client, &mut events).await;
+        }
+        if let Some(media_pause) = media_pause.as_mut() {
+            if let Some(until) = media_pause.run(&client, &mut events).await {
+                let mut state = state.lock().expect("State lock poisoned.");
+                // A longer snooze set by hand is kept.
+                if state
+                    .store
+                    .state()
+                    .snoozed_until
+                    .map_or(true, |s| s < until)
+                {
+                    if let Err(e) = state.store.update(|s| s.snoozed_until = Some(until)) {
+                        warn!("Failed to snooze during media playback: {}", e);
+                    }
+                }
+            }
+        }
+        let reported = state
+            .lock()
+            .expect("State lock poisoned.")
+            .sensors
+            .take_reported();
+        for (sensor, values) in reported.iter() {
+            homebridge.update_virtual_sensor(sensor, values);
+        }
+
+        // Maintenance set through the control API applies to every write of this loop.
         {
-            Ok(()) => info!("Successfully executed evening lights control program."),
-            Err(e) => error!("Error running programing to control evening lights: {}", e),
-        };
+            let mut state = state.lock().expect("State lock poisoned.");
+            let expired = state.maintenance.expire(&clock::now());
+            for accessory in expired.iter() {
+                info!("Maintenance of '{}' ended.", accessory);
+            }
+            if !expired.is_empty() {
+                if let Err(e) = state.save_maintenance() {
+                    warn!("Failed to persist the end of maintenance: {}", e);
+                }
+            }
+            homebridge.maintenance = state.maintenance.clone();
+        }
+
+        if !single {
+            // Nudges are explicit requests, so they are applied even while snoozed.
+            let nudges = std::mem::take(&mut state.lock().expect("State lock poisoned.").nudges);
+            for nudge in nudges.iter() {
+                match apply_nudge(&client, &mut homebridge, nudge).await {
+                    Ok(delta) => {
+                        info!("Nudged '{}' by {:+}.", nudge.accessory, delta);
+                        for evening_lights in
+                            programs.instances_mut::<ControlEveningLightsProgram>()
+                        {
+                            evening_lights.nudge(&nudge.accessory, delta);
+                        }
+                    }
+                    Err(e) => error!("Error nudging '{}': {}", nudge.accessory, e),
+                }
+            }
+            let requests = std::mem::take(
+                &mut state
+                    .lock()
+                    .expect("State lock poisoned.")
+                    .brightness_requests,
+            );
+            for request in requests.iter() {
+                match apply_brightness(&client, &mut homebridge, request).await {
+                    Ok(delta) => {
+                        info!(
+                            "Set '{}' to brightness {}.",
+                            request.accessory, request.brightness
+                        );
+                        for evening_lights in
+                            programs.instances_mut::<ControlEveningLightsProgram>()
+                        {
+                            evening_lights.nudge(&request.accessory, delta);
+                        }
+                    }
+                    Err(e) => error!("Error setting '{}': {}", request.accessory, e),
+                }
+            }
+
+            // Actions are explicit requests too.
+            let requested =
+                std::mem::take(&mut state.lock().expect("State lock poisoned.").actions);
+            if !requested.is_empty() {
+                let mut positions = state
+                    .lock()
+                    .expect("State lock poisoned.")
+                    .store
+                    .state()
+                    .cycle_positions
+                    .clone();
+                for name in requested.iter() {
+                    let decision = match actions
+                        .run(&client, &mut homebridge, name, &mut positions)
+                        .await
+                    {
+                        Ok(done) => {
+                            info!("Action '{}': {}", name, done);
+                            Decision::ran(format!("'{}': {}", name, done))
+                        }
+                        Err(e) => {
+                            error!("Error running action '{}': {}", name, e);
+                            if config.desktop_notifications {
+                                #[cfg(feature = "desktop")]
+                                if let Err(e) = desktop::DesktopNotifier::notify(
+                                    "Homebridge controller",
+                                    &format!("Action '{}': {}", name, e),
+                                ) {
+                                    warn!("Failed to show desktop notification: {}", e);
+                                }
+                            }
+                            Decision::failed(format!("'{}': {}", name, e))
+                        }
+                    };
+                    record_decision(&state, ACTION_SOURCE, decision);
+                }
+                let mut state = state.lock().expect("State lock poisoned.");
+                if let Err(e) = state.store.update(|s| s.cycle_positions = positions) {
+                    warn!("Failed to persist cycle positions: {}", e);
+                }
+            }
+
+            // So are triggered programs; a fade runs to its end unless triggered again.
+            let triggers =
+                std::mem::take(&mut state.lock().expect("State lock poisoned.").triggers);
+            for trigger in triggers.iter() {
+                let Some(morning_config) = config.morning_light.as_ref() else {
+                    continue;
+                };
+                match MorningLightProgram::triggered(
+                    morning_config,
+                    &trigger.overrides,
+                    &clock::now(),
+                ) {
+                    Ok(program) => {
+                        info!("Starting a triggered {} run.", trigger.program);
+                        triggered_fade = Some(program);
+                    }
+                    Err(e) => error!("Error triggering '{}': {}", trigger.program, e),
+                }
+            }
+            if triggered_fade
+                .as_ref()
+                .is_some_and(|p| p.finished(&clock::now()))
+            {
+                info!("Triggered fade finished.");
+                triggered_fade = None;
+            }
+            if let Some(program) = triggered_fade.as_mut() {
+                let result = within(program_timeout, program.run(&client, &mut homebridge)).await;
+                record_result(&state, morning_light::PROGRAM_NAME, result, None);
+            }
+        }
+
+        let snoozed_until = state
+            .lock()
+            .expect("State lock poisoned.")
+            .store
+            .snoozed_until(&clock::now());
+        // All programs write to accessories, so all are held while snoozed.
+        if let Some(until) = snoozed_until {
+            let until = time_format::show(&until);
+            info!("Snoozed until {} - skipping write-capable programs.", until);
+            for program in programs.names() {
+                record_decision(
+                    &state,
+                    program,
+                    Decision::skipped(format!("Snoozed until {}", until)),
+                );
+            }
+        } else {
+            // Programs see what changed while the controller was down without acting on it.
+            homebridge.observe_only = clock::now() < grace_until;
+            suntimes.take_used();
+            let mut forks: Vec<Fork> = programs
+                .iter_mut()
+                .map(|_| Fork::new(&homebridge, &suntimes, &events, &weather, &effects))
+                .collect();
+            let runs = programs
+                .iter_mut()
+                .zip(forks.iter_mut())
+                .map(|(program, fork)| {
+                    run_program(&client, &state, program.as_mut(), fork, program_timeout)
+                });
+            futures::future::join_all(runs).await;
+            for fork in forks {
+                fork.merge_into(&mut suntimes, &mut events, &mut weather, &mut effects);
+            }
+            effects
+                .restore_ended(&client, &mut homebridge, &clock::now())
+                .await;
+            homebridge.observe_only = false;
+        }
+        // The failsafe holds while snoozed, too.
+        if !single {
+            enforce_run_times(
+                &client,
+                &mut homebridge,
+                &state,
+                config.desktop_notifications,
+            )
+            .await;
+            if let (Some(calibration), Some(evening_lights)) =
+                (calibration.as_mut(), calibrated(&mut programs))
+            {
+                calibration
+                    .run(&client, &mut homebridge, &mut suntimes, evening_lights)
+                    .await;
+            }
+        }
+        {
+            let mut state = state.lock().expect("State lock poisoned.");
+            state.accessory_latency = homebridge.latency.lock().status();
+            state.recent_writes = homebridge
+                .journal
+                .lock()
+                .iter()
+                .rev()
+                .take(DASHBOARD_WRITES)
+                .cloned()
+                .collect();
+        }
+        if let Some(cache) = homebridge.take_accessory_cache() {
+            let mut state = state.lock().expect("State lock poisoned.");
+            if let Err(e) = state.store.update(|s| s.accessory_cache = cache) {
+                warn!("Failed to persist the accessory cache: {}", e);
+            }
+            // Accessories put in maintenance by a name not known before are found by it now.
+            let ControllerState {
+                store, maintenance, ..
+            } = &mut *state;
+            let cache = &store.state().accessory_cache;
+            let rekeyed = maintenance.rekey(|accessory| cache.key(accessory));
+            if rekeyed {
+                if let Err(e) = state.save_maintenance() {
+                    warn!("Failed to persist maintenance: {}", e);
+                }
+            }
+        }
+        let run_starts = homebridge.run_times.lock().take_changed();
+        if let Some(run_starts) = run_starts {
+            let mut state = state.lock().expect("State lock poisoned.");
+            if let Err(e) = state.store.update(|s| s.run_starts = run_starts) {
+                warn!("Failed to persist the starts of limited runs: {}", e);
+            }
+        }
+        let deviations = homebridge.tolerances.lock().take_changed();
+        if let Some(deviations) = deviations {
+            let mut state = state.lock().expect("State lock poisoned.");
+            if let Err(e) = state.store.update(|s| s.read_back_deviations = deviations) {
+                warn!("Failed to persist read-back deviations: {}", e);
+            }
+        }
+        webhooks.dispatch(&client, &homebridge.take_changes());
+        if let Some(update_check) = update_check.as_mut() {
+            update_check.run(&client).await;
+        }
+        if let Some(usage_ping) = usage_ping.as_mut() {
+            usage_ping.run(&client, &programs, &config);
+        }
+        if let Some(retention) = config.retention.as_ref().filter(|_| !single) {
+            let today = clock::now().date_naive();
+            if last_prune != Some(today) {
+                last_prune = Some(today);
+                if let Err(e) = retention::prune(retention, SystemTime::now()) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        if let Some(preview) = schedule_preview.as_mut() {
+            let now = clock::now();
+            if let Some(days) = preview.due(&now) {
+                let lines = week_schedule(&client, &suntimes, &programs, &days).await;
+                preview.send(&now, &lines);
+            }
+        }
         info!("Finished program loop.");
-        sleep(Duration::from_secs_f32(config.program_loop_pause)).await;
+        if once {
+            // The process ends with the pass, so webhooks are sent before it does.
+            webhooks.flush().await;
+            return match single {
+                true => report_single_pass(&state, &programs),
+                false => ExitCode::SUCCESS,
+            };
+        }
+        let pause = match &config.loop_pause_tuning {
+            Some(tuning) => {
+                let acted = state
+                    .lock()
+                    .expect("State lock poisoned.")
+                    .decisions
+                    .total_ran()
+                    > ran_before;
+                tuned_loop_pause(
+                    &client,
+                    &mut suntimes,
+                    &programs,
+                    tuning,
+                    acted,
+                    config.program_loop_pause,
+                )
+                .await
+            }
+            None => config.program_loop_pause,
+        };
+        debug!("Pausing {} seconds.", pause);
+        state
+            .lock()
+            .expect("State lock poisoned.")
+            .loop_pause_seconds = Some(pause);
+        let stop = tokio::select! {
+            biased;
+            _ = shutdown.notified() => true,
+            _ = wake.notified() => false,
+            _ = clock::sleep(Duration::from_secs_f32(pause)) => false,
+        };
+        if stop {
+            if config.shutdown.restore_effects {
+                effects
+                    .restore_all(&client, &mut homebridge, &clock::now())
+                    .await;
+            }
+            info!("Shut down.");
+            return ExitCode::from(SHUTDOWN_EXIT_CODE);
+        }
     }
 }
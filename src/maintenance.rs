@@ -0,0 +1,204 @@
+use crate::actions::ACTION_SOURCE;
+use crate::control::{BRIGHTNESS_SOURCE, NUDGE_SOURCE};
+use crate::time_format;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// An accessory taken out of the controller's hands, e.g. while its bulb is replaced.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceEntry {
+    pub accessory: String,
+    /// When the maintenance ends by itself; `None` until it is ended by hand.
+    #[serde(serialize_with = "time_format::serialize_option")]
+    pub until: Option<DateTime<Local>>,
+}
+
+impl fmt::Display for MaintenanceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.until {
+            Some(until) => write!(
+                f,
+                "'{}' is in maintenance until {}",
+                self.accessory,
+                time_format::show(until)
+            ),
+            None => write!(f, "'{}' is in maintenance", self.accessory),
+        }
+    }
+}
+
+/// Maintenance of an accessory as kept in the state file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceRecord {
+    /// Name (or prefixed `uniqueId`) the maintenance was started for.
+    pub accessory: String,
+    #[serde(default)]
+    pub until: Option<DateTime<Local>>,
+}
+
+/// Accessories in maintenance, by key: the prefixed `uniqueId` of a known accessory, else the
+/// name it was given by (see [`AccessoryCache::key`](crate::homebridge::AccessoryCache::key)),
+/// so an accessory is found however it is addressed.
+///
+/// Programs skip writes to them; writes asked for by hand are rejected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Maintenance {
+    entries: BTreeMap<String, MaintenanceEntry>,
+}
+
+impl Maintenance {
+    /// Maintenance kept in the state file before a restart.
+    pub fn restore(records: &BTreeMap<String, MaintenanceRecord>) -> Self {
+        let entries = records
+            .iter()
+            .map(|(key, record)| {
+                let entry = MaintenanceEntry {
+                    accessory: record.accessory.clone(),
+                    until: record.until,
+                };
+                (key.clone(), entry)
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Maintenance to keep in the state file.
+    pub fn records(&self) -> BTreeMap<String, MaintenanceRecord> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| {
+                let record = MaintenanceRecord {
+                    accessory: entry.accessory.clone(),
+                    until: entry.until,
+                };
+                (key.clone(), record)
+            })
+            .collect()
+    }
+
+    pub fn start(
+        &mut self,
+        key: &str,
+        accessory: &str,
+        until: Option<DateTime<Local>>,
+    ) -> MaintenanceEntry {
+        let entry = MaintenanceEntry {
+            accessory: accessory.to_string(),
+            until,
+        };
+        self.entries.insert(key.to_string(), entry.clone());
+        entry
+    }
+
+    /// End the maintenance of an accessory, returning whether it was in maintenance.
+    pub fn end(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Drop the entries whose time ran out, returning their accessories.
+    pub fn expire(&mut self, now: &DateTime<Local>) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.entries.retain(|_, e| {
+            let running = e.until.map_or(true, |until| until > *now);
+            if !running {
+                expired.push(e.accessory.clone());
+            }
+            running
+        });
+        expired
+    }
+
+    /// Key the entries again, e.g. once accessories started by name are known by `uniqueId`.
+    ///
+    /// Returns whether a key changed.
+    pub fn rekey(&mut self, key: impl Fn(&str) -> String) -> bool {
+        let entries: BTreeMap<String, MaintenanceEntry> = self
+            .entries
+            .values()
+            .map(|e| (key(&e.accessory), e.clone()))
+            .collect();
+        let changed = !entries.keys().eq(self.entries.keys());
+        self.entries = entries;
+        changed
+    }
+
+    /// The accessory's maintenance, if it is in maintenance at `now`.
+    pub fn find(&self, key: &str, now: &DateTime<Local>) -> Option<&MaintenanceEntry> {
+        self.entries
+            .get(key)
+            .filter(|e| e.until.map_or(true, |until| until > *now))
+    }
+
+    /// Accessories in maintenance at `now`, by name.
+    pub fn current(&self, now: &DateTime<Local>) -> Vec<&MaintenanceEntry> {
+        self.entries
+            .values()
+            .filter(|e| e.until.map_or(true, |until| until > *now))
+            .collect()
+    }
+}
+
+/// Whether a write from `source` was asked for by hand, and is rejected rather than skipped
+/// for an accessory in maintenance.
+pub fn rejects(source: &str) -> bool {
+    [NUDGE_SOURCE, BRIGHTNESS_SOURCE, ACTION_SOURCE].contains(&source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn maintenance_ends_by_hand_or_in_time() {
+        let now = Local::now();
+        let mut maintenance = Maintenance::default();
+        maintenance.start("Bed Light", "Bed Light", None);
+        maintenance.start("Porch Light", "Porch Light", Some(now + Duration::hours(2)));
+        assert_eq!(Maintenance::restore(&maintenance.records()), maintenance);
+        assert!(maintenance.find("Bed Light", &now).is_some());
+        assert!(maintenance.find("Hall Light", &now).is_none());
+        assert_eq!(maintenance.current(&now).len(), 2);
+
+        let later = now + Duration::hours(3);
+        assert!(maintenance.find("Porch Light", &later).is_none());
+        assert_eq!(maintenance.expire(&later), vec!["Porch Light".to_string()]);
+        assert!(maintenance.expire(&later).is_empty());
+        assert_eq!(
+            maintenance.find("Bed Light", &later).unwrap().to_string(),
+            "'Bed Light' is in maintenance"
+        );
+        assert!(maintenance.end("Bed Light"));
+        assert!(!maintenance.end("Bed Light"));
+        assert!(maintenance.current(&later).is_empty());
+
+        assert!(rejects(NUDGE_SOURCE));
+        assert!(!rejects("evening_lights"));
+    }
+
+    #[test]
+    fn maintenance_is_found_by_key() {
+        let now = Local::now();
+        let mut maintenance = Maintenance::default();
+        maintenance.start("uniqueId:abc", "Bed Light", None);
+        maintenance.start("Desk Lamp", "Desk Lamp", None);
+        assert!(maintenance.find("Bed Light", &now).is_none());
+        assert_eq!(
+            maintenance.find("uniqueId:abc", &now).unwrap().accessory,
+            "Bed Light"
+        );
+
+        // The desk lamp, started before it was known, is found by its `uniqueId` once it is.
+        let key = |accessory: &str| match accessory {
+            "Bed Light" => "uniqueId:abc".to_string(),
+            "Desk Lamp" => "uniqueId:def".to_string(),
+            other => other.to_string(),
+        };
+        assert!(maintenance.rekey(key));
+        assert!(!maintenance.rekey(key));
+        assert!(maintenance.find("uniqueId:def", &now).is_some());
+        assert!(maintenance.end("uniqueId:abc"));
+    }
+}
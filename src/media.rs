@@ -0,0 +1,217 @@
+use crate::clock;
+use crate::configuration::{MediaPauseConfig, MediaServer};
+use crate::events::EventBus;
+use chrono::{DateTime, Duration, Local};
+use log::{info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::time::Duration as StdDuration;
+
+/// Condition set while a watched player is playing.
+pub const MEDIA_PLAYING: &str = "media_playing";
+/// Sessions are read in the program loop, so a slow media server must not hold it up for long.
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MediaError {
+    #[error("Failed to read sessions from the media server: {0}")]
+    FetchError(#[from] reqwest::Error),
+    #[error("Unexpected sessions from the media server: {0}")]
+    ParseError(#[from] serde_json::Error),
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexSessions {
+    #[serde(rename = "MediaContainer")]
+    container: PlexContainer,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexContainer {
+    #[serde(default, rename = "Metadata")]
+    metadata: Vec<PlexSession>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexSession {
+    #[serde(rename = "Player")]
+    player: PlexPlayer,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexPlayer {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    state: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinSession {
+    #[serde(default)]
+    device_name: String,
+    #[serde(default)]
+    now_playing_item: Option<Value>,
+    #[serde(default)]
+    play_state: Option<JellyfinPlayState>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinPlayState {
+    #[serde(default)]
+    is_paused: bool,
+}
+
+/// Players currently playing (not paused) in a sessions response.
+fn playing_players(server: MediaServer, sessions: Value) -> Result<Vec<String>, MediaError> {
+    Ok(match server {
+        MediaServer::Plex => serde_json::from_value::<PlexSessions>(sessions)?
+            .container
+            .metadata
+            .into_iter()
+            .filter(|s| s.player.state == "playing")
+            .map(|s| s.player.title)
+            .collect(),
+        MediaServer::Jellyfin => serde_json::from_value::<Vec<JellyfinSession>>(sessions)?
+            .into_iter()
+            .filter(|s| s.now_playing_item.is_some())
+            .filter(|s| !s.play_state.as_ref().is_some_and(|p| p.is_paused))
+            .map(|s| s.device_name)
+            .collect(),
+    })
+}
+
+/// Holds the programs while something plays on a Plex or Jellyfin player, e.g. the living
+/// room TV, and publishes `media_playing`.
+#[derive(Debug)]
+pub struct MediaPause {
+    config: MediaPauseConfig,
+    token: Option<String>,
+    last_check: Option<DateTime<Local>>,
+    playing: bool,
+}
+
+impl MediaPause {
+    pub fn new(config: &MediaPauseConfig) -> Self {
+        let token = env::var(&config.token_env).ok();
+        if token.is_none() {
+            warn!(
+                "No media server token in `{}` - session requests are unauthenticated.",
+                config.token_env
+            );
+        }
+        Self {
+            config: config.clone(),
+            token,
+            last_check: None,
+            playing: false,
+        }
+    }
+
+    async fn sessions(&self, client: &Client) -> Result<Value, MediaError> {
+        let base = self.config.url.trim_end_matches('/');
+        let mut request = match self.config.server {
+            MediaServer::Plex => client
+                .get(format!("{}/status/sessions", base))
+                .header("Accept", "application/json"),
+            MediaServer::Jellyfin => client.get(format!("{}/Sessions", base)),
+        };
+        if let Some(token) = &self.token {
+            request = match self.config.server {
+                MediaServer::Plex => request.header("X-Plex-Token", token),
+                MediaServer::Jellyfin => request.header("X-Emby-Token", token),
+            };
+        }
+        Ok(request
+            .timeout(StdDuration::from_secs(REQUEST_TIMEOUT_SECS))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())?
+            .json()
+            .await?)
+    }
+
+    /// Whether a watched player is playing.
+    async fn is_playing(&self, client: &Client) -> Result<bool, MediaError> {
+        let players = playing_players(self.config.server, self.sessions(client).await?)?;
+        Ok(players
+            .iter()
+            .any(|p| self.config.players.is_empty() || self.config.players.iter().any(|w| w == p)))
+    }
+
+    /// Check the sessions if the interval has passed and update `media_playing`.
+    ///
+    /// Returns until when the programs should be snoozed while something is playing.
+    pub async fn run(&mut self, client: &Client, events: &mut EventBus) -> Option<DateTime<Local>> {
+        let now = clock::now();
+        if self
+            .last_check
+            .is_some_and(|t| now - t < Duration::seconds(self.config.interval_seconds))
+        {
+            return None;
+        }
+        self.last_check = Some(now);
+        let playing = match self.is_playing(client).await {
+            Ok(playing) => playing,
+            // Keep the last known state rather than guessing.
+            Err(e) => {
+                warn!("{}", e);
+                self.playing
+            }
+        };
+        if playing != self.playing {
+            info!(
+                "Media {} - {} programs.",
+                if playing { "playing" } else { "stopped" },
+                if playing { "pausing" } else { "releasing" }
+            );
+            self.playing = playing;
+        }
+        match playing {
+            true => {
+                events.publish(MEDIA_PLAYING, &now);
+                Some(now + Duration::minutes(self.config.release_after_minutes))
+            }
+            false => {
+                events.clear(MEDIA_PLAYING);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn finds_playing_players() {
+        let plex = json!({"MediaContainer": {"size": 2, "Metadata": [
+            {"title": "Film", "Player": {"title": "Living Room TV", "state": "playing"}},
+            {"title": "Song", "Player": {"title": "Kitchen", "state": "paused"}}
+        ]}});
+        assert_eq!(
+            playing_players(MediaServer::Plex, plex).unwrap(),
+            vec!["Living Room TV"]
+        );
+        let idle = json!({"MediaContainer": {"size": 0}});
+        assert!(playing_players(MediaServer::Plex, idle).unwrap().is_empty());
+
+        let jellyfin = json!([
+            {"DeviceName": "Living Room TV", "NowPlayingItem": {"Name": "Film"},
+             "PlayState": {"IsPaused": false}},
+            {"DeviceName": "Phone", "NowPlayingItem": {"Name": "Song"},
+             "PlayState": {"IsPaused": true}},
+            {"DeviceName": "Browser", "PlayState": {}}
+        ]);
+        assert_eq!(
+            playing_players(MediaServer::Jellyfin, jellyfin).unwrap(),
+            vec!["Living Room TV"]
+        );
+    }
+}
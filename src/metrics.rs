@@ -0,0 +1,211 @@
+use crate::control::ControllerState;
+use std::fmt::Write;
+
+/// Append a gauge in the Prometheus text exposition format.
+fn gauge(out: &mut String, name: &str, help: &str, labels: &str, value: f64) {
+    gauge_family(out, name, help, &[(labels.to_string(), value)]);
+}
+
+/// Append a gauge with one sample per label set.
+fn gauge_family(out: &mut String, name: &str, help: &str, samples: &[(String, f64)]) {
+    family(out, name, help, "gauge", samples);
+}
+
+/// Append a metric of a type with one sample per label set.
+fn family(out: &mut String, name: &str, help: &str, kind: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples.iter() {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn accessory_label(accessory: &str) -> String {
+    format!("{{accessory=\"{}\"}}", escape(accessory))
+}
+
+/// Render the controller's metrics in the Prometheus text format.
+pub fn render(state: &ControllerState) -> String {
+    let mut out = String::new();
+    if let Some(bridge) = &state.bridge_status {
+        gauge(
+            &mut out,
+            "homebridge_cpu_load_percent",
+            "Current CPU load of the Homebridge host.",
+            "",
+            bridge.cpu_load_percent as f64,
+        );
+        gauge(
+            &mut out,
+            "homebridge_memory_total_bytes",
+            "Total memory of the Homebridge host.",
+            "",
+            bridge.memory_total_bytes as f64,
+        );
+        gauge(
+            &mut out,
+            "homebridge_memory_available_bytes",
+            "Available memory of the Homebridge host.",
+            "",
+            bridge.memory_available_bytes as f64,
+        );
+        gauge(
+            &mut out,
+            "homebridge_host_uptime_seconds",
+            "Uptime of the Homebridge host.",
+            "",
+            bridge.host_uptime_seconds,
+        );
+        gauge(
+            &mut out,
+            "homebridge_process_uptime_seconds",
+            "Uptime of the Homebridge process.",
+            "",
+            bridge.process_uptime_seconds,
+        );
+        gauge(
+            &mut out,
+            "homebridge_node_info",
+            "Node.js version running Homebridge.",
+            &format!("{{version=\"{}\"}}", escape(&bridge.node_version)),
+            1.0,
+        );
+        gauge(
+            &mut out,
+            "homebridge_status_fetched_timestamp_seconds",
+            "When the bridge status was last scraped.",
+            "",
+            bridge.fetched_at.timestamp() as f64,
+        );
+    }
+    let latency = &state.accessory_latency;
+    gauge_family(
+        &mut out,
+        "homebridge_accessory_latency_seconds",
+        "Average round-trip time of requests to an accessory.",
+        &latency
+            .iter()
+            .map(|l| (accessory_label(&l.accessory), l.average_seconds))
+            .collect::<Vec<_>>(),
+    );
+    gauge_family(
+        &mut out,
+        "homebridge_accessory_slow",
+        "Whether an accessory is consistently slow and its requests are spaced out.",
+        &latency
+            .iter()
+            .map(|l| (accessory_label(&l.accessory), l.slow as u8 as f64))
+            .collect::<Vec<_>>(),
+    );
+    let mut decisions = Vec::new();
+    for (program, counts) in state.decisions.counts().iter() {
+        for (outcome, count) in [
+            ("ran", counts.ran),
+            ("skipped", counts.skipped),
+            ("failed", counts.failed),
+        ] {
+            decisions.push((
+                format!(
+                    "{{program=\"{}\",outcome=\"{}\"}}",
+                    escape(program),
+                    outcome
+                ),
+                count as f64,
+            ));
+        }
+    }
+    family(
+        &mut out,
+        "homebridge_program_decisions_total",
+        "Decisions of a program since the controller started, by outcome.",
+        "counter",
+        &decisions,
+    );
+    if let Some(pause) = state.loop_pause_seconds {
+        gauge(
+            &mut out,
+            "homebridge_loop_pause_seconds",
+            "Pause before the next program loop.",
+            "",
+            pause as f64,
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decisions::Decision;
+    use crate::homebridge::BridgeStatus;
+    use crate::latency::LatencyStatus;
+    use crate::state::StateStore;
+    use chrono::Local;
+    use std::path::Path;
+
+    #[test]
+    fn renders_the_text_exposition_format() {
+        let store = StateStore::load(Path::new("/nonexistent/hb-metrics-state.json")).unwrap();
+        let mut state = ControllerState::new(store);
+        assert_eq!(render(&state), "");
+
+        state.bridge_status = Some(BridgeStatus {
+            fetched_at: Local::now(),
+            cpu_load_percent: 12.5,
+            memory_total_bytes: 1024,
+            memory_available_bytes: 512,
+            host_uptime_seconds: 60.0,
+            process_uptime_seconds: 30.0,
+            node_version: "v20.11.0".to_string(),
+        });
+        state.accessory_latency = vec![LatencyStatus {
+            accessory: "Kid's \"Lamp\"".to_string(),
+            average_seconds: 0.25,
+            last_seconds: 0.5,
+            samples: 4,
+            slow: true,
+            spacing_seconds: 1.0,
+        }];
+        state
+            .decisions
+            .record("evening_lights", Decision::ran("Done"));
+        state
+            .decisions
+            .record("evening_lights", Decision::skipped("Too early"));
+        state.loop_pause_seconds = Some(2.0);
+        let out = render(&state);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert!(lines.contains(&"# TYPE homebridge_cpu_load_percent gauge"));
+        assert!(lines.contains(&"homebridge_cpu_load_percent 12.5"));
+        assert!(lines.contains(&"homebridge_node_info{version=\"v20.11.0\"} 1"));
+        assert!(lines.contains(
+            &"homebridge_accessory_latency_seconds{accessory=\"Kid's \\\"Lamp\\\"\"} 0.25"
+        ));
+        assert!(lines.contains(&"homebridge_accessory_slow{accessory=\"Kid's \\\"Lamp\\\"\"} 1"));
+        assert!(lines.contains(&"# TYPE homebridge_program_decisions_total counter"));
+        assert!(lines.contains(
+            &"homebridge_program_decisions_total{program=\"evening_lights\",outcome=\"ran\"} 1"
+        ));
+        assert!(lines.contains(
+            &"homebridge_program_decisions_total{program=\"evening_lights\",outcome=\"failed\"} 0"
+        ));
+        assert!(lines.contains(&"homebridge_loop_pause_seconds 2"));
+        // Every family has its help and type once, before its samples.
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                let name = rest.split(' ').next().unwrap();
+                assert!(lines[i + 1].starts_with(&format!("# TYPE {} ", name)));
+                assert!(lines[i + 2].starts_with(name));
+                assert_eq!(out.matches(&format!("# HELP {} ", name)).count(), 1);
+            }
+        }
+    }
+}
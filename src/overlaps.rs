@@ -0,0 +1,108 @@
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+
+/// Time during which a program may write an accessory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramWindow {
+    pub program: String,
+    pub accessory: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+}
+
+/// Two programs whose windows on the same accessory overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlap {
+    pub accessory: String,
+    pub programs: (String, String),
+    /// Earliest overlapping stretch.
+    pub first: (DateTime<Local>, DateTime<Local>),
+    /// Number of overlapping window pairs.
+    pub count: usize,
+}
+
+/// Overlaps between windows of different programs on the same accessory, one per accessory
+/// and pair of programs.
+pub fn find_overlaps(windows: &[ProgramWindow]) -> Vec<Overlap> {
+    let mut overlaps: BTreeMap<(&str, &str, &str), Overlap> = BTreeMap::new();
+    for (i, a) in windows.iter().enumerate() {
+        for b in windows[i + 1..].iter() {
+            if a.program == b.program || a.accessory != b.accessory {
+                continue;
+            }
+            let start = a.start.max(b.start);
+            let end = a.end.min(b.end);
+            if end <= start {
+                continue;
+            }
+            let programs = match a.program < b.program {
+                true => (a.program.as_str(), b.program.as_str()),
+                false => (b.program.as_str(), a.program.as_str()),
+            };
+            overlaps
+                .entry((&a.accessory, programs.0, programs.1))
+                .and_modify(|o| {
+                    o.count += 1;
+                    o.first = o.first.min((start, end));
+                })
+                .or_insert(Overlap {
+                    accessory: a.accessory.clone(),
+                    programs: (programs.0.to_string(), programs.1.to_string()),
+                    first: (start, end),
+                    count: 1,
+                });
+        }
+    }
+    overlaps.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn window(program: &str, accessory: &str, start: i64, end: i64) -> ProgramWindow {
+        let midnight = Local.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        ProgramWindow {
+            program: program.to_string(),
+            accessory: accessory.to_string(),
+            start: midnight + Duration::minutes(start),
+            end: midnight + Duration::minutes(end),
+        }
+    }
+
+    #[test]
+    fn reports_overlaps_on_the_same_accessory() {
+        let windows = vec![
+            window("morning_light", "Bed Light", 360, 390),
+            window("turn_morning_lights_off", "Bed Light", 380, 400),
+            window("color_shift", "Bed Light", 390, 420),
+            window("color_shift", "Lamp", 370, 420),
+            window("morning_light", "Bed Light", 1800, 1830),
+            window("turn_morning_lights_off", "Bed Light", 1810, 1840),
+        ];
+        let overlaps = find_overlaps(&windows);
+        assert_eq!(overlaps.len(), 2);
+        let fade_vs_off = &overlaps[1];
+        assert_eq!(
+            fade_vs_off.programs,
+            (
+                "morning_light".to_string(),
+                "turn_morning_lights_off".to_string()
+            )
+        );
+        assert_eq!(fade_vs_off.count, 2);
+        assert_eq!(
+            fade_vs_off.first,
+            (windows[1].start, windows[0].end),
+            "earliest stretch"
+        );
+        assert_eq!(
+            overlaps[0].programs,
+            (
+                "color_shift".to_string(),
+                "turn_morning_lights_off".to_string()
+            )
+        );
+    }
+}
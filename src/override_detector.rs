@@ -0,0 +1,320 @@
+use crate::audit::WriteJournal;
+use chrono::{DateTime, Duration, Local};
+use log::debug;
+use serde_json::Value;
+
+/// Outcome of comparing an observed value against the controller's own writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverrideStatus {
+    /// The value is what the controller last set (or it has not set anything yet).
+    NoOverride,
+    /// Someone else changed the value; leave it alone.
+    Overridden { since: DateTime<Local> },
+    /// A manual value was left untouched for the cooldown and is now the new baseline.
+    Resumed { value: f64 },
+}
+
+/// Manual value the detector is waiting to settle.
+#[derive(Debug, Clone, Copy)]
+struct PendingOverride {
+    since: DateTime<Local>,
+    value: f64,
+}
+
+/// Numeric value of a characteristic as written or read (numbers, numeric strings, or bools).
+pub fn numeric_value(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        Value::Bool(b) => Some(*b as u8 as f64),
+        _ => None,
+    }
+}
+
+/// Detects whether a human changed a characteristic since the controller last wrote it.
+///
+/// The controller's own writes come from the write journal. Observed values within `tolerance`
+/// of the last write (devices often round) are not overrides; a tolerance learned from the
+/// device's read-backs widens it. With a `cooldown`, a manual value
+/// that stays unchanged for that long is accepted as the new baseline; without one, an override
+/// lasts until the detector is reset.
+#[derive(Debug, Clone)]
+pub struct OverrideDetector {
+    accessory: String,
+    characteristic: String,
+    tolerance: f64,
+    learned_tolerance: Option<f64>,
+    cooldown: Option<Duration>,
+    ignore_before: Option<DateTime<Local>>,
+    accepted: Option<(DateTime<Local>, f64)>,
+    pending: Option<PendingOverride>,
+}
+
+impl OverrideDetector {
+    pub fn new(
+        accessory: &str,
+        characteristic: &str,
+        tolerance: f64,
+        cooldown: Option<Duration>,
+    ) -> Self {
+        Self {
+            accessory: accessory.to_string(),
+            characteristic: characteristic.to_string(),
+            tolerance,
+            learned_tolerance: None,
+            cooldown,
+            ignore_before: None,
+            accepted: None,
+            pending: None,
+        }
+    }
+
+    /// Use the tolerance learned for the characteristic where it exceeds the configured one.
+    pub fn learn_tolerance(&mut self, tolerance: Option<f64>) {
+        self.learned_tolerance = tolerance;
+    }
+
+    /// Forget all overrides and ignore writes made before `now` (e.g. at the end of a window).
+    pub fn reset(&mut self, now: &DateTime<Local>) {
+        self.ignore_before = Some(*now);
+        self.accepted = None;
+        self.pending = None;
+    }
+
+    /// The value the characteristic is expected to have, and since when.
+    fn baseline(&self, journal: &WriteJournal) -> Option<(DateTime<Local>, f64)> {
+        let own_write = journal
+            .last_write(&self.accessory, &self.characteristic)
+            .filter(|w| match self.ignore_before {
+                Some(t) => w.when >= t,
+                None => true,
+            })
+            .and_then(|w| numeric_value(&w.after).map(|v| (w.when, v)));
+        match (own_write, self.accepted) {
+            (Some(w), Some(a)) => Some(if w.0 >= a.0 { w } else { a }),
+            (w, a) => w.or(a),
+        }
+    }
+
+    fn matches(&self, a: f64, b: f64) -> bool {
+        (a - b).abs() <= self.tolerance.max(self.learned_tolerance.unwrap_or(0.0))
+    }
+
+    pub fn check(
+        &mut self,
+        journal: &WriteJournal,
+        observed: f64,
+        now: &DateTime<Local>,
+    ) -> OverrideStatus {
+        let Some((_, expected)) = self.baseline(journal) else {
+            return OverrideStatus::NoOverride;
+        };
+        if self.matches(observed, expected) {
+            self.pending = None;
+            return OverrideStatus::NoOverride;
+        }
+        debug!(
+            "'{}' {} is {} but was set to {}.",
+            self.accessory, self.characteristic, observed, expected
+        );
+
+        match (self.pending, self.cooldown) {
+            (Some(pending), Some(cooldown)) if self.matches(pending.value, observed) => {
+                if *now - pending.since < cooldown {
+                    return OverrideStatus::Overridden {
+                        since: pending.since,
+                    };
+                }
+                self.pending = None;
+                self.accepted = Some((*now, observed));
+                OverrideStatus::Resumed { value: observed }
+            }
+            (Some(pending), None) => OverrideStatus::Overridden {
+                since: pending.since,
+            },
+            _ => {
+                self.pending = Some(PendingOverride {
+                    since: *now,
+                    value: observed,
+                });
+                OverrideStatus::Overridden { since: *now }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::WriteRecord;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn at(minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 12, 1, 18, minute, 0).unwrap()
+    }
+
+    fn journal_with_write(when: DateTime<Local>, value: Value) -> WriteJournal {
+        let mut journal = WriteJournal::new(10);
+        journal.record(WriteRecord {
+            when,
+            program: "test".to_string(),
+            accessory: "Lamp".to_string(),
+            characteristic: "Brightness".to_string(),
+            before: None,
+            after: value,
+        });
+        journal
+    }
+
+    #[test]
+    fn no_override_without_own_write() {
+        let mut detector = OverrideDetector::new("Lamp", "Brightness", 0.0, None);
+        let journal = WriteJournal::new(10);
+        assert_eq!(
+            detector.check(&journal, 42.0, &at(0)),
+            OverrideStatus::NoOverride
+        );
+    }
+
+    #[test]
+    fn rounding_within_tolerance_is_not_an_override() {
+        let mut detector = OverrideDetector::new("Lamp", "Brightness", 1.0, None);
+        let journal = journal_with_write(at(0), json!(57));
+        assert_eq!(
+            detector.check(&journal, 56.0, &at(1)),
+            OverrideStatus::NoOverride
+        );
+        assert_eq!(
+            detector.check(&journal, 58.0, &at(1)),
+            OverrideStatus::NoOverride
+        );
+        assert_eq!(
+            detector.check(&journal, 55.0, &at(1)),
+            OverrideStatus::Overridden { since: at(1) }
+        );
+    }
+
+    #[test]
+    fn learned_tolerance_widens_the_configured_one() {
+        let mut detector = OverrideDetector::new("Lamp", "Brightness", 0.0, None);
+        let journal = journal_with_write(at(0), json!(57));
+        detector.learn_tolerance(Some(2.0));
+        assert_eq!(
+            detector.check(&journal, 55.0, &at(1)),
+            OverrideStatus::NoOverride
+        );
+        assert_eq!(
+            detector.check(&journal, 54.0, &at(1)),
+            OverrideStatus::Overridden { since: at(1) }
+        );
+    }
+
+    #[test]
+    fn string_writes_are_compared_numerically() {
+        let mut detector = OverrideDetector::new("Lamp", "Brightness", 0.0, None);
+        let journal = journal_with_write(at(0), json!("40"));
+        assert_eq!(
+            detector.check(&journal, 40.0, &at(1)),
+            OverrideStatus::NoOverride
+        );
+    }
+
+    #[test]
+    fn override_without_cooldown_lasts_until_reset() {
+        let mut detector = OverrideDetector::new("Lamp", "Brightness", 0.0, None);
+        let journal = journal_with_write(at(0), json!(50));
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(1)),
+            OverrideStatus::Overridden { since: at(1) }
+        );
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(59)),
+            OverrideStatus::Overridden { since: at(1) }
+        );
+        detector.reset(&at(59));
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(59)),
+            OverrideStatus::NoOverride
+        );
+    }
+
+    #[test]
+    fn resumes_exactly_at_cooldown() {
+        let mut detector =
+            OverrideDetector::new("Lamp", "Brightness", 0.0, Some(Duration::minutes(10)));
+        let journal = journal_with_write(at(0), json!(50));
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(1)),
+            OverrideStatus::Overridden { since: at(1) }
+        );
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(10)),
+            OverrideStatus::Overridden { since: at(1) }
+        );
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(11)),
+            OverrideStatus::Resumed { value: 20.0 }
+        );
+        // The manual value is now the baseline.
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(12)),
+            OverrideStatus::NoOverride
+        );
+    }
+
+    #[test]
+    fn further_manual_change_restarts_cooldown() {
+        let mut detector =
+            OverrideDetector::new("Lamp", "Brightness", 0.0, Some(Duration::minutes(10)));
+        let journal = journal_with_write(at(0), json!(50));
+        detector.check(&journal, 20.0, &at(1));
+        assert_eq!(
+            detector.check(&journal, 30.0, &at(8)),
+            OverrideStatus::Overridden { since: at(8) }
+        );
+        assert_eq!(
+            detector.check(&journal, 30.0, &at(12)),
+            OverrideStatus::Overridden { since: at(8) }
+        );
+        assert_eq!(
+            detector.check(&journal, 30.0, &at(18)),
+            OverrideStatus::Resumed { value: 30.0 }
+        );
+    }
+
+    #[test]
+    fn newer_own_write_supersedes_accepted_value() {
+        let mut detector =
+            OverrideDetector::new("Lamp", "Brightness", 0.0, Some(Duration::minutes(0)));
+        let mut journal = journal_with_write(at(0), json!(50));
+        detector.check(&journal, 20.0, &at(1));
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(2)),
+            OverrideStatus::Resumed { value: 20.0 }
+        );
+        journal.record(WriteRecord {
+            when: at(3),
+            program: "test".to_string(),
+            accessory: "Lamp".to_string(),
+            characteristic: "Brightness".to_string(),
+            before: Some(json!(20)),
+            after: json!(25),
+        });
+        assert_eq!(
+            detector.check(&journal, 25.0, &at(4)),
+            OverrideStatus::NoOverride
+        );
+    }
+
+    #[test]
+    fn writes_before_reset_are_ignored() {
+        let mut detector = OverrideDetector::new("Lamp", "Brightness", 0.0, None);
+        let journal = journal_with_write(at(0), json!(50));
+        detector.reset(&at(5));
+        assert_eq!(
+            detector.check(&journal, 20.0, &at(6)),
+            OverrideStatus::NoOverride
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::clock;
+use crate::configuration::StatePollingConfig;
+use crate::homebridge::Homebridge;
+use chrono::{DateTime, Duration, Local};
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Accessory status as last polled.
+#[derive(Debug, Clone)]
+struct Polled {
+    /// Payload as returned by the bridge, normalized.
+    status: Value,
+    fetched: DateTime<Local>,
+}
+
+/// States of the accessories kept fresh by the poller, read by programs in place of their own
+/// requests.
+#[derive(Debug, Clone)]
+pub struct PolledStates {
+    states: Arc<Mutex<HashMap<String, Polled>>>,
+    max_age: Duration,
+}
+
+impl PolledStates {
+    pub fn new(config: &StatePollingConfig) -> Self {
+        let max_age = config
+            .max_age_seconds
+            .unwrap_or(2 * config.interval_seconds);
+        Self {
+            states: Arc::default(),
+            max_age: Duration::seconds(max_age as i64),
+        }
+    }
+
+    fn store(&self, accessory: &str, status: Value) {
+        let polled = Polled {
+            status,
+            fetched: clock::now(),
+        };
+        self.states
+            .lock()
+            .expect("Poller lock poisoned.")
+            .insert(accessory.to_string(), polled);
+    }
+
+    /// The polled status of an accessory and its age, unless it is missing or too old.
+    pub fn fresh(&self, accessory: &str) -> Option<(Value, Duration)> {
+        let states = self.states.lock().expect("Poller lock poisoned.");
+        let polled = states.get(accessory)?;
+        let age = clock::now() - polled.fetched;
+        (age <= self.max_age).then(|| (polled.status.clone(), age))
+    }
+
+    /// Age of the polled status of an accessory, if there is one.
+    pub fn age(&self, accessory: &str) -> Option<Duration> {
+        let states = self.states.lock().expect("Poller lock poisoned.");
+        states.get(accessory).map(|p| clock::now() - p.fetched)
+    }
+
+    /// Take over a value the controller wrote, so reads until the next poll see it.
+    pub fn written(&self, accessory: &str, characteristic: &str, value: &Value) {
+        let mut states = self.states.lock().expect("Poller lock poisoned.");
+        if let Some(values) = states
+            .get_mut(accessory)
+            .and_then(|p| p.status.get_mut("values"))
+            .and_then(Value::as_object_mut)
+        {
+            values.insert(characteristic.to_string(), value.clone());
+        }
+    }
+}
+
+/// Fetch the state of `accessories` every interval, with a client of its own.
+pub async fn poll(
+    config: StatePollingConfig,
+    accessories: Vec<String>,
+    client: Client,
+    mut homebridge: Homebridge,
+    states: PolledStates,
+) {
+    if accessories.is_empty() {
+        return;
+    }
+    info!(
+        "Polling the state of {} accessory(s) every {} s.",
+        accessories.len(),
+        config.interval_seconds
+    );
+    let mut failing = HashSet::new();
+    loop {
+        for accessory in accessories.iter() {
+            match homebridge
+                .accessory(accessory)
+                .status::<Value>(&client)
+                .await
+            {
+                Ok(status) => {
+                    if failing.remove(accessory) {
+                        info!("Polling '{}' again.", accessory);
+                    }
+                    states.store(accessory, status);
+                }
+                Err(e) => {
+                    if failing.insert(accessory.clone()) {
+                        warn!("Failed to poll '{}': {}", accessory, e);
+                    } else {
+                        debug!("Failed to poll '{}': {}", accessory, e);
+                    }
+                }
+            }
+        }
+        clock::sleep(std::time::Duration::from_secs(config.interval_seconds)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serves_fresh_states_with_writes() {
+        let config = StatePollingConfig {
+            interval_seconds: 30,
+            max_age_seconds: None,
+        };
+        let states = PolledStates::new(&config);
+        assert!(states.fresh("Bed Light").is_none());
+        states.store("Bed Light", json!({"values": {"On": 0, "Brightness": 40}}));
+        states.written("Bed Light", "On", &json!(true));
+        let (status, age) = states.fresh("Bed Light").unwrap();
+        assert_eq!(status["values"], json!({"On": true, "Brightness": 40}));
+        assert!(age <= Duration::seconds(60));
+
+        let stale = PolledStates {
+            max_age: Duration::seconds(-1),
+            ..states
+        };
+        assert!(stale.fresh("Bed Light").is_none());
+        assert!(stale.age("Bed Light").is_some());
+    }
+
+    #[tokio::test]
+    async fn polled_states_end_runs_switched_off_by_hand() {
+        let (url, switches) = crate::bench::mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let heater = switches[0].clone();
+        let accessories =
+            serde_json::from_value(json!({ &heater: { "max_on_minutes": 120 } })).unwrap();
+        let client = Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        homebridge
+            .run_times
+            .replace(crate::run_time::RunTimeLimits::from_config(&accessories));
+        let started = clock::now() - Duration::hours(3);
+        homebridge
+            .run_times
+            .lock()
+            .restore([(heater.clone(), started)].into());
+        assert_eq!(homebridge.run_times.lock().overdue(&clock::now()).len(), 1);
+
+        // Switched off by hand and polled by the poller's own client.
+        Homebridge::new(&url, "user", "password")
+            .accessory(&heater)
+            .set(
+                &client,
+                "manual",
+                &crate::characteristic::Characteristic::On,
+                0,
+            )
+            .await
+            .unwrap();
+        let states = PolledStates::new(&StatePollingConfig {
+            interval_seconds: 30,
+            max_age_seconds: None,
+        });
+        homebridge.polled = Some(states.clone());
+        let poller = tokio::spawn(poll(
+            StatePollingConfig {
+                interval_seconds: 30,
+                max_age_seconds: None,
+            },
+            vec![heater.clone()],
+            client.clone(),
+            homebridge.detached(),
+            states.clone(),
+        ));
+        while states.fresh(&heater).is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        poller.abort();
+        assert!(homebridge
+            .run_times
+            .lock()
+            .overdue(&clock::now())
+            .is_empty());
+        let ended = homebridge.run_times.lock().take_ended();
+        assert_eq!(ended.len(), 1);
+
+        // A read served from the polled state ends a run as well.
+        homebridge
+            .run_times
+            .lock()
+            .restore([(heater.clone(), started)].into());
+        let status: Value = homebridge.accessory(&heater).status(&client).await.unwrap();
+        assert_eq!(status["values"]["On"], json!(0));
+        assert!(homebridge
+            .run_times
+            .lock()
+            .overdue(&clock::now())
+            .is_empty());
+        assert!(homebridge.journal.lock().iter().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn polled_states_are_sampled_for_read_back_tolerances() {
+        let (url, lights) = crate::bench::mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let client = Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        homebridge
+            .tolerances
+            .replace(crate::tolerance::ToleranceTuner::new(true, 5.0, 10, 1));
+        homebridge
+            .accessory(&lights[0])
+            .set(
+                &client,
+                "test",
+                &crate::characteristic::Characteristic::Brightness,
+                57,
+            )
+            .await
+            .unwrap();
+
+        // The bulb rounds what it was sent.
+        let states = PolledStates::new(&StatePollingConfig {
+            interval_seconds: 30,
+            max_age_seconds: None,
+        });
+        states.store(&lights[0], json!({"values": {"On": 1, "Brightness": 56}}));
+        homebridge.polled = Some(states);
+        let _: Value = homebridge
+            .accessory(&lights[0])
+            .status(&client)
+            .await
+            .unwrap();
+        let tolerance = homebridge
+            .detached()
+            .tolerances
+            .lock()
+            .tolerance(&lights[0], "Brightness");
+        assert_eq!(tolerance, Some(1.0));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::clock;
+use crate::configuration::PresenceConfig;
+use crate::events::EventBus;
+use chrono::{DateTime, Duration, Local};
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration as StdDuration;
+
+/// Condition set while at least one person is home.
+pub const SOMEONE_HOME: &str = "someone_home";
+/// Condition set while everyone is away.
+pub const EVERYONE_AWAY: &str = "everyone_away";
+/// Presence is read in the program loop, so a slow Home Assistant must not hold it up for long.
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PresenceError {
+    #[error("Failed to read '{entity}' from Home Assistant: {source}")]
+    FetchError {
+        entity: String,
+        source: reqwest::Error,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct EntityState {
+    state: String,
+}
+
+/// Condition set while a person is home.
+fn home_condition(person: &str) -> String {
+    format!("{}_home", person)
+}
+
+/// Who is home, read from `person` or `device_tracker` entities of Home Assistant and
+/// published as conditions programs can require: `<person>_home`, `someone_home`, and
+/// `everyone_away`.
+#[derive(Debug)]
+pub struct Presence {
+    config: PresenceConfig,
+    token: Option<String>,
+    last_check: Option<DateTime<Local>>,
+    home: BTreeMap<String, bool>,
+}
+
+impl Presence {
+    pub fn new(config: &PresenceConfig) -> Self {
+        let token = env::var(&config.token_env).ok();
+        if token.is_none() {
+            warn!(
+                "No Home Assistant token in `{}` - presence requests are unauthenticated.",
+                config.token_env
+            );
+        }
+        Self {
+            config: config.clone(),
+            token,
+            last_check: None,
+            home: BTreeMap::new(),
+        }
+    }
+
+    async fn is_home(&self, client: &Client, entity: &str) -> Result<bool, PresenceError> {
+        let url = format!(
+            "{}/api/states/{}",
+            self.config.home_assistant_url.trim_end_matches('/'),
+            entity
+        );
+        let mut request = client
+            .get(url)
+            .timeout(StdDuration::from_secs(REQUEST_TIMEOUT_SECS));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let fetch_error = |source| PresenceError::FetchError {
+            entity: entity.to_string(),
+            source,
+        };
+        let state = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(fetch_error)?
+            .json::<EntityState>()
+            .await
+            .map_err(fetch_error)?;
+        // Away is "not_home" or the name of another zone.
+        Ok(state.state == "home")
+    }
+
+    /// Read who is home if the interval has passed and update the conditions.
+    pub async fn run(&mut self, client: &Client, events: &mut EventBus) {
+        let now = clock::now();
+        if self
+            .last_check
+            .is_some_and(|t| now - t < Duration::minutes(self.config.interval_minutes))
+        {
+            return;
+        }
+        self.last_check = Some(now);
+        // Everyone at once, so people on a slow entity cost one timeout in all.
+        let checks = self
+            .config
+            .people
+            .values()
+            .map(|entity| self.is_home(client, entity));
+        let results = futures::future::join_all(checks).await;
+        for (person, result) in self.config.people.keys().zip(results) {
+            match result {
+                Ok(home) => {
+                    if self.home.insert(person.clone(), home) != Some(home) {
+                        info!("{} is {}.", person, if home { "home" } else { "away" });
+                    }
+                }
+                // Keep the last known state rather than guessing.
+                Err(e) => warn!("{}", e),
+            }
+        }
+        publish(&self.home, events, &now);
+    }
+}
+
+/// Set and clear the presence conditions for who is home.
+fn publish(home: &BTreeMap<String, bool>, events: &mut EventBus, now: &DateTime<Local>) {
+    for (person, is_home) in home.iter() {
+        match is_home {
+            true => events.publish(&home_condition(person), now),
+            false => events.clear(&home_condition(person)),
+        }
+    }
+    if home.is_empty() {
+        debug!("Presence unknown - leaving the conditions unset.");
+        return;
+    }
+    match home.values().any(|h| *h) {
+        true => {
+            events.publish(SOMEONE_HOME, now);
+            events.clear(EVERYONE_AWAY);
+        }
+        false => {
+            events.publish(EVERYONE_AWAY, now);
+            events.clear(SOMEONE_HOME);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_who_is_home() {
+        let now = Local::now();
+        let mut events = EventBus::default();
+        let mut home = BTreeMap::new();
+        home.insert("alex".to_string(), true);
+        home.insert("sam".to_string(), false);
+        publish(&home, &mut events, &now);
+        assert!(events.is_set("alex_home"));
+        assert!(!events.is_set("sam_home"));
+        assert!(events.is_set(SOMEONE_HOME));
+        assert!(!events.is_set(EVERYONE_AWAY));
+
+        home.insert("alex".to_string(), false);
+        publish(&home, &mut events, &now);
+        assert!(!events.is_set("alex_home"));
+        assert!(!events.is_set(SOMEONE_HOME));
+        assert!(events.is_set(EVERYONE_AWAY));
+    }
+}
@@ -1,2 +1,387 @@
+use crate::configuration::DarkHoursConfig;
+use crate::control::SharedState;
+use crate::decisions::Decision;
+use crate::effects::TemporaryEffects;
+use crate::events::EventBus;
+use crate::expression::Expression;
+use crate::homebridge::Homebridge;
+use crate::overlaps::ProgramWindow;
+use crate::suntimes::{SunDay, SunTimes};
+use crate::weather::Weather;
+use futures::future::LocalBoxFuture;
+use std::any::Any;
+use std::fmt;
+use std::time::Duration;
+
+pub mod color_shift;
+pub mod condition_actions;
 pub mod control_evening_lights;
+pub mod http_poll;
+pub mod irrigation;
+pub mod morning_light;
+pub mod registry;
+pub mod script;
 pub mod turn_morning_lights_off;
+
+pub use registry::ProgramRegistry;
+
+/// Identifier of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProgramId {
+    MorningLight,
+    TurnMorningLightsOff,
+    ControlEveningLights,
+    Irrigation,
+    ConditionActions,
+    HttpPoll,
+    ColorShift,
+    Script,
+}
+
+impl ProgramId {
+    pub const ALL: [ProgramId; 8] = [
+        ProgramId::MorningLight,
+        ProgramId::TurnMorningLightsOff,
+        ProgramId::ControlEveningLights,
+        ProgramId::Irrigation,
+        ProgramId::ConditionActions,
+        ProgramId::HttpPoll,
+        ProgramId::ColorShift,
+        ProgramId::Script,
+    ];
+
+    /// Name used in logs, the write journal, and the decision history.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgramId::MorningLight => morning_light::PROGRAM_NAME,
+            ProgramId::TurnMorningLightsOff => turn_morning_lights_off::PROGRAM_NAME,
+            ProgramId::ControlEveningLights => control_evening_lights::PROGRAM_NAME,
+            ProgramId::Irrigation => irrigation::PROGRAM_NAME,
+            ProgramId::ConditionActions => condition_actions::PROGRAM_NAME,
+            ProgramId::HttpPoll => http_poll::PROGRAM_NAME,
+            ProgramId::ColorShift => color_shift::PROGRAM_NAME,
+            ProgramId::Script => script::PROGRAM_NAME,
+        }
+    }
+
+    /// Configuration key of the program's section.
+    pub fn config_key(&self) -> &'static str {
+        match self {
+            ProgramId::HttpPoll => "http_polls",
+            ProgramId::Script => "scripts_dir",
+            _ => self.name(),
+        }
+    }
+
+    pub fn from_config_key(key: &str) -> Option<ProgramId> {
+        ProgramId::ALL.into_iter().find(|id| id.config_key() == key)
+    }
+
+    /// Shorter name accepted on the command line.
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            ProgramId::TurnMorningLightsOff => "lights_off",
+            ProgramId::ControlEveningLights => "evening_lights",
+            ProgramId::HttpPoll => "http_poll",
+            _ => self.name(),
+        }
+    }
+
+    /// Program by its name or short name.
+    pub fn from_name(name: &str) -> Option<ProgramId> {
+        ProgramId::ALL
+            .into_iter()
+            .find(|id| id.name() == name || id.short_name() == name)
+    }
+
+    /// Full name of a program or one instance of it, given by name or short name, e.g.
+    /// `control_evening_lights:office` for `evening_lights:office`.
+    pub fn full_name(name: &str) -> Option<String> {
+        let (program, instance) = match name.split_once(':') {
+            Some((program, instance)) => (program, Some(instance)),
+            None => (name, None),
+        };
+        let id = ProgramId::from_name(program)?;
+        Some(match instance {
+            Some(instance) => format!("{}:{}", id.name(), instance),
+            None => id.name().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ProgramId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Everything a program may use during a run of the program loop.
+pub struct ProgramContext<'a> {
+    pub client: &'a reqwest::Client,
+    pub homebridge: &'a mut Homebridge,
+    pub suntimes: &'a mut SunTimes,
+    pub events: &'a mut EventBus,
+    pub weather: &'a mut Weather,
+    pub effects: &'a mut TemporaryEffects,
+    pub state: &'a SharedState,
+}
+
+/// A program's own copy of the loop's context, so programs run side by side.
+///
+/// The client is a handle on the loop's; the rest is merged back once the runs of the pass are
+/// done, so conditions published during a pass are seen by the other programs from the next.
+pub struct Fork {
+    pub homebridge: Homebridge,
+    pub suntimes: SunTimes,
+    pub events: EventBus,
+    pub weather: Weather,
+    pub effects: TemporaryEffects,
+    base_events: EventBus,
+}
+
+impl Fork {
+    pub fn new(
+        homebridge: &Homebridge,
+        suntimes: &SunTimes,
+        events: &EventBus,
+        weather: &Weather,
+        effects: &TemporaryEffects,
+    ) -> Self {
+        Self {
+            homebridge: homebridge.clone(),
+            suntimes: suntimes.clone(),
+            events: events.clone(),
+            weather: weather.clone(),
+            effects: effects.clone(),
+            base_events: events.clone(),
+        }
+    }
+
+    pub fn context<'a>(
+        &'a mut self,
+        client: &'a reqwest::Client,
+        state: &'a SharedState,
+    ) -> ProgramContext<'a> {
+        ProgramContext {
+            client,
+            homebridge: &mut self.homebridge,
+            suntimes: &mut self.suntimes,
+            events: &mut self.events,
+            weather: &mut self.weather,
+            effects: &mut self.effects,
+            state,
+        }
+    }
+
+    /// Take over what the program changed on its copy.
+    pub fn merge_into(
+        self,
+        suntimes: &mut SunTimes,
+        events: &mut EventBus,
+        weather: &mut Weather,
+        effects: &mut TemporaryEffects,
+    ) {
+        suntimes.merge(self.suntimes);
+        events.merge(&self.base_events, self.events);
+        weather.merge(self.weather);
+        effects.merge(self.effects);
+    }
+}
+
+/// The result of a program run, or a failure if it does not finish within `limit`.
+///
+/// The run is dropped at the limit, so a hung request to Homebridge cannot hold up the pass;
+/// writes it already sent still finish and are journaled.
+pub async fn within<E: Into<anyhow::Error>>(
+    limit: Duration,
+    run: impl std::future::Future<Output = Result<Decision, E>>,
+) -> anyhow::Result<Decision> {
+    match tokio::time::timeout(limit, run).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(anyhow::anyhow!(
+            "Timed out after {} s - abandoned the run",
+            limit.as_secs()
+        )),
+    }
+}
+
+/// A program run by the program loop.
+///
+/// The loop checks `requires`, `only_when_dark`, and `condition` before each run, so a program
+/// only declares them. The other methods feed the schedule preview, the overlap check, and
+/// `explain`; their defaults suit programs without fixed times.
+pub trait Program: Any {
+    fn id(&self) -> ProgramId;
+
+    /// Name used in logs, the write journal, and the decision history; that of the program
+    /// unless it is one of several instances.
+    fn name(&self) -> &str {
+        self.id().name()
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    /// Conditions that must be set for the program to run.
+    fn requires(&self) -> &[String] {
+        &[]
+    }
+
+    /// Expression over accessory values that must hold for the program to run.
+    fn condition(&self) -> Option<&Expression> {
+        None
+    }
+
+    /// Hours outside which the program does not run.
+    fn only_when_dark(&self) -> Option<&DarkHoursConfig> {
+        None
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>>;
+
+    /// Windows in which the program may write accessories on a day with the given sun times.
+    fn windows(&self, _day: &SunDay) -> Vec<ProgramWindow> {
+        Vec::new()
+    }
+
+    /// Line of the schedule preview for a day with the given sun times.
+    fn preview(&self, _day: &SunDay) -> Option<String> {
+        None
+    }
+
+    /// How the program decides what to do at the current time, or `None` if it cannot tell.
+    fn explain<'a>(
+        &'a self,
+        _client: &'a reqwest::Client,
+        _suntimes: &'a mut SunTimes,
+    ) -> LocalBoxFuture<'a, Option<anyhow::Result<Vec<String>>>> {
+        Box::pin(async { None })
+    }
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::mock_bridge;
+    use crate::characteristic::Characteristic;
+    use crate::clock;
+    use crate::control::ControllerState;
+    use crate::state::StateStore;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// Dims a light, through a client of its own if it has one, and publishes its label.
+    struct Dim {
+        label: &'static str,
+        light: String,
+        bridge: Option<Homebridge>,
+    }
+
+    impl Program for Dim {
+        fn id(&self) -> ProgramId {
+            ProgramId::Script
+        }
+
+        fn run<'a>(
+            &'a mut self,
+            ctx: ProgramContext<'a>,
+        ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+            Box::pin(async move {
+                let homebridge = self.bridge.as_mut().unwrap_or(ctx.homebridge);
+                homebridge
+                    .accessory(&self.light)
+                    .set(ctx.client, self.label, &Characteristic::Brightness, 10)
+                    .await?;
+                ctx.events.publish(self.label, &clock::now());
+                Ok(Decision::ran("Dimmed"))
+            })
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn a_hung_bridge_does_not_hold_up_the_other_programs() {
+        let (hung_url, _) = mock_bridge(1, Duration::from_secs(3600)).unwrap();
+        let (url, lights) = mock_bridge(1, Duration::from_millis(50)).unwrap();
+        let client = reqwest::Client::new();
+        let homebridge = Homebridge::new(&url, "user", "password");
+        let mut suntimes = SunTimes::new(-71.06, 42.36);
+        let mut events = EventBus::default();
+        let mut weather = Weather::new(-71.06, 42.36);
+        let mut effects = TemporaryEffects::default();
+        let state: SharedState = Arc::new(Mutex::new(ControllerState::new(
+            StateStore::load(Path::new("/nonexistent/state.json")).unwrap(),
+        )));
+        let mut programs: Vec<Box<dyn Program>> = vec![
+            Box::new(Dim {
+                label: "hung",
+                light: lights[0].clone(),
+                bridge: Some(Homebridge::new(&hung_url, "user", "password")),
+            }),
+            Box::new(Dim {
+                label: "dimmed",
+                light: lights[0].clone(),
+                bridge: None,
+            }),
+        ];
+
+        let limit = Duration::from_secs(1);
+        // Programs in the order their runs ended.
+        let finished = std::cell::RefCell::new(Vec::new());
+        let mut forks: Vec<Fork> = programs
+            .iter()
+            .map(|_| Fork::new(&homebridge, &suntimes, &events, &weather, &effects))
+            .collect();
+        let runs =
+            programs
+                .iter_mut()
+                .zip(forks.iter_mut())
+                .enumerate()
+                .map(|(i, (program, fork))| {
+                    let (client, state, finished) = (&client, &state, &finished);
+                    async move {
+                        let run = program.run(fork.context(client, state));
+                        let result = within(limit, run).await;
+                        finished.borrow_mut().push(i);
+                        result
+                    }
+                });
+        let results = futures::future::join_all(runs).await;
+        for fork in forks {
+            fork.merge_into(&mut suntimes, &mut events, &mut weather, &mut effects);
+        }
+
+        let timed_out = results[0].as_ref().unwrap_err().to_string();
+        assert!(
+            timed_out.starts_with("Timed out after 1 s"),
+            "{}",
+            timed_out
+        );
+        // The second program ran alongside the hung one rather than after it.
+        assert!(results[1].is_ok());
+        assert_eq!(finished.into_inner(), vec![1, 0]);
+        assert!(events.is_set("dimmed"));
+        assert!(!events.is_set("hung"));
+        let written = homebridge
+            .journal
+            .lock()
+            .last_write(&lights[0], "Brightness")
+            .map(|w| w.program.clone());
+        assert_eq!(written.as_deref(), Some("dimmed"));
+    }
+}
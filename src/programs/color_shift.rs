@@ -0,0 +1,324 @@
+use crate::characteristic::Characteristic;
+use crate::clock;
+use crate::configuration::ColorShiftConfig;
+use crate::decisions::Decision;
+use crate::expression::Expression;
+use crate::homebridge::{HBError, Homebridge};
+use crate::overlaps::ProgramWindow;
+use crate::override_detector::{OverrideDetector, OverrideStatus};
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::suntimes::{SunDay, SunTimes};
+use crate::time_window::TimeWindow;
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime};
+use futures::future::LocalBoxFuture;
+use log::{debug, error, info};
+use serde_json::json;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+pub const PROGRAM_NAME: &str = "color_shift";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ColorShiftProgramError {
+    #[error("Error during Homebridge interaction.")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("{0}")]
+    ConfigError(String),
+}
+
+/// Shifts the color temperature of tagged lights from cool to warm between two fixed times,
+/// in steps, independent of sunset.
+///
+/// Each light has its own override detector: a light whose color temperature was changed by
+/// hand is left alone until the change settles (with `resume_after_minutes`) or the next
+/// window.
+#[derive(Debug)]
+pub struct ColorShiftProgram {
+    pub active: bool,
+    pub lights: Vec<String>,
+    pub window: TimeWindow,
+    pub from_mired: u32,
+    pub to_mired: u32,
+    pub step_minutes: i64,
+    pub requires: Vec<String>,
+    pub condition: Option<Expression>,
+    in_window: bool,
+    override_detectors: BTreeMap<String, OverrideDetector>,
+}
+
+impl ColorShiftProgram {
+    /// Program shifting `lights`, the accessories tagged with `config.tag`.
+    pub fn new(
+        config: &ColorShiftConfig,
+        lights: Vec<String>,
+    ) -> Result<Self, ColorShiftProgramError> {
+        info!("Creating a `ColorShiftProgram` object.");
+        if config.start == config.end {
+            error!("Logical errors in `ColorShiftProgram` configuration.");
+            return Err(ColorShiftProgramError::ConfigError(
+                "The color shift must start and end at different times.".to_string(),
+            ));
+        }
+        if config.step_minutes < 1 {
+            return Err(ColorShiftProgramError::ConfigError(
+                "The color shift steps must be at least 1 minute.".to_string(),
+            ));
+        }
+        if lights.is_empty() {
+            return Err(ColorShiftProgramError::ConfigError(format!(
+                "No accessories are tagged '{}' for the color shift.",
+                config.tag
+            )));
+        }
+        let override_detectors = lights
+            .iter()
+            .map(|light| {
+                let detector = OverrideDetector::new(
+                    light,
+                    "ColorTemperature",
+                    config.override_tolerance,
+                    config.resume_after_minutes.map(Duration::minutes),
+                );
+                (light.clone(), detector)
+            })
+            .collect();
+        Ok(Self {
+            active: config.active,
+            lights,
+            window: TimeWindow::new(config.start, config.end),
+            from_mired: config.from_mired,
+            to_mired: config.to_mired,
+            step_minutes: config.step_minutes,
+            requires: config.requires.clone(),
+            condition: config
+                .condition
+                .as_deref()
+                .map(Expression::parse)
+                .transpose()
+                .map_err(|e| ColorShiftProgramError::ConfigError(e.to_string()))?,
+            in_window: false,
+            override_detectors,
+        })
+    }
+}
+
+impl ColorShiftProgram {
+    /// Color temperature at a time of day, if it is within the window.
+    ///
+    /// The value changes only at whole steps from the start and reaches `to_mired` at the end.
+    pub fn target(&self, time: NaiveTime) -> Option<u32> {
+        let total = self.window.length().num_minutes();
+        let elapsed = self.window.elapsed(time)?.num_minutes();
+        let stepped = match elapsed == total {
+            true => total,
+            false => elapsed / self.step_minutes * self.step_minutes,
+        };
+        let fraction = stepped as f64 / total as f64;
+        let from = self.from_mired as f64;
+        let to = self.to_mired as f64;
+        Some((from + (to - from) * fraction).round() as u32)
+    }
+
+    /// Start and end of the shift starting on `day`; the end may be on the next day.
+    pub fn window_on(&self, day: NaiveDate) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        self.window.on(day)
+    }
+
+    /// How the shift looks at the current time, without reading or writing the lights.
+    pub fn explain(&self) -> Vec<String> {
+        let now = clock::now();
+        vec![
+            format!("Active: {}", self.active),
+            format!("Lights: {}", self.lights.join(", ")),
+            format!(
+                "Window: {}, {} to {} mired in {}-minute steps",
+                self.window, self.from_mired, self.to_mired, self.step_minutes
+            ),
+            match self.target(now.time()) {
+                Some(mired) => format!("Sets lights that are on to {} mired", mired),
+                None => "Outside of operating times - nothing to do".to_string(),
+            },
+        ]
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+    ) -> Result<Decision, ColorShiftProgramError> {
+        info!("Executing `ColorShiftProgram`.");
+        if !self.active {
+            debug!("Program inactive - nothing to do.");
+            return Ok(Decision::skipped("Program inactive"));
+        }
+
+        let now = clock::now();
+        let Some(target) = self.target(now.time()) else {
+            debug!("Outside of operating times - nothing to do.");
+            if self.in_window {
+                self.in_window = false;
+                self.reset(&now);
+            }
+            return Ok(Decision::skipped("Outside of operating times"));
+        };
+        self.in_window = true;
+        debug!("Target color temperature: {} mired.", target);
+
+        let mut notes: Vec<String> = Vec::new();
+        let mut acted = false;
+        for light in self.lights.iter() {
+            let values = homebridge.get_light_status(client, light).await?.values;
+            if values.is_off() {
+                debug!("'{}' is off - nothing to do.", light);
+                notes.push(format!("'{}' is off", light));
+                continue;
+            }
+            let Some(current) = values.color_temperature else {
+                debug!("'{}' reports no color temperature - skipping.", light);
+                notes.push(format!("'{}' has no color temperature", light));
+                continue;
+            };
+            let detector = self
+                .override_detectors
+                .get_mut(light)
+                .expect("Detector for every light.");
+            detector.learn_tolerance(
+                homebridge
+                    .tolerances
+                    .lock()
+                    .tolerance(light, "ColorTemperature"),
+            );
+            match detector.check(&homebridge.journal.lock(), current as f64, &now) {
+                OverrideStatus::Overridden { since } => {
+                    info!(
+                        "'{}' color temperature adjusted at {} - skipping.",
+                        light, since
+                    );
+                    notes.push(format!("'{}' adjusted by hand", light));
+                    continue;
+                }
+                OverrideStatus::Resumed { value } => {
+                    info!("Manual change of '{}' settled at {} mired.", light, value);
+                }
+                OverrideStatus::NoOverride => {}
+            }
+            if current == target {
+                notes.push(format!("'{}' already at {} mired", light, target));
+                continue;
+            }
+            info!("Setting '{}' to {} mired.", light, target);
+            homebridge
+                .apply_values(
+                    client,
+                    PROGRAM_NAME,
+                    light,
+                    &[(Characteristic::ColorTemperature, json!(target))],
+                    false,
+                )
+                .await?;
+            notes.push(format!("Set '{}' to {} mired", light, target));
+            acted = true;
+        }
+        let reason = notes.join("; ");
+        Ok(match acted {
+            true => Decision::ran(reason),
+            false => Decision::skipped(reason),
+        })
+    }
+
+    /// Forget manual changes once the window is over.
+    fn reset(&mut self, now: &DateTime<Local>) {
+        for detector in self.override_detectors.values_mut() {
+            detector.reset(now);
+        }
+    }
+}
+
+impl Program for ColorShiftProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::ColorShift
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move { Ok(self.run(ctx.client, ctx.homebridge).await?) })
+    }
+
+    fn windows(&self, day: &SunDay) -> Vec<ProgramWindow> {
+        let Some((start, end)) = self.window_on(day.sunrise.date_naive()) else {
+            return Vec::new();
+        };
+        self.lights
+            .iter()
+            .map(|light| ProgramWindow {
+                program: PROGRAM_NAME.to_string(),
+                accessory: light.clone(),
+                start,
+                end,
+            })
+            .collect()
+    }
+
+    fn explain<'a>(
+        &'a self,
+        _client: &'a reqwest::Client,
+        _suntimes: &'a mut SunTimes,
+    ) -> LocalBoxFuture<'a, Option<anyhow::Result<Vec<String>>>> {
+        Box::pin(async move { Some(Ok(self.explain())) })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(start: &str, end: &str) -> ColorShiftProgram {
+        let config: ColorShiftConfig = serde_json::from_value(json!({
+            "start": start,
+            "end": end,
+            "from_mired": 200,
+            "to_mired": 400,
+            "step_minutes": 15
+        }))
+        .unwrap();
+        ColorShiftProgram::new(&config, vec!["Lamp".to_string()]).unwrap()
+    }
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn shifts_in_steps_across_midnight() {
+        let program = program("23:00:00", "01:00:00");
+        assert_eq!(program.target(at("22:59")), None);
+        assert_eq!(program.target(at("23:00")), Some(200));
+        assert_eq!(program.target(at("23:14")), Some(200));
+        assert_eq!(program.target(at("23:15")), Some(225));
+        assert_eq!(program.target(at("00:00")), Some(300));
+        assert_eq!(program.target(at("01:00")), Some(400));
+        assert_eq!(program.target(at("01:01")), None);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::characteristic::Characteristic;
+use crate::clock;
+use crate::configuration::ConditionActionConfig;
+use crate::decisions::Decision;
+use crate::effects::TemporaryEffects;
+use crate::events::EventBus;
+use crate::expression::Expression;
+use crate::homebridge::{HBError, Homebridge};
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::suntimes::{SunTimes, SuntimesError};
+use chrono::{DateTime, Duration, Local};
+use futures::future::LocalBoxFuture;
+use log::{debug, info, warn};
+use serde_json::json;
+use std::any::Any;
+
+pub const PROGRAM_NAME: &str = "condition_actions";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConditionActionsProgramError {
+    #[error("{0}")]
+    ConfigError(String),
+    #[error("Error during Homebridge interaction.")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("{0}")]
+    NoSunTimesData(#[from] SuntimesError),
+}
+
+#[derive(Debug)]
+struct ConditionAction {
+    config: ConditionActionConfig,
+    condition: Option<Expression>,
+    /// When the triggering condition was set the last time this action ran.
+    last_trigger: Option<DateTime<Local>>,
+}
+
+impl ConditionAction {
+    /// The time the triggering condition was set, if the action should run for it.
+    fn due(&self, events: &EventBus) -> Option<DateTime<Local>> {
+        let set_since = events.set_since(&self.config.triggered_by)?;
+        if self.last_trigger == Some(set_since) {
+            return None;
+        }
+        let missing = events.missing(&self.config.requires);
+        if !missing.is_empty() {
+            debug!(
+                "Action '{}' waiting on conditions: {}.",
+                self.config.name,
+                missing.join(", ")
+            );
+            return None;
+        }
+        Some(set_since)
+    }
+}
+
+/// Set lights once each time a condition published by another program is set.
+#[derive(Debug)]
+pub struct ConditionActionsProgram {
+    actions: Vec<ConditionAction>,
+}
+
+impl ConditionActionsProgram {
+    pub fn new(configs: &[ConditionActionConfig]) -> Result<Self, ConditionActionsProgramError> {
+        for config in configs.iter() {
+            if config.on.is_none() && config.brightness.is_none() {
+                return Err(ConditionActionsProgramError::ConfigError(format!(
+                    "Action '{}' sets neither `on` nor `brightness`.",
+                    config.name
+                )));
+            }
+            if config.brightness.is_some_and(|b| b > 100) {
+                return Err(ConditionActionsProgramError::ConfigError(format!(
+                    "Brightness of action '{}' must be at most 100.",
+                    config.name
+                )));
+            }
+            if config.revert_after_minutes.is_some_and(|m| m <= 0) {
+                return Err(ConditionActionsProgramError::ConfigError(format!(
+                    "`revert_after_minutes` of action '{}' must be positive.",
+                    config.name
+                )));
+            }
+        }
+        Ok(Self {
+            actions: configs
+                .iter()
+                .map(|c| {
+                    Ok(ConditionAction {
+                        config: c.clone(),
+                        condition: c
+                            .condition
+                            .as_deref()
+                            .map(Expression::parse)
+                            .transpose()
+                            .map_err(|e| {
+                                ConditionActionsProgramError::ConfigError(e.to_string())
+                            })?,
+                        last_trigger: None,
+                    })
+                })
+                .collect::<Result<Vec<_>, ConditionActionsProgramError>>()?,
+        })
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        suntimes: &mut SunTimes,
+        events: &EventBus,
+        effects: &mut TemporaryEffects,
+    ) -> Result<Decision, ConditionActionsProgramError> {
+        info!("Executing `ConditionActionsProgram`.");
+        let mut ran: Vec<String> = Vec::new();
+        for action in self.actions.iter_mut() {
+            let Some(trigger) = action.due(events) else {
+                continue;
+            };
+            // The trigger stays due, so the action runs once writes are allowed.
+            if homebridge.observe_only {
+                debug!("Action '{}' waiting - observing only.", action.config.name);
+                continue;
+            }
+            let config = &action.config;
+            if let Some(hours) = &config.only_when_dark {
+                if !suntimes.is_dark(client, &clock::now(), hours).await? {
+                    debug!("Action '{}' waiting for dark.", config.name);
+                    continue;
+                }
+            }
+            if let Some(condition) = &action.condition {
+                match condition.holds(client, homebridge).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        debug!("Action '{}' waiting on its condition.", config.name);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Action '{}' waiting - could not evaluate its condition: {}",
+                            config.name, e
+                        );
+                        continue;
+                    }
+                }
+            }
+            info!(
+                "Condition '{}' set - running action '{}' on '{}'.",
+                config.triggered_by, config.name, config.accessory
+            );
+            // Mark the trigger as handled first so a failing accessory is not retried every loop.
+            action.last_trigger = Some(trigger);
+            let writes = match (config.on, config.brightness) {
+                (Some(true), Some(brightness)) => {
+                    homebridge.turn_on_writes(&config.accessory, brightness)
+                }
+                (on, brightness) => on
+                    .map(|on| (Characteristic::On, json!(on as u8)))
+                    .into_iter()
+                    .chain(brightness.map(|b| (Characteristic::Brightness, json!(b))))
+                    .collect(),
+            };
+            if let Some(minutes) = config.revert_after_minutes {
+                let current = homebridge
+                    .get_accessory_values(client, &config.accessory)
+                    .await?;
+                let characteristics: Vec<Characteristic> =
+                    writes.iter().map(|(c, _)| c.clone()).collect();
+                effects.start(
+                    &config.name,
+                    &config.accessory,
+                    &characteristics,
+                    &current,
+                    clock::now() + Duration::minutes(minutes),
+                );
+            }
+            homebridge
+                .apply_values(
+                    client,
+                    PROGRAM_NAME,
+                    &config.accessory,
+                    &writes,
+                    config.rollback_on_failure,
+                )
+                .await?;
+            ran.push(config.name.clone());
+        }
+        Ok(match ran.is_empty() {
+            true => Decision::skipped("No action triggered"),
+            false => Decision::ran(format!("Ran {}", ran.join(", "))),
+        })
+    }
+}
+
+/// Each action checks its own trigger and condition, so the loop runs the program every time.
+impl Program for ConditionActionsProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::ConditionActions
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move {
+            Ok(self
+                .run(
+                    ctx.client,
+                    ctx.homebridge,
+                    ctx.suntimes,
+                    ctx.events,
+                    ctx.effects,
+                )
+                .await?)
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::mock_bridge;
+
+    /// Reason of the program's decision for a pass with `events`.
+    async fn run(
+        program: &mut ConditionActionsProgram,
+        homebridge: &mut Homebridge,
+        events: &EventBus,
+    ) -> String {
+        let client = reqwest::Client::new();
+        let mut suntimes = SunTimes::new(-71.06, 42.36);
+        let mut effects = TemporaryEffects::default();
+        program
+            .run(&client, homebridge, &mut suntimes, events, &mut effects)
+            .await
+            .unwrap()
+            .reason
+    }
+
+    #[tokio::test]
+    async fn runs_each_action_once_per_trigger() {
+        let (url, lights) = mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        let configs: Vec<ConditionActionConfig> = serde_json::from_value(json!([
+            {
+                "name": "dim",
+                "triggered_by": "movie",
+                "requires": ["evening"],
+                "accessory": lights[0],
+                "brightness": 20
+            },
+            {
+                "name": "switch_on",
+                "triggered_by": "movie",
+                "condition": format!("light('{}').off", lights[0]),
+                "accessory": lights[0],
+                "on": true
+            }
+        ]))
+        .unwrap();
+        let mut program = ConditionActionsProgram::new(&configs).unwrap();
+
+        let start = Local::now();
+        let mut events = EventBus::default();
+        assert_eq!(
+            run(&mut program, &mut homebridge, &events).await,
+            "No action triggered"
+        );
+        events.publish("movie", &start);
+        // Waiting on the required condition.
+        assert_eq!(
+            run(&mut program, &mut homebridge, &events).await,
+            "No action triggered"
+        );
+        events.publish("evening", &start);
+        // The light is on, so `switch_on` keeps waiting on its condition.
+        assert_eq!(run(&mut program, &mut homebridge, &events).await, "Ran dim");
+        assert_eq!(
+            run(&mut program, &mut homebridge, &events).await,
+            "No action triggered"
+        );
+        // Set again.
+        events.clear("movie");
+        events.publish("movie", &(start + Duration::minutes(1)));
+        assert_eq!(run(&mut program, &mut homebridge, &events).await, "Ran dim");
+
+        let written = homebridge
+            .journal
+            .lock()
+            .last_write(&lights[0], "Brightness")
+            .map(|w| (w.program.clone(), w.after.clone()));
+        assert_eq!(written, Some((PROGRAM_NAME.to_string(), json!(20))));
+    }
+
+    #[test]
+    fn rejects_actions_without_writes() {
+        let configs: Vec<ConditionActionConfig> = serde_json::from_value(json!([
+            {"name": "nothing", "triggered_by": "movie", "accessory": "Lamp"}
+        ]))
+        .unwrap();
+        let error = ConditionActionsProgram::new(&configs).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Action 'nothing' sets neither `on` nor `brightness`."
+        );
+    }
+}
@@ -1,11 +1,28 @@
-use crate::homebridge::Homebridge;
-use crate::suntimes::{SunTimes, SuntimesError};
+use crate::characteristic::Characteristic;
+use crate::clock;
+use crate::configuration::{BiasLightingConfig, DarkHoursConfig};
+use crate::decisions::Decision;
+use crate::events::EventBus;
+use crate::expression::Expression;
+use crate::homebridge::{HBLightbulb, Homebridge};
+use crate::overlaps::ProgramWindow;
+use crate::override_detector::{numeric_value, OverrideDetector, OverrideStatus};
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::suntimes::{SunDay, SunEvent, SunTimes, SuntimesError};
+use crate::time_format;
+use crate::time_window::TimeWindow;
 use crate::{configuration::ControlEveningLightsConfig, homebridge::HBError};
-use chrono::{DateTime, Duration, Local, Timelike};
-use core::time;
-use log::{debug, error, info};
+use chrono::{DateTime, Duration, Local, NaiveTime, Timelike};
+use futures::future::LocalBoxFuture;
+use log::{debug, error, info, warn};
+use std::any::Any;
 use std::cmp::{max, min};
-use std::thread;
+
+pub const PROGRAM_NAME: &str = "control_evening_lights";
+/// Condition set while the evening window is running.
+pub const RAMP_STARTED: &str = "evening_ramp_started";
+/// Condition set once the evening window has ended, until the next one starts.
+pub const RAMP_FINISHED: &str = "evening_ramp_finished";
 
 #[derive(thiserror::Error, Debug)]
 pub enum ControlEveningLightsProgramError {
@@ -22,102 +39,293 @@ pub enum ControlEveningLightsProgramError {
 #[derive(Debug, Clone, Copy)]
 struct LightsHistory {
     when: DateTime<Local>,
-    brightness: u8,
     // set_by_program: bool,
 }
 
+/// Offset added to the curve after resuming so the ramp continues from the manual value.
+#[derive(Debug, Clone, Copy)]
+struct ResumeOffset {
+    when: DateTime<Local>,
+    offset: f32,
+}
+
 #[derive(Debug)]
 pub struct ControlEveningLightsProgram {
+    /// `control_evening_lights`, or `control_evening_lights:<name>` for a named instance.
+    pub name: String,
     pub active: bool,
+    pub light: String,
+    /// Event the window is timed against, sunset by default.
+    pub sun_event: SunEvent,
     pub minutes_before_sunset_start: i64,
     pub minutes_after_sunset_peak: i64,
     pub minutes_after_sunset_finish: i64,
+    pub sunset_fallback: Option<NaiveTime>,
     pub start_brightness: u8,
     pub max_brightness: u8,
     pub final_brightness: u8,
+    pub requires: Vec<String>,
+    pub condition: Option<Expression>,
+    pub only_when_dark: Option<DarkHoursConfig>,
+    pub bias_lighting: Option<BiasLightingConfig>,
+    /// Whether this is the instance the sunset calibration learns from.
+    pub calibrated: bool,
+    in_window: bool,
+    nudge_offset: i32,
     history: Option<LightsHistory>,
+    override_detector: OverrideDetector,
+    resume: Option<ResumeOffset>,
 }
 
 impl ControlEveningLightsProgram {
     pub fn new(
         config: &ControlEveningLightsConfig,
     ) -> Result<Self, ControlEveningLightsProgramError> {
-        if !((-1 * config.minutes_before_sunset_start) <= config.minutes_after_sunset_peak) {
+        if -config.minutes_before_sunset_start > config.minutes_after_sunset_peak {
             error!("Logical errors in `ControlEveningLightsProgram` configuration.");
             return Err(ControlEveningLightsProgramError::ConfigurationError(
                 "The start time must precede the peak time.".to_string(),
             ));
         }
-        if !(config.minutes_after_sunset_peak <= config.minutes_after_sunset_finish) {
+        if config.minutes_after_sunset_peak > config.minutes_after_sunset_finish {
             error!("Logical errors in `ControlEveningLightsProgram` configuration.");
             return Err(ControlEveningLightsProgramError::ConfigurationError(
                 "The time for peak must precede the finish time.".to_string(),
             ));
         }
+        if let Some(cap) = config.bias_lighting.as_ref().and_then(|b| b.max_brightness) {
+            if cap == 0 || cap > 100 {
+                return Err(ControlEveningLightsProgramError::ConfigurationError(
+                    "The bias lighting brightness cap must be between 1 and 100.".to_string(),
+                ));
+            }
+        }
 
         Ok(Self {
+            name: match &config.name {
+                Some(name) => format!("{}:{}", PROGRAM_NAME, name),
+                None => PROGRAM_NAME.to_string(),
+            },
             active: config.active,
+            light: config.light.clone(),
+            sun_event: config.sun_event,
             minutes_before_sunset_start: config.minutes_before_sunset_start,
             minutes_after_sunset_peak: config.minutes_after_sunset_peak,
             minutes_after_sunset_finish: config.minutes_after_sunset_finish,
+            sunset_fallback: config.sunset_fallback,
             start_brightness: config.start_brightness,
             max_brightness: config.max_brightness,
             final_brightness: config.final_brightness,
+            requires: config.requires.clone(),
+            condition: config
+                .condition
+                .as_deref()
+                .map(Expression::parse)
+                .transpose()
+                .map_err(|e| ControlEveningLightsProgramError::ConfigurationError(e.to_string()))?,
+            only_when_dark: config.only_when_dark,
+            bias_lighting: config.bias_lighting.clone(),
+            calibrated: config.calibration.is_some(),
+            in_window: false,
+            nudge_offset: 0,
             history: None,
+            override_detector: OverrideDetector::new(
+                &config.light,
+                "Brightness",
+                config.override_tolerance,
+                config.resume_after_minutes.map(Duration::minutes),
+            ),
+            resume: None,
         })
     }
 }
 
-#[derive(Debug)]
-struct TimeBrightCoord {
-    dt: DateTime<Local>,
-    b: f32,
+/// Whether the bias lighting switch is on; an unreadable switch counts as off.
+async fn bias_lighting_on(
+    client: &reqwest::Client,
+    homebridge: &mut Homebridge,
+    bias: &BiasLightingConfig,
+) -> bool {
+    match homebridge
+        .get_characteristic(client, &bias.switch, &Characteristic::On)
+        .await
+    {
+        Ok(on) => numeric_value(&on).is_some_and(|v| v != 0.0),
+        Err(e) => {
+            warn!("Could not read '{}', ignoring it: {}", bias.switch, e);
+            false
+        }
+    }
 }
 
-impl TimeBrightCoord {
-    fn new(dt: DateTime<Local>, b: u8) -> Self {
-        return Self { dt, b: b as f32 };
+impl ControlEveningLightsProgram {
+    /// Brightness of the plain curve at `now`: rising from the start to the peak, then falling
+    /// to the end, and held at the start or final brightness outside the window.
+    fn curve_brightness(&self, now: &DateTime<Local>, sunset: &DateTime<Local>) -> f32 {
+        let (start, peak, end) = self.window(sunset);
+        // Times within the window, measured from its start, so a window may span midnight.
+        let window = TimeWindow::new(start.time(), end.time());
+        let Some(elapsed) = window.elapsed(now.time()) else {
+            return match now < &start {
+                true => self.start_brightness as f32,
+                false => self.final_brightness as f32,
+            };
+        };
+        let to_peak = peak - start;
+        let ((t1, b1), (t2, b2)) = match elapsed <= to_peak {
+            true => (
+                (Duration::zero(), self.start_brightness),
+                (to_peak, self.max_brightness),
+            ),
+            false => (
+                (to_peak, self.max_brightness),
+                (window.length(), self.final_brightness),
+            ),
+        };
+        debug!("c1: {:?} at {}, c2: {:?} at {}", b1, t1, b2, t2);
+        let span = (t2 - t1).num_seconds() as f32;
+        if span <= 0.0 {
+            return b2 as f32;
+        }
+        let slope = (b2 as f32 - b1 as f32) / span;
+        let brightness = slope * (elapsed - t1).num_seconds() as f32 + b1 as f32;
+        debug!("slope: {}, brightness: {}", slope, brightness);
+        brightness
     }
 
-    fn sec_since_midnight(&self) -> f32 {
-        return self.dt.num_seconds_from_midnight() as f32;
+    /// Offset from a resumed override, fading out linearly by the end of the window.
+    fn resume_offset(&self, now: &DateTime<Local>, sunset: &DateTime<Local>) -> f32 {
+        let Some(resume) = self.resume else {
+            return 0.0;
+        };
+        let end = *sunset + Duration::minutes(self.minutes_after_sunset_finish);
+        let total = (end - resume.when).num_seconds() as f32;
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let remaining = (end - *now).num_seconds() as f32;
+        resume.offset * (remaining / total).clamp(0.0, 1.0)
     }
-}
 
-impl ControlEveningLightsProgram {
     fn current_brightness(&self, now: &DateTime<Local>, sunset: &DateTime<Local>) -> u8 {
-        let peak_time = sunset.clone() + Duration::minutes(self.minutes_after_sunset_peak);
-        let (c1, c2) = match now <= &peak_time {
-            true => {
-                let start = TimeBrightCoord::new(
-                    sunset.clone() - Duration::minutes(self.minutes_before_sunset_start),
-                    self.start_brightness,
-                );
-                let peak = TimeBrightCoord::new(
-                    sunset.clone() + Duration::minutes(self.minutes_after_sunset_peak),
-                    self.max_brightness,
-                );
-                (start, peak)
-            }
-            false => {
-                let peak = TimeBrightCoord::new(
-                    sunset.clone() + Duration::minutes(self.minutes_after_sunset_peak),
-                    self.max_brightness,
-                );
-                let end = TimeBrightCoord::new(
-                    sunset.clone() + Duration::minutes(self.minutes_after_sunset_finish),
-                    self.final_brightness,
-                );
-                (peak, end)
-            }
-        };
+        let brightness = self.curve_brightness(now, sunset)
+            + self.resume_offset(now, sunset)
+            + self.nudge_offset as f32;
+        brightness.clamp(0.0, 100.0) as u8
+    }
 
-        debug!("c1: {:?}, c2: {:?}", c1, c2);
-        let slope = (c1.b - c2.b) / (c1.sec_since_midnight() - c2.sec_since_midnight());
-        let brightness =
-            slope * (now.num_seconds_from_midnight() as f32 - c1.sec_since_midnight()) + c1.b;
-        debug!("slope: {}, brightness: {}", slope, brightness);
-        brightness as u8
+    /// Apply a nudge of the accessory on top of the curve for the rest of the window.
+    ///
+    /// Returns false if the nudge does not concern a running ramp.
+    pub fn nudge(&mut self, accessory: &str, delta: i32) -> bool {
+        if accessory != self.light || !self.in_window {
+            return false;
+        }
+        self.nudge_offset += delta;
+        info!(
+            "Evening ramp nudged by {:+} (offset now {:+}).",
+            delta, self.nudge_offset
+        );
+        true
+    }
+
+    /// Start, peak, and end of the evening window.
+    pub fn window(
+        &self,
+        sunset: &DateTime<Local>,
+    ) -> (DateTime<Local>, DateTime<Local>, DateTime<Local>) {
+        (
+            *sunset - Duration::minutes(self.minutes_before_sunset_start),
+            *sunset + Duration::minutes(self.minutes_after_sunset_peak),
+            *sunset + Duration::minutes(self.minutes_after_sunset_finish),
+        )
+    }
+
+    /// Brightness of the plain curve, without nudges or resumed overrides, every `step` through
+    /// the window.
+    pub fn curve(&self, sunset: &DateTime<Local>, step: Duration) -> Vec<(DateTime<Local>, u8)> {
+        let (start, _, end) = self.window(sunset);
+        let mut points = Vec::new();
+        let mut at = start;
+        while at < end {
+            points.push((
+                at,
+                self.curve_brightness(&at, sunset).clamp(0.0, 100.0) as u8,
+            ));
+            at += step;
+        }
+        points.push((end, self.final_brightness));
+        points
+    }
+
+    /// Start, peak, and end of the window on a day with the given sunset.
+    pub fn preview(&self, sunset: &DateTime<Local>) -> String {
+        let (start, peak, end) = self.window(sunset);
+        format!(
+            "{}-{} (peak {})",
+            start.format("%H:%M"),
+            end.format("%H:%M"),
+            peak.format("%H:%M")
+        )
+    }
+
+    /// How the schedule looks at the current time, without reading or writing the light.
+    pub async fn explain(
+        &self,
+        client: &reqwest::Client,
+        suntimes: &mut SunTimes,
+    ) -> Result<Vec<String>, ControlEveningLightsProgramError> {
+        let sunset = suntimes
+            .event_or(client, self.sun_event, self.sunset_fallback)
+            .await?;
+        let now = clock::now();
+        let (start, peak, end) = self.window(&sunset);
+        let mut trace = vec![
+            format!("Active: {}", self.active),
+            format!(
+                "Timed against {}: {}",
+                self.sun_event,
+                time_format::show(&sunset)
+            ),
+            format!(
+                "Window: brightening from {} ({}) to {} ({}), then dimming until {} ({})",
+                time_format::show_time(start.time()),
+                self.start_brightness,
+                time_format::show_time(peak.time()),
+                self.max_brightness,
+                time_format::show_time(end.time()),
+                self.final_brightness
+            ),
+        ];
+        if now < start || end < now {
+            trace.push("Outside of operating times - nothing to do".to_string());
+            return Ok(trace);
+        }
+        let brightness = self.current_brightness(&now, &sunset);
+        trace.push(match now <= peak {
+            true => format!(
+                "Brightening: brightness {}, unless the light is already brighter",
+                brightness
+            ),
+            false => format!(
+                "Dimming: brightness {}, unless the light is already dimmer",
+                brightness
+            ),
+        });
+        trace.push(
+            "At runtime: skipped if the light was turned off or adjusted by hand during the window"
+                .to_string(),
+        );
+        if let Some(bias) = &self.bias_lighting {
+            trace.push(match bias.max_brightness {
+                Some(cap) => format!(
+                    "At runtime: brightness capped at {} while '{}' is on",
+                    cap, bias.switch
+                ),
+                None => format!("At runtime: skipped while '{}' is on", bias.switch),
+            });
+        }
+        Ok(trace)
     }
 
     pub async fn run(
@@ -125,85 +333,284 @@ impl ControlEveningLightsProgram {
         client: &reqwest::Client,
         homebridge: &mut Homebridge,
         suntimes: &mut SunTimes,
-    ) -> Result<(), ControlEveningLightsProgramError> {
+        events: &mut EventBus,
+    ) -> Result<Decision, ControlEveningLightsProgramError> {
         info!("Executing `ControlEveningLightsProgram`.");
         let sunset = suntimes
-            .sunset(client)
+            .event_or(client, self.sun_event, self.sunset_fallback)
             .await
             .map_err(ControlEveningLightsProgramError::NoSunTimesData)?;
-        let now = Local::now();
+        let now = clock::now();
 
         debug!("Now: {:?}", now);
         debug!("Sunset: {:?}", sunset);
 
-        let _start = sunset - Duration::minutes(self.minutes_before_sunset_start);
-        let _peak = sunset + Duration::minutes(self.minutes_after_sunset_peak);
-        let _end = sunset + Duration::minutes(self.minutes_after_sunset_finish);
+        let (_start, _peak, _end) = self.window(&sunset);
         let in_a = (_start <= now) && (now <= _peak);
         let in_b = (_peak < now) && (now <= _end);
 
-        debug!("Start: {}", _start);
-        debug!("Peak: {}", _peak);
-        debug!("End: {}", _end);
+        debug!("Start: {}", time_format::show(&_start));
+        debug!("Peak: {}", time_format::show(&_peak));
+        debug!("End: {}", time_format::show(&_end));
         debug!("In A: {}, in B: {}", in_a, in_b);
 
         // Check if within operating window, else exit early.
         if !in_a && !in_b {
             debug!("Outside of operating times - nothing to do.");
-            if self.history.is_some() {
-                self.history = None;
+            if self.in_window && now > _end {
+                events.clear(RAMP_STARTED);
+                events.publish(RAMP_FINISHED, &now);
             }
-            return Ok(());
+            self.in_window = false;
+            self.nudge_offset = 0;
+            self.history = None;
+            self.override_detector.reset(&now);
+            self.resume = None;
+            return Ok(Decision::skipped("Outside of operating times"));
+        }
+        if !self.in_window {
+            self.in_window = true;
+            events.clear(RAMP_FINISHED);
+            events.publish(RAMP_STARTED, &now);
         }
 
-        let current_bulb = homebridge.get_bed_light_status(client).await?.values;
+        let current_bulb = homebridge
+            .accessory(&self.light)
+            .status::<HBLightbulb>(client)
+            .await?
+            .values;
         debug!("Current bulb values: {:?}", current_bulb);
 
         if current_bulb.is_off() && self.history.is_some() {
-            info!("Bed light turned OFF after program started - doing nothing.");
-            return Ok(());
+            info!(
+                "'{}' turned OFF after program started - doing nothing.",
+                self.light
+            );
+            return Ok(Decision::skipped(format!(
+                "'{}' turned OFF after program started",
+                self.light
+            )));
         }
 
-        if let Some(history) = self.history {
-            if current_bulb.brightness != history.brightness {
-                info!("Bed light brightness adjusted externally - doing nothing.");
-                return Ok(());
+        self.override_detector.learn_tolerance(
+            homebridge
+                .tolerances
+                .lock()
+                .tolerance(&self.light, "Brightness"),
+        );
+        match self.override_detector.check(
+            &homebridge.journal.lock(),
+            current_bulb.brightness() as f64,
+            &now,
+        ) {
+            OverrideStatus::Overridden { since } => {
+                info!(
+                    "'{}' brightness adjusted externally at {} - doing nothing.",
+                    self.light,
+                    time_format::show(&since)
+                );
+                return Ok(Decision::skipped(format!(
+                    "'{}' brightness adjusted externally at {}",
+                    self.light,
+                    time_format::show(&since)
+                )));
             }
-            if history.when.minute() == now.minute() {
-                info!("Already changed values this minute - doing nothing.");
-                return Ok(());
+            OverrideStatus::Resumed { value } => {
+                info!(
+                    "Manual change settled - resuming ramp from brightness {}.",
+                    value
+                );
+                self.resume = Some(ResumeOffset {
+                    when: now,
+                    offset: value as f32 - self.curve_brightness(&now, &sunset),
+                });
+            }
+            OverrideStatus::NoOverride => {
+                if let Some(history) = self.history {
+                    if history.when.minute() == now.minute() {
+                        info!("Already changed values this minute - doing nothing.");
+                        return Ok(Decision::skipped("Already changed values this minute"));
+                    }
+                }
             }
         }
 
         let mut new_brightness = self.current_brightness(&now, &sunset);
         if in_a {
             // Only increase the brightness during step A.
-            new_brightness = max(new_brightness, current_bulb.brightness);
+            new_brightness = max(new_brightness, current_bulb.brightness());
         } else if in_b {
             // Only decrease the brightness during step B.
-            new_brightness = min(new_brightness, current_bulb.brightness);
+            new_brightness = min(new_brightness, current_bulb.brightness());
+        }
+
+        if let Some(bias) = &self.bias_lighting {
+            if bias_lighting_on(client, homebridge, bias).await {
+                let Some(cap) = bias.max_brightness else {
+                    info!("'{}' is on - leaving '{}' alone.", bias.switch, self.light);
+                    return Ok(Decision::skipped(format!("'{}' is on", bias.switch)));
+                };
+                debug!("'{}' is on - capping brightness at {}.", bias.switch, cap);
+                new_brightness = min(new_brightness, cap);
+            }
         }
 
         if new_brightness == 0 {
             info!("Skipping setting brightness to 0.");
-            return Ok(());
-        } else if new_brightness == current_bulb.brightness {
+            return Ok(Decision::skipped("Skipping setting brightness to 0"));
+        } else if new_brightness == current_bulb.brightness() {
             info!("New brightness same as current brightness - doing nothing.");
-            return Ok(());
+            return Ok(Decision::skipped(
+                "New brightness same as current brightness",
+            ));
         }
 
-        if homebridge.bed_light_is_off(client).await? {
-            homebridge.turn_bedlight_on(client).await?;
-            thread::sleep(time::Duration::from_millis(250));
+        let mut light = homebridge.accessory(&self.light);
+        if light.is_off(client).await? {
+            homebridge
+                .turn_light_on_at(client, &self.name, &self.light, new_brightness)
+                .await?;
+        } else {
+            light
+                .set(
+                    client,
+                    &self.name,
+                    &Characteristic::Brightness,
+                    new_brightness,
+                )
+                .await?;
         }
-        homebridge
-            .set_bedlight_brightness(client, new_brightness)
-            .await?;
-        thread::sleep(time::Duration::from_millis(250));
-        self.history = Some(LightsHistory {
-            when: now,
-            brightness: new_brightness,
-        });
-        Ok(())
+        self.history = Some(LightsHistory { when: now });
+        Ok(Decision::ran(format!(
+            "Set brightness to {}",
+            new_brightness
+        )))
+    }
+}
+
+impl Program for ControlEveningLightsProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::ControlEveningLights
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+
+    fn only_when_dark(&self) -> Option<&DarkHoursConfig> {
+        self.only_when_dark.as_ref()
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move {
+            Ok(self
+                .run(ctx.client, ctx.homebridge, ctx.suntimes, ctx.events)
+                .await?)
+        })
+    }
+
+    fn windows(&self, day: &SunDay) -> Vec<ProgramWindow> {
+        let Some(sunset) = day.at(self.sun_event) else {
+            return Vec::new();
+        };
+        let (start, _, end) = self.window(&sunset);
+        vec![ProgramWindow {
+            program: self.name.clone(),
+            accessory: self.light.clone(),
+            start,
+            end,
+        }]
+    }
+
+    fn preview(&self, day: &SunDay) -> Option<String> {
+        Some(match day.at(self.sun_event) {
+            Some(sunset) => format!("evening lights {}", self.preview(&sunset)),
+            None => format!("evening lights: no {}", self.sun_event),
+        })
+    }
+
+    fn explain<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        suntimes: &'a mut SunTimes,
+    ) -> LocalBoxFuture<'a, Option<anyhow::Result<Vec<String>>>> {
+        Box::pin(async move { Some(self.explain(client, suntimes).await.map_err(Into::into)) })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn curve_runs_from_start_through_peak_to_final() {
+        let config: ControlEveningLightsConfig = serde_json::from_value(json!({
+            "minutes_before_sunset_start": 30,
+            "minutes_after_sunset_peak": 30,
+            "minutes_after_sunset_finish": 60,
+            "start_brightness": 20,
+            "max_brightness": 80,
+            "final_brightness": 50
+        }))
+        .unwrap();
+        let program = ControlEveningLightsProgram::new(&config).unwrap();
+        let sunset = Local.with_ymd_and_hms(2024, 12, 1, 16, 15, 0).unwrap();
+        let curve = program.curve(&sunset, Duration::minutes(15));
+        let brightness: Vec<u8> = curve.iter().map(|(_, b)| *b).collect();
+        assert_eq!(brightness, vec![20, 35, 50, 65, 80, 65, 50]);
+        assert_eq!(curve[0].0, sunset - Duration::minutes(30));
+        assert_eq!(curve[6].0, sunset + Duration::minutes(60));
+    }
+
+    #[test]
+    fn curve_follows_a_window_past_midnight() {
+        // 22:00-01:00 with the peak at 23:30.
+        let config: ControlEveningLightsConfig = serde_json::from_value(json!({
+            "minutes_before_sunset_start": 60,
+            "minutes_after_sunset_peak": 30,
+            "minutes_after_sunset_finish": 120,
+            "start_brightness": 20,
+            "max_brightness": 80,
+            "final_brightness": 50
+        }))
+        .unwrap();
+        let program = ControlEveningLightsProgram::new(&config).unwrap();
+        let sunset = Local.with_ymd_and_hms(2024, 6, 1, 23, 0, 0).unwrap();
+        let brightness: Vec<u8> = program
+            .curve(&sunset, Duration::minutes(30))
+            .iter()
+            .map(|(_, b)| *b)
+            .collect();
+        assert_eq!(brightness, vec![20, 40, 60, 80, 70, 60, 50]);
+        let at = |h, m| Local.with_ymd_and_hms(2024, 6, 2, h, m, 0).unwrap();
+        assert_eq!(program.current_brightness(&at(0, 15), &sunset), 65);
+        assert_eq!(program.current_brightness(&at(2, 0), &sunset), 50);
+        let before = Local.with_ymd_and_hms(2024, 6, 1, 21, 0, 0).unwrap();
+        assert_eq!(program.current_brightness(&before, &sunset), 20);
     }
 }
@@ -0,0 +1,235 @@
+use crate::characteristic::Characteristic;
+use crate::clock;
+use crate::configuration::HttpPollConfig;
+use crate::decisions::Decision;
+use crate::events::EventBus;
+use crate::expression::Expression;
+use crate::homebridge::{HBError, Homebridge};
+use crate::hysteresis::Hysteresis;
+use crate::override_detector::numeric_value;
+use crate::programs::{Program, ProgramContext, ProgramId};
+use chrono::{DateTime, Duration, Local};
+use futures::future::LocalBoxFuture;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use std::any::Any;
+use std::time::Duration as StdDuration;
+
+pub const PROGRAM_NAME: &str = "http_poll";
+
+const POLL_TIMEOUT_SECS: u64 = 30;
+
+#[derive(thiserror::Error, Debug)]
+pub enum HttpPollProgramError {
+    #[error("{0}")]
+    ConfigError(String),
+    #[error("Error during Homebridge interaction.")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("Failed to poll URL: {0}")]
+    FetchError(#[from] reqwest::Error),
+    #[error("{0}")]
+    ValueError(String),
+}
+
+#[derive(Debug)]
+struct HttpPoll {
+    config: HttpPollConfig,
+    condition: Option<Expression>,
+    hysteresis: Hysteresis,
+    last_poll: Option<DateTime<Local>>,
+}
+
+impl HttpPoll {
+    fn due(&self, now: &DateTime<Local>) -> bool {
+        self.last_poll.map_or(true, |t| {
+            *now - t >= Duration::minutes(self.config.interval_minutes)
+        })
+    }
+
+    async fn fetch_value(&self, client: &reqwest::Client) -> Result<f64, HttpPollProgramError> {
+        let body = client
+            .get(&self.config.url)
+            .timeout(StdDuration::from_secs(POLL_TIMEOUT_SECS))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        let value = body.pointer(&self.config.pointer).ok_or_else(|| {
+            HttpPollProgramError::ValueError(format!(
+                "No value at '{}' in the response.",
+                self.config.pointer
+            ))
+        })?;
+        numeric_value(value).ok_or_else(|| {
+            HttpPollProgramError::ValueError(format!(
+                "Value {} at '{}' is not a number.",
+                value, self.config.pointer
+            ))
+        })
+    }
+
+    async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        now: &DateTime<Local>,
+    ) -> Result<(), HttpPollProgramError> {
+        self.last_poll = Some(*now);
+        let value = self.fetch_value(client).await?;
+        let was_on = self.hysteresis.is_on();
+        let is_on = self.hysteresis.update(value, now);
+        debug!(
+            "Poll '{}' read {} (on: {}).",
+            self.config.name, value, is_on
+        );
+        if is_on == was_on {
+            return Ok(());
+        }
+        info!(
+            "Poll '{}' read {} - switching '{}' {}.",
+            self.config.name,
+            value,
+            self.config.accessory,
+            if is_on { "on" } else { "off" }
+        );
+        let writes = match is_on {
+            true => &self.config.on_writes,
+            false => &self.config.off_writes,
+        };
+        let writes: Vec<(Characteristic, Value)> = writes
+            .iter()
+            .map(|w| (w.characteristic.clone(), w.value.clone()))
+            .collect();
+        homebridge
+            .apply_values(client, PROGRAM_NAME, &self.config.accessory, &writes, false)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Config-declared integrations: poll a JSON API and write an accessory on threshold crossings.
+#[derive(Debug)]
+pub struct HttpPollProgram {
+    polls: Vec<HttpPoll>,
+}
+
+impl HttpPollProgram {
+    pub fn new(configs: &[HttpPollConfig]) -> Result<Self, HttpPollProgramError> {
+        for config in configs.iter() {
+            if config.interval_minutes < 1 {
+                return Err(HttpPollProgramError::ConfigError(format!(
+                    "Poll '{}' must have an interval of at least one minute.",
+                    config.name
+                )));
+            }
+            if !config.pointer.is_empty() && !config.pointer.starts_with('/') {
+                return Err(HttpPollProgramError::ConfigError(format!(
+                    "Pointer of poll '{}' must be empty or start with '/'.",
+                    config.name
+                )));
+            }
+            if config.on_writes.is_empty() && config.off_writes.is_empty() {
+                return Err(HttpPollProgramError::ConfigError(format!(
+                    "Poll '{}' writes nothing.",
+                    config.name
+                )));
+            }
+        }
+        Ok(Self {
+            polls: configs
+                .iter()
+                .map(|c| {
+                    Ok(HttpPoll {
+                        config: c.clone(),
+                        condition: c
+                            .condition
+                            .as_deref()
+                            .map(Expression::parse)
+                            .transpose()
+                            .map_err(|e| HttpPollProgramError::ConfigError(e.to_string()))?,
+                        hysteresis: Hysteresis::from_config(&c.thresholds),
+                        last_poll: None,
+                    })
+                })
+                .collect::<Result<Vec<_>, HttpPollProgramError>>()?,
+        })
+    }
+
+    /// Run the polls that are due; a failing poll does not hold up the others.
+    pub async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        events: &EventBus,
+    ) -> Result<Decision, HttpPollProgramError> {
+        info!("Executing `HttpPollProgram`.");
+        // A reading would move the hysteresis without the accessory following it.
+        if homebridge.observe_only {
+            return Err(HBError::ObserveOnly.into());
+        }
+        let now = clock::now();
+        let mut polled: Vec<String> = Vec::new();
+        for poll in self.polls.iter_mut().filter(|p| p.due(&now)) {
+            let missing = events.missing(&poll.config.requires);
+            if !missing.is_empty() {
+                debug!(
+                    "Poll '{}' waiting on conditions: {}.",
+                    poll.config.name,
+                    missing.join(", ")
+                );
+                continue;
+            }
+            if let Some(condition) = &poll.condition {
+                match condition.holds(client, homebridge).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        debug!("Poll '{}' waiting on its condition.", poll.config.name);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Poll '{}' waiting - could not evaluate its condition: {}",
+                            poll.config.name, e
+                        );
+                        continue;
+                    }
+                }
+            }
+            polled.push(poll.config.name.clone());
+            match poll.run(client, homebridge, &now).await {
+                Ok(()) => {}
+                Err(HttpPollProgramError::HomebridgeInteraction(e)) => {
+                    error!("Poll '{}' could not write: {}", poll.config.name, e)
+                }
+                Err(e) => warn!("Poll '{}' failed: {}", poll.config.name, e),
+            }
+        }
+        Ok(match polled.is_empty() {
+            true => Decision::skipped("No poll due"),
+            false => Decision::ran(format!("Polled {}", polled.join(", "))),
+        })
+    }
+}
+
+/// Each poll checks its own condition, so the loop runs the program every time.
+impl Program for HttpPollProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::HttpPoll
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move { Ok(self.run(ctx.client, ctx.homebridge, ctx.events).await?) })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,435 @@
+use crate::clock;
+use crate::configuration::{IrrigationConfig, IrrigationZoneConfig};
+use crate::decisions::Decision;
+use crate::expression::Expression;
+use crate::homebridge::{HBError, Homebridge};
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::suntimes::{SunDay, SunTimes, SuntimesError};
+use crate::time_format;
+use crate::weather::Weather;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, Weekday};
+use futures::future::LocalBoxFuture;
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::collections::BTreeMap;
+
+pub const PROGRAM_NAME: &str = "irrigation";
+
+/// Longest duration HomeKit valves accept for `SetDuration`.
+const MAX_DURATION_MINUTES: u32 = 60;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IrrigationProgramError {
+    #[error("Error during Homebridge interaction.")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("{0}")]
+    ConfigError(String),
+    #[error("{0}")]
+    NoSunTimesData(#[from] SuntimesError),
+}
+
+#[derive(Debug)]
+pub struct IrrigationZone {
+    pub valve: String,
+    pub minutes_after_sunrise: i64,
+    pub sunrise_fallback: Option<NaiveTime>,
+    pub duration_minutes: u32,
+    pub days: Vec<Weekday>,
+    last_run: Option<NaiveDate>,
+    running_until: Option<DateTime<Local>>,
+}
+
+impl IrrigationZone {
+    fn new(config: &IrrigationZoneConfig) -> Result<Self, IrrigationProgramError> {
+        if config.duration_minutes == 0 || config.duration_minutes > MAX_DURATION_MINUTES {
+            error!("Logical errors in `IrrigationProgram` configuration.");
+            return Err(IrrigationProgramError::ConfigError(format!(
+                "Watering duration for '{}' must be between 1 and {} minutes.",
+                config.valve, MAX_DURATION_MINUTES
+            )));
+        }
+        Ok(Self {
+            valve: config.valve.clone(),
+            minutes_after_sunrise: config.minutes_after_sunrise,
+            sunrise_fallback: config.sunrise_fallback,
+            duration_minutes: config.duration_minutes,
+            days: config.days.clone(),
+            last_run: None,
+            running_until: None,
+        })
+    }
+
+    fn scheduled_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// Skip watering when at least `threshold_mm` of rain fell or is forecast around now.
+#[derive(Debug, Clone, Copy)]
+pub struct RainCheck {
+    pub threshold_mm: f32,
+    pub lookback_hours: i64,
+    pub lookahead_hours: i64,
+}
+
+impl RainCheck {
+    async fn rain_expected(
+        &self,
+        client: &reqwest::Client,
+        weather: &mut Weather,
+        now: &DateTime<Local>,
+    ) -> bool {
+        let from = *now - Duration::hours(self.lookback_hours);
+        let to = *now + Duration::hours(self.lookahead_hours);
+        match weather.precipitation(client, &from, &to).await {
+            Ok(rain) => {
+                debug!("Precipitation from {} to {}: {} mm.", from, to, rain);
+                rain >= self.threshold_mm
+            }
+            Err(e) => {
+                warn!("No weather data, watering anyway: {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IrrigationProgram {
+    pub active: bool,
+    pub rain_check: Option<RainCheck>,
+    pub zones: Vec<IrrigationZone>,
+    pub requires: Vec<String>,
+    pub condition: Option<Expression>,
+}
+
+impl IrrigationProgram {
+    pub fn new(config: &IrrigationConfig) -> Result<Self, IrrigationProgramError> {
+        info!("Creating an `IrrigationProgram` object.");
+        let zones = config
+            .zones
+            .iter()
+            .map(IrrigationZone::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            active: config.active,
+            rain_check: config.rain_threshold_mm.map(|threshold_mm| RainCheck {
+                threshold_mm,
+                lookback_hours: config.rain_lookback_hours,
+                lookahead_hours: config.rain_lookahead_hours,
+            }),
+            zones,
+            requires: config.requires.clone(),
+            condition: config
+                .condition
+                .as_deref()
+                .map(Expression::parse)
+                .transpose()
+                .map_err(|e| IrrigationProgramError::ConfigError(e.to_string()))?,
+        })
+    }
+}
+
+impl IrrigationProgram {
+    /// Day each valve was last handled.
+    pub fn last_runs(&self) -> BTreeMap<String, NaiveDate> {
+        self.zones
+            .iter()
+            .filter_map(|zone| zone.last_run.map(|day| (zone.valve.clone(), day)))
+            .collect()
+    }
+
+    /// Take over the days valves were handled by an earlier run of the controller.
+    pub fn restore_last_runs(&mut self, last_runs: &BTreeMap<String, NaiveDate>) {
+        for zone in self.zones.iter_mut() {
+            if let Some(day) = last_runs.get(&zone.valve) {
+                zone.last_run = zone.last_run.max(Some(*day));
+            }
+        }
+    }
+
+    /// Watering times of the zones scheduled on a day with the given sunrise.
+    pub fn preview(&self, sunrise: &DateTime<Local>) -> Vec<String> {
+        self.zones
+            .iter()
+            .filter(|zone| zone.scheduled_on(sunrise.weekday()))
+            .map(|zone| {
+                let start = *sunrise + Duration::minutes(zone.minutes_after_sunrise);
+                let end = start + Duration::minutes(zone.duration_minutes as i64);
+                format!(
+                    "'{}' {}-{}",
+                    zone.valve,
+                    start.format("%H:%M"),
+                    end.format("%H:%M")
+                )
+            })
+            .collect()
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        suntimes: &mut SunTimes,
+        weather: &mut Weather,
+    ) -> Result<Decision, IrrigationProgramError> {
+        info!("Executing `IrrigationProgram`.");
+        if !self.active {
+            debug!("Program inactive - nothing to do.");
+            return Ok(Decision::skipped("Program inactive"));
+        }
+
+        let now = clock::now();
+        let today = now.date_naive();
+        // Only look at the weather once a zone is actually due.
+        let mut skip_for_rain: Option<bool> = None;
+        // What happened to each zone, for the decision.
+        let mut notes: Vec<String> = Vec::new();
+        let mut acted = false;
+
+        for zone in self.zones.iter_mut() {
+            if let Some(until) = zone.running_until {
+                if now < until {
+                    let until = time_format::show(&until);
+                    debug!("Watering '{}' until {}.", zone.valve, until);
+                    notes.push(format!("Watering '{}' until {}", zone.valve, until));
+                    continue;
+                }
+                // Valves normally close themselves after `SetDuration`; make sure this one did.
+                if homebridge
+                    .get_valve_status(client, &zone.valve)
+                    .await?
+                    .values
+                    .is_active()
+                {
+                    homebridge
+                        .set_valve_active(client, PROGRAM_NAME, &zone.valve, false)
+                        .await?;
+                }
+                info!("Finished watering '{}'.", zone.valve);
+                zone.running_until = None;
+                notes.push(format!("Finished watering '{}'", zone.valve));
+                acted = true;
+                continue;
+            }
+
+            if zone.last_run == Some(today) {
+                debug!("Already handled '{}' today - nothing to do.", zone.valve);
+                notes.push(format!("Already handled '{}' today", zone.valve));
+                continue;
+            }
+            if !zone.scheduled_on(now.weekday()) {
+                debug!("'{}' not scheduled today - nothing to do.", zone.valve);
+                notes.push(format!("'{}' not scheduled today", zone.valve));
+                continue;
+            }
+
+            let start = suntimes.sunrise_or(client, zone.sunrise_fallback).await?
+                + Duration::minutes(zone.minutes_after_sunrise);
+            let end = start + Duration::minutes(zone.duration_minutes as i64);
+            if now < start {
+                let start = time_format::show(&start);
+                debug!("Not yet time to water '{}' (starts {}).", zone.valve, start);
+                notes.push(format!("'{}' starts {}", zone.valve, start));
+                continue;
+            }
+            if end <= now {
+                warn!("Missed the watering window for '{}' today.", zone.valve);
+                zone.last_run = Some(today);
+                notes.push(format!("Missed the watering window for '{}'", zone.valve));
+                continue;
+            }
+            let skip = match (skip_for_rain, self.rain_check) {
+                (Some(skip), _) => skip,
+                (None, Some(rain_check)) => {
+                    let skip = rain_check.rain_expected(client, weather, &now).await;
+                    skip_for_rain = Some(skip);
+                    skip
+                }
+                (None, None) => false,
+            };
+            if skip {
+                info!(
+                    "Rain recent or expected - skipping watering '{}'.",
+                    zone.valve
+                );
+                zone.last_run = Some(today);
+                notes.push(format!(
+                    "Rain recent or expected - skipped '{}'",
+                    zone.valve
+                ));
+                continue;
+            }
+
+            info!(
+                "Watering '{}' for {} minutes.",
+                zone.valve, zone.duration_minutes
+            );
+            homebridge
+                .set_valve_duration(
+                    client,
+                    PROGRAM_NAME,
+                    &zone.valve,
+                    zone.duration_minutes * 60,
+                )
+                .await?;
+            homebridge
+                .set_valve_active(client, PROGRAM_NAME, &zone.valve, true)
+                .await?;
+            zone.last_run = Some(today);
+            zone.running_until = Some(end);
+            notes.push(format!(
+                "Started watering '{}' for {} minutes",
+                zone.valve, zone.duration_minutes
+            ));
+            acted = true;
+        }
+        let reason = notes.join("; ");
+        Ok(match acted {
+            true => Decision::ran(reason),
+            false => Decision::skipped(reason),
+        })
+    }
+}
+
+impl Program for IrrigationProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::Irrigation
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+
+    /// Persists the days valves were handled, so restarts and one-shot runs do not water twice
+    /// a day.
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move {
+            let last_runs = ctx
+                .state
+                .lock()
+                .expect("State lock poisoned.")
+                .store
+                .state()
+                .irrigation_last_runs
+                .clone();
+            self.restore_last_runs(&last_runs);
+            let result = self
+                .run(ctx.client, ctx.homebridge, ctx.suntimes, ctx.weather)
+                .await;
+            let handled = self.last_runs();
+            if handled != last_runs {
+                let mut state = ctx.state.lock().expect("State lock poisoned.");
+                if let Err(e) = state.store.update(|s| s.irrigation_last_runs = handled) {
+                    warn!("Failed to persist irrigation runs: {}", e);
+                }
+            }
+            Ok(result?)
+        })
+    }
+
+    fn preview(&self, day: &SunDay) -> Option<String> {
+        let zones = self.preview(&day.sunrise);
+        (!zones.is_empty()).then(|| format!("irrigation {}", zones.join(", ")))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::mock_bridge;
+    use crate::configuration::SuntimesSource;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn waters_unless_rain_fell_or_is_forecast() {
+        let (url, valves) = mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let client = reqwest::Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        let mut suntimes =
+            SunTimes::new(-71.06, 42.36).with_source(SuntimesSource::Calculated, None);
+        let now = clock::now();
+        let sunrise = suntimes.sunrise(&client).await.unwrap();
+        // The zone is due from five minutes ago.
+        let config: IrrigationConfig = serde_json::from_value(json!({
+            "zones": [{
+                "valve": valves[0],
+                "minutes_after_sunrise": (now - sunrise).num_minutes() - 5,
+                "duration_minutes": 30
+            }],
+            "rain_threshold_mm": 5.0
+        }))
+        .unwrap();
+        let forecast = |precipitation: Value| {
+            let hour = |h: i64| (now + Duration::hours(h)).timestamp();
+            json!({
+                "hourly": {
+                    "time": [hour(-6), hour(-1), hour(1), hour(6)],
+                    "precipitation": precipitation
+                }
+            })
+            .to_string()
+        };
+        let mut weather = Weather::new(-71.06, 42.36);
+
+        weather
+            .load(&forecast(json!([4.0, null, 1.5, 0.0])))
+            .unwrap();
+        let mut program = IrrigationProgram::new(&config).unwrap();
+        let decision = program
+            .run(&client, &mut homebridge, &mut suntimes, &mut weather)
+            .await
+            .unwrap();
+        assert_eq!(
+            decision.reason,
+            format!("Rain recent or expected - skipped '{}'", valves[0])
+        );
+        assert!(homebridge.journal.lock().iter().next().is_none());
+        // Handled for today: it is not watered after all once the rain passes.
+        let decision = program
+            .run(&client, &mut homebridge, &mut suntimes, &mut weather)
+            .await
+            .unwrap();
+        assert_eq!(
+            decision.reason,
+            format!("Already handled '{}' today", valves[0])
+        );
+
+        weather
+            .load(&forecast(json!([1.0, null, 0.5, null])))
+            .unwrap();
+        let mut program = IrrigationProgram::new(&config).unwrap();
+        let decision = program
+            .run(&client, &mut homebridge, &mut suntimes, &mut weather)
+            .await
+            .unwrap();
+        assert_eq!(
+            decision.reason,
+            format!("Started watering '{}' for 30 minutes", valves[0])
+        );
+        let journal = homebridge.journal.lock();
+        let duration = journal.last_write(&valves[0], "SetDuration");
+        assert_eq!(duration.map(|w| w.after.clone()), Some(json!(1800)));
+        let active = journal.last_write(&valves[0], "Active");
+        assert_eq!(active.map(|w| w.after.clone()), Some(json!(1)));
+    }
+}
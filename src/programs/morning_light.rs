@@ -0,0 +1,516 @@
+use crate::clock;
+use crate::configuration::{DarkHoursConfig, DaylightCheckConfig, MorningLightConfig};
+use crate::decisions::Decision;
+use crate::expression::Expression;
+use crate::homebridge::{HBError, Homebridge};
+use crate::overlaps::ProgramWindow;
+use crate::override_detector::{OverrideDetector, OverrideStatus};
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::suntimes::{SunDay, SunTimes};
+use crate::time_format;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, Timelike};
+use futures::future::LocalBoxFuture;
+use log::{debug, error, info, warn};
+use serde_json::{json, Map, Value};
+use std::any::Any;
+
+pub const PROGRAM_NAME: &str = "morning_light";
+
+/// Brightness difference from the last write still treated as the program's own value.
+const OVERRIDE_TOLERANCE: f64 = 1.0;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MorningLightProgramError {
+    #[error("{0}")]
+    ParseError(String),
+    #[error("Error during Homebridge interaction.")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("{0}")]
+    ConfigError(String),
+}
+
+/// What the program decided for the current morning.
+#[derive(Debug, Clone, Copy)]
+struct MorningPlan {
+    day: NaiveDate,
+    /// Fraction of the final brightness to fade to (0 skips the fade).
+    scale: f32,
+    started: bool,
+    stopped: bool,
+    last_write: Option<DateTime<Local>>,
+}
+
+#[derive(Debug)]
+pub struct MorningLightProgram {
+    pub active: bool,
+    pub light: String,
+    pub start: NaiveTime,
+    pub duration: u32,
+    pub final_brightness: u8,
+    pub start_hue: u32,
+    pub final_hue: u32,
+    pub daylight_check: Option<DaylightCheckConfig>,
+    pub requires: Vec<String>,
+    pub condition: Option<Expression>,
+    pub only_when_dark: Option<DarkHoursConfig>,
+    plan: Option<MorningPlan>,
+    override_detector: OverrideDetector,
+}
+
+impl MorningLightProgram {
+    pub fn new(config: &MorningLightConfig) -> Result<Self, MorningLightProgramError> {
+        info!("Creating a `MorningLightProgram` object.");
+        let start = NaiveTime::parse_from_str(&config.start, "%H:%M:%S").map_err(|e| {
+            MorningLightProgramError::ParseError(format!("Error parsing start time: {}", e))
+        })?;
+        if config.duration == 0 {
+            error!("Logical errors in `MorningLightProgram` configuration.");
+            return Err(MorningLightProgramError::ConfigError(
+                "The fade duration must be at least one minute.".to_string(),
+            ));
+        }
+        if config.final_brightness == 0 || config.final_brightness > 100 {
+            return Err(MorningLightProgramError::ConfigError(
+                "The final brightness must be between 1 and 100.".to_string(),
+            ));
+        }
+        if let Some(check) = &config.daylight_check {
+            if check.reduce_above_lux > check.skip_above_lux {
+                return Err(MorningLightProgramError::ConfigError(
+                    "`reduce_above_lux` must not exceed `skip_above_lux`.".to_string(),
+                ));
+            }
+        }
+        Ok(Self {
+            active: config.active,
+            light: config.light.clone(),
+            start,
+            duration: config.duration,
+            final_brightness: config.final_brightness,
+            start_hue: config.start_hue,
+            final_hue: config.final_hue,
+            daylight_check: config.daylight_check.clone(),
+            requires: config.requires.clone(),
+            condition: config
+                .condition
+                .as_deref()
+                .map(Expression::parse)
+                .transpose()
+                .map_err(|e| MorningLightProgramError::ConfigError(e.to_string()))?,
+            only_when_dark: config.only_when_dark,
+            plan: None,
+            override_detector: OverrideDetector::new(
+                &config.light,
+                "Brightness",
+                OVERRIDE_TOLERANCE,
+                None,
+            ),
+        })
+    }
+}
+
+impl MorningLightProgram {
+    /// A single fade starting at `now`, built from the program's configuration with some of
+    /// its keys (e.g. `duration`, `final_brightness`) overridden.
+    pub fn triggered(
+        config: &MorningLightConfig,
+        overrides: &Map<String, Value>,
+        now: &DateTime<Local>,
+    ) -> Result<Self, MorningLightProgramError> {
+        let mut values = serde_json::to_value(config)
+            .map_err(|e| MorningLightProgramError::ParseError(e.to_string()))?;
+        let fields = values.as_object_mut().expect("Configuration is an object.");
+        for (key, value) in overrides.iter() {
+            if key == "start" || !fields.contains_key(key) {
+                return Err(MorningLightProgramError::ConfigError(format!(
+                    "'{}' cannot be overridden.",
+                    key
+                )));
+            }
+            fields.insert(key.clone(), value.clone());
+        }
+        fields.insert(
+            "start".to_string(),
+            json!(now.format("%H:%M:%S").to_string()),
+        );
+        fields.insert("active".to_string(), json!(true));
+        let config: MorningLightConfig = serde_json::from_value(values).map_err(|e| {
+            MorningLightProgramError::ConfigError(format!("Invalid override: {}", e))
+        })?;
+        Self::new(&config)
+    }
+
+    /// Whether the fade of the day of `now` is over.
+    pub fn finished(&self, now: &DateTime<Local>) -> bool {
+        self.fade_window(now).map_or(true, |(_, end)| end < *now)
+    }
+}
+
+/// Fraction of the fade to keep at the given light level.
+fn daylight_scale(check: &DaylightCheckConfig, lux: f64) -> f32 {
+    if lux >= check.skip_above_lux {
+        0.0
+    } else if lux <= check.reduce_above_lux {
+        1.0
+    } else {
+        let span = check.skip_above_lux - check.reduce_above_lux;
+        (1.0 - (lux - check.reduce_above_lux) / span) as f32
+    }
+}
+
+impl MorningLightProgram {
+    /// Decide how much of the fade to run this morning from the room's light level.
+    async fn plan_scale(
+        &self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        now: &DateTime<Local>,
+    ) -> f32 {
+        let Some(check) = &self.daylight_check else {
+            return 1.0;
+        };
+        if !check.days.is_empty() && !check.days.contains(&now.weekday()) {
+            debug!("No daylight check on {}.", now.weekday());
+            return 1.0;
+        }
+        match homebridge
+            .get_light_sensor_status(client, &check.lux_sensor)
+            .await
+        {
+            Ok(sensor) => {
+                let lux = sensor.values.current_ambient_light_level;
+                let scale = daylight_scale(check, lux);
+                info!(
+                    "Room light level is {} lux - fading to {:.0}% of the final brightness.",
+                    lux,
+                    scale * 100.0
+                );
+                scale
+            }
+            Err(e) => {
+                warn!(
+                    "Could not read '{}', running the full fade: {}",
+                    check.lux_sensor, e
+                );
+                1.0
+            }
+        }
+    }
+
+    /// Start and end of today's fade.
+    pub fn fade_window(&self, now: &DateTime<Local>) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        let start = now
+            .date_naive()
+            .and_time(self.start)
+            .and_local_timezone(Local)
+            .earliest()?;
+        Some((start, start + Duration::minutes(self.duration as i64)))
+    }
+
+    /// Progress, brightness, and hue of the fade at `now`.
+    fn fade_values(
+        &self,
+        now: &DateTime<Local>,
+        start: &DateTime<Local>,
+        end: &DateTime<Local>,
+        scale: f32,
+    ) -> (f32, u8, f32) {
+        let progress = ((*now - *start).num_seconds() as f32
+            / (*end - *start).num_seconds() as f32)
+            .clamp(0.0, 1.0);
+        let brightness = (self.final_brightness as f32 * scale * progress).round() as u8;
+        let brightness = brightness.max(1);
+        let hue =
+            self.start_hue as f32 + (self.final_hue as f32 - self.start_hue as f32) * progress;
+        (progress, brightness, hue)
+    }
+
+    /// How the fade looks at the current time, without reading or writing accessories.
+    /// Start and end of the fade on the day of `day`.
+    pub fn preview(&self, day: &DateTime<Local>) -> Option<String> {
+        let (start, end) = self.fade_window(day)?;
+        Some(format!("{}-{}", start.format("%H:%M"), end.format("%H:%M")))
+    }
+
+    pub fn explain(&self) -> Vec<String> {
+        let now = clock::now();
+        let mut trace = vec![format!("Active: {}", self.active)];
+        let Some((start, end)) = self.fade_window(&now) else {
+            trace.push("Start time does not exist today - nothing to do".to_string());
+            return trace;
+        };
+        trace.push(format!(
+            "Fade from {} to {}",
+            time_format::show_time(start.time()),
+            time_format::show_time(end.time())
+        ));
+        if now < start || end < now {
+            trace.push("Outside of operating times - nothing to do".to_string());
+            return trace;
+        }
+        let (progress, brightness, hue) = self.fade_values(&now, &start, &end, 1.0);
+        trace.push(format!(
+            "{:.0}% through the fade: brightness {}, hue {}",
+            progress * 100.0,
+            brightness,
+            hue.round()
+        ));
+        if let Some(check) = &self.daylight_check {
+            trace.push(format!(
+                "At runtime: brightness reduced above {} lux and the fade skipped above {} lux of '{}'",
+                check.reduce_above_lux, check.skip_above_lux, check.lux_sensor
+            ));
+        }
+        trace
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+    ) -> Result<Decision, MorningLightProgramError> {
+        info!("Executing `MorningLightProgram`.");
+        if !self.active {
+            debug!("Program inactive - nothing to do.");
+            return Ok(Decision::skipped("Program inactive"));
+        }
+
+        let now = clock::now();
+        let Some((start, end)) = self.fade_window(&now) else {
+            debug!("Start time does not exist today - nothing to do.");
+            return Ok(Decision::skipped("Start time does not exist today"));
+        };
+        debug!(
+            "Fade from {} to {}.",
+            time_format::show(&start),
+            time_format::show(&end)
+        );
+        if now < start || end < now {
+            debug!("Outside of operating times - nothing to do.");
+            return Ok(Decision::skipped("Outside of operating times"));
+        }
+
+        let plan = match self.plan.filter(|p| p.day == now.date_naive()) {
+            Some(plan) => plan,
+            None => {
+                self.override_detector.reset(&now);
+                MorningPlan {
+                    day: now.date_naive(),
+                    scale: self.plan_scale(client, homebridge, &now).await,
+                    started: false,
+                    stopped: false,
+                    last_write: None,
+                }
+            }
+        };
+        self.plan = Some(plan);
+        if plan.stopped {
+            debug!("Fade stopped for today - nothing to do.");
+            return Ok(Decision::skipped("Fade stopped for today"));
+        }
+        if plan.scale <= 0.0 {
+            info!("Room already bright - skipping the fade today.");
+            self.stop();
+            return Ok(Decision::skipped(
+                "Room already bright - skipping the fade today",
+            ));
+        }
+        if let Some(last_write) = plan.last_write {
+            if last_write.minute() == now.minute() {
+                debug!("Already changed values this minute - nothing to do.");
+                return Ok(Decision::skipped("Already changed values this minute"));
+            }
+        }
+
+        let current = homebridge
+            .get_light_status(client, &self.light)
+            .await?
+            .values;
+        if plan.started {
+            if current.is_off() {
+                info!("Light turned OFF during the fade - stopping for today.");
+                self.stop();
+                return Ok(Decision::skipped(
+                    "Light turned OFF during the fade - stopping for today",
+                ));
+            }
+            self.override_detector.learn_tolerance(
+                homebridge
+                    .tolerances
+                    .lock()
+                    .tolerance(&self.light, "Brightness"),
+            );
+            if let OverrideStatus::Overridden { since } = self.override_detector.check(
+                &homebridge.journal.lock(),
+                current.brightness() as f64,
+                &now,
+            ) {
+                info!(
+                    "Brightness adjusted externally at {} - stopping for today.",
+                    time_format::show(&since)
+                );
+                self.stop();
+                return Ok(Decision::skipped(format!(
+                    "Brightness adjusted externally at {} - stopping for today",
+                    time_format::show(&since)
+                )));
+            }
+        } else if current.is_on() {
+            info!("Light already ON at the start of the fade - leaving it alone.");
+            self.stop();
+            return Ok(Decision::skipped(
+                "Light already ON at the start of the fade - leaving it alone",
+            ));
+        }
+
+        let (progress, brightness, hue) = self.fade_values(&now, &start, &end, plan.scale);
+        debug!(
+            "Progress: {}, brightness: {}, hue: {}",
+            progress, brightness, hue
+        );
+
+        if !plan.started {
+            homebridge
+                .turn_light_on_at(client, PROGRAM_NAME, &self.light, brightness)
+                .await?;
+        } else if brightness != current.brightness() {
+            homebridge
+                .set_light_brightness(client, PROGRAM_NAME, &self.light, brightness)
+                .await?;
+        }
+        homebridge
+            .set_light_hue(client, PROGRAM_NAME, &self.light, hue.round() as u32)
+            .await?;
+        self.plan = Some(MorningPlan {
+            started: true,
+            last_write: Some(now),
+            ..plan
+        });
+        Ok(Decision::ran(format!(
+            "Set brightness to {} and hue to {}",
+            brightness,
+            hue.round()
+        )))
+    }
+
+    fn stop(&mut self) {
+        if let Some(plan) = self.plan.as_mut() {
+            plan.stopped = true;
+        }
+    }
+}
+
+impl Program for MorningLightProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::MorningLight
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+
+    fn only_when_dark(&self) -> Option<&DarkHoursConfig> {
+        self.only_when_dark.as_ref()
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move { Ok(self.run(ctx.client, ctx.homebridge).await?) })
+    }
+
+    fn windows(&self, day: &SunDay) -> Vec<ProgramWindow> {
+        self.fade_window(&day.sunrise)
+            .map(|(start, end)| ProgramWindow {
+                program: PROGRAM_NAME.to_string(),
+                accessory: self.light.clone(),
+                start,
+                end,
+            })
+            .into_iter()
+            .collect()
+    }
+
+    fn preview(&self, day: &SunDay) -> Option<String> {
+        self.preview(&day.sunrise)
+            .map(|fade| format!("morning light {}", fade))
+    }
+
+    fn explain<'a>(
+        &'a self,
+        _client: &'a reqwest::Client,
+        _suntimes: &'a mut SunTimes,
+    ) -> LocalBoxFuture<'a, Option<anyhow::Result<Vec<String>>>> {
+        Box::pin(async move { Some(Ok(self.explain())) })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::mock_bridge;
+    use crate::characteristic::Characteristic;
+
+    #[tokio::test]
+    async fn fade_starts_once_the_grace_period_is_over() {
+        let (url, lights) = mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let client = reqwest::Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        homebridge
+            .detached()
+            .accessory(&lights[0])
+            .set(&client, "test", &Characteristic::On, 0)
+            .await
+            .unwrap();
+        let config: MorningLightConfig = serde_json::from_value(json!({
+            "light": lights[0], "start": "06:00:00", "duration": 30,
+            "final_brightness": 80, "start_hue": 20, "final_hue": 40
+        }))
+        .unwrap();
+        let mut program =
+            MorningLightProgram::triggered(&config, &Map::new(), &clock::now()).unwrap();
+
+        // Within the grace period the light is left off, and the fade is not marked started.
+        homebridge.observe_only = true;
+        let error = anyhow::Error::from(program.run(&client, &mut homebridge).await.unwrap_err());
+        assert!(crate::homebridge::observing_only(&error));
+        assert!(homebridge
+            .journal
+            .lock()
+            .last_write(&lights[0], "On")
+            .is_none());
+        assert!(homebridge
+            .get_light_status(&client, &lights[0])
+            .await
+            .unwrap()
+            .values
+            .is_off());
+
+        homebridge.observe_only = false;
+        let decision = program.run(&client, &mut homebridge).await.unwrap();
+        assert!(decision.reason.starts_with("Set brightness to 1"));
+        assert!(homebridge
+            .get_light_status(&client, &lights[0])
+            .await
+            .unwrap()
+            .values
+            .is_on());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::configuration::Configuration;
+use crate::programs::color_shift::{ColorShiftProgram, ColorShiftProgramError};
+use crate::programs::condition_actions::ConditionActionsProgram;
+use crate::programs::control_evening_lights::ControlEveningLightsProgram;
+use crate::programs::http_poll::HttpPollProgram;
+use crate::programs::irrigation::IrrigationProgram;
+use crate::programs::morning_light::MorningLightProgram;
+use crate::programs::script::ScriptProgram;
+use crate::programs::turn_morning_lights_off::TurnMorningLightsOffProgram;
+use crate::programs::{Program, ProgramId};
+
+/// Build the instances of a program from its section of the configuration, none if it is not
+/// configured.
+fn build(id: ProgramId, config: &Configuration) -> Result<Vec<Box<dyn Program>>, String> {
+    fn boxed<P: Program, E: ToString>(
+        program: Result<Option<P>, E>,
+    ) -> Result<Vec<Box<dyn Program>>, String> {
+        program
+            .map(|p| {
+                p.map(|p| Box::new(p) as Box<dyn Program>)
+                    .into_iter()
+                    .collect()
+            })
+            .map_err(|e| e.to_string())
+    }
+    match id {
+        ProgramId::MorningLight => boxed(
+            config
+                .morning_light
+                .as_ref()
+                .map(MorningLightProgram::new)
+                .transpose(),
+        ),
+        ProgramId::TurnMorningLightsOff => boxed(
+            TurnMorningLightsOffProgram::new(&config.turn_morning_lights_off)
+                .map(|p| Some(p.with_desktop_notifications(config.desktop_notifications))),
+        ),
+        ProgramId::ControlEveningLights => config
+            .control_evening_lights
+            .iter()
+            .map(|c| boxed(ControlEveningLightsProgram::new(c).map(Some)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|instances| instances.into_iter().flatten().collect()),
+        ProgramId::Irrigation => boxed(
+            config
+                .irrigation
+                .as_ref()
+                .map(IrrigationProgram::new)
+                .transpose(),
+        ),
+        ProgramId::ConditionActions => {
+            boxed(ConditionActionsProgram::new(&config.condition_actions).map(Some))
+        }
+        ProgramId::HttpPoll => boxed(HttpPollProgram::new(&config.http_polls).map(Some)),
+        ProgramId::ColorShift => boxed(color_shift_program(config)),
+        ProgramId::Script => match &config.scripts_dir {
+            Some(dir) => ScriptProgram::load_dir(dir)
+                .map(|scripts| {
+                    scripts
+                        .into_iter()
+                        .map(|s| Box::new(s) as Box<dyn Program>)
+                        .collect()
+                })
+                .map_err(|e| e.to_string()),
+            None => Ok(Vec::new()),
+        },
+    }
+}
+
+/// Color shift of the accessories carrying its tag, if configured.
+fn color_shift_program(
+    config: &Configuration,
+) -> Result<Option<ColorShiftProgram>, ColorShiftProgramError> {
+    let Some(shift) = &config.color_shift else {
+        return Ok(None);
+    };
+    let lights = config
+        .accessories
+        .iter()
+        .filter(|(_, a)| a.tags.contains(&shift.tag))
+        .map(|(name, _)| name.clone())
+        .collect();
+    ColorShiftProgram::new(shift, lights).map(Some)
+}
+
+/// The configured programs, in the order the program loop runs them.
+#[derive(Default)]
+pub struct ProgramRegistry {
+    programs: Vec<Box<dyn Program>>,
+}
+
+impl ProgramRegistry {
+    /// Build every program that is configured.
+    pub fn from_config(config: &Configuration) -> Result<Self, String> {
+        let mut registry = Self::default();
+        for id in ProgramId::ALL {
+            registry.rebuild(id, config)?;
+        }
+        Ok(registry)
+    }
+
+    /// What keeps each configured program from being built, instead of only the first error.
+    pub fn problems(config: &Configuration) -> Vec<(ProgramId, String)> {
+        ProgramId::ALL
+            .into_iter()
+            .filter_map(|id| build(id, config).err().map(|e| (id, e)))
+            .collect()
+    }
+
+    /// Replace the instances of one program with new ones built from `config`, dropping their
+    /// in-memory state.
+    ///
+    /// The program is removed if it is no longer configured.
+    pub fn rebuild(&mut self, id: ProgramId, config: &Configuration) -> Result<(), String> {
+        let instances = build(id, config)?;
+        self.programs.retain(|p| p.id() != id);
+        let at = self
+            .programs
+            .iter()
+            .position(|p| p.id() > id)
+            .unwrap_or(self.programs.len());
+        self.programs.splice(at..at, instances);
+        Ok(())
+    }
+
+    /// Keep only the instances of the program of `name` (e.g. `evening_lights`), or the single
+    /// instance it names (e.g. `control_evening_lights:office`).
+    pub fn retain_named(&mut self, name: &str) -> Result<(), String> {
+        let full_name =
+            ProgramId::full_name(name).ok_or_else(|| format!("Unknown program '{}'.", name))?;
+        self.programs
+            .retain(|p| p.name() == full_name || p.id().name() == full_name);
+        match self.programs.is_empty() {
+            true => Err(format!("'{}' is not configured.", name)),
+            false => Ok(()),
+        }
+    }
+
+    /// Names of the configured program instances.
+    pub fn names(&self) -> Vec<&str> {
+        self.programs.iter().map(|p| p.name()).collect()
+    }
+
+    /// The first instance of a program.
+    pub fn get(&self, id: ProgramId) -> Option<&dyn Program> {
+        self.programs.iter().find(|p| p.id() == id).map(|p| &**p)
+    }
+
+    /// The program instance of a name, e.g. `control_evening_lights:office`.
+    pub fn get_named(&self, name: &str) -> Option<&dyn Program> {
+        self.programs
+            .iter()
+            .find(|p| p.name() == name)
+            .map(|p| &**p)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Program> {
+        self.programs.iter().map(|p| &**p)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn Program>> {
+        self.programs.iter_mut()
+    }
+
+    /// The instances of a concrete program type, e.g. for the evening lights' curves.
+    pub fn instances<P: Program>(&self) -> impl Iterator<Item = &P> {
+        self.programs
+            .iter()
+            .filter_map(|p| p.as_any().downcast_ref::<P>())
+    }
+
+    /// The instances of a concrete program type, for what only it supports (e.g. nudges of the
+    /// evening lights).
+    pub fn instances_mut<P: Program>(&mut self) -> impl Iterator<Item = &mut P> {
+        self.programs
+            .iter_mut()
+            .filter_map(|p| p.as_any_mut().downcast_mut::<P>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn builds_configured_programs_in_order() {
+        let mut config: Configuration = serde_json::from_value(json!({
+            "turn_morning_lights_off": {
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": {
+                "minutes_before_sunset_start": 45,
+                "minutes_after_sunset_peak": 15,
+                "minutes_after_sunset_finish": 60,
+                "start_brightness": 30,
+                "max_brightness": 100,
+                "final_brightness": 75
+            },
+            "program_loop_pause": 2.0,
+            "bridge": { "host": "127.0.0.1" },
+            "latitude": 42.36,
+            "longitude": -71.06
+        }))
+        .unwrap();
+        let mut registry = ProgramRegistry::from_config(&config).unwrap();
+        assert!(registry.get(ProgramId::MorningLight).is_none());
+        assert!(registry.get(ProgramId::TurnMorningLightsOff).is_some());
+        assert_eq!(
+            registry
+                .instances_mut::<ControlEveningLightsProgram>()
+                .count(),
+            1
+        );
+
+        config.irrigation = Some(serde_json::from_value(json!({"zones": []})).unwrap());
+        registry.rebuild(ProgramId::Irrigation, &config).unwrap();
+        let ids: Vec<ProgramId> = registry.iter().map(|p| p.id()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                ProgramId::TurnMorningLightsOff,
+                ProgramId::ControlEveningLights,
+                ProgramId::Irrigation,
+                ProgramId::ConditionActions,
+                ProgramId::HttpPoll,
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_one_program_per_instance() {
+        let curve = json!({
+            "minutes_before_sunset_start": 45,
+            "minutes_after_sunset_peak": 15,
+            "minutes_after_sunset_finish": 60,
+            "start_brightness": 30,
+            "max_brightness": 100,
+            "final_brightness": 75
+        });
+        let instance = |name: &str, light: &str| {
+            let mut instance = curve.clone();
+            instance["name"] = json!(name);
+            instance["light"] = json!(light);
+            instance
+        };
+        let config: Configuration = serde_json::from_value(json!({
+            "turn_morning_lights_off": {
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": [
+                instance("bedroom", "Bed Light"),
+                instance("office", "Desk Lamp")
+            ],
+            "program_loop_pause": 2.0,
+            "bridge": { "host": "127.0.0.1" },
+            "latitude": 42.36,
+            "longitude": -71.06
+        }))
+        .unwrap();
+        let mut registry = ProgramRegistry::from_config(&config).unwrap();
+        assert_eq!(
+            registry.names(),
+            vec![
+                "turn_morning_lights_off",
+                "control_evening_lights:bedroom",
+                "control_evening_lights:office",
+                "condition_actions",
+                "http_poll",
+            ]
+        );
+        let lights: Vec<String> = registry
+            .instances_mut::<ControlEveningLightsProgram>()
+            .map(|p| p.light.clone())
+            .collect();
+        assert_eq!(lights, vec!["Bed Light", "Desk Lamp"]);
+
+        let mut office = ProgramRegistry::from_config(&config).unwrap();
+        office.retain_named("evening_lights:office").unwrap();
+        assert_eq!(office.names(), vec!["control_evening_lights:office"]);
+        registry.retain_named("control_evening_lights").unwrap();
+        assert_eq!(registry.names().len(), 2);
+        assert!(registry.retain_named("morning_light").is_err());
+        assert!(registry.retain_named("evening").is_err());
+    }
+}
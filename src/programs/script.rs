@@ -0,0 +1,243 @@
+use crate::clock;
+use crate::control::SharedState;
+use crate::decisions::Decision;
+use crate::homebridge::{HBError, Homebridge};
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::script::{Script, ScriptError, Snapshot, Step};
+use crate::suntimes::{SunTimes, SuntimesError};
+use futures::future::LocalBoxFuture;
+use log::{info, warn};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const PROGRAM_NAME: &str = "script";
+
+/// Extension of the script files loaded from `scripts_dir`.
+pub const EXTENSION: &str = "script";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ScriptProgramError {
+    #[error("Failed to read '{0}': {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Invalid script '{0}': {1}")]
+    Invalid(PathBuf, #[source] ScriptError),
+    #[error("{0}")]
+    Script(#[from] ScriptError),
+    #[error("Error during Homebridge interaction.")]
+    HomebridgeInteraction(#[from] HBError),
+    #[error("{0}")]
+    NoSunTimesData(#[from] SuntimesError),
+}
+
+/// A user script from `scripts_dir`, run as a program of its own.
+#[derive(Debug)]
+pub struct ScriptProgram {
+    /// `script:` and the file name without its extension, e.g. `script:porch`.
+    name: String,
+    pub path: PathBuf,
+    script: Script,
+}
+
+impl ScriptProgram {
+    pub fn load(path: &Path) -> Result<Self, ScriptProgramError> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| ScriptProgramError::Read(path.to_path_buf(), e))?;
+        let script = Script::parse(&source)
+            .map_err(|e| ScriptProgramError::Invalid(path.to_path_buf(), e))?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        Ok(Self {
+            name: format!("{}:{}", PROGRAM_NAME, stem),
+            path: path.to_path_buf(),
+            script,
+        })
+    }
+
+    /// The scripts in `dir`, by file name.
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>, ScriptProgramError> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| ScriptProgramError::Read(dir.to_path_buf(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == EXTENSION))
+            .collect();
+        paths.sort();
+        paths.iter().map(|path| Self::load(path)).collect()
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        suntimes: &mut SunTimes,
+        state: &SharedState,
+    ) -> Result<Decision, ScriptProgramError> {
+        info!("Executing script '{}'.", self.path.display());
+        let now = clock::now();
+        let mut values = HashMap::new();
+        for accessory in self.script.accessories() {
+            let current = homebridge.get_accessory_values(client, accessory).await?;
+            values.insert(accessory.to_string(), current);
+        }
+        let sun = match self.script.uses_sun() {
+            true => Some(suntimes.today(client).await?),
+            false => None,
+        };
+        let marks = state
+            .lock()
+            .expect("State lock poisoned.")
+            .store
+            .state()
+            .script_marks
+            .get(&self.name)
+            .map(|marks| {
+                marks
+                    .iter()
+                    .filter(|(_, day)| **day == now.date_naive())
+                    .map(|(mark, _)| mark.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let snapshot = Snapshot {
+            now,
+            values,
+            sun,
+            marks,
+        };
+
+        let mut written = Vec::new();
+        let mut marked = Vec::new();
+        for step in self.script.plan(&snapshot)? {
+            match &step {
+                Step::Set {
+                    accessory,
+                    characteristic,
+                    value,
+                } => {
+                    homebridge
+                        .apply_values(
+                            client,
+                            &self.name,
+                            accessory,
+                            &[(characteristic.clone(), value.clone())],
+                            false,
+                        )
+                        .await?;
+                    written.push(step.to_string());
+                }
+                Step::Mark(mark) => marked.push(mark.clone()),
+            }
+        }
+        // Marks are kept only once the writes before them went through.
+        if !marked.is_empty() {
+            let mut state = state.lock().expect("State lock poisoned.");
+            let updated = state.store.update(|s| {
+                let marks = s.script_marks.entry(self.name.clone()).or_default();
+                for mark in marked {
+                    marks.insert(mark, now.date_naive());
+                }
+            });
+            if let Err(e) = updated {
+                warn!("Failed to persist the marks of '{}': {}", self.name, e);
+            }
+        }
+        Ok(match written.is_empty() {
+            true => Decision::skipped("Nothing to do"),
+            false => Decision::ran(written.join("; ")),
+        })
+    }
+}
+
+impl Program for ScriptProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::Script
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move {
+            Ok(self
+                .run(ctx.client, ctx.homebridge, ctx.suntimes, ctx.state)
+                .await?)
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::mock_bridge;
+    use crate::control::ControllerState;
+    use crate::state::StateStore;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn runs_scripts_from_a_directory() {
+        let dir = std::env::temp_dir().join(format!("hb-scripts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (url, lights) = mock_bridge(1, std::time::Duration::ZERO).unwrap();
+        let source = format!(
+            "if light('{0}').on && !marked('dimmed') then\n  set '{0}' Brightness 20\n  mark 'dimmed'\nend\n",
+            lights[0]
+        );
+        fs::write(dir.join("b_dim.script"), source).unwrap();
+        fs::write(dir.join("a_noop.script"), "# Nothing yet.\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a script").unwrap();
+        let mut scripts = ScriptProgram::load_dir(&dir).unwrap();
+        assert_eq!(
+            scripts.iter().map(|s| s.name()).collect::<Vec<_>>(),
+            vec!["script:a_noop", "script:b_dim"]
+        );
+
+        let client = reqwest::Client::new();
+        let mut homebridge = Homebridge::new(&url, "user", "password");
+        let mut suntimes = SunTimes::new(-71.06, 42.36);
+        let state: SharedState = Arc::new(Mutex::new(ControllerState::new(
+            StateStore::load(&dir.join("state.json")).unwrap(),
+        )));
+        let script = &mut scripts[1];
+        let decision = script
+            .run(&client, &mut homebridge, &mut suntimes, &state)
+            .await
+            .unwrap();
+        assert_eq!(
+            decision.reason,
+            format!("Set Brightness of '{}' to 20", lights[0])
+        );
+        let written = homebridge
+            .journal
+            .lock()
+            .last_write(&lights[0], "Brightness")
+            .map(|w| w.program.clone());
+        assert_eq!(written.as_deref(), Some("script:b_dim"));
+        // The mark holds for the rest of the day.
+        let decision = script
+            .run(&client, &mut homebridge, &mut suntimes, &state)
+            .await
+            .unwrap();
+        assert_eq!(decision.reason, "Nothing to do");
+
+        fs::write(dir.join("c_broken.script"), "if light('A').on then\n").unwrap();
+        let error = ScriptProgram::load_dir(&dir).unwrap_err().to_string();
+        assert!(
+            error.ends_with("c_broken.script': Line 1: 'if' without 'end'"),
+            "{}",
+            error
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,24 @@
+use crate::characteristic::Characteristic;
+use crate::clock;
+use crate::configuration::{OffVerificationConfig, RetryPolicyConfig};
+use crate::decisions::Decision;
+use crate::events::EventBus;
+use crate::expression::Expression;
 use crate::homebridge::Homebridge;
-use crate::suntimes::{SunTimes, SuntimesError};
+use crate::overlaps::ProgramWindow;
+use crate::programs::{Program, ProgramContext, ProgramId};
+use crate::suntimes::{SunDay, SunEvent, SunTimes, SuntimesError};
+use crate::time_format;
 use crate::{configuration::TurningMorningLightsOffConfig, homebridge::HBError};
-use chrono::{DateTime, Duration, Local, NaiveTime};
-use core::time;
-use log::{debug, error, info, warn};
-use std::thread;
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime};
+use futures::future::LocalBoxFuture;
+use log::{debug, info, warn};
+use std::any::Any;
+
+pub const PROGRAM_NAME: &str = "turn_morning_lights_off";
+
+/// Condition set for the rest of the day when the light could not be turned off in time.
+pub const OFF_MISSED: &str = "morning_light_off_missed";
 
 #[derive(thiserror::Error, Debug)]
 pub enum TurnMorningLightsOffProgramError {
@@ -19,12 +33,28 @@ pub enum TurnMorningLightsOffProgramError {
 }
 
 pub struct TurnMorningLightsOffProgram {
-    pub duration: u32,
+    pub light: String,
     pub off_time: Option<NaiveTime>,
     pub after_sunrise: Option<i64>,
+    /// Event `after_sunrise` counts from.
+    pub sun_event: SunEvent,
+    pub sunrise_fallback: Option<NaiveTime>,
     pub active: bool,
     pub last_call_after_scheduled_off: u32,
+    pub retry: RetryPolicyConfig,
+    pub verification: Option<OffVerificationConfig>,
+    pub requires: Vec<String>,
+    pub condition: Option<Expression>,
+    /// Show a desktop notification when turning the light off was missed.
+    pub notify_desktop: bool,
     last_turned_light_off: Option<DateTime<Local>>,
+    /// Attempts to turn the light off on the day of the last attempt.
+    attempts: u32,
+    last_attempt: Option<DateTime<Local>>,
+    /// Day turning the light off was missed.
+    missed_on: Option<NaiveDate>,
+    /// Next check that the light stayed off.
+    next_verification: Option<DateTime<Local>>,
 }
 
 impl TurnMorningLightsOffProgram {
@@ -50,79 +80,482 @@ impl TurnMorningLightsOffProgram {
         };
 
         Ok(TurnMorningLightsOffProgram {
+            light: config.light.clone(),
             off_time,
             after_sunrise: config.after_sunrise,
-            duration: config.duration,
+            sun_event: config.sun_event,
+            sunrise_fallback: config.sunrise_fallback,
             active: config.active,
             last_turned_light_off: Option::None,
             last_call_after_scheduled_off: config.last_call_after_scheduled_off,
+            retry: config.retry,
+            verification: config.verification,
+            requires: config.requires.clone(),
+            condition: config
+                .condition
+                .as_deref()
+                .map(Expression::parse)
+                .transpose()
+                .map_err(|e| TurnMorningLightsOffProgramError::ConfigError(e.to_string()))?,
+            notify_desktop: false,
+            attempts: 0,
+            last_attempt: None,
+            missed_on: None,
+            next_verification: None,
         })
     }
+
+    pub fn with_desktop_notifications(mut self, notify_desktop: bool) -> Self {
+        self.notify_desktop = notify_desktop;
+        self
+    }
 }
 
 impl TurnMorningLightsOffProgram {
+    /// Off-time on a day with the given sun times, if one is configured and the sun event
+    /// happens.
+    ///
+    /// Offsets from the sun event may move it to the day before or after.
+    pub fn off_time_on(&self, day: &SunDay) -> Option<DateTime<Local>> {
+        match (self.off_time, self.after_sunrise) {
+            (Some(ot), _) => day
+                .sunrise
+                .date_naive()
+                .and_time(ot)
+                .and_local_timezone(Local)
+                .earliest(),
+            (None, Some(after_sunrise)) => day
+                .at(self.sun_event)
+                .map(|event| event + Duration::minutes(after_sunrise)),
+            (None, None) => None,
+        }
+    }
+
+    /// From the off-time to the last call on a day with the given sun times.
+    pub fn window_on(&self, day: &SunDay) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        let start = self.off_time_on(day)?;
+        let verification = self
+            .verification
+            .map_or(Duration::zero(), |v| Duration::minutes(v.window_minutes));
+        Some((start, self.last_call(&start) + verification))
+    }
+
+    fn last_call(&self, off_time: &DateTime<Local>) -> DateTime<Local> {
+        *off_time + Duration::minutes(self.last_call_after_scheduled_off as i64)
+    }
+
+    /// The off-time `now` is scheduled against: the one anchored on today's sun event, unless
+    /// its last call has passed and the one anchored on tomorrow's falls on today.
+    fn scheduled(
+        &self,
+        today: DateTime<Local>,
+        tomorrow: Option<DateTime<Local>>,
+        now: &DateTime<Local>,
+    ) -> DateTime<Local> {
+        match tomorrow {
+            Some(tomorrow)
+                if self.last_call(&today) < *now && tomorrow.date_naive() == now.date_naive() =>
+            {
+                tomorrow
+            }
+            _ => today,
+        }
+    }
+
+    /// Off-time anchored on the sun event of `date`, or the fixed off-time on `date`.
+    async fn off_time_on_date(
+        &self,
+        client: &reqwest::Client,
+        suntimes: &mut SunTimes,
+        date: NaiveDate,
+    ) -> Result<DateTime<Local>, TurnMorningLightsOffProgramError> {
+        match (self.off_time, self.after_sunrise) {
+            (Some(ot), _) => date
+                .and_time(ot)
+                .and_local_timezone(Local)
+                .earliest()
+                .ok_or_else(|| {
+                    TurnMorningLightsOffProgramError::ConfigError(format!(
+                        "Off-time {} does not exist on {}.",
+                        ot, date
+                    ))
+                }),
+            (None, Some(after_sunrise)) => {
+                let sunrise = suntimes
+                    .event_on_or(client, self.sun_event, date, self.sunrise_fallback)
+                    .await
+                    .map_err(TurnMorningLightsOffProgramError::NoSunTimesData)?;
+                debug!("{}: {}", self.sun_event, time_format::show(&sunrise));
+                Ok(sunrise + Duration::minutes(after_sunrise))
+            }
+            (None, None) => Err(TurnMorningLightsOffProgramError::ConfigError(
+                "Both off-times are None.".to_string(),
+            )),
+        }
+    }
+
+    /// Calculate the off-time at `now` depending on the configuration.
+    ///
+    /// Tomorrow's sun event is only looked up once today's schedule is over, for offsets that
+    /// bring it forward to today.
+    async fn off_time(
+        &self,
+        client: &reqwest::Client,
+        suntimes: &mut SunTimes,
+        now: &DateTime<Local>,
+    ) -> Result<DateTime<Local>, TurnMorningLightsOffProgramError> {
+        let today = now.date_naive();
+        let off_time = self.off_time_on_date(client, suntimes, today).await?;
+        let tomorrow = match today.succ_opt() {
+            Some(tomorrow)
+                if self.off_time.is_none()
+                    && self.after_sunrise.is_some_and(|after| after < 0)
+                    && self.last_call(&off_time) < *now =>
+            {
+                Some(self.off_time_on_date(client, suntimes, tomorrow).await?)
+            }
+            _ => None,
+        };
+        Ok(self.scheduled(off_time, tomorrow, now))
+    }
+
+    /// How the schedule looks at the current time, without reading or writing the light.
+    pub async fn explain(
+        &self,
+        client: &reqwest::Client,
+        suntimes: &mut SunTimes,
+    ) -> Result<Vec<String>, TurnMorningLightsOffProgramError> {
+        let now = clock::now();
+        let off_time = self.off_time(client, suntimes, &now).await?;
+        let last_call = self.last_call(&off_time);
+        let mut trace =
+            vec![
+                format!("Active: {}", self.active),
+                format!(
+                    "Off-time: {}, last call: {}",
+                    time_format::show(&off_time),
+                    time_format::show(&last_call)
+                ),
+                format!(
+                    "Retries: every {} minutes, {}",
+                    self.retry.interval_minutes,
+                    self.retry.max_attempts.map_or(
+                        "until the last call".to_string(),
+                        |max| format!("at most {} attempts", max)
+                    )
+                ),
+            ];
+        if let Some(verification) = self.verification {
+            trace.push(format!(
+                "Checks the light stays off every {} minutes for {} minutes after turning it off",
+                verification.interval_minutes, verification.window_minutes
+            ));
+        }
+        trace.push(if now < off_time {
+            "Not yet time to turn off light - nothing to do".to_string()
+        } else if last_call < now {
+            "After last-call time - nothing to do".to_string()
+        } else {
+            "Turns the light off, unless already done since the off-time".to_string()
+        });
+        Ok(trace)
+    }
+
     pub async fn run(
         &mut self,
         client: &reqwest::Client,
         homebridge: &mut Homebridge,
         suntimes: &mut SunTimes,
-    ) -> Result<(), TurnMorningLightsOffProgramError> {
+        events: &mut EventBus,
+    ) -> Result<Decision, TurnMorningLightsOffProgramError> {
         info!("Executing `TurnMorningLightsOffProgram`.");
         if !self.active {
             debug!("Program inactive - nothing to do.");
-            return Ok(());
+            return Ok(Decision::skipped("Program inactive"));
         }
 
-        let now = Local::now();
+        let now = clock::now();
         debug!("Now: {}", now);
+        let today = now.date_naive();
+        if self.missed_on.is_some_and(|day| day != today) {
+            self.missed_on = None;
+            events.clear(OFF_MISSED);
+        }
+
+        let off_time = self.off_time(client, suntimes, &now).await?;
+        debug!("Off-time: {}", time_format::show(&off_time));
+        if self.last_attempt.is_some_and(|t| t < off_time) {
+            self.attempts = 0;
+            self.last_attempt = None;
+        }
 
         if let Some(last_turned_off) = self.last_turned_light_off {
-            if last_turned_off.date_naive() == now.date_naive() {
+            if last_turned_off >= off_time {
+                if let Some(decision) = self
+                    .verify_off(client, homebridge, &last_turned_off, &now)
+                    .await?
+                {
+                    return Ok(decision);
+                }
                 debug!("Already turned off the morning light today - nothing to do.");
-                return Ok(());
+                return Ok(Decision::skipped(
+                    "Already turned off the morning light today",
+                ));
             }
         }
 
-        // Calculate the off-time depending on the configuration.
-        let off_time = match (self.off_time, self.after_sunrise) {
-            (Some(ot), _) => ot,
-            (None, Some(after_sunrise)) => {
-                let sunrise = suntimes
-                    .sunrise(client)
-                    .await
-                    .map_err(TurnMorningLightsOffProgramError::NoSunTimesData)?;
-                debug!("Sunrise: {}", sunrise);
-                sunrise.time() + Duration::minutes(after_sunrise)
-            }
-            (None, None) => {
-                return Err(TurnMorningLightsOffProgramError::ConfigError(
-                    "Both off-times are None.".to_string(),
-                ))
-            }
-        };
-        debug!("Off-time: {}", off_time);
-
-        if now.time() < off_time {
+        if now < off_time {
             debug!("Not yet time to turn off light - nothing to do.");
-            return Ok(());
+            return Ok(Decision::skipped("Not yet time to turn off light"));
         }
-        if (off_time + Duration::minutes(self.last_call_after_scheduled_off as i64)) < now.time() {
+        let out_of_attempts = self
+            .retry
+            .max_attempts
+            .is_some_and(|max| self.attempts >= max);
+        let past_last_call = self.last_call(&off_time) < now;
+        if past_last_call || out_of_attempts {
+            if self.attempts > 0 && self.missed_on.is_none() {
+                return Ok(self.report_missed(events, &now));
+            }
+            if out_of_attempts {
+                debug!("Gave up turning the light off today - nothing to do.");
+                return Ok(Decision::skipped(format!(
+                    "Gave up after {} attempts",
+                    self.attempts
+                )));
+            }
             debug!("After last-call time - nothing to do.");
-            return Ok(());
+            return Ok(Decision::skipped("After last-call time"));
+        }
+        if let Some(last_attempt) = self.last_attempt {
+            let retry_at = last_attempt + Duration::minutes(self.retry.interval_minutes);
+            if now < retry_at {
+                let retry_at = time_format::show(&retry_at);
+                debug!("Waiting to retry turning the light off at {}.", retry_at);
+                return Ok(Decision::skipped(format!("Retrying at {}", retry_at)));
+            }
         }
 
-        info!("After registered off-time, attempting to turn the light off.");
-        homebridge
-            .turn_bedlight_off(client)
+        if homebridge.observe_only {
+            debug!("Observing only - not counting an attempt yet.");
+            return Err(HBError::ObserveOnly.into());
+        }
+        self.attempts += 1;
+        self.last_attempt = Some(now);
+        info!(
+            "After registered off-time, attempting to turn the light off (attempt {}).",
+            self.attempts
+        );
+        let mut light = homebridge.accessory(&self.light);
+        light
+            .set(client, PROGRAM_NAME, &Characteristic::On, 0)
             .await
             .map_err(TurnMorningLightsOffProgramError::HomebridgeInteraction)?;
-        thread::sleep(time::Duration::from_millis(250));
-        if homebridge.bed_light_is_off(client).await? {
-            info!("Successfully turned OFF bed light.");
+        if light.is_off(client).await? {
+            info!("Successfully turned OFF '{}'.", self.light);
             self.last_turned_light_off = Some(now);
+            self.next_verification = self
+                .verification
+                .map(|v| now + Duration::minutes(v.interval_minutes));
+            Ok(Decision::ran(format!("Turned '{}' off", self.light)))
         } else {
-            warn!("The bed light is still ON after switching OFF.");
+            warn!("'{}' is still ON after switching OFF.", self.light);
+            Ok(Decision::ran(format!(
+                "'{}' still on after switching it off",
+                self.light
+            )))
+        }
+    }
+
+    /// Check that the light stayed off during the verification window, switching it off again
+    /// if it came back on.
+    ///
+    /// Returns `None` once the window has passed or without a verification.
+    async fn verify_off(
+        &mut self,
+        client: &reqwest::Client,
+        homebridge: &mut Homebridge,
+        turned_off: &DateTime<Local>,
+        now: &DateTime<Local>,
+    ) -> Result<Option<Decision>, TurnMorningLightsOffProgramError> {
+        let Some(verification) = self.verification else {
+            return Ok(None);
+        };
+        let window_end = *turned_off + Duration::minutes(verification.window_minutes);
+        let Some(next) = self.next_verification.filter(|next| *next <= window_end) else {
+            return Ok(None);
+        };
+        if *now < next {
+            let next = time_format::show(&next);
+            debug!("Checking '{}' stays off at {}.", self.light, next);
+            return Ok(Some(Decision::skipped(format!(
+                "Checking '{}' stays off at {}",
+                self.light, next
+            ))));
+        }
+        self.next_verification = Some(*now + Duration::minutes(verification.interval_minutes));
+        let mut light = homebridge.accessory(&self.light);
+        if light.is_off(client).await? {
+            debug!("'{}' is still off.", self.light);
+            return Ok(Some(Decision::skipped(format!(
+                "'{}' still off",
+                self.light
+            ))));
+        }
+        warn!("'{}' came back on - turning it off again.", self.light);
+        light
+            .set(client, PROGRAM_NAME, &Characteristic::On, 0)
+            .await?;
+        Ok(Some(Decision::ran(format!(
+            "'{}' came back on; turned it off again",
+            self.light
+        ))))
+    }
+
+    /// Publish that the light was never turned off today and notify about it.
+    fn report_missed(&mut self, events: &mut EventBus, now: &DateTime<Local>) -> Decision {
+        let message = format!(
+            "Never turned '{}' off ({} attempts).",
+            self.light, self.attempts
+        );
+        warn!("{}", message);
+        self.missed_on = Some(now.date_naive());
+        events.publish(OFF_MISSED, now);
+        if self.notify_desktop {
+            #[cfg(feature = "desktop")]
+            if let Err(e) =
+                crate::desktop::DesktopNotifier::notify("Homebridge controller", &message)
+            {
+                warn!("Failed to show desktop notification: {}", e);
+            }
+        }
+        Decision::failed(message)
+    }
+}
+
+impl Program for TurnMorningLightsOffProgram {
+    fn id(&self) -> ProgramId {
+        ProgramId::TurnMorningLightsOff
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        ctx: ProgramContext<'a>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Decision>> {
+        Box::pin(async move {
+            Ok(self
+                .run(ctx.client, ctx.homebridge, ctx.suntimes, ctx.events)
+                .await?)
+        })
+    }
+
+    fn windows(&self, day: &SunDay) -> Vec<ProgramWindow> {
+        self.window_on(day)
+            .map(|(start, end)| ProgramWindow {
+                program: PROGRAM_NAME.to_string(),
+                accessory: self.light.clone(),
+                start,
+                end,
+            })
+            .into_iter()
+            .collect()
+    }
+
+    fn preview(&self, day: &SunDay) -> Option<String> {
+        self.off_time_on(day)
+            .map(|off_time| format!("lights off {}", off_time.format("%H:%M")))
+    }
+
+    fn explain<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        suntimes: &'a mut SunTimes,
+    ) -> LocalBoxFuture<'a, Option<anyhow::Result<Vec<String>>>> {
+        Box::pin(async move { Some(self.explain(client, suntimes).await.map_err(Into::into)) })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 6, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn sunrise_on(day: u32) -> SunDay {
+        SunDay {
+            sunrise: at(day, 5, 30),
+            sunset: at(day, 21, 0),
+            twilight: BTreeMap::new(),
         }
-        Ok(())
+    }
+
+    #[test]
+    fn schedules_against_the_sunrise_of_the_right_day() {
+        let config: TurningMorningLightsOffConfig = serde_json::from_value(json!({
+            "after_sunrise": -420,
+            "last_call_after_scheduled_off": 20
+        }))
+        .unwrap();
+        let program = TurnMorningLightsOffProgram::new(&config).unwrap();
+
+        // Seven hours before sunrise is the evening before, not later the same day.
+        let today = program.off_time_on(&sunrise_on(10)).unwrap();
+        let tomorrow = program.off_time_on(&sunrise_on(11)).unwrap();
+        assert_eq!(today, at(9, 22, 30));
+        assert_eq!(tomorrow, at(10, 22, 30));
+
+        assert_eq!(
+            program.scheduled(today, Some(tomorrow), &at(10, 0, 10)),
+            tomorrow
+        );
+        assert_eq!(
+            program.scheduled(today, Some(tomorrow), &at(10, 22, 40)),
+            tomorrow
+        );
+
+        let config: TurningMorningLightsOffConfig = serde_json::from_value(json!({
+            "after_sunrise": 30,
+            "last_call_after_scheduled_off": 20
+        }))
+        .unwrap();
+        let program = TurnMorningLightsOffProgram::new(&config).unwrap();
+        let today = program.off_time_on(&sunrise_on(10)).unwrap();
+        let tomorrow = program.off_time_on(&sunrise_on(11)).unwrap();
+        assert_eq!(
+            program.scheduled(today, Some(tomorrow), &at(10, 0, 10)),
+            today
+        );
+        // Late at night, tomorrow's off-time is still tomorrow.
+        assert_eq!(
+            program.scheduled(today, Some(tomorrow), &at(10, 23, 50)),
+            today
+        );
     }
 }
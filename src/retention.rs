@@ -0,0 +1,116 @@
+use crate::configuration::RetentionConfig;
+use log::{debug, info};
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RetentionError {
+    #[error("Failed to read '{0}': {1}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Failed to remove '{0}': {1}")]
+    Remove(PathBuf, #[source] io::Error),
+}
+
+/// A file that may be pruned.
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Files directly in `dir`, newest first; a missing directory has none.
+fn entries(dir: &Path) -> Result<Vec<Entry>, RetentionError> {
+    let read_error = |e| RetentionError::Read(dir.to_path_buf(), e);
+    let listing = match fs::read_dir(dir) {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(read_error(e)),
+    };
+    let mut entries = Vec::new();
+    for item in listing {
+        let item = item.map_err(read_error)?;
+        let metadata = item.metadata().map_err(read_error)?;
+        if !metadata.is_file() {
+            continue;
+        }
+        entries.push(Entry {
+            path: item.path(),
+            modified: metadata.modified().map_err(read_error)?,
+            size: metadata.len(),
+        });
+    }
+    entries.sort_by_key(|e| Reverse(e.modified));
+    Ok(entries)
+}
+
+/// Files to remove: those older than the maximum age, then the oldest while the rest are
+/// larger than the maximum size.
+fn expired(entries: Vec<Entry>, config: &RetentionConfig, now: SystemTime) -> Vec<PathBuf> {
+    let cutoff = config
+        .max_age_days
+        .map(|days| now - Duration::from_secs(days.max(0) as u64 * 24 * 60 * 60));
+    let max_bytes = config.max_size_mb.map(|mb| mb * 1024 * 1024);
+    let mut kept_bytes = 0;
+    entries
+        .into_iter()
+        .filter(|entry| {
+            if cutoff.is_some_and(|cutoff| entry.modified < cutoff) {
+                return true;
+            }
+            kept_bytes += entry.size;
+            max_bytes.is_some_and(|max| kept_bytes > max)
+        })
+        .map(|entry| entry.path)
+        .collect()
+}
+
+/// Remove rolled files beyond the retention limits, returning the removed files.
+pub fn prune(config: &RetentionConfig, now: SystemTime) -> Result<Vec<PathBuf>, RetentionError> {
+    let removed = expired(entries(&config.directory)?, config, now);
+    for path in removed.iter() {
+        fs::remove_file(path).map_err(|e| RetentionError::Remove(path.clone(), e))?;
+        info!("Pruned '{}'.", path.display());
+    }
+    if removed.is_empty() {
+        debug!("Nothing to prune in '{}'.", config.directory.display());
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, days_old: u64, size: u64, now: SystemTime) -> Entry {
+        Entry {
+            path: PathBuf::from(name),
+            modified: now - Duration::from_secs(days_old * 24 * 60 * 60),
+            size,
+        }
+    }
+
+    #[test]
+    fn prunes_by_age_then_size() {
+        let now = SystemTime::now();
+        let mb = 1024 * 1024;
+        let entries = vec![
+            entry("new.log", 0, 2 * mb, now),
+            entry("recent.log", 1, 2 * mb, now),
+            entry("older.log", 3, 2 * mb, now),
+            entry("old.log", 10, mb, now),
+        ];
+        let config = RetentionConfig {
+            directory: PathBuf::from("log-archive"),
+            max_age_days: Some(7),
+            max_size_mb: Some(5),
+        };
+        assert_eq!(
+            expired(entries, &config, now),
+            vec![PathBuf::from("older.log"), PathBuf::from("old.log")]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::configuration::AccessoryConfig;
+use crate::override_detector::numeric_value;
+use chrono::{DateTime, Duration, Local};
+use log::debug;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Program name recorded for writes turning accessories off after their maximum run time.
+pub const SOURCE: &str = "max_run_time";
+
+/// Characteristic whose writes start and end a run.
+const ON: &str = "On";
+
+fn is_on(value: &Value) -> bool {
+    numeric_value(value).is_some_and(|v| v > 0.0)
+}
+
+/// Failsafe for switches and outlets the controller turns on, e.g. a heater outlet that must not
+/// stay on because a later program failed to turn it off.
+///
+/// A run starts with the controller's own write switching a limited accessory on and ends with
+/// any write or read seeing it off. Runs outlasting the accessory's `max_on_minutes` are due to
+/// be turned off.
+#[derive(Debug, Default)]
+pub struct RunTimeLimits {
+    limits: HashMap<String, Duration>,
+    /// Start of the current run of each limited accessory that is on.
+    on_since: BTreeMap<String, DateTime<Local>>,
+    /// Runs that ended since last taken, with how long they lasted.
+    ended: Vec<(String, Duration)>,
+    changed: bool,
+}
+
+impl RunTimeLimits {
+    pub fn from_config(accessories: &BTreeMap<String, AccessoryConfig>) -> Self {
+        let limits = accessories
+            .iter()
+            .filter_map(|(name, a)| {
+                a.max_on_minutes
+                    .map(|minutes| (name.clone(), Duration::minutes(minutes as i64)))
+            })
+            .collect();
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Continue the runs of an earlier run of the controller, of accessories still limited.
+    pub fn restore(&mut self, on_since: BTreeMap<String, DateTime<Local>>) {
+        self.on_since = on_since
+            .into_iter()
+            .filter(|(accessory, _)| self.limits.contains_key(accessory))
+            .collect();
+    }
+
+    /// Take note of a write of the controller.
+    pub fn written(
+        &mut self,
+        accessory: &str,
+        characteristic: &str,
+        value: &Value,
+        now: DateTime<Local>,
+    ) {
+        if characteristic != ON || !self.limits.contains_key(accessory) {
+            return;
+        }
+        if !is_on(value) {
+            self.end(accessory, now);
+        } else if !self.on_since.contains_key(accessory) {
+            debug!("Run of '{}' started.", accessory);
+            self.on_since.insert(accessory.to_string(), now);
+            self.changed = true;
+        }
+    }
+
+    /// Take note of a value read from the bridge, which ends a run if the accessory was turned
+    /// off some other way.
+    pub fn read(
+        &mut self,
+        accessory: &str,
+        characteristic: &str,
+        value: &Value,
+        now: DateTime<Local>,
+    ) {
+        if characteristic == ON && !is_on(value) {
+            self.end(accessory, now);
+        }
+    }
+
+    fn end(&mut self, accessory: &str, now: DateTime<Local>) {
+        if let Some(since) = self.on_since.remove(accessory) {
+            self.ended.push((accessory.to_string(), now - since));
+            self.changed = true;
+        }
+    }
+
+    /// Accessories on for longer than their limit, with how long they have been on and the limit.
+    pub fn overdue(&self, now: &DateTime<Local>) -> Vec<(String, Duration, Duration)> {
+        self.on_since
+            .iter()
+            .filter_map(|(accessory, since)| {
+                let limit = *self.limits.get(accessory)?;
+                let on_for = *now - *since;
+                (on_for >= limit).then(|| (accessory.clone(), on_for, limit))
+            })
+            .collect()
+    }
+
+    /// Runs that ended since the last call, and whether each ended within its limit.
+    pub fn take_ended(&mut self) -> Vec<(String, Duration, bool)> {
+        std::mem::take(&mut self.ended)
+            .into_iter()
+            .map(|(accessory, on_for)| {
+                let in_time = self.limits.get(&accessory).map_or(true, |l| on_for < *l);
+                (accessory, on_for, in_time)
+            })
+            .collect()
+    }
+
+    /// Starts of the current runs to persist, if they changed since the last call.
+    pub fn take_changed(&mut self) -> Option<BTreeMap<String, DateTime<Local>>> {
+        std::mem::take(&mut self.changed).then(|| self.on_since.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tracks_runs_of_limited_accessories() {
+        let accessories: BTreeMap<String, AccessoryConfig> = serde_json::from_value(json!({
+            "Heater": { "max_on_minutes": 120 },
+            "Lamp": {}
+        }))
+        .unwrap();
+        let mut limits = RunTimeLimits::from_config(&accessories);
+        let start = Local::now();
+        limits.written("Lamp", "On", &json!(true), start);
+        limits.written("Heater", "Brightness", &json!(50), start);
+        assert!(limits.take_changed().is_none());
+
+        limits.written("Heater", "On", &json!(1), start);
+        limits.written("Heater", "On", &json!(true), start + Duration::minutes(30));
+        assert_eq!(limits.take_changed().unwrap()["Heater"], start);
+        assert!(limits.overdue(&(start + Duration::minutes(119))).is_empty());
+        let later = start + Duration::minutes(121);
+        assert_eq!(
+            limits.overdue(&later),
+            vec![(
+                "Heater".to_string(),
+                Duration::minutes(121),
+                Duration::minutes(120)
+            )]
+        );
+        limits.written("Heater", "On", &json!(false), later);
+        assert_eq!(
+            limits.take_ended(),
+            vec![("Heater".to_string(), Duration::minutes(121), false)]
+        );
+
+        // Turned off by hand, noticed on the next read.
+        limits.written("Heater", "On", &json!(true), later);
+        limits.read("Heater", "On", &json!(0), later + Duration::minutes(5));
+        assert_eq!(
+            limits.take_ended(),
+            vec![("Heater".to_string(), Duration::minutes(5), true)]
+        );
+        assert!(limits.take_changed().unwrap().is_empty());
+
+        limits.restore(BTreeMap::from([
+            ("Heater".to_string(), start),
+            ("Lamp".to_string(), start),
+        ]));
+        assert_eq!(limits.overdue(&later).len(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::configuration::SchedulePreviewConfig;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate};
+use log::{info, warn};
+
+/// Sends the coming days' program times once a week, e.g. to notice the morning off-time
+/// drifting past an alarm as sunrise shifts.
+///
+/// The schedule goes to the log and, with desktop notifications, to the desktop.
+pub struct SchedulePreview {
+    config: SchedulePreviewConfig,
+    notify_desktop: bool,
+    last_sent: Option<NaiveDate>,
+}
+
+impl SchedulePreview {
+    pub fn new(config: &SchedulePreviewConfig, notify_desktop: bool) -> Self {
+        Self {
+            config: config.clone(),
+            notify_desktop,
+            last_sent: None,
+        }
+    }
+
+    /// Days to preview if the preview is due at `now`, starting tomorrow.
+    pub fn due(&self, now: &DateTime<Local>) -> Option<Vec<NaiveDate>> {
+        let today = now.date_naive();
+        if !self.config.active
+            || now.weekday() != self.config.weekday
+            || now.time() < self.config.time
+            || self.last_sent == Some(today)
+        {
+            return None;
+        }
+        Some(
+            (1..=self.config.days as i64)
+                .map(|d| today + Duration::days(d))
+                .collect(),
+        )
+    }
+
+    /// Send the schedule, one line per day.
+    pub fn send(&mut self, now: &DateTime<Local>, lines: &[String]) {
+        self.last_sent = Some(now.date_naive());
+        if lines.is_empty() {
+            warn!("No schedule to preview.");
+            return;
+        }
+        info!("Schedule for the coming days:\n{}", lines.join("\n"));
+        if self.notify_desktop {
+            #[cfg(feature = "desktop")]
+            if let Err(e) = crate::desktop::DesktopNotifier::notify(
+                "Homebridge controller schedule",
+                &lines.join("\n"),
+            ) {
+                warn!("Failed to show desktop notification: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone, Weekday};
+
+    #[test]
+    fn due_once_after_the_configured_time() {
+        let config = SchedulePreviewConfig {
+            active: true,
+            weekday: Weekday::Sun,
+            time: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            days: 7,
+        };
+        let mut preview = SchedulePreview::new(&config, false);
+        // 2024-12-01 is a Sunday.
+        let before = Local.with_ymd_and_hms(2024, 12, 1, 17, 59, 0).unwrap();
+        let after = Local.with_ymd_and_hms(2024, 12, 1, 18, 30, 0).unwrap();
+        assert!(preview.due(&before).is_none());
+        let days = preview.due(&after).unwrap();
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[0], NaiveDate::from_ymd_opt(2024, 12, 2).unwrap());
+        preview.send(&after, &[]);
+        assert!(preview.due(&after).is_none());
+    }
+}
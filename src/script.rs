@@ -0,0 +1,702 @@
+use crate::characteristic::Characteristic;
+use crate::expression::{Expression, ExpressionError};
+use crate::override_detector::numeric_value;
+use crate::suntimes::{SunDay, SunEvent};
+use chrono::{DateTime, Duration, Local, NaiveTime};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ScriptError {
+    #[error("Line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error("{0}")]
+    Expression(#[from] ExpressionError),
+    #[error("There is no {0:?} today.")]
+    NoSunEvent(SunEvent),
+}
+
+/// Time of day in a condition: a clock time, or a sun event shifted by some minutes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeOfDay {
+    Clock(NaiveTime),
+    Sun { event: SunEvent, offset: Duration },
+}
+
+/// Condition of an `if`, e.g. `light('Porch').off && after sunset + 15`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// A check of an accessory, as in the `condition` of programs.
+    Accessory(Expression),
+    /// Whether the script set the mark today, e.g. `marked('evening')`.
+    Marked(String),
+    After(TimeOfDay),
+    Before(TimeOfDay),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+/// One line of a script, or an `if` block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Set {
+        accessory: String,
+        characteristic: Characteristic,
+        value: Value,
+    },
+    Mark(String),
+    If {
+        condition: Condition,
+        then: Vec<Statement>,
+    },
+}
+
+/// What a run of a script does, in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Set {
+        accessory: String,
+        characteristic: Characteristic,
+        value: Value,
+    },
+    Mark(String),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Set {
+                accessory,
+                characteristic,
+                value,
+            } => write!(f, "Set {} of '{}' to {}", characteristic, accessory, value),
+            Step::Mark(mark) => write!(f, "Marked '{}'", mark),
+        }
+    }
+}
+
+/// What the conditions of a run are evaluated against.
+pub struct Snapshot {
+    pub now: DateTime<Local>,
+    /// Values of the accessories the script reads or writes, as of the start of the run.
+    pub values: HashMap<String, Value>,
+    /// Today's sun times, if the script uses them.
+    pub sun: Option<SunDay>,
+    /// Marks the script set today.
+    pub marks: Vec<String>,
+}
+
+/// A user script, run as a program.
+///
+/// ```text
+/// # Porch light for the evening, once a day.
+/// if light('Porch Light').off && after sunset + 15 && !marked('evening') then
+///     set 'Porch Light' Brightness 60
+///     set 'Porch Light' On 1
+///     mark 'evening'
+/// end
+/// if after 23:30 || before sunrise then
+///     set 'Porch Light' On 0
+/// end
+/// ```
+///
+/// Statements are `set '<accessory>' <characteristic> <value>`, `mark '<name>'`, and
+/// `if <condition> then ... end`. Conditions combine accessory checks (as in a program's
+/// `condition`), `marked('<name>')`, and `after`/`before` a clock time (`23:30`) or sun event
+/// (`sunset - 30`) with `!`, `&&`, `||`, and parentheses. Everything after a `#` is a comment.
+/// There are no variables, loops, or functions: a script states what the accessories should be
+/// and when, and anything more is a program of its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f64),
+    Time(NaiveTime),
+    LParen,
+    RParen,
+    Dot,
+    Not,
+    And,
+    Or,
+    Plus,
+    Minus,
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '.' | '!' | '+' | '-' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '.' => Token::Dot,
+                    '!' => Token::Not,
+                    '+' => Token::Plus,
+                    _ => Token::Minus,
+                });
+            }
+            '&' | '|' => {
+                chars.next();
+                if chars.next() != Some(c) {
+                    return Err(format!("expected '{}{}'", c, c));
+                }
+                tokens.push(match c {
+                    '&' => Token::And,
+                    _ => Token::Or,
+                });
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => s.push(ch),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_ascii_digit() => {
+                let mut s = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_ascii_digit() || ch == '.' || ch == ':') {
+                        break;
+                    }
+                    s.push(ch);
+                    chars.next();
+                }
+                tokens.push(match s.contains(':') {
+                    true => Token::Time(
+                        NaiveTime::parse_from_str(&s, "%H:%M")
+                            .map_err(|_| format!("invalid time '{}'", s))?,
+                    ),
+                    false => {
+                        Token::Number(s.parse().map_err(|_| format!("invalid number '{}'", s))?)
+                    }
+                });
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_alphanumeric() || ch == '_') {
+                        break;
+                    }
+                    s.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(s));
+            }
+            c => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser of one line; `||` binds weaker than `&&`, which binds weaker
+/// than `!`.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(format!("expected {}", what)),
+        }
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s),
+            _ => Err(format!("expected {} in quotes", what)),
+        }
+    }
+
+    /// A quoted argument in parentheses, e.g. `('home')`.
+    fn argument(&mut self, what: &str) -> Result<String, String> {
+        self.expect(Token::LParen, "'('")?;
+        let argument = self.string(what)?;
+        self.expect(Token::RParen, "')'")?;
+        Ok(argument)
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        match self.pos < self.tokens.len() {
+            true => Err("unexpected trailing input".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            left = Condition::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            left = Condition::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Condition, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Condition::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let inner = self.or()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(inner)
+            }
+            Some(Token::Ident(word)) => match word.as_str() {
+                "sensor" | "light" | "accessory" => {
+                    let accessory = self.argument("an accessory name")?;
+                    self.expect(Token::Dot, "'.' and a property")?;
+                    let Some(Token::Ident(property)) = self.next() else {
+                        return Err("expected a property".to_string());
+                    };
+                    Ok(Condition::Accessory(Expression::check(
+                        &accessory, &property,
+                    )?))
+                }
+                "marked" => Ok(Condition::Marked(self.argument("a mark")?)),
+                "after" => Ok(Condition::After(self.time_of_day()?)),
+                "before" => Ok(Condition::Before(self.time_of_day()?)),
+                _ => Err(format!("unknown check '{}'", word)),
+            },
+            _ => Err("expected a check, '!' or '('".to_string()),
+        }
+    }
+
+    fn time_of_day(&mut self) -> Result<TimeOfDay, String> {
+        match self.next() {
+            Some(Token::Time(time)) => Ok(TimeOfDay::Clock(time)),
+            Some(Token::Ident(name)) => {
+                let event: SunEvent = serde_json::from_value(json!(name))
+                    .map_err(|_| format!("unknown sun event '{}'", name))?;
+                let sign = match self.peek() {
+                    Some(Token::Plus) => 1,
+                    Some(Token::Minus) => -1,
+                    _ => {
+                        return Ok(TimeOfDay::Sun {
+                            event,
+                            offset: Duration::zero(),
+                        })
+                    }
+                };
+                self.next();
+                match self.next() {
+                    Some(Token::Number(minutes)) if minutes.fract() == 0.0 => Ok(TimeOfDay::Sun {
+                        event,
+                        offset: Duration::minutes(sign * minutes as i64),
+                    }),
+                    _ => Err("expected whole minutes after the sign".to_string()),
+                }
+            }
+            _ => Err("expected a time like 23:30 or a sun event like sunset".to_string()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Number(n)) if n.fract() == 0.0 => Ok(json!(n as i64)),
+            Some(Token::Number(n)) => Ok(json!(n)),
+            Some(Token::Str(s)) => Ok(json!(s)),
+            Some(Token::Ident(word)) if word == "true" => Ok(json!(1)),
+            Some(Token::Ident(word)) if word == "false" => Ok(json!(0)),
+            _ => Err("expected a number, true, false, or a quoted value".to_string()),
+        }
+    }
+}
+
+/// A line of the script: a statement, or the start or end of a block.
+enum Line {
+    Statement(Statement),
+    If(Condition),
+    End,
+}
+
+fn parse_line(tokens: Vec<Token>) -> Result<Line, String> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let Some(Token::Ident(keyword)) = parser.next() else {
+        return Err("expected a statement".to_string());
+    };
+    let line = match keyword.as_str() {
+        "set" => {
+            let accessory = parser.string("an accessory name")?;
+            let Some(Token::Ident(characteristic)) = parser.next() else {
+                return Err("expected a characteristic".to_string());
+            };
+            Line::Statement(Statement::Set {
+                accessory,
+                characteristic: Characteristic::parse_strict(&characteristic)?,
+                value: parser.value()?,
+            })
+        }
+        "mark" => Line::Statement(Statement::Mark(parser.string("a mark")?)),
+        "if" => {
+            let condition = parser.or()?;
+            parser.expect(Token::Ident("then".to_string()), "'then'")?;
+            Line::If(condition)
+        }
+        "end" => Line::End,
+        _ => return Err(format!("unknown statement '{}'", keyword)),
+    };
+    parser.finish()?;
+    Ok(line)
+}
+
+/// An `if` block being parsed.
+struct Block {
+    line: usize,
+    condition: Condition,
+    then: Vec<Statement>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self, ScriptError> {
+        let mut statements = Vec::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, text) in source.lines().enumerate() {
+            let error = |message: String| ScriptError::Parse {
+                line: i + 1,
+                message,
+            };
+            let tokens = tokenize(text).map_err(error)?;
+            if tokens.is_empty() {
+                continue;
+            }
+            let statement = match parse_line(tokens).map_err(error)? {
+                Line::Statement(statement) => statement,
+                Line::If(condition) => {
+                    blocks.push(Block {
+                        line: i + 1,
+                        condition,
+                        then: Vec::new(),
+                    });
+                    continue;
+                }
+                Line::End => {
+                    let block = blocks
+                        .pop()
+                        .ok_or_else(|| error("'end' outside of an 'if'".to_string()))?;
+                    Statement::If {
+                        condition: block.condition,
+                        then: block.then,
+                    }
+                }
+            };
+            match blocks.last_mut() {
+                Some(block) => block.then.push(statement),
+                None => statements.push(statement),
+            }
+        }
+        if let Some(block) = blocks.last() {
+            return Err(ScriptError::Parse {
+                line: block.line,
+                message: "'if' without 'end'".to_string(),
+            });
+        }
+        Ok(Self { statements })
+    }
+
+    /// Accessories the script reads or writes, each once.
+    pub fn accessories(&self) -> Vec<&str> {
+        let mut accessories = Vec::new();
+        visit(&self.statements, &mut |statement| match statement {
+            Statement::Set { accessory, .. } => accessories.push(accessory.as_str()),
+            Statement::If { condition, .. } => condition.visit(&mut |c| {
+                if let Condition::Accessory(expression) = c {
+                    accessories.extend(expression.accessories());
+                }
+            }),
+            _ => {}
+        });
+        let mut unique = Vec::new();
+        for accessory in accessories {
+            if !unique.contains(&accessory) {
+                unique.push(accessory);
+            }
+        }
+        unique
+    }
+
+    /// Whether a condition depends on the sun times.
+    pub fn uses_sun(&self) -> bool {
+        let mut uses_sun = false;
+        visit(&self.statements, &mut |statement| {
+            if let Statement::If { condition, .. } = statement {
+                condition.visit(&mut |c| {
+                    if let Condition::After(TimeOfDay::Sun { .. })
+                    | Condition::Before(TimeOfDay::Sun { .. }) = c
+                    {
+                        uses_sun = true;
+                    }
+                });
+            }
+        });
+        uses_sun
+    }
+
+    /// The steps of a run.
+    ///
+    /// A `set` to the value the accessory already has is left out, so a script can keep
+    /// stating what it wants without writing every loop.
+    pub fn plan(&self, snapshot: &Snapshot) -> Result<Vec<Step>, ScriptError> {
+        let mut steps = Vec::new();
+        plan(&self.statements, snapshot, &mut steps)?;
+        Ok(steps)
+    }
+}
+
+/// Call `f` on each statement, including those inside blocks.
+fn visit<'a>(statements: &'a [Statement], f: &mut impl FnMut(&'a Statement)) {
+    for statement in statements {
+        f(statement);
+        if let Statement::If { then, .. } = statement {
+            visit(then, f);
+        }
+    }
+}
+
+/// Add the steps of `statements` to `steps`.
+fn plan(
+    statements: &[Statement],
+    snapshot: &Snapshot,
+    steps: &mut Vec<Step>,
+) -> Result<(), ScriptError> {
+    for statement in statements {
+        match statement {
+            Statement::Set {
+                accessory,
+                characteristic,
+                value,
+            } => {
+                let current = snapshot
+                    .values
+                    .get(accessory)
+                    .and_then(|values| values.get(characteristic.as_str()));
+                if !current.is_some_and(|current| same_value(current, value)) {
+                    steps.push(Step::Set {
+                        accessory: accessory.clone(),
+                        characteristic: characteristic.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+            Statement::Mark(mark) => steps.push(Step::Mark(mark.clone())),
+            Statement::If { condition, then } => {
+                if condition.evaluate(snapshot)? {
+                    plan(then, snapshot, steps)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compare values, treating e.g. `1`, `true`, and `"1"` as equal.
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (numeric_value(a), numeric_value(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl TimeOfDay {
+    fn on(&self, snapshot: &Snapshot) -> Result<DateTime<Local>, ScriptError> {
+        match self {
+            TimeOfDay::Clock(time) => Ok(snapshot
+                .now
+                .date_naive()
+                .and_time(*time)
+                .and_local_timezone(Local)
+                .earliest()
+                .unwrap_or(snapshot.now)),
+            TimeOfDay::Sun { event, offset } => snapshot
+                .sun
+                .as_ref()
+                .and_then(|day| day.at(*event))
+                .map(|at| at + *offset)
+                .ok_or(ScriptError::NoSunEvent(*event)),
+        }
+    }
+}
+
+impl Condition {
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Condition)) {
+        f(self);
+        match self {
+            Condition::Not(inner) => inner.visit(f),
+            Condition::And(a, b) | Condition::Or(a, b) => {
+                a.visit(f);
+                b.visit(f);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn evaluate(&self, snapshot: &Snapshot) -> Result<bool, ScriptError> {
+        Ok(match self {
+            Condition::Accessory(expression) => expression.evaluate(&snapshot.values)?,
+            Condition::Marked(mark) => snapshot.marks.contains(mark),
+            Condition::After(time) => snapshot.now >= time.on(snapshot)?,
+            Condition::Before(time) => snapshot.now < time.on(snapshot)?,
+            Condition::Not(inner) => !inner.evaluate(snapshot)?,
+            Condition::And(a, b) => a.evaluate(snapshot)? && b.evaluate(snapshot)?,
+            Condition::Or(a, b) => a.evaluate(snapshot)? || b.evaluate(snapshot)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+    use std::collections::BTreeMap;
+
+    const PORCH: &str = "
+        # Porch light for the evening, once a day.
+        if light('Porch Light').off && after sunset + 15 && !marked('evening') then
+            set 'Porch Light' Brightness 60
+            set 'Porch Light' On true
+            mark 'evening'
+        end
+        if after 23:30 || before sunrise - 30 then  # Also before dawn.
+            set 'Porch Light' On 0
+        end
+    ";
+
+    fn at(time: &str) -> DateTime<Local> {
+        Local
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(2024, 10, 1)
+                    .unwrap()
+                    .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap()),
+            )
+            .unwrap()
+    }
+
+    fn snapshot(now: &str, porch: Value) -> Snapshot {
+        Snapshot {
+            now: at(now),
+            values: HashMap::from([("Porch Light".to_string(), porch)]),
+            sun: Some(SunDay {
+                sunrise: at("07:00"),
+                sunset: at("18:30"),
+                twilight: BTreeMap::new(),
+            }),
+            marks: Vec::new(),
+        }
+    }
+
+    fn turn_on(on: i64) -> Step {
+        Step::Set {
+            accessory: "Porch Light".to_string(),
+            characteristic: Characteristic::On,
+            value: json!(on),
+        }
+    }
+
+    #[test]
+    fn plans_the_steps_of_a_run() {
+        let script = Script::parse(PORCH).unwrap();
+        assert_eq!(script.accessories(), vec!["Porch Light"]);
+        assert!(script.uses_sun());
+
+        let off = json!({"On": 0, "Brightness": 60});
+        // The brightness is already right, so only the light is turned on.
+        assert_eq!(
+            script.plan(&snapshot("19:00", off.clone())).unwrap(),
+            vec![turn_on(1), Step::Mark("evening".to_string())]
+        );
+        let marked = Snapshot {
+            marks: vec!["evening".to_string()],
+            ..snapshot("19:00", off.clone())
+        };
+        assert_eq!(script.plan(&marked).unwrap(), vec![]);
+        assert_eq!(script.plan(&snapshot("18:40", off)).unwrap(), vec![]);
+
+        // An unchanged value is not written again.
+        let night = Snapshot {
+            marks: vec!["evening".to_string()],
+            ..snapshot("23:45", json!({"On": 1}))
+        };
+        assert_eq!(script.plan(&night).unwrap(), vec![turn_on(0)]);
+        let early = snapshot("06:00", json!({"On": 0}));
+        assert_eq!(script.plan(&early).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn missing_values_and_sun_times_fail_the_run() {
+        let script =
+            Script::parse("if light('Hall').on && before civil_dusk then\n mark 'x'\nend").unwrap();
+        assert!(script.uses_sun());
+        assert_eq!(script.accessories(), vec!["Hall"]);
+        let without_hall = snapshot("12:00", json!({}));
+        assert!(matches!(
+            script.plan(&without_hall),
+            Err(ScriptError::Expression(_))
+        ));
+        let mut with_hall = snapshot("12:00", json!({}));
+        with_hall
+            .values
+            .insert("Hall".to_string(), json!({"On": 1}));
+        assert!(matches!(
+            script.plan(&with_hall),
+            Err(ScriptError::NoSunEvent(SunEvent::CivilDusk))
+        ));
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        for (source, line) in [
+            ("mark 'a'\nset 'Porch' Brightnes 10", 2),
+            ("if light('A').on\nend", 1),
+            ("if light('A').on then\nmark 'a'", 1),
+            ("mark 'a'\n\nend", 3),
+            ("if after 25:00 then\nend", 1),
+            ("if after noon then\nend", 1),
+            ("if light('A').on then\nelse\nend", 2),
+            ("turn 'Porch' on", 1),
+            ("mark 'a' 'b'", 1),
+        ] {
+            match Script::parse(source) {
+                Err(ScriptError::Parse { line: l, .. }) => assert_eq!(l, line, "{}", source),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+}
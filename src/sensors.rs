@@ -0,0 +1,117 @@
+use crate::configuration::VirtualSensorConfig;
+use log::info;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SensorError {
+    #[error("No virtual sensor '{0}' is configured.")]
+    Unknown(String),
+    #[error("'{0}' is not a sensor value (expected a number, true/false, or on/off).")]
+    InvalidValue(String),
+}
+
+/// Characteristic value of a reported reading: numbers as they are, and booleans, "on"/"off",
+/// or "true"/"false" as 1/0.
+fn reading(value: &Value) -> Result<Value, SensorError> {
+    let invalid = || SensorError::InvalidValue(value.to_string());
+    match value {
+        Value::Number(_) => Ok(value.clone()),
+        Value::Bool(b) => Ok(Value::from(*b as u8)),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "on" | "true" => Ok(Value::from(1)),
+            "off" | "false" => Ok(Value::from(0)),
+            other => other
+                .parse::<f64>()
+                .ok()
+                .and_then(Number::from_f64)
+                .map(Value::Number)
+                .ok_or_else(invalid),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+/// Values reported by sensors outside Homebridge, which programs and conditions read like
+/// those of any accessory.
+#[derive(Debug, Default)]
+pub struct VirtualSensors {
+    config: BTreeMap<String, VirtualSensorConfig>,
+    /// Reports not yet handed to the program loop.
+    reported: BTreeMap<String, Map<String, Value>>,
+}
+
+impl VirtualSensors {
+    pub fn new(config: &BTreeMap<String, VirtualSensorConfig>) -> Self {
+        Self {
+            config: config.clone(),
+            reported: BTreeMap::new(),
+        }
+    }
+
+    /// Record a report of a sensor: a single value for its configured characteristic, or an
+    /// object of values by characteristic.
+    pub fn report(&mut self, name: &str, body: &Value) -> Result<Map<String, Value>, SensorError> {
+        let config = self
+            .config
+            .get(name)
+            .ok_or_else(|| SensorError::Unknown(name.to_string()))?;
+        let values = match body {
+            Value::Object(values) => values
+                .iter()
+                .map(|(c, v)| reading(v).map(|v| (c.clone(), v)))
+                .collect::<Result<Map<_, _>, _>>()?,
+            value => Map::from_iter([(config.characteristic.to_string(), reading(value)?)]),
+        };
+        info!("Sensor '{}' reported {:?}.", name, values);
+        self.reported
+            .entry(name.to_string())
+            .or_default()
+            .extend(values.clone());
+        Ok(values)
+    }
+
+    /// Reports since the last call, by sensor.
+    pub fn take_reported(&mut self) -> BTreeMap<String, Map<String, Value>> {
+        std::mem::take(&mut self.reported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characteristic::Characteristic;
+    use serde_json::json;
+
+    #[test]
+    fn reports_become_characteristic_values() {
+        let config = BTreeMap::from([(
+            "Garage Door".to_string(),
+            VirtualSensorConfig {
+                characteristic: Characteristic::ContactSensorState,
+            },
+        )]);
+        let mut sensors = VirtualSensors::new(&config);
+        sensors.report("Garage Door", &json!("ON")).unwrap();
+        sensors
+            .report(
+                "Garage Door",
+                &json!({"CurrentTemperature": "21.5", "StatusLowBattery": false}),
+            )
+            .unwrap();
+        assert_eq!(
+            Value::Object(sensors.take_reported()["Garage Door"].clone()),
+            json!({"ContactSensorState": 1, "CurrentTemperature": 21.5, "StatusLowBattery": 0})
+        );
+        assert!(sensors.take_reported().is_empty());
+
+        assert!(matches!(
+            sensors.report("Shed", &json!(1)),
+            Err(SensorError::Unknown(_))
+        ));
+        assert!(matches!(
+            sensors.report("Garage Door", &json!("ajar")),
+            Err(SensorError::InvalidValue(_))
+        ));
+    }
+}
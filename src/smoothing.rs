@@ -0,0 +1,85 @@
+use crate::configuration::{SmoothingConfig, SmoothingMethod};
+use std::collections::VecDeque;
+
+/// Filter for noisy measurements.
+#[derive(Debug, Clone)]
+pub enum Smoother {
+    /// Exponential moving average; each reading moves the average by `alpha` of the difference.
+    Ema { alpha: f64, average: Option<f64> },
+    /// Median of the last `window` readings.
+    Median {
+        window: usize,
+        readings: VecDeque<f64>,
+    },
+}
+
+impl Smoother {
+    pub fn from_config(config: &SmoothingConfig) -> Self {
+        let window = config.window.max(1);
+        match config.method {
+            // Same center of mass as a simple average over `window` readings.
+            SmoothingMethod::Ema => Smoother::Ema {
+                alpha: 2.0 / (window as f64 + 1.0),
+                average: None,
+            },
+            SmoothingMethod::Median => Smoother::Median {
+                window,
+                readings: VecDeque::with_capacity(window),
+            },
+        }
+    }
+
+    /// Add a reading and return the smoothed value.
+    pub fn update(&mut self, value: f64) -> f64 {
+        match self {
+            Smoother::Ema { alpha, average } => {
+                let next = match average {
+                    Some(avg) => *avg + *alpha * (value - *avg),
+                    None => value,
+                };
+                *average = Some(next);
+                next
+            }
+            Smoother::Median { window, readings } => {
+                if readings.len() == *window {
+                    readings.pop_front();
+                }
+                readings.push_back(value);
+                let mut sorted: Vec<f64> = readings.iter().copied().collect();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let mid = sorted.len() / 2;
+                match sorted.len() % 2 {
+                    0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+                    _ => sorted[mid],
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smoother(method: SmoothingMethod, window: usize) -> Smoother {
+        Smoother::from_config(&SmoothingConfig { method, window })
+    }
+
+    #[test]
+    fn median_ignores_a_single_spike() {
+        let mut median = smoother(SmoothingMethod::Median, 3);
+        let smoothed: Vec<f64> = [50.0, 52.0, 95.0, 51.0]
+            .into_iter()
+            .map(|v| median.update(v))
+            .collect();
+        assert_eq!(smoothed, vec![50.0, 51.0, 52.0, 52.0]);
+    }
+
+    #[test]
+    fn ema_dampens_a_single_spike() {
+        let mut ema = smoother(SmoothingMethod::Ema, 3);
+        assert_eq!(ema.update(50.0), 50.0);
+        assert_eq!(ema.update(90.0), 70.0);
+        assert_eq!(ema.update(50.0), 60.0);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::homebridge::AccessoryCache;
+use crate::maintenance::MaintenanceRecord;
+use crate::suntimes::SunEvent;
+use crate::tolerance::Deviations;
+use chrono::{DateTime, Local, NaiveDate};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum StateError {
+    #[error("Error reading or writing state file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Error (de)serializing state: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// Last sunrise/sunset data and recent failures to fetch it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SuntimesRecord {
+    /// When the recorded times were fetched.
+    #[serde(default)]
+    pub fetched_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub sunrise: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub sunset: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub twilight: BTreeMap<SunEvent, DateTime<Local>>,
+    /// Failed fetches since the last success.
+    #[serde(default)]
+    pub failures: Vec<DateTime<Local>>,
+}
+
+/// One evening watched by the sunset calibration.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CalibrationDay {
+    pub day: NaiveDate,
+    /// `minutes_before_sunset_start` in effect that evening.
+    pub start_minutes: i64,
+    /// When the evening light was switched on by hand before the ramp, in minutes before sunset.
+    pub manual_on_minutes: Option<i64>,
+}
+
+/// Evenings watched by the sunset calibration and the start it applied.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct CalibrationRecord {
+    #[serde(default)]
+    pub days: Vec<CalibrationDay>,
+    #[serde(default)]
+    pub applied_start_minutes: Option<i64>,
+}
+
+/// Controller state that must survive restarts.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct PersistentState {
+    #[serde(default)]
+    pub snoozed_until: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub suntimes: SuntimesRecord,
+    #[serde(default)]
+    pub calibration: CalibrationRecord,
+    /// Index of the scene last applied by each cycle action.
+    #[serde(default)]
+    pub cycle_positions: BTreeMap<String, usize>,
+    /// Day each irrigation valve was last handled.
+    #[serde(default)]
+    pub irrigation_last_runs: BTreeMap<String, NaiveDate>,
+    /// Day each mark of a script was last set, by script and mark.
+    #[serde(default)]
+    pub script_marks: BTreeMap<String, BTreeMap<String, NaiveDate>>,
+    /// Recent deviations of values read back from what was written, per accessory.
+    #[serde(default)]
+    pub read_back_deviations: BTreeMap<String, Deviations>,
+    /// Accessories of the bridge and their characteristics.
+    #[serde(default)]
+    pub accessory_cache: AccessoryCache,
+    /// When the controller turned on each accessory with a maximum run time that is still on.
+    #[serde(default)]
+    pub run_starts: BTreeMap<String, DateTime<Local>>,
+    /// Programs held by hand until the given time, by program name.
+    #[serde(default)]
+    pub paused: BTreeMap<String, DateTime<Local>>,
+    /// Accessories in maintenance, by key.
+    #[serde(default)]
+    pub maintenance: BTreeMap<String, MaintenanceRecord>,
+}
+
+/// Persistent state backed by a JSON file.
+#[derive(Debug)]
+pub struct StateStore {
+    path: PathBuf,
+    state: PersistentState,
+}
+
+impl StateStore {
+    /// Load the state file, starting from an empty state if it does not exist.
+    pub fn load(path: &Path) -> Result<Self, StateError> {
+        let state = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No state file at {:?}, starting with empty state.", path);
+                PersistentState::default()
+            }
+            Err(e) => return Err(StateError::Io(e)),
+        };
+        debug!("Loaded state: {:?}", state);
+        Ok(Self {
+            path: path.to_path_buf(),
+            state,
+        })
+    }
+
+    pub fn state(&self) -> &PersistentState {
+        &self.state
+    }
+
+    /// Modify the state and write it back to disk.
+    pub fn update<F>(&mut self, f: F) -> Result<(), StateError>
+    where
+        F: FnOnce(&mut PersistentState),
+    {
+        f(&mut self.state);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), StateError> {
+        // Write to a temporary file and rename so a crash never leaves a truncated state file.
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.state)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl StateStore {
+    /// End of the current snooze, if automations are snoozed at `now`.
+    pub fn snoozed_until(&mut self, now: &DateTime<Local>) -> Option<DateTime<Local>> {
+        match self.state.snoozed_until {
+            Some(until) if &until > now => Some(until),
+            Some(_) => {
+                info!("Snooze expired.");
+                if let Err(e) = self.update(|s| s.snoozed_until = None) {
+                    warn!("Failed to clear expired snooze: {}", e);
+                }
+                None
+            }
+            None => None,
+        }
+    }
+}
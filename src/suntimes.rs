@@ -1,7 +1,25 @@
-use chrono::{DateTime, Local, Utc};
-use log::{debug, error};
+use crate::clock;
+use crate::configuration::{DarkHoursConfig, SuntimesSource};
+use crate::control::SharedState;
+use crate::state::SuntimesRecord;
+use crate::time_format;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, Utc};
+use log::{debug, error, info, warn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Wait after the first failed fetch; doubled for every further failure.
+const MIN_RETRY_MINUTES: i64 = 5;
+const MAX_RETRY_MINUTES: i64 = 6 * 60;
+const MAX_RECORDED_FAILURES: usize = 10;
+
+/// Altitude of the sun's center at sunrise and sunset: the upper edge of the disc on the
+/// horizon, raised by refraction.
+const HORIZON_DEGREES: f64 = -0.833;
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+const J2000_JULIAN_DAY: f64 = 2_451_545.0;
 
 #[derive(thiserror::Error, Debug)]
 pub enum SuntimesError {
@@ -13,12 +31,247 @@ pub enum SuntimesError {
     FailedConnection(#[from] reqwest::Error),
     #[error("{0}")]
     FailedAssumption(String),
+    #[error(
+        "Sunrise/sunset API unavailable until {} and no earlier data to fall back on.",
+        time_format::show(.0)
+    )]
+    BackingOff(DateTime<Local>),
+    #[error("No sunrise or sunset on {date} ({}).", if *midnight_sun { "midnight sun" } else { "polar night" })]
+    NoSunEvents { date: NaiveDate, midnight_sun: bool },
+    #[error("No {event} on {date}: the sun does not get that far below the horizon.")]
+    NoTwilight { date: NaiveDate, event: SunEvent },
+}
+
+/// Sun event a program can be timed against: sunrise, sunset, or where a twilight phase begins
+/// or ends.
+///
+/// Dawn is the beginning of the morning twilight, dusk the end of the evening twilight, e.g.
+/// `civil_dusk` is when the sun is 6° below the horizon after sunset.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    AstronomicalDawn,
+    NauticalDawn,
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+    NauticalDusk,
+    AstronomicalDusk,
+}
+
+impl SunEvent {
+    /// Twilight events, as pairs of the dawn and dusk at the same altitude.
+    const TWILIGHT: [(SunEvent, SunEvent); 3] = [
+        (SunEvent::CivilDawn, SunEvent::CivilDusk),
+        (SunEvent::NauticalDawn, SunEvent::NauticalDusk),
+        (SunEvent::AstronomicalDawn, SunEvent::AstronomicalDusk),
+    ];
+
+    /// Altitude of the sun's center at the event, in degrees.
+    fn altitude(self) -> f64 {
+        match self {
+            SunEvent::Sunrise | SunEvent::Sunset => HORIZON_DEGREES,
+            SunEvent::CivilDawn | SunEvent::CivilDusk => -6.0,
+            SunEvent::NauticalDawn | SunEvent::NauticalDusk => -12.0,
+            SunEvent::AstronomicalDawn | SunEvent::AstronomicalDusk => -18.0,
+        }
+    }
+
+    /// The sunrise-sunset.org API's time of the event.
+    fn in_api_data(self, data: &SunriseSunsetData) -> Option<&str> {
+        match self {
+            SunEvent::Sunrise => Some(&data.sunrise),
+            SunEvent::Sunset => Some(&data.sunset),
+            SunEvent::CivilDawn => data.civil_twilight_begin.as_deref(),
+            SunEvent::CivilDusk => data.civil_twilight_end.as_deref(),
+            SunEvent::NauticalDawn => data.nautical_twilight_begin.as_deref(),
+            SunEvent::NauticalDusk => data.nautical_twilight_end.as_deref(),
+            SunEvent::AstronomicalDawn => data.astronomical_twilight_begin.as_deref(),
+            SunEvent::AstronomicalDusk => data.astronomical_twilight_end.as_deref(),
+        }
+    }
+}
+
+impl fmt::Display for SunEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SunEvent::AstronomicalDawn => "astronomical dawn",
+            SunEvent::NauticalDawn => "nautical dawn",
+            SunEvent::CivilDawn => "civil dawn",
+            SunEvent::Sunrise => "sunrise",
+            SunEvent::Sunset => "sunset",
+            SunEvent::CivilDusk => "civil dusk",
+            SunEvent::NauticalDusk => "nautical dusk",
+            SunEvent::AstronomicalDusk => "astronomical dusk",
+        })
+    }
+}
+
+/// Times used in place of fetched or calculated ones.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuntimesFallback {
+    /// Earlier days' times moved to today, while fetching is backing off.
+    Estimated,
+    /// The configured fallback times.
+    Configured,
+}
+
+/// Where the sun times in use come from and how current they are.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SuntimesFreshness {
+    /// When the times in use were fetched or calculated.
+    #[serde(serialize_with = "time_format::serialize_option")]
+    pub fetched_at: Option<DateTime<Local>>,
+    pub provider: SuntimesSource,
+    pub fallback: Option<SuntimesFallback>,
+}
+
+impl fmt::Display for SuntimesFreshness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let provider = match self.provider {
+            SuntimesSource::Api => "sunrise-sunset.org",
+            SuntimesSource::Calculated => "calculation",
+        };
+        match self.fetched_at {
+            Some(at) => write!(f, "from {} at {}", provider, time_format::show(&at))?,
+            None => write!(f, "none from {}", provider)?,
+        }
+        match self.fallback {
+            Some(SuntimesFallback::Estimated) => write!(f, ", moved to today"),
+            Some(SuntimesFallback::Configured) => write!(f, ", using fallback times"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sun times of one day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SunDay {
+    pub sunrise: DateTime<Local>,
+    pub sunset: DateTime<Local>,
+    /// Twilight events of the day; missing where the sun does not get that far below the horizon.
+    pub twilight: BTreeMap<SunEvent, DateTime<Local>>,
+}
+
+impl SunDay {
+    /// Time of `event` on the day, if it happens.
+    pub fn at(&self, event: SunEvent) -> Option<DateTime<Local>> {
+        match event {
+            SunEvent::Sunrise => Some(self.sunrise),
+            SunEvent::Sunset => Some(self.sunset),
+            twilight => self.twilight.get(&twilight).copied(),
+        }
+    }
+}
+
+/// Whether the API's time is its placeholder for a sun event that does not happen.
+fn is_placeholder(time: &DateTime<Utc>) -> bool {
+    // The API reports "1970-01-01T00:00:01+00:00" during polar day and night.
+    time.timestamp() <= 86_400
+}
+
+/// Whether a day without sun events at `latitude` is polar day rather than polar night.
+fn midnight_sun(latitude: f32, date: NaiveDate) -> bool {
+    let northern_summer = (4..=9).contains(&date.month());
+    (latitude >= 0.0) == northern_summer
+}
+
+/// When the sun's center passes `altitude` degrees on `date` in the morning and in the evening,
+/// from NOAA's solar position equations.
+///
+/// Within about a minute of the API away from the polar circles.
+fn calculate(
+    latitude: f32,
+    longitude: f32,
+    date: NaiveDate,
+    altitude: f64,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), SuntimesError> {
+    let (latitude, longitude) = (latitude as f64, longitude as f64);
+    let midnight = date.and_hms_opt(0, 0, 0).expect("Valid time.").and_utc();
+    // Evaluated at the approximate solar noon of the location.
+    let julian_day =
+        midnight.timestamp() as f64 / 86_400.0 + UNIX_EPOCH_JULIAN_DAY + 0.5 - longitude / 360.0;
+    let t = (julian_day - J2000_JULIAN_DAY) / 36_525.0;
+
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = 357.52911 + t * (35999.05029 - 0.0001537 * t);
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+    let m = mean_anomaly.to_radians();
+    let center = m.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * m).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * m).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude =
+        (mean_longitude + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l0 = mean_longitude.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * l0).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l0).cos()
+            - 0.5 * y * y * (4.0 * l0).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (altitude.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return Err(SuntimesError::NoSunEvents {
+            date,
+            midnight_sun: cos_hour_angle < -1.0,
+        });
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    // Minutes after midnight UTC.
+    let noon = 720.0 - 4.0 * longitude - equation_of_time;
+    let at = |minutes: f64| midnight + Duration::seconds((minutes * 60.0).round() as i64);
+    Ok((at(noon - 4.0 * hour_angle), at(noon + 4.0 * hour_angle)))
+}
+
+/// Sunrise, sunset, and twilight times of a day.
+pub fn calculate_day(
+    latitude: f32,
+    longitude: f32,
+    date: NaiveDate,
+) -> Result<SunDay, SuntimesError> {
+    let (sunrise, sunset) = calculate(latitude, longitude, date, HORIZON_DEGREES)?;
+    let mut twilight = BTreeMap::new();
+    for (dawn, dusk) in SunEvent::TWILIGHT {
+        if let Ok((begin, end)) = calculate(latitude, longitude, date, dawn.altitude()) {
+            twilight.insert(dawn, DateTime::from(begin));
+            twilight.insert(dusk, DateTime::from(end));
+        }
+    }
+    Ok(SunDay {
+        sunrise: DateTime::from(sunrise),
+        sunset: DateTime::from(sunset),
+        twilight,
+    })
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct SunriseSunsetData {
     sunrise: String,
     sunset: String,
+    #[serde(default)]
+    civil_twilight_begin: Option<String>,
+    #[serde(default)]
+    civil_twilight_end: Option<String>,
+    #[serde(default)]
+    nautical_twilight_begin: Option<String>,
+    #[serde(default)]
+    nautical_twilight_end: Option<String>,
+    #[serde(default)]
+    astronomical_twilight_begin: Option<String>,
+    #[serde(default)]
+    astronomical_twilight_end: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -26,11 +279,36 @@ struct SunriseSunsetResponse {
     results: SunriseSunsetData,
 }
 
+#[derive(Clone)]
 pub struct SunTimes {
     longitude: f32,
     latitude: f32,
     sunrise: Option<DateTime<Local>>,
     sunset: Option<DateTime<Local>>,
+    /// Twilight events of the same day as the sunrise and sunset.
+    twilight: BTreeMap<SunEvent, DateTime<Local>>,
+    /// Sun times of days other than today, looked up once each.
+    other_days: BTreeMap<NaiveDate, SunDay>,
+    /// Set when the times are estimates from earlier data; fetch again after this time.
+    estimated_until: Option<DateTime<Local>>,
+    /// Earlier times older than this are not used as estimates.
+    stale_after: Duration,
+    /// When the times of today were fetched or calculated.
+    fetched_at: Option<DateTime<Local>>,
+    /// Whether programs currently run on configured fallback times.
+    degraded: bool,
+    /// Whether times were asked for, or a fallback time used, since last taken.
+    used: bool,
+    fell_back: bool,
+    /// Day without sunrise or sunset, and whether the sun stays up.
+    polar: Option<(NaiveDate, bool)>,
+    state: Option<SharedState>,
+    source: SuntimesSource,
+    /// Largest difference between calculated and fetched times before warning.
+    cross_check_minutes: Option<i64>,
+    cross_checked: Option<NaiveDate>,
+    /// Counts the refreshes and mode changes, so the copy that saw the latest wins a merge.
+    revision: u64,
 }
 
 impl SunTimes {
@@ -40,52 +318,385 @@ impl SunTimes {
             latitude: lat,
             sunrise: None,
             sunset: None,
+            twilight: BTreeMap::new(),
+            other_days: BTreeMap::new(),
+            estimated_until: None,
+            stale_after: Duration::days(3),
+            fetched_at: None,
+            degraded: false,
+            used: false,
+            fell_back: false,
+            polar: None,
+            state: None,
+            source: SuntimesSource::Api,
+            cross_check_minutes: None,
+            cross_checked: None,
+            revision: 0,
         }
     }
+
+    /// Take the times from `source`, comparing calculated times with the API's once a day if
+    /// `cross_check_minutes` is set.
+    pub fn with_source(mut self, source: SuntimesSource, cross_check_minutes: Option<i64>) -> Self {
+        self.source = source;
+        self.cross_check_minutes = cross_check_minutes;
+        self
+    }
+
+    /// Stop estimating from earlier times once they are older than `days`.
+    pub fn with_stale_after(mut self, days: i64) -> Self {
+        self.stale_after = Duration::days(days);
+        self
+    }
+
+    /// Persist fetched times and failures in the state store so restarts respect the backoff.
+    pub fn with_state(mut self, state: SharedState) -> Self {
+        self.state = Some(state);
+        self
+    }
+}
+
+/// When the next fetch may be attempted after the recorded failures.
+fn retry_after(record: &SuntimesRecord) -> Option<DateTime<Local>> {
+    let last = record.failures.last()?;
+    let exponent = (record.failures.len() as u32 - 1).min(16);
+    let minutes = (MIN_RETRY_MINUTES * 2_i64.pow(exponent)).min(MAX_RETRY_MINUTES);
+    Some(*last + Duration::minutes(minutes))
+}
+
+/// The configured `fallback` for the sunrise or sunset on `date`.
+fn fallback_on(
+    date: NaiveDate,
+    what: &str,
+    fallback: NaiveTime,
+) -> Result<DateTime<Local>, SuntimesError> {
+    date.and_time(fallback)
+        .and_local_timezone(Local)
+        .earliest()
+        .ok_or_else(|| {
+            SuntimesError::FailedAssumption(format!(
+                "Fallback {} {} does not exist on {}.",
+                what,
+                time_format::show_time(fallback),
+                date
+            ))
+        })
+}
+
+/// The same time of day as `dt`, but today.
+fn on_today(dt: &DateTime<Local>, now: &DateTime<Local>) -> Option<DateTime<Local>> {
+    now.date_naive()
+        .and_time(dt.time())
+        .and_local_timezone(Local)
+        .earliest()
 }
 
 impl SunTimes {
-    async fn collect_sunrise_sunset_data(&mut self, client: &Client) -> Result<(), SuntimesError> {
+    /// Fetch the sun times of `date`, without caching them.
+    pub async fn fetch_on(
+        &self,
+        client: &Client,
+        date: NaiveDate,
+    ) -> Result<SunDay, SuntimesError> {
         let mut endpt = "https://api.sunrise-sunset.org/json?".to_string();
         endpt.push_str(&format!("lat={}&lng={}", self.latitude, self.longitude));
-        endpt.push_str("&date=today&formatted=0");
-        let res = client.get(&endpt).send().await;
-        let suntimes_data = match res {
-            Ok(dt_res) => dt_res.json::<SunriseSunsetResponse>().await.unwrap(),
-            Err(e) => {
-                error!("Could not get sunrise time.");
-                panic!("{}", e);
-            }
+        endpt.push_str(&format!("&date={}&formatted=0", date.format("%Y-%m-%d")));
+        let suntimes_data = client
+            .get(&endpt)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SunriseSunsetResponse>()
+            .await?;
+        let parse = |event: SunEvent| -> Result<Option<DateTime<Utc>>, SuntimesError> {
+            event
+                .in_api_data(&suntimes_data.results)
+                .map(|time| {
+                    time.parse::<DateTime<Utc>>().map_err(|e| {
+                        SuntimesError::ParseError(format!(
+                            "Error parsing {} datetime: {}",
+                            event, e
+                        ))
+                    })
+                })
+                .transpose()
         };
-        let sunrise = suntimes_data
-            .results
-            .sunrise
-            .parse::<DateTime<Utc>>()
-            .map_err(|e| {
-                SuntimesError::ParseError(format!("Error parsing sunrise datetime: {}", e))
-            })?;
+        let sunrise = parse(SunEvent::Sunrise)?.expect("Sunrise is always present.");
         debug!("Sunrise: {:?}", sunrise);
-        let sunset = suntimes_data
-            .results
-            .sunset
-            .parse::<DateTime<Utc>>()
-            .map_err(|e| {
-                SuntimesError::ParseError(format!("Error parsing sunset datetime: {}", e))
-            })?;
+        let sunset = parse(SunEvent::Sunset)?.expect("Sunset is always present.");
         debug!("Sunset: {:?}", sunset);
-        self.sunrise = Some(DateTime::from(sunrise));
-        self.sunset = Some(DateTime::from(sunset));
+        if is_placeholder(&sunrise) || is_placeholder(&sunset) {
+            return Err(SuntimesError::NoSunEvents {
+                date,
+                midnight_sun: midnight_sun(self.latitude, date),
+            });
+        }
+        let mut twilight = BTreeMap::new();
+        for event in SunEvent::TWILIGHT
+            .iter()
+            .flat_map(|(dawn, dusk)| [*dawn, *dusk])
+        {
+            if let Some(time) = parse(event)?.filter(|t| !is_placeholder(t)) {
+                twilight.insert(event, DateTime::from(time));
+            }
+        }
+        Ok(SunDay {
+            sunrise: DateTime::from(sunrise),
+            sunset: DateTime::from(sunset),
+            twilight,
+        })
+    }
+
+    /// Sun times of `date` from the configured source, without caching them.
+    pub async fn times_on(
+        &self,
+        client: &Client,
+        date: NaiveDate,
+    ) -> Result<SunDay, SuntimesError> {
+        match self.source {
+            SuntimesSource::Api => self.fetch_on(client, date).await,
+            SuntimesSource::Calculated => calculate_day(self.latitude, self.longitude, date),
+        }
+    }
+
+    /// Compare today's calculated times with the API's, at most once a day.
+    async fn cross_check(&mut self, client: &Client) {
+        let today = clock::now().date_naive();
+        let Some(tolerance) = self.cross_check_minutes else {
+            return;
+        };
+        if self.source != SuntimesSource::Calculated || self.cross_checked == Some(today) {
+            return;
+        }
+        self.cross_checked = Some(today);
+        let calculated = self.times_on(client, today).await;
+        match (calculated, self.fetch_on(client, today).await) {
+            (Ok(calculated), Ok(fetched)) => {
+                let minutes = (calculated.sunrise - fetched.sunrise)
+                    .num_minutes()
+                    .abs()
+                    .max((calculated.sunset - fetched.sunset).num_minutes().abs());
+                if minutes > tolerance {
+                    warn!(
+                        "Calculated sunrise/sunset ({} / {}) differ from the API's ({} / {}) by {} minutes - check the latitude and longitude.",
+                        time_format::show(&calculated.sunrise),
+                        time_format::show(&calculated.sunset),
+                        time_format::show(&fetched.sunrise),
+                        time_format::show(&fetched.sunset),
+                        minutes
+                    );
+                } else {
+                    debug!("Calculated sun times within {} minutes of the API's.", minutes);
+                }
+            }
+            (Err(SuntimesError::NoSunEvents { .. }), Err(SuntimesError::NoSunEvents { .. })) => {}
+            (Ok(_), Err(SuntimesError::NoSunEvents { .. }))
+            | (Err(SuntimesError::NoSunEvents { .. }), Ok(_)) => warn!(
+                "Calculation and sunrise/sunset API disagree on whether the sun rises and sets today."
+            ),
+            (_, Err(e)) | (Err(e), _) => debug!("Not cross-checking the sun times: {}", e),
+        }
+    }
+
+    async fn collect_sunrise_sunset_data(&mut self, client: &Client) -> Result<(), SuntimesError> {
+        let today = clock::now().date_naive();
+        let fetched = self.times_on(client, today).await;
+        self.polar = match &fetched {
+            Err(SuntimesError::NoSunEvents { midnight_sun, .. }) => {
+                info!(
+                    "No sunrise or sunset today ({}).",
+                    if *midnight_sun {
+                        "midnight sun"
+                    } else {
+                        "polar night"
+                    }
+                );
+                self.sunrise = None;
+                self.sunset = None;
+                self.twilight.clear();
+                self.estimated_until = None;
+                Some((today, *midnight_sun))
+            }
+            _ => None,
+        };
+        let day = fetched?;
+        self.check_plausible(&day.sunrise, &day.sunset);
+        self.fetched_at = Some(clock::now());
+        self.sunrise = Some(day.sunrise);
+        self.sunset = Some(day.sunset);
+        self.twilight = day.twilight;
+        self.estimated_until = None;
         Ok(())
     }
 
+    /// Warn about times that point at a configuration mistake rather than the location.
+    fn check_plausible(&self, sunrise: &DateTime<Local>, sunset: &DateTime<Local>) {
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).expect("Valid time.");
+        if sunset.time() < noon || sunrise.time() > noon {
+            warn!(
+                "Sunrise at {} and sunset at {} are on the wrong side of local noon for latitude {} and longitude {}. Check that they are not swapped, that the longitude is negative west of Greenwich, and that the system time zone is right.",
+                time_format::show_time(sunrise.time()),
+                time_format::show_time(sunset.time()),
+                self.latitude,
+                self.longitude
+            );
+        }
+    }
+
+    fn record(&self) -> SuntimesRecord {
+        match &self.state {
+            Some(state) => state
+                .lock()
+                .expect("State lock poisoned.")
+                .store
+                .state()
+                .suntimes
+                .clone(),
+            None => SuntimesRecord::default(),
+        }
+    }
+
+    fn save_record(&self, record: SuntimesRecord) {
+        if let Some(state) = &self.state {
+            let mut state = state.lock().expect("State lock poisoned.");
+            if let Err(e) = state.store.update(|s| s.suntimes = record) {
+                warn!("Failed to persist sunrise/sunset data: {}", e);
+            }
+        }
+    }
+
+    /// Use earlier times moved to today until the next fetch is allowed.
+    fn estimate(
+        &mut self,
+        record: &SuntimesRecord,
+        now: &DateTime<Local>,
+        retry_at: DateTime<Local>,
+    ) -> Result<(), SuntimesError> {
+        let fresh = |t: &DateTime<Local>| *now - *t <= self.stale_after;
+        let fetched_at = self.fetched_at.or(record.fetched_at);
+        let sunrise = self.sunrise.or(record.sunrise).filter(fresh);
+        let sunset = self.sunset.or(record.sunset).filter(fresh);
+        let twilight = match self.sunrise {
+            Some(_) => &self.twilight,
+            None => &record.twilight,
+        };
+        let twilight = twilight
+            .iter()
+            .filter(|(_, t)| fresh(t))
+            .filter_map(|(event, t)| Some((*event, on_today(t, now)?)))
+            .collect();
+        match (
+            sunrise.and_then(|t| on_today(&t, now)),
+            sunset.and_then(|t| on_today(&t, now)),
+        ) {
+            (Some(sunrise), Some(sunset)) => {
+                info!(
+                    "Using earlier sunrise/sunset times until {}: {} / {}.",
+                    time_format::show(&retry_at),
+                    time_format::show(&sunrise),
+                    time_format::show(&sunset)
+                );
+                self.sunrise = Some(sunrise);
+                self.sunset = Some(sunset);
+                self.twilight = twilight;
+                self.fetched_at = fetched_at;
+                self.estimated_until = Some(retry_at);
+                Ok(())
+            }
+            _ => Err(SuntimesError::BackingOff(retry_at)),
+        }
+    }
+
+    /// Make sure today's times are available, respecting the backoff after failures.
+    async fn refresh(&mut self, client: &Client) -> Result<(), SuntimesError> {
+        self.revision += 1;
+        if self.source == SuntimesSource::Calculated {
+            self.cross_check(client).await;
+            return self.collect_sunrise_sunset_data(client).await;
+        }
+        let now = clock::now();
+        let mut record = self.record();
+        if let (Some(sunrise), Some(sunset)) = (record.sunrise, record.sunset) {
+            if sunrise.date_naive() == now.date_naive() && self.estimated_until.is_none() {
+                debug!("Using today's sunrise/sunset data from the state file.");
+                self.fetched_at = record.fetched_at;
+                self.sunrise = Some(sunrise);
+                self.sunset = Some(sunset);
+                self.twilight = record.twilight;
+                return Ok(());
+            }
+        }
+        if let Some(retry_at) = retry_after(&record).filter(|t| &now < t) {
+            debug!(
+                "Not fetching sunrise/sunset data before {}.",
+                time_format::show(&retry_at)
+            );
+            return self.estimate(&record, &now, retry_at);
+        }
+        match self.collect_sunrise_sunset_data(client).await {
+            Ok(()) => {
+                record.fetched_at = self.fetched_at;
+                record.sunrise = self.sunrise;
+                record.sunset = self.sunset;
+                record.twilight = self.twilight.clone();
+                record.failures.clear();
+                self.save_record(record);
+                Ok(())
+            }
+            // Not a failure: there is nothing to fetch until the sun rises or sets again.
+            Err(e @ SuntimesError::NoSunEvents { .. }) => {
+                record.failures.clear();
+                self.save_record(record);
+                Err(e)
+            }
+            Err(e) => {
+                error!("Could not get sunrise/sunset data: {}", e);
+                record.failures.push(now);
+                if record.failures.len() > MAX_RECORDED_FAILURES {
+                    record.failures.remove(0);
+                }
+                let retry_at = retry_after(&record).unwrap_or(now);
+                let estimated = self.estimate(&record, &now, retry_at);
+                self.save_record(record);
+                estimated.map_err(|_| e)
+            }
+        }
+    }
+
+    /// The error for today if it has no sunrise or sunset.
+    fn polar_today(&self) -> Option<SuntimesError> {
+        let today = clock::now().date_naive();
+        self.polar
+            .filter(|(date, _)| *date == today)
+            .map(|(date, midnight_sun)| SuntimesError::NoSunEvents { date, midnight_sun })
+    }
+
+    /// Whether the sun rises and sets today (false during polar day and night).
+    pub async fn has_sun_events_today(&mut self, client: &Client) -> Result<bool, SuntimesError> {
+        match self.sunrise(client).await {
+            Ok(_) => Ok(true),
+            Err(SuntimesError::NoSunEvents { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn is_current(&self, time: &DateTime<Local>) -> bool {
+        let now = clock::now();
+        time.date_naive() == now.date_naive() && self.estimated_until.map_or(true, |t| now < t)
+    }
+
     pub async fn sunrise(&mut self, client: &Client) -> Result<DateTime<Local>, SuntimesError> {
+        self.used = true;
+        if let Some(e) = self.polar_today() {
+            return Err(e);
+        }
         if let Some(sunrise) = self.sunrise {
-            if sunrise.date_naive() == Local::now().date_naive() {
+            if self.is_current(&sunrise) {
                 return Ok(sunrise);
             }
             debug!("Sunrise data stale.")
         }
-        self.collect_sunrise_sunset_data(client).await?;
+        self.refresh(client).await?;
         match self.sunrise {
             Some(sunrise) => Ok(sunrise),
             None => {
@@ -98,13 +709,17 @@ impl SunTimes {
     }
 
     pub async fn sunset(&mut self, client: &Client) -> Result<DateTime<Local>, SuntimesError> {
+        self.used = true;
+        if let Some(e) = self.polar_today() {
+            return Err(e);
+        }
         if let Some(sunset) = self.sunset {
-            if sunset.date_naive() == Local::now().date_naive() {
+            if self.is_current(&sunset) {
                 return Ok(sunset);
             }
             debug!("Sunset data stale.")
         }
-        self.collect_sunrise_sunset_data(client).await?;
+        self.refresh(client).await?;
         match self.sunset {
             Some(sunset) => Ok(sunset),
             None => {
@@ -115,4 +730,355 @@ impl SunTimes {
             }
         }
     }
+
+    /// The time from `result`, or the configured `fallback` on `date` if there is none.
+    fn or_fallback(
+        &mut self,
+        result: Result<DateTime<Local>, SuntimesError>,
+        what: &str,
+        date: NaiveDate,
+        fallback: Option<NaiveTime>,
+    ) -> Result<DateTime<Local>, SuntimesError> {
+        match (result, fallback) {
+            (
+                Err(SuntimesError::NoSunEvents { .. } | SuntimesError::NoTwilight { .. }),
+                Some(fallback),
+            ) => {
+                self.fell_back = true;
+                debug!(
+                    "No {} on {} - using {}.",
+                    what,
+                    date,
+                    time_format::show_time(fallback)
+                );
+                fallback_on(date, what, fallback)
+            }
+            (Ok(time), _) => {
+                if self.degraded {
+                    info!("Sunrise/sunset times available again - leaving degraded mode.");
+                    self.degraded = false;
+                    self.revision += 1;
+                }
+                Ok(time)
+            }
+            (Err(e), Some(fallback)) => {
+                if !self.degraded {
+                    warn!(
+                        "Degraded mode: no usable sunrise/sunset times ({}) - running on fallback times.",
+                        e
+                    );
+                    self.degraded = true;
+                    self.revision += 1;
+                }
+                self.fell_back = true;
+                debug!(
+                    "Using fallback {} {}.",
+                    what,
+                    time_format::show_time(fallback)
+                );
+                fallback_on(date, what, fallback)
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// Sunrise, or today at `fallback` when no sunrise times are available.
+    pub async fn sunrise_or(
+        &mut self,
+        client: &Client,
+        fallback: Option<NaiveTime>,
+    ) -> Result<DateTime<Local>, SuntimesError> {
+        let sunrise = self.sunrise(client).await;
+        self.or_fallback(sunrise, "sunrise", clock::now().date_naive(), fallback)
+    }
+
+    /// Sunset, or today at `fallback` when no sunset times are available.
+    pub async fn sunset_or(
+        &mut self,
+        client: &Client,
+        fallback: Option<NaiveTime>,
+    ) -> Result<DateTime<Local>, SuntimesError> {
+        let sunset = self.sunset(client).await;
+        self.or_fallback(sunset, "sunset", clock::now().date_naive(), fallback)
+    }
+
+    /// Today's time of `event`.
+    pub async fn event(
+        &mut self,
+        client: &Client,
+        event: SunEvent,
+    ) -> Result<DateTime<Local>, SuntimesError> {
+        match event {
+            SunEvent::Sunrise => self.sunrise(client).await,
+            SunEvent::Sunset => self.sunset(client).await,
+            // Twilight times are kept current along with the sunrise.
+            twilight => {
+                let sunrise = self.sunrise(client).await?;
+                self.twilight
+                    .get(&twilight)
+                    .copied()
+                    .ok_or(SuntimesError::NoTwilight {
+                        date: sunrise.date_naive(),
+                        event,
+                    })
+            }
+        }
+    }
+
+    /// Today's time of `event`, or today at `fallback` when it is unavailable or does not happen.
+    pub async fn event_or(
+        &mut self,
+        client: &Client,
+        event: SunEvent,
+        fallback: Option<NaiveTime>,
+    ) -> Result<DateTime<Local>, SuntimesError> {
+        let time = self.event(client, event).await;
+        self.or_fallback(
+            time,
+            &event.to_string(),
+            clock::now().date_naive(),
+            fallback,
+        )
+    }
+
+    /// Time of `event` on `date`, or `fallback` on that day when it is unavailable or does not
+    /// happen.
+    ///
+    /// Today's times are those of [`SunTimes::event_or`]; other days are looked up once and
+    /// kept until the day has come.
+    pub async fn event_on_or(
+        &mut self,
+        client: &Client,
+        event: SunEvent,
+        date: NaiveDate,
+        fallback: Option<NaiveTime>,
+    ) -> Result<DateTime<Local>, SuntimesError> {
+        let today = clock::now().date_naive();
+        if date == today {
+            return self.event_or(client, event, fallback).await;
+        }
+        self.used = true;
+        self.other_days.retain(|day, _| *day > today);
+        let day = match self.other_days.get(&date) {
+            Some(day) => Ok(day.clone()),
+            None => self.times_on(client, date).await.inspect(|day| {
+                self.other_days.insert(date, day.clone());
+            }),
+        };
+        let time = day.and_then(|day| {
+            day.at(event)
+                .ok_or(SuntimesError::NoTwilight { date, event })
+        });
+        self.or_fallback(time, &event.to_string(), date, fallback)
+    }
+
+    /// Take over what a program learnt on its copy, e.g. times it fetched.
+    pub fn merge(&mut self, mut copy: SunTimes) {
+        if copy.revision > self.revision {
+            std::mem::swap(self, &mut copy);
+        }
+        self.other_days.append(&mut copy.other_days);
+    }
+
+    /// Where today's times come from and how current they are.
+    pub fn freshness(&self) -> SuntimesFreshness {
+        let fallback = if self.degraded || self.fell_back {
+            Some(SuntimesFallback::Configured)
+        } else if self.estimated_until.is_some() {
+            Some(SuntimesFallback::Estimated)
+        } else {
+            None
+        };
+        SuntimesFreshness {
+            fetched_at: self.fetched_at,
+            provider: self.source,
+            fallback,
+        }
+    }
+
+    /// The freshness of the times asked for since the last call, if any were.
+    ///
+    /// Called around a program run, it tells which times the program's decision rests on.
+    pub fn take_used(&mut self) -> Option<SuntimesFreshness> {
+        let freshness = self.used.then(|| self.freshness());
+        self.used = false;
+        self.fell_back = false;
+        freshness
+    }
+
+    /// Today's sun times.
+    pub async fn today(&mut self, client: &Client) -> Result<SunDay, SuntimesError> {
+        Ok(SunDay {
+            sunrise: self.sunrise(client).await?,
+            sunset: self.sunset(client).await?,
+            twilight: self.twilight.clone(),
+        })
+    }
+
+    /// Whether `now` is between the configured dusk and dawn events (sunset and sunrise by
+    /// default), shifted by the margins.
+    ///
+    /// Without fallback times, it is dark all day during polar night and never during polar day,
+    /// nor on days the sun does not get as far below the horizon as the configured twilight.
+    pub async fn is_dark(
+        &mut self,
+        client: &Client,
+        now: &DateTime<Local>,
+        hours: &DarkHoursConfig,
+    ) -> Result<bool, SuntimesError> {
+        let times = match (
+            self.event_or(client, hours.dark_from, hours.sunset_fallback)
+                .await,
+            self.event_or(client, hours.dark_until, hours.sunrise_fallback)
+                .await,
+        ) {
+            (Err(SuntimesError::NoSunEvents { midnight_sun, .. }), _)
+            | (_, Err(SuntimesError::NoSunEvents { midnight_sun, .. })) => {
+                return Ok(!midnight_sun)
+            }
+            (Err(SuntimesError::NoTwilight { .. }), _)
+            | (_, Err(SuntimesError::NoTwilight { .. })) => return Ok(false),
+            (sunset, sunrise) => (sunset?, sunrise?),
+        };
+        let sunset = times.0 + Duration::minutes(hours.sunset_margin_minutes);
+        let sunrise = times.1 + Duration::minutes(hours.sunrise_margin_minutes);
+        Ok(*now >= sunset || *now <= sunrise)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_polar_day_and_night() {
+        let placeholder = "1970-01-01T00:00:01+00:00"
+            .parse::<DateTime<Utc>>()
+            .unwrap();
+        assert!(is_placeholder(&placeholder));
+        let sunrise = "2024-06-21T01:30:00+00:00"
+            .parse::<DateTime<Utc>>()
+            .unwrap();
+        assert!(!is_placeholder(&sunrise));
+
+        let june = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let december = NaiveDate::from_ymd_opt(2024, 12, 21).unwrap();
+        assert!(midnight_sun(69.6, june));
+        assert!(!midnight_sun(69.6, december));
+        assert!(midnight_sun(-77.8, december));
+    }
+
+    #[test]
+    fn calculates_sunrise_and_sunset() {
+        let june = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let close_to = |time: DateTime<Utc>, expected: &str| {
+            let expected = expected.parse::<DateTime<Utc>>().unwrap();
+            (time - expected).num_minutes().abs() <= 1
+        };
+        // Boston, as given by the API.
+        let (sunrise, sunset) = calculate(42.36, -71.06, june, HORIZON_DEGREES).unwrap();
+        assert!(
+            close_to(sunrise, "2024-06-21T09:07:25+00:00"),
+            "{}",
+            sunrise
+        );
+        assert!(close_to(sunset, "2024-06-22T00:24:53+00:00"), "{}", sunset);
+        // Sydney, where the sunrise is on the previous day in UTC.
+        let (sunrise, sunset) = calculate(-33.87, 151.21, june, HORIZON_DEGREES).unwrap();
+        assert!(
+            close_to(sunrise, "2024-06-20T20:59:52+00:00"),
+            "{}",
+            sunrise
+        );
+        assert!(close_to(sunset, "2024-06-21T06:53:44+00:00"), "{}", sunset);
+
+        assert!(matches!(
+            calculate(69.65, 18.96, june, HORIZON_DEGREES),
+            Err(SuntimesError::NoSunEvents {
+                midnight_sun: true,
+                ..
+            })
+        ));
+        assert!(matches!(
+            calculate(-77.85, 166.67, june, HORIZON_DEGREES),
+            Err(SuntimesError::NoSunEvents {
+                midnight_sun: false,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn calculates_twilight() {
+        let june = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let boston = calculate_day(42.36, -71.06, june).unwrap();
+        let events: Vec<DateTime<Local>> = [
+            SunEvent::AstronomicalDawn,
+            SunEvent::NauticalDawn,
+            SunEvent::CivilDawn,
+            SunEvent::Sunrise,
+            SunEvent::Sunset,
+            SunEvent::CivilDusk,
+            SunEvent::NauticalDusk,
+            SunEvent::AstronomicalDusk,
+        ]
+        .into_iter()
+        .map(|event| boston.at(event).unwrap())
+        .collect();
+        assert!(events.windows(2).all(|pair| pair[0] < pair[1]));
+        // Civil twilight lasts about half an hour at midsummer in Boston.
+        let civil = boston.at(SunEvent::CivilDusk).unwrap() - boston.sunset;
+        assert!((25..=45).contains(&civil.num_minutes()), "{}", civil);
+
+        // Edinburgh, where it never gets darker than civil twilight at midsummer.
+        let edinburgh = calculate_day(55.95, -3.19, june).unwrap();
+        assert!(edinburgh.at(SunEvent::CivilDusk).is_some());
+        assert!(edinburgh.at(SunEvent::NauticalDusk).is_none());
+        assert!(edinburgh.at(SunEvent::AstronomicalDawn).is_none());
+
+        let event: SunEvent = serde_json::from_str("\"nautical_dusk\"").unwrap();
+        assert_eq!(event, SunEvent::NauticalDusk);
+        assert_eq!(event.to_string(), "nautical dusk");
+    }
+
+    #[test]
+    fn tells_where_the_times_come_from() {
+        let mut suntimes = SunTimes::new(-71.06, 42.36);
+        assert_eq!(suntimes.take_used(), None);
+        assert_eq!(
+            suntimes.freshness(),
+            SuntimesFreshness {
+                fetched_at: None,
+                provider: SuntimesSource::Api,
+                fallback: None
+            }
+        );
+
+        let now = clock::now();
+        let yesterday = now - Duration::days(1);
+        let record = SuntimesRecord {
+            fetched_at: Some(yesterday),
+            sunrise: Some(yesterday),
+            sunset: Some(yesterday + Duration::hours(10)),
+            ..SuntimesRecord::default()
+        };
+        suntimes
+            .estimate(&record, &now, now + Duration::minutes(5))
+            .unwrap();
+        let estimated = suntimes.freshness();
+        assert_eq!(estimated.fetched_at, Some(yesterday));
+        assert_eq!(estimated.fallback, Some(SuntimesFallback::Estimated));
+
+        suntimes.used = true;
+        let fallback = NaiveTime::from_hms_opt(18, 0, 0).unwrap();
+        let backing_off = Err(SuntimesError::BackingOff(now));
+        suntimes
+            .or_fallback(backing_off, "sunset", now.date_naive(), Some(fallback))
+            .unwrap();
+        let used = suntimes.take_used().unwrap();
+        assert_eq!(used.fallback, Some(SuntimesFallback::Configured));
+        assert!(used.to_string().starts_with("from sunrise-sunset.org at "));
+        assert!(used.to_string().ends_with(", using fallback times"));
+        assert_eq!(suntimes.take_used(), None);
+    }
 }
@@ -0,0 +1,646 @@
+use crate::audit;
+use crate::clock;
+use crate::configuration::TelegramConfig;
+use crate::control::{execute, ControlCommand, SharedState};
+use crate::decisions::{Decision, Outcome};
+use crate::fuzzy;
+use crate::programs::ProgramId;
+use crate::time_format;
+use chrono::{DateTime, Duration, Local};
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+
+const USAGE: &str = "Commands:
+/status - what the programs did last and do today
+/pause [program] <duration> - hold one program or all, e.g. /pause evening 2h
+/resume [program] - let them run again
+/set <light> <brightness> - e.g. /set bedside 30
+/set <action> - run a configured action";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TelegramError {
+    #[error("Request to Telegram failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Telegram rejected the request: {0}")]
+    Rejected(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiResponse<T> {
+    ok: bool,
+    #[serde(default)]
+    description: Option<String>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Update {
+    update_id: i64,
+    #[serde(default)]
+    message: Option<Message>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    chat: Chat,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Chat {
+    id: i64,
+}
+
+/// A command sent to the bot.
+#[derive(Debug, PartialEq)]
+enum BotCommand {
+    Help,
+    Status,
+    /// Hold a program, or all of them without one.
+    Pause {
+        program: Option<String>,
+        duration: std::time::Duration,
+    },
+    Resume {
+        program: Option<String>,
+    },
+    /// Set a light's brightness, or run an action without a value.
+    Set {
+        target: String,
+        value: Option<u8>,
+    },
+}
+
+fn parse(text: &str) -> Result<BotCommand, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let Some((command, args)) = words.split_first() else {
+        return Err(USAGE.to_string());
+    };
+    // Commands picked from the menu in group chats carry the bot's name, e.g. `/status@bot`.
+    let command = command.split('@').next().unwrap_or(command);
+    let rest = |words: &[&str]| (!words.is_empty()).then(|| words.join(" "));
+    match command {
+        "/start" | "/help" => Ok(BotCommand::Help),
+        "/status" => Ok(BotCommand::Status),
+        "/pause" => {
+            let Some((duration, program)) = args.split_last() else {
+                return Err(
+                    "Usage: /pause [program] <duration>, e.g. /pause evening 2h".to_string()
+                );
+            };
+            let duration = humantime::parse_duration(duration)
+                .map_err(|e| format!("Invalid duration '{}': {}", duration, e))?;
+            Ok(BotCommand::Pause {
+                program: rest(program),
+                duration,
+            })
+        }
+        "/resume" => Ok(BotCommand::Resume {
+            program: rest(args),
+        }),
+        "/set" => match args.split_last() {
+            None => Err("Usage: /set <light> <brightness> or /set <action>".to_string()),
+            Some((value, light)) if !light.is_empty() && value.parse::<u8>().is_ok() => {
+                Ok(BotCommand::Set {
+                    target: light.join(" "),
+                    value: value.parse().ok(),
+                })
+            }
+            Some(_) => Ok(BotCommand::Set {
+                target: args.join(" "),
+                value: None,
+            }),
+        },
+        _ => Err(format!("Unknown command '{}'.\n{}", command, USAGE)),
+    }
+}
+
+/// The configured program (or program instance) meant by `name`, also by its short name or a
+/// part of it (e.g. `evening`).
+///
+/// A part must point at one program: the reply to one matching several lists them instead of
+/// guessing. Instances of a matching program count as that program.
+fn resolve_program(name: &str, instances: &[String]) -> Result<String, String> {
+    let mut candidates: Vec<&str> = instances.iter().map(String::as_str).collect();
+    let configured: BTreeSet<ProgramId> = instances
+        .iter()
+        .filter_map(|i| ProgramId::from_name(i.split(':').next().unwrap_or(i)))
+        .collect();
+    candidates.extend(configured.iter().map(|id| id.short_name()));
+    candidates.extend(configured.iter().map(|id| id.name()));
+    let target = |found: &str| {
+        ProgramId::from_name(found)
+            .map(|id| id.name().to_string())
+            .unwrap_or_else(|| found.to_string())
+    };
+    if candidates.contains(&name) {
+        return Ok(target(name));
+    }
+    let targets: BTreeSet<String> = fuzzy::search(name, candidates)
+        .into_iter()
+        .map(target)
+        .collect();
+    let programs: BTreeSet<&str> = targets
+        .iter()
+        .filter(|t| !t.contains(':'))
+        .map(String::as_str)
+        .collect();
+    let matches: Vec<&str> = targets
+        .iter()
+        .map(String::as_str)
+        .filter(|t| !t.split_once(':').is_some_and(|(p, _)| programs.contains(p)))
+        .collect();
+    only_match("program", name, matches)
+}
+
+/// The one name among those matching `name`, or the reply listing them.
+///
+/// A match differing from `name` only in case or punctuation wins over the others.
+fn only_match(kind: &str, name: &str, matches: Vec<&str>) -> Result<String, String> {
+    if let Some(same) = matches.iter().find(|m| fuzzy::same(m, name)) {
+        return Ok(same.to_string());
+    }
+    match matches.as_slice() {
+        [] => Err(format!("No {} '{}'.", kind, name)),
+        [found] => Ok(found.to_string()),
+        several => Err(format!(
+            "'{}' matches several {}s: {}.",
+            name,
+            kind,
+            several.join(", ")
+        )),
+    }
+}
+
+/// The one of `names` meant by `name`, by a part of it or with a typo.
+fn resolve(kind: &str, name: &str, names: &BTreeSet<String>) -> Result<String, String> {
+    match names.contains(name) {
+        true => Ok(name.to_string()),
+        false => only_match(
+            kind,
+            name,
+            fuzzy::search(name, names.iter().map(String::as_str)),
+        ),
+    }
+}
+
+/// A message for each program that started failing or ran again after failing, among the
+/// decisions after those already seen.
+fn alerts(
+    timeline: &BTreeMap<&str, Vec<&Decision>>,
+    seen: &mut BTreeMap<String, (DateTime<Local>, bool)>,
+) -> Vec<String> {
+    let mut messages = Vec::new();
+    for (program, decisions) in timeline.iter() {
+        let (last_seen, mut failing) = seen
+            .get(*program)
+            .map_or((None, false), |(when, failing)| (Some(*when), *failing));
+        let new = decisions
+            .iter()
+            .rev()
+            .filter(|d| last_seen.map_or(true, |when| d.when > when));
+        for decision in new {
+            match decision.outcome {
+                Outcome::Failed if !failing => {
+                    failing = true;
+                    messages.push(format!("{} failed: {}", program, decision.reason));
+                }
+                Outcome::Ran if failing => {
+                    failing = false;
+                    messages.push(format!("{} works again: {}", program, decision.reason));
+                }
+                _ => {}
+            }
+        }
+        if let Some(newest) = decisions.first() {
+            seen.insert(program.to_string(), (newest.when, failing));
+        }
+    }
+    messages
+}
+
+/// Long-polling bot for the allowed chats.
+struct Bot {
+    config: TelegramConfig,
+    client: Client,
+    state: SharedState,
+    /// Program instances at startup, until the main loop publishes its schedule.
+    programs: Vec<String>,
+    /// Accessories of the configuration, to match light names against.
+    accessories: BTreeSet<String>,
+    actions: BTreeSet<String>,
+    /// Next update to fetch.
+    offset: i64,
+    /// Newest decision seen of each program, and whether it was failing.
+    seen: BTreeMap<String, (DateTime<Local>, bool)>,
+}
+
+impl Bot {
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: serde_json::Value,
+    ) -> Result<T, TelegramError> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config.api_url, self.config.token, method
+        );
+        // Errors would show the URL, which carries the token.
+        let response: ApiResponse<T> = self
+            .client
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?
+            .json()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        match (response.ok, response.result) {
+            (true, Some(result)) => Ok(result),
+            _ => Err(TelegramError::Rejected(
+                response
+                    .description
+                    .unwrap_or_else(|| "no result".to_string()),
+            )),
+        }
+    }
+
+    async fn send(&self, chat: i64, text: &str) {
+        let body = json!({"chat_id": chat, "text": text});
+        if let Err(e) = self.call::<serde_json::Value>("sendMessage", body).await {
+            warn!("Failed to send a Telegram message: {}", e);
+        }
+    }
+
+    async fn updates(&self) -> Result<Vec<Update>, TelegramError> {
+        let body = json!({
+            "offset": self.offset,
+            "timeout": self.config.poll_seconds,
+            "allowed_updates": ["message"],
+        });
+        self.call("getUpdates", body).await
+    }
+
+    async fn send_alerts(&mut self) {
+        let messages = {
+            let state = self.state.lock().expect("State lock poisoned.");
+            alerts(&state.decisions.timeline(), &mut self.seen)
+        };
+        for message in messages.iter() {
+            for chat in self.config.allowed_chats.iter() {
+                self.send(*chat, message).await;
+            }
+        }
+    }
+
+    /// Status of the snooze and each program.
+    fn status(&self) -> String {
+        let now = clock::now();
+        let mut state = self.state.lock().expect("State lock poisoned.");
+        let paused: BTreeMap<String, Option<DateTime<Local>>> = state
+            .schedule
+            .clone()
+            .into_iter()
+            .map(|s| {
+                let until = state.paused_until(&s.program, &now);
+                (s.program, until)
+            })
+            .collect();
+        let suntimes = state.suntimes;
+        let maintenance: Vec<String> = state
+            .maintenance
+            .current(&now)
+            .iter()
+            .map(|entry| format!("{}.", entry))
+            .collect();
+        let status = state.public_status(&now);
+        let mut lines = vec![match status.snoozed_until {
+            Some(until) => format!("Snoozed until {}.", time_format::show(&until)),
+            None => "Not snoozed.".to_string(),
+        }];
+        if let Some(suntimes) = suntimes {
+            lines.push(format!("Sun times {}.", suntimes));
+        }
+        lines.extend(maintenance);
+        for program in status.programs.iter() {
+            let mut line = program.program.to_string();
+            if !program.active {
+                line.push_str(" (inactive)");
+            }
+            if let Some(today) = program.today {
+                line.push_str(&format!(" - today: {}", today));
+            }
+            if let Some(Some(until)) = paused.get(program.program) {
+                line.push_str(&format!(" - paused until {}", time_format::show(until)));
+            }
+            if let Some(decision) = program.last_decision {
+                line.push_str(&format!(
+                    " - last: {} at {}, {}",
+                    format!("{:?}", decision.outcome).to_lowercase(),
+                    decision.when.format("%H:%M"),
+                    decision.reason
+                ));
+            }
+            lines.push(line);
+        }
+        if status.programs.is_empty() {
+            lines.push("No programs have run yet.".to_string());
+        }
+        lines.join("\n")
+    }
+
+    /// Carry out a command, returning the reply.
+    fn handle(&self, command: BotCommand) -> Result<String, String> {
+        let instances: Vec<String> = {
+            let state = self.state.lock().expect("State lock poisoned.");
+            match state.schedule.is_empty() {
+                true => self.programs.clone(),
+                false => state.schedule.iter().map(|s| s.program.clone()).collect(),
+            }
+        };
+        let program = |name: &str| resolve_program(name, &instances);
+        let control = |command| execute(&self.state, command).map_err(|e| e.to_string());
+        match command {
+            BotCommand::Help => Ok(USAGE.to_string()),
+            BotCommand::Status => Ok(self.status()),
+            BotCommand::Pause {
+                program: None,
+                duration,
+            } => {
+                let until =
+                    clock::now() + Duration::from_std(duration).map_err(|e| e.to_string())?;
+                control(ControlCommand::Snooze { until })?;
+                Ok(format!(
+                    "Paused all programs until {}.",
+                    time_format::show(&until)
+                ))
+            }
+            BotCommand::Pause {
+                program: Some(name),
+                duration,
+            } => {
+                let program = program(&name)?;
+                let until =
+                    clock::now() + Duration::from_std(duration).map_err(|e| e.to_string())?;
+                control(ControlCommand::PauseProgram {
+                    program: program.clone(),
+                    until,
+                })?;
+                Ok(format!(
+                    "Paused {} until {}.",
+                    program,
+                    time_format::show(&until)
+                ))
+            }
+            BotCommand::Resume { program: None } => {
+                control(ControlCommand::Unsnooze)?;
+                Ok("Programs run again.".to_string())
+            }
+            BotCommand::Resume {
+                program: Some(name),
+            } => {
+                let program = program(&name)?;
+                control(ControlCommand::ResumeProgram {
+                    program: program.clone(),
+                })?;
+                Ok(format!("{} runs again.", program))
+            }
+            BotCommand::Set {
+                target,
+                value: None,
+            } => {
+                let action = resolve("action", &target, &self.actions)?;
+                control(ControlCommand::RunAction {
+                    name: action.clone(),
+                })?;
+                Ok(format!("Running '{}'.", action))
+            }
+            BotCommand::Set {
+                target,
+                value: Some(brightness),
+            } => {
+                let accessory = resolve("light", &target, &self.accessories)?;
+                control(ControlCommand::SetBrightness {
+                    accessory: accessory.clone(),
+                    brightness,
+                })?;
+                Ok(format!("Setting '{}' to {}.", accessory, brightness))
+            }
+        }
+    }
+
+    async fn on_message(&self, message: Message) {
+        let chat = message.chat.id;
+        let Some(text) = message.text else {
+            return;
+        };
+        if !self.config.allowed_chats.contains(&chat) {
+            warn!(
+                target: audit::LOG_TARGET,
+                "Ignored Telegram message from chat {} (not allowed).", chat
+            );
+            return;
+        }
+        info!(target: audit::LOG_TARGET, "Telegram '{}' from chat {}.", text, chat);
+        let reply = match parse(&text).and_then(|command| self.handle(command)) {
+            Ok(reply) => reply,
+            Err(e) => e,
+        };
+        self.send(chat, &reply).await;
+    }
+}
+
+/// Answer commands from the allowed chats and send them alerts, until the controller stops.
+///
+/// Alerts go out between requests for new messages, so up to `poll_seconds` after the failure.
+pub async fn run(
+    config: TelegramConfig,
+    state: SharedState,
+    programs: Vec<String>,
+    accessories: BTreeSet<String>,
+    actions: BTreeSet<String>,
+) {
+    let client = match Client::builder()
+        .timeout(std::time::Duration::from_secs(config.poll_seconds + 10))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Could not start the Telegram bot: {}", e);
+            return;
+        }
+    };
+    info!(
+        "Started the Telegram bot for {} chat(s).",
+        config.allowed_chats.len()
+    );
+    let mut bot = Bot {
+        config,
+        client,
+        state,
+        programs,
+        accessories,
+        actions,
+        offset: 0,
+        seen: BTreeMap::new(),
+    };
+    let mut failing = false;
+    loop {
+        if bot.config.alerts {
+            bot.send_alerts().await;
+        }
+        match bot.updates().await {
+            Ok(updates) => {
+                if failing {
+                    info!("Reaching Telegram again.");
+                    failing = false;
+                }
+                for update in updates {
+                    bot.offset = bot.offset.max(update.update_id + 1);
+                    if let Some(message) = update.message {
+                        bot.on_message(message).await;
+                    }
+                }
+            }
+            Err(e) => {
+                match failing {
+                    false => warn!("Failed to fetch Telegram messages: {}", e),
+                    true => debug!("Failed to fetch Telegram messages: {}", e),
+                }
+                failing = true;
+                clock::sleep(std::time::Duration::from_secs(bot.config.poll_seconds)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("/status@home_bot"), Ok(BotCommand::Status));
+        assert_eq!(
+            parse("/pause evening 2h"),
+            Ok(BotCommand::Pause {
+                program: Some("evening".to_string()),
+                duration: std::time::Duration::from_secs(7200),
+            })
+        );
+        assert_eq!(
+            parse("/pause 30m"),
+            Ok(BotCommand::Pause {
+                program: None,
+                duration: std::time::Duration::from_secs(1800),
+            })
+        );
+        assert_eq!(
+            parse("/set bed light 30"),
+            Ok(BotCommand::Set {
+                target: "bed light".to_string(),
+                value: Some(30),
+            })
+        );
+        assert_eq!(
+            parse("/set bedroom_scenes"),
+            Ok(BotCommand::Set {
+                target: "bedroom_scenes".to_string(),
+                value: None,
+            })
+        );
+        assert!(parse("/pause evening soon").is_err());
+        assert!(parse("/reboot").is_err());
+
+        let instances = vec![
+            "turn_morning_lights_off".to_string(),
+            "control_evening_lights:office".to_string(),
+            "control_evening_lights:hall".to_string(),
+            "http_poll".to_string(),
+        ];
+        assert_eq!(
+            resolve_program("evening", &instances).as_deref(),
+            Ok("control_evening_lights")
+        );
+        assert_eq!(
+            resolve_program("office", &instances).as_deref(),
+            Ok("control_evening_lights:office")
+        );
+        assert_eq!(
+            resolve_program("lights_off", &instances).as_deref(),
+            Ok("turn_morning_lights_off")
+        );
+        assert_eq!(
+            resolve_program("irrigation", &instances),
+            Err("No program 'irrigation'.".to_string())
+        );
+    }
+
+    #[test]
+    fn ambiguous_program_names_list_the_candidates() {
+        let instances = vec![
+            "turn_morning_lights_off".to_string(),
+            "control_evening_lights".to_string(),
+            "condition_actions".to_string(),
+        ];
+        assert_eq!(
+            resolve_program("lights", &instances),
+            Err(
+                "'lights' matches several programs: control_evening_lights, turn_morning_lights_off."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            resolve_program("morning", &instances).as_deref(),
+            Ok("turn_morning_lights_off")
+        );
+        // An exact name wins even where it is also part of another.
+        assert_eq!(
+            resolve_program("evening_lights", &instances).as_deref(),
+            Ok("control_evening_lights")
+        );
+
+        let lights: BTreeSet<String> = ["Lamp", "Desk Lamp", "Porch Light"]
+            .map(String::from)
+            .into();
+        assert_eq!(resolve("light", "lamp", &lights).as_deref(), Ok("Lamp"));
+        assert_eq!(
+            resolve("light", "porch", &lights).as_deref(),
+            Ok("Porch Light")
+        );
+        assert_eq!(
+            resolve("light", "amp", &lights),
+            Err("'amp' matches several lights: Lamp, Desk Lamp.".to_string())
+        );
+    }
+
+    #[test]
+    fn alerts_on_failures_and_recoveries_once() {
+        let mut seen = BTreeMap::new();
+        let failed = Decision::failed("Bridge unreachable");
+        let mut again = Decision::failed("Bridge unreachable");
+        again.when = failed.when + Duration::seconds(1);
+        let mut skipped = Decision::skipped("Backing off");
+        skipped.when = failed.when + Duration::seconds(2);
+        let timeline = BTreeMap::from([("evening", vec![&skipped, &again, &failed])]);
+        assert_eq!(
+            alerts(&timeline, &mut seen),
+            vec!["evening failed: Bridge unreachable"]
+        );
+        assert!(alerts(&timeline, &mut seen).is_empty());
+
+        let mut ran = Decision::ran("Set brightness 40");
+        ran.when = failed.when + Duration::seconds(3);
+        let timeline = BTreeMap::from([("evening", vec![&ran, &skipped])]);
+        assert_eq!(
+            alerts(&timeline, &mut seen),
+            vec!["evening works again: Set brightness 40"]
+        );
+    }
+}
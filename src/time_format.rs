@@ -0,0 +1,139 @@
+use crate::clock;
+use crate::configuration::{TimeFormatConfig, TimeZoneSetting};
+use chrono::{
+    DateTime, FixedOffset, Local, NaiveDateTime, NaiveTime, SecondsFormat, TimeZone, Utc,
+};
+use serde::{Deserialize, Deserializer, Serializer};
+use std::sync::OnceLock;
+
+static FORMAT: OnceLock<TimeFormatConfig> = OnceLock::new();
+
+/// Show times in logs and status API responses as configured.
+///
+/// Only the first call has an effect; until then, times are shown in the default format.
+pub fn init(config: &TimeFormatConfig) {
+    let _ = FORMAT.set(*config);
+}
+
+fn config() -> TimeFormatConfig {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// A time in the configured zone.
+fn in_zone<Tz: TimeZone>(time: &DateTime<Tz>, zone: TimeZoneSetting) -> DateTime<FixedOffset> {
+    match zone {
+        TimeZoneSetting::Local => time.with_timezone(&Local).fixed_offset(),
+        TimeZoneSetting::Utc => time.with_timezone(&Utc).fixed_offset(),
+        TimeZoneSetting::Fixed(offset) => time.with_timezone(&offset),
+    }
+}
+
+fn format<Tz: TimeZone>(
+    time: &DateTime<Tz>,
+    config: TimeFormatConfig,
+    precision: SecondsFormat,
+) -> String {
+    let time = in_zone(time, config.timezone);
+    match (config.iso8601, precision) {
+        (true, precision) => time.to_rfc3339_opts(precision, true),
+        (false, SecondsFormat::Millis) => time.format("%Y-%m-%d %H:%M:%S%.3f %:z").to_string(),
+        (false, _) => time.format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+    }
+}
+
+/// A time as shown in log messages, e.g. `2024-12-01T17:45:00+01:00`.
+pub fn show<Tz: TimeZone>(time: &DateTime<Tz>) -> String {
+    format(time, config(), SecondsFormat::Secs)
+}
+
+/// A time with milliseconds, for the timestamps of log lines.
+pub fn show_precise<Tz: TimeZone>(time: &DateTime<Tz>) -> String {
+    format(time, config(), SecondsFormat::Millis)
+}
+
+/// A local wall-clock time of today (e.g. a configured off-time), as shown in log messages.
+pub fn show_time(time: NaiveTime) -> String {
+    let config = config();
+    let today = clock::now().date_naive().and_time(time);
+    match (config.timezone, today.and_local_timezone(Local).earliest()) {
+        (TimeZoneSetting::Local, _) | (_, None) => time.format("%H:%M:%S").to_string(),
+        (zone, Some(time)) => in_zone(&time, zone).format("%H:%M:%S %:z").to_string(),
+    }
+}
+
+/// Local time in RFC 3339 or as "YYYY-MM-DDTHH:MM[:SS]", as given on the command line or in
+/// API queries.
+pub fn parse_local(s: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Local));
+    }
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .and_then(|t| t.and_local_timezone(Local).earliest())
+        .ok_or_else(|| format!("'{}' is not a local time like 2024-12-01T17:45", s))
+}
+
+/// Deserialize an optional time with [`parse_local`], e.g. from a query string.
+pub fn deserialize_local_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DateTime<Local>>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_local(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Serialize a time for status API responses, e.g. with `#[serde(serialize_with = ...)]`.
+pub fn serialize<S: Serializer, Tz: TimeZone>(
+    time: &DateTime<Tz>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&show(time))
+}
+
+/// Serialize an optional time for status API responses.
+pub fn serialize_option<S: Serializer, Tz: TimeZone>(
+    time: &Option<DateTime<Tz>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_times_in_the_configured_zone() {
+        let time = Utc.with_ymd_and_hms(2024, 12, 1, 16, 45, 0).unwrap();
+        let plus_one = TimeZoneSetting::Fixed(FixedOffset::east_opt(3600).unwrap());
+        let shown = |timezone, iso8601| {
+            format(
+                &time,
+                TimeFormatConfig { timezone, iso8601 },
+                SecondsFormat::Secs,
+            )
+        };
+        assert_eq!(shown(TimeZoneSetting::Utc, true), "2024-12-01T16:45:00Z");
+        assert_eq!(shown(plus_one, true), "2024-12-01T17:45:00+01:00");
+        assert_eq!(shown(plus_one, false), "2024-12-01 17:45:00 +01:00");
+        assert_eq!(
+            format(
+                &time,
+                TimeFormatConfig {
+                    timezone: plus_one,
+                    iso8601: false
+                },
+                SecondsFormat::Millis
+            ),
+            "2024-12-01 17:45:00.000 +01:00"
+        );
+
+        let zone: TimeZoneSetting = serde_json::from_str("\"+01:00\"").unwrap();
+        assert_eq!(zone, plus_one);
+        assert!(serde_json::from_str::<TimeZoneSetting>("\"Europe/Berlin\"").is_err());
+    }
+}
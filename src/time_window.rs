@@ -0,0 +1,210 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime};
+use std::fmt;
+
+/// Time of day from `start` to `end`, both included; a window with `end` before `start` spans
+/// midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn new(start: NaiveTime, end: NaiveTime) -> Self {
+        Self { start, end }
+    }
+
+    /// Whether the window runs past midnight into the next day.
+    pub fn spans_midnight(&self) -> bool {
+        self.end < self.start
+    }
+
+    /// Time from the start to the end; zero for a window that starts and ends at once.
+    pub fn length(&self) -> Duration {
+        wrapped(self.end - self.start)
+    }
+
+    /// Whether `time` is within the window.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        self.elapsed(time).is_some()
+    }
+
+    /// Time since the start at `time`, if it is within the window.
+    pub fn elapsed(&self, time: NaiveTime) -> Option<Duration> {
+        let elapsed = wrapped(time - self.start);
+        (elapsed <= self.length()).then_some(elapsed)
+    }
+
+    /// Start and end of the window starting on `day`; the end may be on the next day.
+    ///
+    /// `None` if the start does not exist on that day, skipped by a daylight saving change.
+    pub fn on(&self, day: NaiveDate) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        let start = day
+            .and_time(self.start)
+            .and_local_timezone(Local)
+            .earliest()?;
+        Some((start, start + self.length()))
+    }
+
+    /// The occurrence of the window that `after` is in, or else the next one to start.
+    pub fn next_occurrence(
+        &self,
+        after: &DateTime<Local>,
+    ) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        // An occurrence spanning midnight may have started the day before; one skipped by a
+        // daylight saving change is followed by the next day's.
+        let today = after.date_naive();
+        (-1..=2)
+            .filter_map(|days| self.on(today + Duration::days(days)))
+            .find(|(_, end)| end >= after)
+    }
+
+    /// Start of the next occurrence of the window at or after `after`.
+    pub fn next_start(&self, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let today = after.date_naive();
+        (0..=2)
+            .filter_map(|days| self.on(today + Duration::days(days)))
+            .map(|(start, _)| start)
+            .find(|start| start >= after)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// A difference of times of day moved into a single day, from zero up to 24 hours.
+fn wrapped(difference: Duration) -> Duration {
+    match difference < Duration::zero() {
+        true => difference + Duration::days(1),
+        false => difference,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn window(start: &str, end: &str) -> TimeWindow {
+        TimeWindow::new(at(start), at(end))
+    }
+
+    fn local(day: u32, time: &str) -> DateTime<Local> {
+        Local
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(2024, 6, day)
+                    .unwrap()
+                    .and_time(at(time)),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn contains_times_within_the_same_day() {
+        let day = window("07:00", "20:00");
+        assert!(!day.spans_midnight());
+        assert_eq!(day.length(), Duration::hours(13));
+        assert!(day.contains(at("07:00")));
+        assert!(day.contains(at("12:00")));
+        assert!(day.contains(at("20:00")));
+        assert!(!day.contains(at("06:59")));
+        assert!(!day.contains(at("20:01")));
+        assert!(!day.contains(at("00:00")));
+        assert_eq!(day.elapsed(at("08:30")), Some(Duration::minutes(90)));
+        assert_eq!(day.elapsed(at("21:00")), None);
+        assert_eq!(day.to_string(), "07:00-20:00");
+    }
+
+    #[test]
+    fn contains_times_across_midnight() {
+        let night = window("22:00", "02:00");
+        assert!(night.spans_midnight());
+        assert_eq!(night.length(), Duration::hours(4));
+        assert!(night.contains(at("22:00")));
+        assert!(night.contains(at("23:59")));
+        assert!(night.contains(at("00:00")));
+        assert!(night.contains(at("02:00")));
+        assert!(!night.contains(at("02:01")));
+        assert!(!night.contains(at("12:00")));
+        assert!(!night.contains(at("21:59")));
+        assert_eq!(night.elapsed(at("01:00")), Some(Duration::hours(3)));
+
+        let from_midnight = window("00:00", "06:00");
+        assert!(!from_midnight.spans_midnight());
+        assert!(from_midnight.contains(at("00:00")));
+        assert!(!from_midnight.contains(at("23:59")));
+        let to_midnight = window("18:00", "00:00");
+        assert!(to_midnight.spans_midnight());
+        assert!(to_midnight.contains(at("00:00")));
+        assert!(!to_midnight.contains(at("00:01")));
+    }
+
+    #[test]
+    fn instant_window_contains_only_its_time() {
+        let instant = window("09:00", "09:00");
+        assert_eq!(instant.length(), Duration::zero());
+        assert!(instant.contains(at("09:00")));
+        assert!(!instant.contains(at("09:01")));
+        assert!(!instant.contains(at("08:59")));
+    }
+
+    #[test]
+    fn finds_current_and_next_occurrences() {
+        let night = window("22:00", "02:00");
+        // Before, during (on either side of midnight), and after the window.
+        assert_eq!(
+            night.next_occurrence(&local(10, "12:00")),
+            Some((local(10, "22:00"), local(11, "02:00")))
+        );
+        assert_eq!(
+            night.next_occurrence(&local(10, "23:00")),
+            Some((local(10, "22:00"), local(11, "02:00")))
+        );
+        assert_eq!(
+            night.next_occurrence(&local(11, "01:00")),
+            Some((local(10, "22:00"), local(11, "02:00")))
+        );
+        assert_eq!(
+            night.next_occurrence(&local(11, "02:00")),
+            Some((local(10, "22:00"), local(11, "02:00")))
+        );
+        assert_eq!(
+            night.next_occurrence(&local(11, "02:01")),
+            Some((local(11, "22:00"), local(12, "02:00")))
+        );
+        assert_eq!(
+            night.next_start(&local(11, "01:00")),
+            Some(local(11, "22:00"))
+        );
+        assert_eq!(
+            night.next_start(&local(11, "22:00")),
+            Some(local(11, "22:00"))
+        );
+        assert_eq!(
+            night.next_start(&local(11, "22:01")),
+            Some(local(12, "22:00"))
+        );
+
+        let day = window("07:00", "20:00");
+        assert_eq!(
+            day.next_occurrence(&local(10, "21:00")),
+            Some((local(11, "07:00"), local(11, "20:00")))
+        );
+        assert_eq!(
+            day.on(local(10, "00:00").date_naive()).unwrap().1,
+            local(10, "20:00")
+        );
+    }
+}
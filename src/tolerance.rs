@@ -0,0 +1,164 @@
+use crate::configuration::AdaptiveToleranceConfig;
+use crate::override_detector::numeric_value;
+use log::{debug, info};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Read-back deviations of one accessory by characteristic, oldest first.
+pub type Deviations = BTreeMap<String, Vec<f64>>;
+
+/// Share of read-backs the learned tolerance covers; the rest are taken for outliers.
+const COVERAGE: f64 = 0.9;
+
+/// Learns how far each accessory's values stray from what the controller wrote.
+///
+/// Bulbs often round or quantize what they are sent, e.g. a brightness of 57 reads back as 56.
+/// The first read after each of the controller's own writes is a sample of that behavior.
+/// Deviations above `max_tolerance` are taken for manual changes and not sampled. Once
+/// `min_samples` read-backs are in, the tolerance is the deviation that covers most of them.
+#[derive(Debug)]
+pub struct ToleranceTuner {
+    active: bool,
+    max_tolerance: f64,
+    samples: usize,
+    min_samples: usize,
+    /// Values written and not read back yet, by accessory and characteristic.
+    pending: HashMap<(String, String), f64>,
+    deviations: BTreeMap<String, Deviations>,
+    changed: bool,
+}
+
+impl Default for ToleranceTuner {
+    fn default() -> Self {
+        Self::from_config(&AdaptiveToleranceConfig::default())
+    }
+}
+
+impl ToleranceTuner {
+    pub fn new(active: bool, max_tolerance: f64, samples: usize, min_samples: usize) -> Self {
+        Self {
+            active,
+            max_tolerance,
+            samples,
+            min_samples,
+            pending: HashMap::new(),
+            deviations: BTreeMap::new(),
+            changed: false,
+        }
+    }
+
+    pub fn from_config(config: &AdaptiveToleranceConfig) -> Self {
+        Self::new(
+            config.active,
+            config.max_tolerance,
+            config.samples,
+            config.min_samples,
+        )
+    }
+
+    /// Start from read-backs sampled by an earlier run of the controller.
+    pub fn restore(&mut self, deviations: BTreeMap<String, Deviations>) {
+        self.deviations = deviations;
+    }
+
+    /// Note a value the controller wrote.
+    pub fn written(&mut self, accessory: &str, characteristic: &str, value: &Value) {
+        // Switches only have two values, so there is nothing to round.
+        if !self.active || value.is_boolean() {
+            return;
+        }
+        if let Some(value) = numeric_value(value) {
+            self.pending
+                .insert((accessory.to_string(), characteristic.to_string()), value);
+        }
+    }
+
+    /// Note a value read from the accessory, sampling it if it is the first since a write.
+    pub fn read(&mut self, accessory: &str, characteristic: &str, value: &Value) {
+        let key = (accessory.to_string(), characteristic.to_string());
+        let Some(written) = self.pending.remove(&key) else {
+            return;
+        };
+        let Some(observed) = numeric_value(value) else {
+            return;
+        };
+        let deviation = (observed - written).abs();
+        if deviation > self.max_tolerance {
+            debug!(
+                "'{}' {} read back {} after writing {} - not sampled.",
+                accessory, characteristic, observed, written
+            );
+            return;
+        }
+        let before = self.tolerance(accessory, characteristic);
+        let samples = self
+            .deviations
+            .entry(key.0)
+            .or_default()
+            .entry(key.1)
+            .or_default();
+        samples.push(deviation);
+        if samples.len() > self.samples {
+            samples.remove(0);
+        }
+        self.changed = true;
+        let after = self.tolerance(accessory, characteristic);
+        if after != before {
+            if let Some(tolerance) = after {
+                info!(
+                    "Learned override tolerance of '{}' {}: {}.",
+                    accessory, characteristic, tolerance
+                );
+            }
+        }
+    }
+
+    /// Learned tolerance of a characteristic, once enough read-backs are in.
+    pub fn tolerance(&self, accessory: &str, characteristic: &str) -> Option<f64> {
+        if !self.active {
+            return None;
+        }
+        let mut samples = self.deviations.get(accessory)?.get(characteristic)?.clone();
+        if samples.len() < self.min_samples {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+        let index = ((samples.len() - 1) as f64 * COVERAGE) as usize;
+        Some(samples[index])
+    }
+
+    /// Read-backs to persist, if any were sampled since the last call.
+    pub fn take_changed(&mut self) -> Option<BTreeMap<String, Deviations>> {
+        std::mem::take(&mut self.changed).then(|| self.deviations.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn learns_rounding_from_read_backs() {
+        let mut tuner = ToleranceTuner::new(true, 3.0, 20, 5);
+        for (written, observed) in [(57, 56), (40, 40), (33, 32), (71, 70), (12, 10)] {
+            assert_eq!(tuner.tolerance("Lamp", "Brightness"), None);
+            tuner.written("Lamp", "Brightness", &json!(written));
+            tuner.read("Lamp", "Brightness", &json!(observed));
+            // Only the first read after a write is sampled.
+            tuner.read("Lamp", "Brightness", &json!(observed + 1));
+        }
+        assert_eq!(tuner.tolerance("Lamp", "Brightness"), Some(1.0));
+        assert!(tuner.take_changed().is_some());
+        assert!(tuner.take_changed().is_none());
+    }
+
+    #[test]
+    fn large_deviations_are_not_sampled() {
+        let mut tuner = ToleranceTuner::new(true, 3.0, 20, 1);
+        tuner.written("Lamp", "Brightness", &json!(80));
+        tuner.read("Lamp", "Brightness", &json!(20));
+        assert_eq!(tuner.tolerance("Lamp", "Brightness"), None);
+        assert!(tuner.take_changed().is_none());
+    }
+}
@@ -0,0 +1,568 @@
+use serde_json::{Map, Number, Value};
+use std::collections::HashSet;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct TomlError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Parse a TOML document into the JSON value the configuration is read from.
+///
+/// Supports what configurations need: tables, arrays of tables, dotted keys, strings (basic,
+/// literal, and multi-line), integers, floats, booleans, arrays, and inline tables. Dates and
+/// times (e.g. `off_time = 07:30:00`) are read as strings.
+pub fn from_str(text: &str) -> Result<Value, TomlError> {
+    Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    }
+    .document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+/// Characters of bare keys and of unquoted values.
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_value_char(c: char) -> bool {
+    is_bare(c) || matches!(c, '+' | '.' | ':')
+}
+
+/// The table at `path` below `root`, created if missing; arrays of tables lead to their last
+/// entry.
+fn table_at<'a>(
+    root: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Result<&'a mut Map<String, Value>, String> {
+    let mut table = root;
+    for key in path {
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        let entry = match entry {
+            Value::Array(items) => items.last_mut(),
+            other => Some(other),
+        };
+        table = match entry {
+            Some(Value::Object(map)) => map,
+            _ => return Err(format!("'{}' is not a table", key)),
+        };
+    }
+    Ok(table)
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, TomlError> {
+        Err(self.at_line(message.into()))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn at_line(&self, message: String) -> TomlError {
+        TomlError {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), TomlError> {
+        match self.next() {
+            Some(next) if next == c => Ok(()),
+            Some(next) => self.error(format!("expected '{}', found '{}'", c, next)),
+            None => self.error(format!("expected '{}' at the end of the file", c)),
+        }
+    }
+
+    /// Skip spaces and tabs.
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
+
+    /// Skip whitespace, line breaks, and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.next();
+                }
+                Some('#') => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.next();
+        }
+    }
+
+    /// Nothing but a comment may follow on the line.
+    fn end_of_line(&mut self) -> Result<(), TomlError> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('#') => self.skip_comment(),
+            Some('\r') if self.starts_with("\r\n") => {}
+            Some('\n') | None => {}
+            Some(c) => return self.error(format!("unexpected '{}' after a value", c)),
+        }
+        Ok(())
+    }
+
+    fn document(&mut self) -> Result<Value, TomlError> {
+        let mut root = Map::new();
+        let mut table: Vec<String> = Vec::new();
+        // Tables given a header, which may not be given another.
+        let mut headers: HashSet<Vec<String>> = HashSet::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(Value::Object(root)),
+                Some('[') if self.starts_with("[[") => {
+                    self.pos += 2;
+                    table = self.header("]]")?;
+                    let (last, parents) = table.split_last().expect("Headers have a key.");
+                    let parent = table_at(&mut root, parents).map_err(|e| self.at_line(e))?;
+                    match parent
+                        .entry(last.clone())
+                        .or_insert_with(|| Value::Array(Vec::new()))
+                    {
+                        Value::Array(items) => items.push(Value::Object(Map::new())),
+                        _ => return self.error(format!("'{}' is not an array of tables", last)),
+                    }
+                    // Tables below the previous entry may be given again in the new one.
+                    headers.retain(|header| !header.starts_with(&table));
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    table = self.header("]")?;
+                    if !headers.insert(table.clone()) {
+                        return self.error(format!("table '{}' is defined twice", table.join(".")));
+                    }
+                    table_at(&mut root, &table).map_err(|e| self.at_line(e))?;
+                }
+                Some(_) => {
+                    let current = table_at(&mut root, &table).map_err(|e| self.at_line(e))?;
+                    self.key_value(current)?;
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    /// The key of a table header, up to the closing brackets.
+    fn header(&mut self, close: &str) -> Result<Vec<String>, TomlError> {
+        let key = self.key()?;
+        self.skip_spaces();
+        for c in close.chars() {
+            self.expect(c)?;
+        }
+        Ok(key)
+    }
+
+    /// A dotted key, e.g. `bridge.host` or `accessories."Bed Light"`.
+    fn key(&mut self) -> Result<Vec<String>, TomlError> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_spaces();
+            parts.push(match self.peek() {
+                Some('"') => {
+                    self.next();
+                    self.basic_string()?
+                }
+                Some('\'') => {
+                    self.next();
+                    self.literal_string()?
+                }
+                Some(c) if is_bare(c) => {
+                    let mut key = String::new();
+                    while let Some(c) = self.peek().filter(|c| is_bare(*c)) {
+                        key.push(c);
+                        self.next();
+                    }
+                    key
+                }
+                Some(c) => return self.error(format!("unexpected '{}' in a key", c)),
+                None => return self.error("missing key at the end of the file"),
+            });
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(parts);
+            }
+            self.next();
+        }
+    }
+
+    /// Read `key = value` into `table`.
+    fn key_value(&mut self, table: &mut Map<String, Value>) -> Result<(), TomlError> {
+        let key = self.key()?;
+        self.expect('=')?;
+        self.skip_spaces();
+        let value = self.value()?;
+        let (last, parents) = key.split_last().expect("Keys have a part.");
+        let parent = table_at(table, parents).map_err(|e| self.at_line(e))?;
+        if parent.contains_key(last) {
+            return self.error(format!("'{}' is defined twice", key.join(".")));
+        }
+        parent.insert(last.clone(), value);
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, TomlError> {
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                self.pos += 3;
+                self.multi_line_string("\"\"\"", true).map(Value::String)
+            }
+            Some('\'') if self.starts_with("'''") => {
+                self.pos += 3;
+                self.multi_line_string("'''", false).map(Value::String)
+            }
+            Some('"') => {
+                self.next();
+                self.basic_string().map(Value::String)
+            }
+            Some('\'') => {
+                self.next();
+                self.literal_string().map(Value::String)
+            }
+            Some('[') => {
+                self.next();
+                self.array()
+            }
+            Some('{') => {
+                self.next();
+                self.inline_table()
+            }
+            Some(c) if is_value_char(c) => self.scalar(),
+            Some(c) => self.error(format!("unexpected '{}' for a value", c)),
+            None => self.error("missing value at the end of the file"),
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, TomlError> {
+        let hex = |parser: &mut Self, digits: usize| {
+            let code: String = (0..digits).filter_map(|_| parser.next()).collect();
+            match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                Some(c) => Ok(c),
+                None => parser.error(format!("invalid escape '{}'", code)),
+            }
+        };
+        match self.next() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('b') => Ok('\u{8}'),
+            Some('f') => Ok('\u{c}'),
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('u') => hex(self, 4),
+            Some('U') => hex(self, 8),
+            Some(c) => self.error(format!("invalid escape '\\{}'", c)),
+            None => self.error("unterminated string"),
+        }
+    }
+
+    /// A `"string"` with escapes, after its opening quote.
+    fn basic_string(&mut self) -> Result<String, TomlError> {
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.escape()?),
+                Some('\n') | None => return self.error("unterminated string"),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// A `'string'` taken as is, after its opening quote.
+    fn literal_string(&mut self) -> Result<String, TomlError> {
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => return self.error("unterminated string"),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// A string of several lines, after its opening quotes; a line break right after them is
+    /// dropped.
+    fn multi_line_string(&mut self, close: &str, escapes: bool) -> Result<String, TomlError> {
+        if self.starts_with("\r\n") {
+            self.pos += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.next();
+        }
+        let mut s = String::new();
+        loop {
+            if self.starts_with(close) {
+                self.pos += close.len();
+                return Ok(s);
+            }
+            match self.next() {
+                Some('\\') if escapes => {
+                    // A backslash at the end of a line joins it with the next non-blank one.
+                    self.skip_spaces();
+                    if matches!(self.peek(), Some('\r' | '\n')) {
+                        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                            self.next();
+                        }
+                    } else {
+                        s.push(self.escape()?);
+                    }
+                }
+                Some(c) => s.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    /// An array, after its opening bracket; it may span lines.
+    fn array(&mut self) -> Result<Value, TomlError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                Some(c) => return self.error(format!("expected ',' or ']', found '{}'", c)),
+                None => return self.error("unterminated array"),
+            }
+        }
+    }
+
+    /// An inline table, after its opening brace.
+    fn inline_table(&mut self) -> Result<Value, TomlError> {
+        let mut table = Map::new();
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::Object(table));
+        }
+        loop {
+            self.key_value(&mut table)?;
+            self.skip_spaces();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Object(table)),
+                Some(c) => return self.error(format!("expected ',' or '}}', found '{}'", c)),
+                None => return self.error("unterminated inline table"),
+            }
+        }
+    }
+
+    /// A boolean, number, date, or time.
+    fn scalar(&mut self) -> Result<Value, TomlError> {
+        let mut token = self.value_token();
+        // A date and a time may be separated by a space, e.g. `2024-05-27 07:32:00`.
+        let at = |i: usize| self.chars.get(self.pos + i).copied();
+        let time_follows = at(0) == Some(' ')
+            && at(1).is_some_and(|c| c.is_ascii_digit())
+            && at(2).is_some_and(|c| c.is_ascii_digit())
+            && at(3) == Some(':');
+        if token.len() == 10 && is_date(&token) && time_follows {
+            self.next();
+            token.push(' ');
+            token.push_str(&self.value_token());
+        }
+        match token.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+        if token.contains(':') || is_date(&token) {
+            return Ok(Value::String(token));
+        }
+        let digits = token.replace('_', "");
+        let (sign, unsigned) = match digits.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, digits.strip_prefix('+').unwrap_or(&digits)),
+        };
+        let radix = match unsigned.get(..2) {
+            Some("0x") => Some(16),
+            Some("0o") => Some(8),
+            Some("0b") => Some(2),
+            _ => None,
+        };
+        let number = match radix {
+            Some(radix) => i64::from_str_radix(&unsigned[2..], radix)
+                .ok()
+                .map(|n| Number::from(sign * n)),
+            None if digits.contains(['.', 'e', 'E']) => digits
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .and_then(Number::from_f64),
+            None => digits.parse::<i64>().ok().map(Number::from),
+        };
+        match number {
+            Some(number) => Ok(Value::Number(number)),
+            None => self.error(format!("invalid value '{}'", token)),
+        }
+    }
+
+    /// The characters of an unquoted value.
+    fn value_token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self.peek().filter(|c| is_value_char(*c)) {
+            token.push(c);
+            self.next();
+        }
+        token
+    }
+}
+
+/// Whether an unquoted value starts with a date, e.g. `2024-05-27`.
+fn is_date(token: &str) -> bool {
+    token.len() >= 10
+        && token.as_bytes()[4] == b'-'
+        && token[..4].chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_configuration_documents() {
+        let text = r#"
+# Controller settings
+latitude = 42.36
+longitude = -71.06
+program_loop_pause = 2.0
+bridge = { host = "127.0.0.1", port = 8_581 }
+
+[turn_morning_lights_off]
+after_sunrise = -30
+sunrise_fallback = 06:45:00
+active = true
+light = 'Bed Light'
+
+[accessories."Bed Light"]
+tags = [
+    "bedroom", # trailing comments
+    "dimmable",
+]
+
+[[webhooks]]
+url = "http://hooks.local/é"
+payload = """
+{"light": "{accessory}"}"""
+
+[[webhooks]]
+url = "http://other.local"
+headers.Authorization = "Bearer token"
+"#;
+        assert_eq!(
+            from_str(text).unwrap(),
+            json!({
+                "latitude": 42.36,
+                "longitude": -71.06,
+                "program_loop_pause": 2.0,
+                "bridge": {"host": "127.0.0.1", "port": 8581},
+                "turn_morning_lights_off": {
+                    "after_sunrise": -30,
+                    "sunrise_fallback": "06:45:00",
+                    "active": true,
+                    "light": "Bed Light"
+                },
+                "accessories": {"Bed Light": {"tags": ["bedroom", "dimmable"]}},
+                "webhooks": [
+                    {"url": "http://hooks.local/é", "payload": "{\"light\": \"{accessory}\"}"},
+                    {"url": "http://other.local", "headers": {"Authorization": "Bearer token"}}
+                ]
+            })
+        );
+
+        let error = from_str("latitude = 42.36\nlatitude = 1\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(from_str("bridge = \"unterminated\n").is_err());
+        assert!(from_str("[bridge\nhost = \"x\"").is_err());
+    }
+
+    #[test]
+    fn reads_dates_and_times_as_strings() {
+        let text = "a = 2024-05-27 07:32:00\nb = 2024-05-27T07:32:00+02:00\nc = 2024-05-27\nd = 07:32:00 # Morning\n";
+        assert_eq!(
+            from_str(text).unwrap(),
+            json!({
+                "a": "2024-05-27 07:32:00",
+                "b": "2024-05-27T07:32:00+02:00",
+                "c": "2024-05-27",
+                "d": "07:32:00"
+            })
+        );
+        assert!(from_str("a = 2024-05-27 later\n").is_err());
+    }
+
+    #[test]
+    fn reads_escapes_in_multi_line_strings() {
+        let text = r#"
+basic = """
+Tab:\t, quote: \", unicode: \u00e9 \
+    joined"""
+literal = '''
+C:\Users\n'''
+"#;
+        assert_eq!(
+            from_str(text).unwrap(),
+            json!({
+                "basic": "Tab:\t, quote: \", unicode: é joined",
+                "literal": "C:\\Users\\n"
+            })
+        );
+        assert!(from_str("a = \"\"\"\n\\q\"\"\"\n").is_err());
+    }
+
+    #[test]
+    fn rejects_tables_defined_twice() {
+        let error = from_str("[bridge]\nhost = \"a\"\n\n[bridge]\nport = 1\n").unwrap_err();
+        assert_eq!(error.line, 4);
+        assert!(error.message.contains("'bridge' is defined twice"));
+        // Subtables may come after their parent, and again in each entry of an array.
+        let text = "[a.b]\nx = 1\n[a]\ny = 2\n[[c]]\n[c.d]\nz = 1\n[[c]]\n[c.d]\nz = 2\n";
+        assert_eq!(
+            from_str(text).unwrap(),
+            json!({"a": {"b": {"x": 1}, "y": 2}, "c": [{"d": {"z": 1}}, {"d": {"z": 2}}]})
+        );
+    }
+}
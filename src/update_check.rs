@@ -0,0 +1,170 @@
+use crate::clock;
+use crate::configuration::UpdateCheckConfig;
+use chrono::{DateTime, Duration, Local};
+use log::{debug, info, warn};
+use reqwest::header::USER_AGENT;
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration as StdDuration;
+
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Lines of the release notes included in the log.
+const CHANGELOG_LINES: usize = 8;
+/// The check runs in the program loop, so a slow release feed must not hold it up for long.
+const CHECK_TIMEOUT_SECS: u64 = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpdateCheckError {
+    #[error("Failed to fetch the latest release: {0}")]
+    FetchError(#[from] reqwest::Error),
+    #[error("Release tag '{0}' is not a version.")]
+    InvalidVersion(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    html_url: Option<String>,
+}
+
+/// Numeric components of a version such as "v1.2.3" (pre-release suffixes are ignored).
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    core.split('.').map(|part| part.parse().ok()).collect()
+}
+
+fn is_newer(latest: &[u64], current: &[u64]) -> bool {
+    let len = latest.len().max(current.len());
+    let pad = |v: &[u64]| {
+        let mut v = v.to_vec();
+        v.resize(len, 0);
+        v
+    };
+    pad(latest) > pad(current)
+}
+
+/// Logs (and with desktop notifications, shows) when a newer release is published.
+pub struct UpdateCheck {
+    config: UpdateCheckConfig,
+    notify_desktop: bool,
+    last_check: Option<DateTime<Local>>,
+    notified: Option<String>,
+}
+
+impl UpdateCheck {
+    pub fn new(config: &UpdateCheckConfig, notify_desktop: bool) -> Self {
+        Self {
+            config: config.clone(),
+            notify_desktop,
+            last_check: None,
+            notified: None,
+        }
+    }
+
+    async fn latest_release(&self, client: &Client) -> Result<Release, UpdateCheckError> {
+        Ok(client
+            .get(&self.config.url)
+            .timeout(StdDuration::from_secs(CHECK_TIMEOUT_SECS))
+            .header(
+                USER_AGENT,
+                format!("homebridge-controller/{}", CURRENT_VERSION),
+            )
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Release>()
+            .await?)
+    }
+
+    /// Check for a newer release if the interval has passed.
+    pub async fn run(&mut self, client: &Client) {
+        if !self.config.active {
+            return;
+        }
+        let now = clock::now();
+        if self
+            .last_check
+            .is_some_and(|t| now - t < Duration::hours(self.config.interval_hours))
+        {
+            return;
+        }
+        self.last_check = Some(now);
+        if let Err(e) = self.check(client).await {
+            warn!("Update check failed: {}", e);
+        }
+    }
+
+    async fn check(&mut self, client: &Client) -> Result<(), UpdateCheckError> {
+        let release = self.latest_release(client).await?;
+        let latest = parse_version(&release.tag_name)
+            .ok_or_else(|| UpdateCheckError::InvalidVersion(release.tag_name.clone()))?;
+        let current = parse_version(CURRENT_VERSION).unwrap_or_default();
+        if !is_newer(&latest, &current) {
+            debug!(
+                "Running the latest release ({} >= {}).",
+                CURRENT_VERSION, release.tag_name
+            );
+            return Ok(());
+        }
+        if self.notified.as_ref() == Some(&release.tag_name) {
+            return Ok(());
+        }
+        let changelog: Vec<&str> = release
+            .body
+            .as_deref()
+            .unwrap_or_default()
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .take(CHANGELOG_LINES)
+            .collect();
+        warn!(
+            "A newer release is available: {} (running {}){}.",
+            release.tag_name,
+            CURRENT_VERSION,
+            release
+                .html_url
+                .as_ref()
+                .map_or(String::new(), |u| format!(" - {}", u))
+        );
+        if !changelog.is_empty() {
+            info!("Changes in {}:\n{}", release.tag_name, changelog.join("\n"));
+        }
+        if self.notify_desktop {
+            #[cfg(feature = "desktop")]
+            if let Err(e) = crate::desktop::DesktopNotifier::notify(
+                "Homebridge controller update",
+                &format!(
+                    "{} is available (running {}).",
+                    release.tag_name, CURRENT_VERSION
+                ),
+            ) {
+                warn!("Failed to show desktop notification: {}", e);
+            }
+        }
+        self.notified = Some(release.tag_name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_compared_numerically() {
+        let v = |s| parse_version(s).unwrap();
+        assert!(is_newer(&v("v0.10.0"), &v("0.9.1")));
+        assert!(is_newer(&v("1.0"), &v("0.9.9")));
+        assert!(!is_newer(&v("0.1.0"), &v("0.1")));
+        assert!(!is_newer(&v("v0.1.0-rc.1"), &v("0.1.0")));
+    }
+
+    #[test]
+    fn non_numeric_tags_are_rejected() {
+        assert_eq!(parse_version("nightly"), None);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::clock;
+use crate::configuration::{Configuration, UsagePingConfig};
+use crate::programs::{ProgramId, ProgramRegistry};
+use chrono::{DateTime, Duration, Local};
+use log::{debug, warn};
+use reqwest::header::USER_AGENT;
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration as StdDuration;
+
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+const PING_TIMEOUT_SECS: u64 = 10;
+
+/// Everything a usage ping sends: no names, locations, accessories, or times.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UsageReport {
+    pub version: &'static str,
+    /// Operating system and architecture of the host, e.g. "linux-aarch64".
+    pub platform: String,
+    /// Number of active instances of each program type, or of entries of the programs running
+    /// a list of them (condition actions and HTTP polls).
+    pub programs: BTreeMap<&'static str, usize>,
+}
+
+impl UsageReport {
+    pub fn new(programs: &ProgramRegistry, config: &Configuration) -> Self {
+        let mut counts = BTreeMap::new();
+        for program in programs.iter().filter(|p| p.is_active()) {
+            let count = match program.id() {
+                ProgramId::ConditionActions => config.condition_actions.len(),
+                ProgramId::HttpPoll => config.http_polls.len(),
+                _ => 1,
+            };
+            if count > 0 {
+                *counts.entry(program.id().name()).or_default() += count;
+            }
+        }
+        Self {
+            version: CURRENT_VERSION,
+            platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            programs: counts,
+        }
+    }
+}
+
+/// Posts anonymous program-type counts to the configured endpoint, only when opted in.
+pub struct UsagePing {
+    config: UsagePingConfig,
+    last_sent: Option<DateTime<Local>>,
+}
+
+impl UsagePing {
+    /// The ping of the configuration, announced in the log with what it sends.
+    pub fn new(
+        ping: &UsagePingConfig,
+        programs: &ProgramRegistry,
+        config: &Configuration,
+    ) -> Option<Self> {
+        if !ping.active {
+            return None;
+        }
+        warn!(
+            "Usage ping is on: every {} hour(s), {} is sent {} (remove `usage_ping` to stop).",
+            ping.interval_hours,
+            ping.url,
+            serde_json::to_string(&UsageReport::new(programs, config)).unwrap_or_default()
+        );
+        Some(Self {
+            config: ping.clone(),
+            last_sent: None,
+        })
+    }
+
+    /// Send the counts of the current programs if the interval has passed.
+    ///
+    /// The ping goes out in the background, so a slow endpoint does not hold up the loop.
+    pub fn run(&mut self, client: &Client, programs: &ProgramRegistry, config: &Configuration) {
+        let now = clock::now();
+        if self
+            .last_sent
+            .is_some_and(|t| now - t < Duration::hours(self.config.interval_hours))
+        {
+            return;
+        }
+        self.last_sent = Some(now);
+        let report = UsageReport::new(programs, config);
+        let request = client
+            .post(&self.config.url)
+            .timeout(StdDuration::from_secs(PING_TIMEOUT_SECS))
+            .header(
+                USER_AGENT,
+                format!("homebridge-controller/{}", CURRENT_VERSION),
+            )
+            .json(&report);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => debug!("Sent usage ping: {:?}", report),
+                Err(e) => warn!("Usage ping failed: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn counts_active_program_types() {
+        let evening = |name: &str, active: bool| {
+            json!({
+                "name": name,
+                "active": active,
+                "minutes_before_sunset_start": 45,
+                "minutes_after_sunset_peak": 15,
+                "minutes_after_sunset_finish": 60,
+                "start_brightness": 30,
+                "max_brightness": 100,
+                "final_brightness": 75
+            })
+        };
+        let config: Configuration = serde_json::from_value(json!({
+            "turn_morning_lights_off": {
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": [
+                evening("office", true),
+                evening("hall", true),
+                evening("porch", false)
+            ],
+            "program_loop_pause": 2.0,
+            "bridge": { "host": "127.0.0.1" },
+            "latitude": 42.36,
+            "longitude": -71.06
+        }))
+        .unwrap();
+        let programs = ProgramRegistry::from_config(&config).unwrap();
+        let report = UsageReport::new(&programs, &config);
+        assert_eq!(
+            report.programs,
+            BTreeMap::from([
+                ("control_evening_lights", 2),
+                ("turn_morning_lights_off", 1)
+            ])
+        );
+        assert_eq!(report.version, CURRENT_VERSION);
+    }
+}
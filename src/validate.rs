@@ -0,0 +1,105 @@
+use crate::actions::Actions;
+use crate::configuration::Configuration;
+use crate::programs::registry::ProgramRegistry;
+use crate::suntimes;
+use chrono::{Days, NaiveDate};
+
+/// What a configuration check found: errors that keep the controller from starting or its
+/// programs from running, and warnings worth a look.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Days ahead checked for sunrise and sunset at the configured location.
+const SUN_CHECK_DAYS: u64 = 366;
+
+/// Check a loaded configuration the way the programs do at construction, and the location
+/// against the sun times of the coming year, without contacting the bridge or any API.
+pub fn check(config: &Configuration, today: NaiveDate) -> Report {
+    let mut report = Report::default();
+    report.warnings.extend(config.deprecations.iter().cloned());
+    for (id, problem) in ProgramRegistry::problems(config) {
+        report.errors.push(format!("{}: {}", id.name(), problem));
+    }
+    if let Err(e) = Actions::new(&config.scenes, &config.actions) {
+        report.errors.push(format!("actions: {}", e));
+    }
+    check_location(config, today, &mut report);
+    report
+}
+
+fn check_location(config: &Configuration, today: NaiveDate, report: &mut Report) {
+    if config.latitude == 0.0 && config.longitude == 0.0 {
+        report.errors.push(
+            "`latitude` and `longitude` are both 0 - set them to where the lights are".to_string(),
+        );
+        return;
+    }
+    let without: Vec<NaiveDate> = (0..SUN_CHECK_DAYS)
+        .filter_map(|n| today.checked_add_days(Days::new(n)))
+        .filter(|date| suntimes::calculate_day(config.latitude, config.longitude, *date).is_err())
+        .collect();
+    if let Some(first) = without.first() {
+        report.warnings.push(format!(
+            "No sunrise or sunset at {}, {} on {} day(s) of the coming year (first on {}); \
+             programs timed by the sun need a fallback time then",
+            config.latitude,
+            config.longitude,
+            without.len(),
+            first
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(latitude: f32, peak: i64) -> Configuration {
+        serde_json::from_value(json!({
+            "turn_morning_lights_off": {
+                "after_sunrise": 30,
+                "last_call_after_scheduled_off": 10
+            },
+            "control_evening_lights": {
+                "minutes_before_sunset_start": 45,
+                "minutes_after_sunset_peak": peak,
+                "minutes_after_sunset_finish": 60,
+                "start_brightness": 30,
+                "max_brightness": 100,
+                "final_brightness": 75
+            },
+            "program_loop_pause": 2.0,
+            "bridge": { "host": "127.0.0.1" },
+            "latitude": latitude,
+            "longitude": -71.06
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_program_and_location_problems() {
+        let today = NaiveDate::from_ymd_opt(2024, 12, 1).unwrap();
+        let report = check(&config(42.36, 15), today);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+
+        let report = check(&config(42.36, 90), today);
+        assert_eq!(
+            report.errors,
+            vec!["control_evening_lights: The time for peak must precede the finish time."]
+        );
+
+        let report = check(&config(78.2, 15), today);
+        assert!(report.errors.is_empty());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("(first on 2024-12-01)"));
+
+        let mut unset = config(0.0, 15);
+        unset.longitude = 0.0;
+        assert_eq!(check(&unset, today).errors.len(), 1);
+    }
+}
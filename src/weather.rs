@@ -0,0 +1,177 @@
+use crate::clock;
+use chrono::{DateTime, Duration, Local};
+use log::debug;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration as StdDuration;
+
+/// How long fetched forecast data is reused before requesting it again.
+const REFRESH_MINUTES: i64 = 60;
+/// The forecast is fetched in the program loop, so a slow API must not hold it up for long.
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum WeatherError {
+    #[error("Failed to connect to weather API.")]
+    FailedConnection(#[from] reqwest::Error),
+    #[error("{0}")]
+    ParseError(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HourlyPrecipitation {
+    time: Vec<i64>,
+    /// `null` for hours the model has no value for, e.g. at the end of the forecast.
+    precipitation: Vec<Option<f32>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ForecastResponse {
+    hourly: HourlyPrecipitation,
+}
+
+/// Hourly precipitation (past and forecast) from the Open-Meteo API.
+#[derive(Clone)]
+pub struct Weather {
+    longitude: f32,
+    latitude: f32,
+    fetched_at: Option<DateTime<Local>>,
+    hourly: Vec<(DateTime<Local>, f32)>,
+}
+
+impl Weather {
+    pub fn new(long: f32, lat: f32) -> Self {
+        Self {
+            longitude: long,
+            latitude: lat,
+            fetched_at: None,
+            hourly: Vec::new(),
+        }
+    }
+}
+
+impl Weather {
+    async fn collect_precipitation_data(&mut self, client: &Client) -> Result<(), WeatherError> {
+        let mut endpt = "https://api.open-meteo.com/v1/forecast?".to_string();
+        endpt.push_str(&format!(
+            "latitude={}&longitude={}",
+            self.latitude, self.longitude
+        ));
+        endpt.push_str("&hourly=precipitation&past_days=2&forecast_days=2&timeformat=unixtime");
+        let body = client
+            .get(&endpt)
+            .timeout(StdDuration::from_secs(REQUEST_TIMEOUT_SECS))
+            .send()
+            .await?
+            .text()
+            .await?;
+        self.load(&body)
+    }
+
+    /// Take over the hourly precipitation of a forecast response, leaving out hours without a
+    /// value.
+    pub fn load(&mut self, body: &str) -> Result<(), WeatherError> {
+        let data = serde_json::from_str::<ForecastResponse>(body)
+            .map_err(|e| WeatherError::ParseError(format!("Error parsing forecast: {}", e)))?;
+        if data.hourly.time.len() != data.hourly.precipitation.len() {
+            return Err(WeatherError::ParseError(
+                "Forecast times and values have different lengths.".to_string(),
+            ));
+        }
+        self.hourly = data
+            .hourly
+            .time
+            .iter()
+            .zip(data.hourly.precipitation.iter())
+            .filter_map(|(t, p)| Some((DateTime::from_timestamp(*t, 0)?.into(), (*p)?)))
+            .collect();
+        self.fetched_at = Some(clock::now());
+        debug!(
+            "Collected {} hours of precipitation data.",
+            self.hourly.len()
+        );
+        Ok(())
+    }
+
+    /// Take over the data a program fetched on its copy, if newer.
+    pub fn merge(&mut self, copy: Weather) {
+        if copy.fetched_at > self.fetched_at {
+            *self = copy;
+        }
+    }
+
+    /// Total precipitation (mm) between two times, observed or forecast.
+    pub async fn precipitation(
+        &mut self,
+        client: &Client,
+        from: &DateTime<Local>,
+        to: &DateTime<Local>,
+    ) -> Result<f32, WeatherError> {
+        let stale = match self.fetched_at {
+            Some(t) => clock::now() - t > Duration::minutes(REFRESH_MINUTES),
+            None => true,
+        };
+        if stale {
+            self.collect_precipitation_data(client).await?;
+        }
+        Ok(self
+            .hourly
+            .iter()
+            .filter(|(t, _)| from <= t && t <= to)
+            .map(|(_, p)| p)
+            .sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn sums_precipitation_skipping_hours_without_values() {
+        let now = clock::now();
+        let hour = |h: i64| (now + Duration::hours(h)).timestamp();
+        let body = json!({
+            "latitude": 42.36,
+            "hourly": {
+                "time": [hour(-3), hour(-2), hour(-1), hour(1), hour(2)],
+                "precipitation": [1.5, null, 0.5, 2.0, null]
+            }
+        });
+        let mut weather = Weather::new(-71.06, 42.36);
+        weather.load(&body.to_string()).unwrap();
+        assert_eq!(weather.hourly.len(), 3);
+
+        // Fresh data is not fetched again.
+        let client = Client::new();
+        let past = weather
+            .precipitation(&client, &(now - Duration::hours(4)), &now)
+            .await
+            .unwrap();
+        assert_eq!(past, 2.0);
+        let all = weather
+            .precipitation(
+                &client,
+                &(now - Duration::hours(4)),
+                &(now + Duration::hours(4)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(all, 4.0);
+    }
+
+    #[test]
+    fn rejects_malformed_forecasts() {
+        let mut weather = Weather::new(-71.06, 42.36);
+        let uneven = json!({"hourly": {"time": [1, 2], "precipitation": [0.1]}});
+        let error = weather.load(&uneven.to_string()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Forecast times and values have different lengths."
+        );
+        let missing = json!({"hourly": {"time": [1]}});
+        assert!(weather.load(&missing.to_string()).is_err());
+        assert!(weather.fetched_at.is_none());
+    }
+}
@@ -0,0 +1,290 @@
+use crate::configuration::{WebhookConfig, WebhookFormat};
+use crate::homebridge::StateChange;
+use crate::override_detector::numeric_value;
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// A value as text, without the quotes of a JSON string.
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Fill `{{placeholder}}` fields of a payload template from a state change.
+///
+/// A string that is exactly one placeholder is replaced by the raw JSON value, so
+/// `"{{new}}"` stays a number; placeholders inside longer strings are substituted as text.
+fn render(template: &Value, change: &StateChange) -> Value {
+    let field = |name: &str| -> Option<Value> {
+        match name {
+            "accessory" => Some(Value::String(change.accessory.clone())),
+            "characteristic" => Some(Value::String(change.characteristic.clone())),
+            "old" => Some(change.old.clone()),
+            "new" => Some(change.new.clone()),
+            "source" => Some(Value::String(change.source.clone())),
+            "when" => Some(Value::String(change.when.to_rfc3339())),
+            _ => None,
+        }
+    };
+    match template {
+        Value::String(s) => {
+            let trimmed = s.trim();
+            if let Some(name) = trimmed
+                .strip_prefix("{{")
+                .and_then(|t| t.strip_suffix("}}"))
+            {
+                if let Some(value) = field(name.trim()) {
+                    return value;
+                }
+            }
+            let mut out = s.clone();
+            for name in [
+                "accessory",
+                "characteristic",
+                "old",
+                "new",
+                "source",
+                "when",
+            ] {
+                let placeholder = format!("{{{{{}}}}}", name);
+                if out.contains(&placeholder) {
+                    out = out.replace(&placeholder, &as_text(&field(name).unwrap_or_default()));
+                }
+            }
+            Value::String(out)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, change)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render(v, change)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Body of the webhook for a change, in the hook's format.
+fn payload(hook: &WebhookConfig, change: &StateChange) -> Value {
+    match (hook.format, &hook.payload) {
+        (WebhookFormat::Ifttt, _) => json!({
+            "value1": change.accessory,
+            "value2": change.characteristic,
+            "value3": as_text(&change.new),
+        }),
+        (WebhookFormat::HomeAssistant, _) => json!({
+            "event_type": "homebridge_controller_state_changed",
+            "accessory": change.accessory,
+            "characteristic": change.characteristic,
+            "from_state": change.old,
+            "to_state": change.new,
+            "source": change.source,
+            "time_fired": change.when.to_rfc3339(),
+        }),
+        (WebhookFormat::Default, Some(template)) => render(template, change),
+        (WebhookFormat::Default, None) => serde_json::to_value(change).unwrap_or_default(),
+    }
+}
+
+fn matches(config: &WebhookConfig, change: &StateChange) -> bool {
+    let accessory_matches = config
+        .accessory
+        .as_ref()
+        .map_or(true, |a| a == &change.accessory);
+    let characteristic_matches = config
+        .characteristic
+        .as_ref()
+        .map_or(true, |c| c.as_str() == change.characteristic);
+    let value_matches = config.value.as_ref().map_or(true, |v| {
+        match (numeric_value(v), numeric_value(&change.new)) {
+            (Some(a), Some(b)) => a == b,
+            _ => v == &change.new,
+        }
+    });
+    accessory_matches && characteristic_matches && value_matches
+}
+
+/// Outbound webhooks fired for accessory changes matching their filters.
+pub struct Webhooks {
+    hooks: Vec<WebhookConfig>,
+    /// Sends still running in the background.
+    pending: Vec<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: &[WebhookConfig]) -> Self {
+        Self {
+            hooks: hooks.to_vec(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Send all webhooks matching the changes in the background, in order. Failures are logged
+    /// and otherwise ignored.
+    pub fn dispatch(&mut self, client: &Client, changes: &[StateChange]) {
+        let mut requests = Vec::new();
+        for change in changes.iter() {
+            debug!("State change: {:?}", change);
+            for hook in self.hooks.iter().filter(|h| matches(h, change)) {
+                let request = client
+                    .post(&hook.url)
+                    .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+                    .json(&payload(hook, change));
+                let sent = format!(
+                    "{} '{}' to {}",
+                    change.characteristic, change.accessory, hook.url
+                );
+                requests.push((hook.url.clone(), sent, request));
+            }
+        }
+        self.pending.retain(|send| !send.is_finished());
+        if requests.is_empty() {
+            return;
+        }
+        self.pending.push(tokio::spawn(async move {
+            for (url, sent, request) in requests {
+                match request.send().await {
+                    Ok(r) if r.status().is_success() => info!("Sent webhook for {}.", sent),
+                    Ok(r) => warn!("Webhook {} responded with {}.", url, r.status()),
+                    Err(e) => warn!("Failed to send webhook to {}: {}", url, e),
+                }
+            }
+        }));
+    }
+
+    /// Wait for the webhooks sent in the background, e.g. before the process exits.
+    pub async fn flush(&mut self) {
+        for send in self.pending.drain(..) {
+            if let Err(e) = send.await {
+                warn!("Failed to send webhooks: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+
+    fn change() -> StateChange {
+        StateChange {
+            when: Local.with_ymd_and_hms(2024, 12, 1, 17, 45, 0).unwrap(),
+            accessory: "Bed Light".to_string(),
+            characteristic: "Brightness".to_string(),
+            old: json!(20),
+            new: json!(45),
+            source: "control_evening_lights".to_string(),
+        }
+    }
+
+    #[test]
+    fn formats_payloads_for_presets() {
+        let change = change();
+        let mut hook: WebhookConfig =
+            serde_json::from_value(json!({"url": "http://localhost/hook", "format": "ifttt"}))
+                .unwrap();
+        assert_eq!(
+            payload(&hook, &change),
+            json!({"value1": "Bed Light", "value2": "Brightness", "value3": "45"})
+        );
+
+        hook.format = WebhookFormat::HomeAssistant;
+        let body = payload(&hook, &change);
+        assert_eq!(body["from_state"], json!(20));
+        assert_eq!(body["to_state"], json!(45));
+        assert_eq!(body["source"], json!("control_evening_lights"));
+
+        hook.format = WebhookFormat::Default;
+        hook.payload = Some(json!({"text": "{{accessory}} at {{new}}"}));
+        assert_eq!(payload(&hook, &change), json!({"text": "Bed Light at 45"}));
+    }
+
+    #[test]
+    fn renders_templates() {
+        let change = change();
+        let template = json!({
+            "value": "{{new}}",
+            "padded": " {{ old }} ",
+            "text": "{{characteristic}} of {{accessory}}: {{old}} -> {{new}} by {{source}}",
+            "at": "{{when}}",
+            "unknown": "{{colour}}",
+            "list": ["{{accessory}}", 1, null],
+            "nested": {"flag": true, "name": "{{accessory}}"}
+        });
+        assert_eq!(
+            render(&template, &change),
+            json!({
+                "value": 45,
+                "padded": 20,
+                "text": "Brightness of Bed Light: 20 -> 45 by control_evening_lights",
+                "at": change.when.to_rfc3339(),
+                "unknown": "{{colour}}",
+                "list": ["Bed Light", 1, null],
+                "nested": {"flag": true, "name": "Bed Light"}
+            })
+        );
+    }
+
+    #[test]
+    fn matches_changes_by_accessory_characteristic_and_value() {
+        let change = change();
+        let hook = |filters: Value| -> WebhookConfig {
+            let mut config = json!({"url": "http://localhost/hook"});
+            config
+                .as_object_mut()
+                .unwrap()
+                .extend(filters.as_object().unwrap().clone());
+            serde_json::from_value(config).unwrap()
+        };
+        assert!(matches(&hook(json!({})), &change));
+        assert!(matches(&hook(json!({"accessory": "Bed Light"})), &change));
+        assert!(!matches(&hook(json!({"accessory": "Desk Lamp"})), &change));
+        assert!(matches(
+            &hook(json!({"accessory": "Bed Light", "characteristic": "Brightness"})),
+            &change
+        ));
+        assert!(!matches(&hook(json!({"characteristic": "On"})), &change));
+        // Numbers compare by value, also when written as text.
+        assert!(matches(&hook(json!({"value": 45.0})), &change));
+        assert!(matches(&hook(json!({"value": "45"})), &change));
+        assert!(!matches(&hook(json!({"value": 44})), &change));
+        assert!(!matches(&hook(json!({"value": "bright"})), &change));
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_the_sends() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A slow receiver, answering well after the request arrived.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            counter.fetch_add(1, Ordering::SeqCst);
+            let response = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let hook: WebhookConfig = serde_json::from_value(json!({ "url": url })).unwrap();
+        let mut webhooks = Webhooks::new(&[hook]);
+        webhooks.dispatch(&Client::new(), &[change()]);
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+        webhooks.flush().await;
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert!(webhooks.pending.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use crate::configuration::GroupWriteConfig;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Order and spacing of writes that go to several accessories at once.
+///
+/// Consecutive accessories are `spacing` apart, unless that would make the whole group take
+/// longer than `max_total`, in which case the spacing shrinks so the group still fits.
+#[derive(Debug)]
+pub struct WriteQueue {
+    spacing: Duration,
+    max_total: Duration,
+    shuffle: bool,
+}
+
+impl Default for WriteQueue {
+    fn default() -> Self {
+        Self::from_config(&GroupWriteConfig::default())
+    }
+}
+
+impl WriteQueue {
+    pub fn new(spacing: Duration, max_total: Duration, shuffle: bool) -> Self {
+        Self {
+            spacing,
+            max_total,
+            shuffle,
+        }
+    }
+
+    pub fn from_config(config: &GroupWriteConfig) -> Self {
+        Self::new(
+            Duration::from_millis(config.spacing_ms),
+            Duration::from_millis(config.max_total_ms),
+            config.shuffle,
+        )
+    }
+
+    /// Pause between two accessories of a group of `count`.
+    fn spacing_for(&self, count: usize) -> Duration {
+        match count {
+            0 | 1 => Duration::ZERO,
+            _ => self.spacing.min(self.max_total / (count as u32 - 1)),
+        }
+    }
+
+    /// Accessories in the order to write them, each with the pause to take before it.
+    pub fn schedule<'a>(&self, accessories: &[&'a str]) -> Vec<(&'a str, Duration)> {
+        let mut order = accessories.to_vec();
+        if self.shuffle {
+            shuffle(&mut order);
+        }
+        let spacing = self.spacing_for(order.len());
+        order
+            .into_iter()
+            .enumerate()
+            .map(|(i, accessory)| match i {
+                0 => (accessory, Duration::ZERO),
+                _ => (accessory, spacing),
+            })
+            .collect()
+    }
+}
+
+/// Fisher-Yates shuffle seeded from the clock; the order only needs to vary between runs.
+fn shuffle<T>(items: &mut [T]) {
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |d| d.as_nanos() as u64)
+        | 1;
+    for i in (1..items.len()).rev() {
+        // xorshift64
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        items.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spacing_shrinks_to_fit_the_total() {
+        let queue = WriteQueue::new(Duration::from_millis(200), Duration::from_secs(1), false);
+        let few = queue.schedule(&["a", "b", "c"]);
+        assert_eq!(
+            few,
+            vec![
+                ("a", Duration::ZERO),
+                ("b", Duration::from_millis(200)),
+                ("c", Duration::from_millis(200)),
+            ]
+        );
+
+        let many: Vec<String> = (0..11).map(|i| i.to_string()).collect();
+        let many: Vec<&str> = many.iter().map(|s| s.as_str()).collect();
+        let total: Duration = queue.schedule(&many).iter().map(|(_, d)| *d).sum();
+        assert_eq!(total, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn shuffling_keeps_every_accessory() {
+        let queue = WriteQueue::new(Duration::ZERO, Duration::ZERO, true);
+        let mut order: Vec<&str> = queue
+            .schedule(&["a", "b", "c", "d"])
+            .into_iter()
+            .map(|(a, _)| a)
+            .collect();
+        order.sort();
+        assert_eq!(order, vec!["a", "b", "c", "d"]);
+    }
+}